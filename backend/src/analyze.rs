@@ -0,0 +1,674 @@
+//! Offline analysis of raw Tsunami traffic, for debugging wiring and
+//! logic-level converter problems without reading hex by hand.
+//!
+//! `analyze` splits bytes seen on the serial line into frames, decodes each
+//! with `wire::command` or `wire::response`, and flags anything that doesn't
+//! look like what the host or the sensor would send: bytes outside of any
+//! frame, corrupted flag or address bytes, frames cut short, unknown
+//! commands, and payloads of the wrong length. Replies are decoded as the
+//! response to the most recent request.
+//!
+//! Framing is heuristic. A corrupted header is only recognized if the frame
+//! it claims to start fits in the dump without overlapping a well-formed
+//! frame, and a frame is considered cut short if a well-formed header shows
+//! up inside it.
+use crate::capture;
+use crate::wire;
+use crate::wire::command;
+use crate::wire::response;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::result;
+
+/// Kind is what a span of the dump was identified as.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Kind {
+    /// A frame sent to the sensor.
+    Request,
+    /// A frame sent by the sensor.
+    Reply,
+    /// A frame with an address that is neither the request nor the reply
+    /// address.
+    Unknown,
+    /// Bytes that are not part of any frame.
+    Noise,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        let s = match self {
+            Kind::Request => "request",
+            Kind::Reply => "reply",
+            Kind::Unknown => "unknown",
+            Kind::Noise => "noise",
+        };
+        // Pad, so that kinds line up in `report`.
+        return f.pad(s);
+    }
+}
+
+/// Problem is something wrong with a span of the dump.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Problem {
+    /// The bytes are not part of any frame.
+    NotAFrame,
+    /// The frame starts with this byte instead of `wire::FLAG`.
+    BadFlag(u8),
+    /// The frame is addressed with this byte, which is neither
+    /// `wire::REQUEST_ADDRESS` nor `wire::REPLY_ADDRESS`.
+    BadAddress(u8),
+    /// The frame's length byte claims more payload than was received before
+    /// the dump ended or the next frame started.
+    Truncated { declared: usize, received: usize },
+    /// A request the sensor doesn't understand.
+    UnknownCommand(Vec<u8>),
+    /// A request for a known command, with the wrong number of bytes.
+    LengthMismatch {
+        command: String,
+        expected: usize,
+        got: usize,
+    },
+    /// A payload that failed to decode.
+    Undecodable(String),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            Problem::NotAFrame => write!(f, "not part of a frame"),
+            Problem::BadFlag(b) => write!(f, "bad flag {:02X}, expected {:02X}", b, wire::FLAG),
+            Problem::BadAddress(b) => write!(
+                f,
+                "bad address {:02X}, expected {:02X} or {:02X}",
+                b,
+                wire::REQUEST_ADDRESS,
+                wire::REPLY_ADDRESS
+            ),
+            Problem::Truncated { declared, received } => write!(
+                f,
+                "truncated, length byte declares {} payload bytes but {} arrived",
+                declared, received
+            ),
+            Problem::UnknownCommand(bs) => {
+                write!(f, "unknown command {}", capture::to_hex(bs))
+            }
+            Problem::LengthMismatch {
+                command,
+                expected,
+                got,
+            } => write!(
+                f,
+                "length mismatch, {} is {} bytes but got {}",
+                command, expected, got
+            ),
+            Problem::Undecodable(e) => write!(f, "undecodable, {}", e),
+        }
+    }
+}
+
+/// Frame is an annotated span of the dump.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    /// Where the span starts in the dump.
+    pub offset: usize,
+    pub bytes: Vec<u8>,
+    pub kind: Kind,
+    /// The decoded command or response, if it decoded.
+    pub decoded: Option<String>,
+    pub problems: Vec<Problem>,
+}
+
+impl Frame {
+    fn payload(&self) -> wire::Payload {
+        return wire::Payload(self.bytes[3..].to_vec());
+    }
+}
+
+// Reply is the response type a request is answered with.
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reply {
+    Ack,
+    GasPPM,
+    SerialNumber,
+    CompileSubvol,
+    CompileDate,
+    Elevation,
+    Status,
+    ABCState,
+    SelfTest,
+    Loopback,
+    StreamData,
+}
+
+type ParseResult = result::Result<String, wire::ParseError>;
+
+// Request is a command the sensor understands. A payload is this command if
+// it starts with `prefix`, and is `len` bytes long (any length if `None`).
+// Commands with arguments are described by `decode`.
+struct Request {
+    name: String,
+    prefix: Vec<u8>,
+    len: Option<usize>,
+    reply: Reply,
+    decode: Option<fn(wire::Payload) -> ParseResult>,
+}
+
+fn fixed<C: Into<wire::Payload> + fmt::Debug>(c: C, reply: Reply) -> Request {
+    let name = format!("{:?}", c);
+    let prefix: Vec<u8> = c.into().into();
+    return Request {
+        name: name,
+        len: Some(prefix.len()),
+        prefix: prefix,
+        reply: reply,
+        decode: None,
+    };
+}
+
+fn with_argument<C: Into<wire::Payload>>(
+    name: &str,
+    c: C,
+    arg_len: usize,
+    reply: Reply,
+    decode: fn(wire::Payload) -> ParseResult,
+) -> Request {
+    let full: Vec<u8> = c.into().into();
+    return Request {
+        name: String::from(name),
+        prefix: full[..full.len() - arg_len].to_vec(),
+        len: Some(full.len()),
+        reply: reply,
+        decode: Some(decode),
+    };
+}
+
+fn decode_update_elevation(p: wire::Payload) -> ParseResult {
+    let command::UpdateElevation(d) = command::UpdateElevation::try_from(p)?;
+    return Ok(format!("UpdateElevation({} ft)", d.feet()));
+}
+
+fn decode_set_single_point_ppm(p: wire::Payload) -> ParseResult {
+    let command::SetSinglePointPPM(c) = command::SetSinglePointPPM::try_from(p)?;
+    return Ok(format!("SetSinglePointPPM({} ppm)", c.ppm()));
+}
+
+fn decode_loopback(p: wire::Payload) -> ParseResult {
+    return Ok(format!("Loopback({})", capture::to_hex(&p[1..])));
+}
+
+// Every command the sensor understands.
+fn requests() -> Vec<Request> {
+    let feet = wire::Distance::Feet(0);
+    let ppm = wire::Concentration::PPM(0);
+    let mut loopback = fixed(command::Loopback(vec![]), Reply::Loopback);
+    loopback.name = String::from("Loopback");
+    loopback.len = None;
+    loopback.decode = Some(decode_loopback);
+    return vec![
+        fixed(command::Read(wire::Variable::GasPPM), Reply::GasPPM),
+        fixed(
+            command::Read(wire::Variable::SerialNumber),
+            Reply::SerialNumber,
+        ),
+        fixed(
+            command::Read(wire::Variable::CompileSubvol),
+            Reply::CompileSubvol,
+        ),
+        fixed(
+            command::Read(wire::Variable::CompileDate),
+            Reply::CompileDate,
+        ),
+        fixed(command::Read(wire::Variable::Elevation), Reply::Elevation),
+        with_argument(
+            "UpdateElevation",
+            command::UpdateElevation(feet),
+            2,
+            Reply::Ack,
+            decode_update_elevation,
+        ),
+        fixed(command::Warmup, Reply::Ack),
+        fixed(command::StartSinglePointCalibration, Reply::Ack),
+        fixed(command::VerifySinglePointCalibration, Reply::GasPPM),
+        with_argument(
+            "SetSinglePointPPM",
+            command::SetSinglePointPPM(ppm),
+            2,
+            Reply::Ack,
+            decode_set_single_point_ppm,
+        ),
+        fixed(command::Status, Reply::Status),
+        fixed(command::Idle(wire::Toggle::On), Reply::Ack),
+        fixed(command::Idle(wire::Toggle::Off), Reply::Ack),
+        fixed(command::ABCLogic, Reply::ABCState),
+        fixed(command::SetABCLogic(wire::Toggle::On), Reply::ABCState),
+        fixed(command::SetABCLogic(wire::Toggle::Off), Reply::ABCState),
+        fixed(command::ResetABCLogic, Reply::Ack),
+        fixed(command::Halt, Reply::Ack),
+        loopback,
+        fixed(command::StartSelfTest, Reply::Ack),
+        fixed(command::SelfTestResults, Reply::SelfTest),
+        fixed(command::StreamData, Reply::StreamData),
+    ];
+}
+
+// Decode a request, returning its description and the reply it expects.
+fn decode_request(p: wire::Payload) -> result::Result<(String, Reply), Problem> {
+    let known = requests();
+    if let Some(r) = known
+        .iter()
+        .find(|r| p.starts_with(&r.prefix) && r.len.is_none_or(|len| len == p.len()))
+    {
+        let decoded = match r.decode {
+            Some(decode) => decode(p).map_err(|e| Problem::Undecodable(e.to_string()))?,
+            None => r.name.clone(),
+        };
+        return Ok((decoded, r.reply));
+    }
+    // A payload that is too long for a known command, or that stops before
+    // its command bytes do.
+    let mismatched = known.iter().find(|r| {
+        !p.is_empty()
+            && (p.starts_with(&r.prefix) || (p.len() < r.prefix.len() && r.prefix.starts_with(&p)))
+    });
+    if let Some(r) = mismatched {
+        // Which of a family of commands (e.g., `Read(...)`) was meant can't
+        // be known when the bytes that tell them apart are missing.
+        let name = if p.len() < r.prefix.len() {
+            r.name.split('(').next().unwrap().to_string()
+        } else {
+            r.name.clone()
+        };
+        return Err(Problem::LengthMismatch {
+            command: name,
+            expected: r.len.unwrap_or(r.prefix.len()),
+            got: p.len(),
+        });
+    }
+    return Err(Problem::UnknownCommand(p.into()));
+}
+
+fn decode_reply(expected: Reply, p: wire::Payload) -> ParseResult {
+    return match expected {
+        Reply::Ack => response::Ack::try_from(p).map(|_| String::from("Ack")),
+        Reply::GasPPM => response::GasPPM::try_from(p)
+            .map(|g| format!("GasPPM({} ppm)", g.concentration().ppm())),
+        Reply::SerialNumber => response::SerialNumber::try_from(p)
+            .map(|s| format!("SerialNumber({:?})", s.to_string())),
+        Reply::CompileSubvol => response::CompileSubvol::try_from(p)
+            .map(|s| format!("CompileSubvol({:?})", s.to_string())),
+        Reply::CompileDate => {
+            response::CompileDate::try_from(p).map(|d| format!("CompileDate({})", d))
+        }
+        Reply::Elevation => response::Elevation::try_from(p)
+            .map(|response::Elevation(d)| format!("Elevation({} ft)", d.feet())),
+        Reply::Status => response::Status::try_from(p).map(|s| s.to_string()),
+        Reply::ABCState => response::ABCState::try_from(p).map(|s| format!("ABCState({:?})", s)),
+        Reply::SelfTest => response::SelfTest::try_from(p).map(|r| {
+            format!(
+                "SelfTest({}, {}/{} good DSP cycles)",
+                if r.passed() { "passed" } else { "failed" },
+                r.good_dsp_cycles(),
+                r.total_dsp_cycles()
+            )
+        }),
+        Reply::Loopback => response::Loopback::try_from(p)
+            .map(|response::Loopback(bs)| format!("Loopback({})", capture::to_hex(&bs))),
+        Reply::StreamData => response::StreamData::try_from(p)
+            .map(|s| format!("StreamData({} ppm)", s.concentration().ppm())),
+    };
+}
+
+fn is_address(b: u8) -> bool {
+    return b == wire::REQUEST_ADDRESS || b == wire::REPLY_ADDRESS;
+}
+
+// Returns `true` if a well-formed frame header starts at `bs[i]`.
+fn is_header(bs: &[u8], i: usize) -> bool {
+    return i + 1 < bs.len() && bs[i] == wire::FLAG && is_address(bs[i + 1]);
+}
+
+// Find the frame starting at `bs[i]`, if there is one. The frame is not yet
+// decoded.
+fn frame_at(bs: &[u8], i: usize) -> Option<Frame> {
+    if i + 2 >= bs.len() {
+        return None;
+    }
+    let (flag, address, declared) = (bs[i], bs[i + 1], bs[i + 2] as usize);
+    let mut problems = Vec::new();
+    if flag != wire::FLAG {
+        problems.push(Problem::BadFlag(flag));
+    }
+    if !is_address(address) {
+        problems.push(Problem::BadAddress(address));
+    }
+    let end = i + 3 + declared;
+    let next_header = (i + 1..end.min(bs.len())).find(|j| is_header(bs, *j));
+    match problems.len() {
+        0 => {}
+        // A header with one bad byte is only believed if the frame it
+        // starts is whole.
+        1 if end <= bs.len() && next_header.is_none() => {}
+        _ => return None,
+    }
+    let stop = match next_header {
+        Some(j) if j < end => j,
+        _ => end.min(bs.len()),
+    };
+    if stop < end {
+        problems.push(Problem::Truncated {
+            declared: declared,
+            received: stop.saturating_sub(i + 3),
+        });
+    }
+    let kind = match address {
+        wire::REQUEST_ADDRESS => Kind::Request,
+        wire::REPLY_ADDRESS => Kind::Reply,
+        _ => Kind::Unknown,
+    };
+    return Some(Frame {
+        offset: i,
+        bytes: bs[i..stop].to_vec(),
+        kind: kind,
+        decoded: None,
+        problems: problems,
+    });
+}
+
+// Decode `f`, given the reply expected from the last request. Updates
+// `expected` for the next frame.
+fn decode(f: &mut Frame, expected: &mut Option<Reply>) {
+    let truncated = f
+        .problems
+        .iter()
+        .any(|p| matches!(p, Problem::Truncated { .. }));
+    if truncated {
+        return;
+    }
+    match f.kind {
+        Kind::Request => match decode_request(f.payload()) {
+            Ok((decoded, reply)) => {
+                f.decoded = Some(decoded);
+                *expected = Some(reply);
+            }
+            Err(problem) => {
+                f.problems.push(problem);
+                *expected = None;
+            }
+        },
+        Kind::Reply => {
+            let reply = match *expected {
+                Some(r) => r,
+                None if f.payload().is_empty() => Reply::Ack,
+                None => {
+                    f.problems.push(Problem::Undecodable(String::from(
+                        "not a reply to any known request",
+                    )));
+                    return;
+                }
+            };
+            match decode_reply(reply, f.payload()) {
+                Ok(decoded) => f.decoded = Some(decoded),
+                Err(e) => f.problems.push(Problem::Undecodable(format!(
+                    "not a valid {:?} reply: {}",
+                    reply, e
+                ))),
+            }
+            // In stream mode, the sensor keeps replying until the next
+            // request.
+            if reply != Reply::StreamData {
+                *expected = None;
+            }
+        }
+        Kind::Unknown | Kind::Noise => {}
+    }
+}
+
+/// Split `bs`, bytes seen on the serial line, into annotated frames. Every
+/// byte of `bs` is covered by exactly one frame.
+pub fn analyze(bs: &[u8]) -> Vec<Frame> {
+    let mut frames = Vec::new();
+    let mut expected = None;
+    let mut noise_start = None;
+    let mut i = 0;
+    let flush_noise = |frames: &mut Vec<Frame>, start: Option<usize>, end: usize| {
+        if let Some(start) = start {
+            frames.push(Frame {
+                offset: start,
+                bytes: bs[start..end].to_vec(),
+                kind: Kind::Noise,
+                decoded: None,
+                problems: vec![Problem::NotAFrame],
+            });
+        }
+    };
+    while i < bs.len() {
+        match frame_at(bs, i) {
+            Some(mut f) => {
+                flush_noise(&mut frames, noise_start.take(), i);
+                decode(&mut f, &mut expected);
+                i += f.bytes.len();
+                frames.push(f);
+            }
+            None => {
+                noise_start.get_or_insert(i);
+                i += 1;
+            }
+        }
+    }
+    flush_noise(&mut frames, noise_start, bs.len());
+    return frames;
+}
+
+/// Parse a hex dump of serial traffic. Besides the format of
+/// `capture::to_hex`, bytes may be prefixed with `0x` and separated by commas
+/// or colons, as exported by most logic analyzers.
+pub fn parse_hex(s: &str) -> capture::Result<Vec<u8>> {
+    let cleaned = s
+        .replace("0x", "")
+        .replace("0X", "")
+        .replace([',', ':'], " ");
+    return capture::from_hex(&cleaned);
+}
+
+/// Write a human-readable report of `frames` to `out`: each frame's offset,
+/// kind and bytes, followed by what it decoded to and any problems with it.
+pub fn report<W: Write>(frames: &[Frame], out: &mut W) -> io::Result<()> {
+    for f in frames {
+        writeln!(
+            out,
+            "{:06X}  {:<7}  {}",
+            f.offset,
+            f.kind,
+            capture::to_hex(&f.bytes)
+        )?;
+        if let Some(decoded) = &f.decoded {
+            writeln!(out, "        {}", decoded)?;
+        }
+        for p in &f.problems {
+            writeln!(out, "        ! {}", p)?;
+        }
+    }
+    let with_problems = frames.iter().filter(|f| !f.problems.is_empty()).count();
+    writeln!(
+        out,
+        "{} frames ({} with problems)",
+        frames.len(),
+        with_problems
+    )?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request<P: Into<wire::Payload>>(p: P) -> Vec<u8> {
+        return wire::Message::from(p.into()).to_vec();
+    }
+
+    fn reply<P: Into<wire::Payload>>(p: P) -> Vec<u8> {
+        return wire::Message::reply(p.into()).to_vec();
+    }
+
+    fn summary(frames: &[Frame]) -> Vec<(Kind, Option<&str>, Vec<Problem>)> {
+        return frames
+            .iter()
+            .map(|f| (f.kind, f.decoded.as_deref(), f.problems.clone()))
+            .collect();
+    }
+
+    #[test]
+    fn test_session() {
+        let bs: Vec<u8> = [
+            request(command::Read(wire::Variable::GasPPM)),
+            reply(response::GasPPM::with_ppm(450)),
+            request(command::UpdateElevation(wire::Distance::Feet(1500))),
+            reply(response::Ack),
+            request(command::StreamData),
+            reply(response::StreamData::with_ppm(451)),
+            reply(response::StreamData::with_ppm(452)),
+        ]
+        .concat();
+        assert_eq!(
+            summary(&analyze(&bs)),
+            vec![
+                (Kind::Request, Some("Read(GasPPM)"), vec![]),
+                (Kind::Reply, Some("GasPPM(450 ppm)"), vec![]),
+                (Kind::Request, Some("UpdateElevation(1500 ft)"), vec![]),
+                (Kind::Reply, Some("Ack"), vec![]),
+                (Kind::Request, Some("StreamData"), vec![]),
+                (Kind::Reply, Some("StreamData(451 ppm)"), vec![]),
+                (Kind::Reply, Some("StreamData(452 ppm)"), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_framing() {
+        let bs = parse_hex(
+            // Noise, then a reply with a corrupted flag, a reply with a
+            // corrupted address, and a reply cut short by a request.
+            "00 13  FF FE 01 B6  7F FA 01 00  FF F8 01 00  FF FE 01 B6  FF FA 02 01  FF FE 01 B6",
+        )
+        .unwrap();
+        assert_eq!(
+            summary(&analyze(&bs)),
+            vec![
+                (Kind::Noise, None, vec![Problem::NotAFrame]),
+                (Kind::Request, Some("Status"), vec![]),
+                (
+                    Kind::Reply,
+                    Some("Status(.....)"),
+                    vec![Problem::BadFlag(0x7F)]
+                ),
+                (Kind::Unknown, None, vec![Problem::BadAddress(0xF8)]),
+                (Kind::Request, Some("Status"), vec![]),
+                (
+                    Kind::Reply,
+                    None,
+                    vec![Problem::Truncated {
+                        declared: 2,
+                        received: 1
+                    }]
+                ),
+                (Kind::Request, Some("Status"), vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bad_payloads() {
+        let bs: Vec<u8> = [
+            request(wire::Payload(vec![0x02, 0x55])),
+            request(wire::Payload(vec![0xB6, 0x00])),
+            request(wire::Payload(vec![0x02])),
+            request(command::Read(wire::Variable::Elevation)),
+            reply(wire::Payload(vec![0x05])),
+            reply(response::GasPPM::with_ppm(400)),
+        ]
+        .concat();
+        assert_eq!(
+            summary(&analyze(&bs)),
+            vec![
+                (
+                    Kind::Request,
+                    None,
+                    vec![Problem::UnknownCommand(vec![0x02, 0x55])]
+                ),
+                (
+                    Kind::Request,
+                    None,
+                    vec![Problem::LengthMismatch {
+                        command: String::from("Status"),
+                        expected: 1,
+                        got: 2
+                    }]
+                ),
+                (
+                    Kind::Request,
+                    None,
+                    vec![Problem::LengthMismatch {
+                        command: String::from("Read"),
+                        expected: 2,
+                        got: 1
+                    }]
+                ),
+                (Kind::Request, Some("Read(Elevation)"), vec![]),
+                (
+                    Kind::Reply,
+                    None,
+                    vec![Problem::Undecodable(String::from(
+                        "not a valid Elevation reply: payload should be 2 bytes, got 1"
+                    ))]
+                ),
+                (
+                    Kind::Reply,
+                    None,
+                    vec![Problem::Undecodable(String::from(
+                        "not a reply to any known request"
+                    ))]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_capture() {
+        let capture =
+            capture::Capture::parse(include_str!("../testdata/noisy_reply.capture")).unwrap();
+        let frames = analyze(&capture.bytes());
+        assert_eq!(
+            summary(&frames),
+            vec![
+                (Kind::Request, Some("Read(GasPPM)"), vec![]),
+                (Kind::Noise, None, vec![Problem::NotAFrame]),
+                (Kind::Reply, Some("GasPPM(706 ppm)"), vec![]),
+            ]
+        );
+
+        let mut out = Vec::new();
+        report(&frames, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "000000  request  FF FE 02 02 03\n        Read(GasPPM)\n\
+             000005  noise    00 13 FF 01\n        ! not part of a frame\n\
+             000009  reply    FF FA 02 02 C2\n        GasPPM(706 ppm)\n\
+             3 frames (1 with problems)\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_hex() {
+        assert_eq!(
+            parse_hex("0xFF,0xFA, 0x01\n0x00").unwrap(),
+            vec![0xFF, 0xFA, 0x01, 0x00]
+        );
+        assert_eq!(
+            parse_hex("ff:fa:01:00").unwrap(),
+            vec![0xFF, 0xFA, 0x01, 0x00]
+        );
+        assert!(parse_hex("FF F").is_err());
+    }
+}
@@ -0,0 +1,430 @@
+//! Types served by the versioned JSON API, under `/api/v1`.
+//!
+//! The original routes serve bare numbers, e.g., `/co2` is just the ppm. The
+//! versioned API serves JSON objects instead, with units spelled out and
+//! timestamps attached, so a client can tell how fresh a value is. Every
+//! error is served in the same envelope, see `ErrorBody`. Fields may be
+//! added to a version of the API, but are never changed or removed.
+use crate::calibration;
+use crate::device;
+use crate::history;
+use crate::wire;
+use serde;
+
+/// The path every route of this version of the API lives under.
+pub const PREFIX: &str = "/api/v1";
+
+// How many feet there are in a meter.
+const FEET_PER_METER: f64 = 3.28084;
+
+/// Status is the set of status flags the sensor reported.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Status {
+    pub error: bool,
+    pub warmup: bool,
+    pub calibration: bool,
+    pub idle: bool,
+    pub self_test: bool,
+}
+
+impl Status {
+    /// Each flag's name, as served, and whether it's set. The names are also
+    /// the `flag` label of the `co2_status` metric.
+    pub fn flags(&self) -> [(&'static str, bool); 5] {
+        return [
+            ("error", self.error),
+            ("warmup", self.warmup),
+            ("calibration", self.calibration),
+            ("idle", self.idle),
+            ("self_test", self.self_test),
+        ];
+    }
+}
+
+impl From<wire::response::Status> for Status {
+    fn from(s: wire::response::Status) -> Status {
+        return Status {
+            error: s.is_err(),
+            warmup: s.in_warmup(),
+            calibration: s.in_calibration(),
+            idle: s.in_idle(),
+            self_test: s.in_self_test(),
+        };
+    }
+}
+
+/// Source is the sensor a reading was taken from.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Source {
+    pub model: String,
+    /// The sensor's serial number, if it reported one.
+    pub serial_number: Option<String>,
+}
+
+impl Source {
+    pub fn new(identity: Option<&device::Identity>) -> Source {
+        return Source {
+            model: String::from("Telaire T6615"),
+            serial_number: identity.map(|id| id.serial_number.clone()),
+        };
+    }
+}
+
+/// Reading is a CO2 measurement, served by `GET /api/v1/co2`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Reading {
+    pub ppm: u16,
+    pub measured_at: chrono::DateTime<chrono::Utc>,
+    /// How long ago the measurement was taken, as of the response.
+    pub age_seconds: f64,
+    /// The sensor's status when the measurement was taken, if known.
+    pub status: Option<Status>,
+    pub source: Source,
+}
+
+impl Reading {
+    /// Describe `r` as of `now`, as taken by the sensor with `identity`.
+    pub fn new(
+        r: &device::Reading,
+        identity: Option<&device::Identity>,
+        now: chrono::DateTime<chrono::Utc>,
+    ) -> Reading {
+        return Reading {
+            ppm: r.concentration.ppm(),
+            measured_at: r.at,
+            age_seconds: (now - r.at).num_milliseconds().max(0) as f64 / 1000.0,
+            status: r.status.map(Status::from),
+            source: Source::new(identity),
+        };
+    }
+}
+
+/// History is the recorded measurements, summarized, served by
+/// `GET /api/v1/history`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct History {
+    pub buckets: Vec<history::Bucket>,
+}
+
+/// Unit is a unit of distance.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub enum Unit {
+    #[serde(rename = "ft")]
+    Feet,
+    #[serde(rename = "m")]
+    Meters,
+}
+
+impl Unit {
+    pub fn symbol(&self) -> &'static str {
+        return match self {
+            Unit::Feet => "ft",
+            Unit::Meters => "m",
+        };
+    }
+}
+
+/// Elevation is the elevation the sensor is configured for. Served in feet,
+/// the unit the sensor uses, but may be configured in either unit.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Elevation {
+    pub value: u16,
+    pub unit: Unit,
+}
+
+impl Elevation {
+    pub fn feet(d: wire::Distance) -> Elevation {
+        return Elevation {
+            value: d.feet(),
+            unit: Unit::Feet,
+        };
+    }
+
+    /// The elevation as a distance the sensor understands, rounded to the
+    /// nearest foot. Fails if it's too high to be represented.
+    pub fn distance(&self) -> Result<wire::Distance, String> {
+        let feet = match self.unit {
+            Unit::Feet => self.value as f64,
+            Unit::Meters => (self.value as f64 * FEET_PER_METER).round(),
+        };
+        if feet > u16::MAX as f64 {
+            return Err(format!(
+                "elevation {} {} is too high",
+                self.value,
+                self.unit.symbol()
+            ));
+        }
+        return Ok(wire::Distance::Feet(feet as u16));
+    }
+}
+
+/// ABCSetting is an automatic baseline correction (ABC) state, or action,
+/// as exchanged over HTTP.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ABCSetting {
+    On,
+    Off,
+    Reset,
+}
+
+impl From<wire::response::ABCState> for ABCSetting {
+    fn from(a: wire::response::ABCState) -> ABCSetting {
+        match a {
+            wire::response::ABCState::On => ABCSetting::On,
+            wire::response::ABCState::Off => ABCSetting::Off,
+        }
+    }
+}
+
+/// Abc is the sensor's automatic baseline correction (ABC) setting. Served
+/// as `on` or `off`, and may also be configured to `reset`.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Abc {
+    pub state: ABCSetting,
+}
+
+/// Ready is whether the sensor is free to take requests, served by
+/// `GET /api/v1/ready`.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+pub struct Ready {
+    pub ready: bool,
+}
+
+/// SelfTestReport is the result of a sensor self-test.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SelfTestReport {
+    pub passed: bool,
+    pub good_dsp_cycles: u8,
+    pub total_dsp_cycles: u8,
+}
+
+impl From<&wire::response::SelfTest> for SelfTestReport {
+    fn from(r: &wire::response::SelfTest) -> SelfTestReport {
+        return SelfTestReport {
+            passed: r.passed(),
+            good_dsp_cycles: r.good_dsp_cycles(),
+            total_dsp_cycles: r.total_dsp_cycles(),
+        };
+    }
+}
+
+/// SelfTest is the result of the last self-test, served by
+/// `GET /api/v1/selftest`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct SelfTest {
+    /// The result of the last self-test, if one has finished.
+    pub last: Option<SelfTestReport>,
+    /// Why the last self-test couldn't be run, if it couldn't.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Event is something that happened to the sensor, pushed to subscribers of
+/// `GET /api/v1/stream` as it happens.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum Event {
+    /// A new measurement was taken.
+    Reading(Reading),
+    /// The sensor's status flags changed since the previous measurement.
+    Status(Status),
+    /// A calibration moved to a new state, see `calibration::State`.
+    Calibration(calibration::Job),
+    /// A self-test finished.
+    SelfTest(SelfTestReport),
+}
+
+impl Event {
+    /// The name of the kind of event, e.g., `reading`.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Event::Reading(_) => "reading",
+            Event::Status(_) => "status",
+            Event::Calibration(_) => "calibration",
+            Event::SelfTest(_) => "self_test",
+        };
+    }
+
+    /// Format the event as a server-sent event, named after the kind of
+    /// event, with the JSON of what happened as its data.
+    pub fn to_sse(&self) -> serde_json::Result<String> {
+        let data = match self {
+            Event::Reading(r) => serde_json::to_string(r)?,
+            Event::Status(s) => serde_json::to_string(s)?,
+            Event::Calibration(j) => serde_json::to_string(j)?,
+            Event::SelfTest(r) => serde_json::to_string(r)?,
+        };
+        return Ok(format!("event: {}\ndata: {}\n\n", self.name(), data));
+    }
+}
+
+/// Request is a message sent by a client of the control channel,
+/// `GET /api/v1/control`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Request {
+    /// Chosen by the client, and attached to the reply to the request.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+/// Command is what a client of the control channel asks for. Every command
+/// but `authenticate` is refused until the client has authenticated.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Command {
+    Authenticate {
+        token: String,
+    },
+    /// Start calibrating, as `POST /api/v1/calibration` does. Progress is
+    /// sent as `calibration` events.
+    Calibrate {
+        reference_ppm: Option<u16>,
+        check_stability: Option<bool>,
+    },
+    SetElevation {
+        elevation: Elevation,
+    },
+    SetAbc {
+        state: ABCSetting,
+    },
+    /// Start a self-test. The result is sent as a `self_test` event.
+    SelfTest,
+}
+
+/// Reply is a message sent to a client of the control channel.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply {
+    /// The client authenticated, and may now send commands.
+    Authenticated,
+    /// A command succeeded, with what the matching route of the API would
+    /// serve, e.g., the new `Elevation`.
+    Result {
+        id: Option<u64>,
+        data: serde_json::Value,
+    },
+    /// A command failed, or a message couldn't be understood.
+    Error { id: Option<u64>, error: ErrorDetail },
+    /// Something happened to the sensor. Only sent once authenticated.
+    Event { event: Event },
+}
+
+/// ErrorBody is the envelope every error is served in.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ErrorBody {
+    pub error: ErrorDetail,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ErrorDetail {
+    /// The HTTP status the error is served with.
+    pub status: u16,
+    /// A stable, machine-readable name for the kind of error, e.g., `busy`.
+    pub code: String,
+    /// A human-readable description of what went wrong.
+    pub message: String,
+}
+
+impl ErrorBody {
+    pub fn new(status: u16, code: &str, message: String) -> ErrorBody {
+        return ErrorBody {
+            error: ErrorDetail {
+                status: status,
+                code: String::from(code),
+                message: message,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_reading() {
+        let mut flags = wire::response::StatusFlags::default();
+        flags.in_warmup = true;
+        let mut r = device::Reading::new(
+            wire::Concentration::PPM(612),
+            chrono::Utc.timestamp(1_600_000_000, 0),
+        );
+        r.status = Some(flags.into());
+        let got = Reading::new(&r, None, chrono::Utc.timestamp(1_600_000_015, 500_000_000));
+        assert_eq!(
+            serde_json::to_value(&got).unwrap(),
+            serde_json::json!({
+                "ppm": 612,
+                "measured_at": "2020-09-13T12:26:40Z",
+                "age_seconds": 15.5,
+                "status": {
+                    "error": false,
+                    "warmup": true,
+                    "calibration": false,
+                    "idle": false,
+                    "self_test": false,
+                },
+                "source": {"model": "Telaire T6615", "serial_number": null},
+            })
+        );
+    }
+
+    #[test]
+    fn test_elevation() {
+        assert_eq!(
+            serde_json::to_string(&Elevation::feet(wire::Distance::Feet(1500))).unwrap(),
+            r#"{"value":1500,"unit":"ft"}"#
+        );
+        let e: Elevation = serde_json::from_str(r#"{"value":500,"unit":"m"}"#).unwrap();
+        assert_eq!(e.distance(), Ok(wire::Distance::Feet(1640)));
+        let e: Elevation = serde_json::from_str(r#"{"value":60000,"unit":"m"}"#).unwrap();
+        assert!(e.distance().is_err());
+        assert!(serde_json::from_str::<Elevation>(r#"{"value":500}"#).is_err());
+        assert!(serde_json::from_str::<Elevation>(r#"{"value":500,"unit":"yd"}"#).is_err());
+    }
+
+    #[test]
+    fn test_request() {
+        let r: Request =
+            serde_json::from_str(r#"{"type":"set_abc","id":7,"state":"off"}"#).unwrap();
+        assert_eq!(
+            r,
+            Request {
+                id: Some(7),
+                command: Command::SetAbc {
+                    state: ABCSetting::Off
+                },
+            }
+        );
+        let r: Request = serde_json::from_str(r#"{"type":"calibrate"}"#).unwrap();
+        assert_eq!(
+            r.command,
+            Command::Calibrate {
+                reference_ppm: None,
+                check_stability: None,
+            }
+        );
+        assert!(serde_json::from_str::<Request>(r#"{"type":"reboot"}"#).is_err());
+    }
+
+    #[test]
+    fn test_event_sse() {
+        let status = Status::from(wire::response::Status::from(
+            wire::response::StatusFlags::default(),
+        ));
+        assert_eq!(
+            Event::Status(status).to_sse().unwrap(),
+            "event: status\ndata: {\"error\":false,\"warmup\":false,\"calibration\":false,\"idle\":false,\"self_test\":false}\n\n"
+        );
+        assert_eq!(
+            serde_json::to_value(Event::Status(status)).unwrap()["type"],
+            "status"
+        );
+    }
+}
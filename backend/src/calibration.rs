@@ -0,0 +1,472 @@
+//! Tracking of calibration jobs.
+//!
+//! Calibrating the sensor takes minutes, so it runs as a background job.
+//! `Tracker` follows the job in progress through each `State`, and keeps the
+//! results of the last few jobs. The results can be persisted to a file,
+//! one JSON object per line, so they survive a restart. The file is
+//! compacted to the retained results whenever it is opened.
+//!
+//! The sensor can be calibrated against outdoor air, or against bottled
+//! reference gas of a known concentration. Either way, the reference must be
+//! within `MIN_REFERENCE_PPM` and `MAX_REFERENCE_PPM`, and a job can first
+//! check that the readings have settled, see `Stability`.
+use crate::device;
+use crate::wire;
+use log::warn;
+use serde;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::result;
+use std::time;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        return self.0.fmt(f);
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<&str> for Error {
+    fn from(e: &str) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// How many finished jobs are kept.
+pub const HISTORY_LEN: usize = 20;
+
+/// The lowest reference concentration accepted. Outdoor air hasn't been this
+/// low in centuries, so a lower reference is almost certainly a typo.
+pub const MIN_REFERENCE_PPM: u16 = 300;
+
+/// The highest reference concentration accepted, the top of the widest
+/// range T6615 sensors measure.
+pub const MAX_REFERENCE_PPM: u16 = 5000;
+
+/// Check that `reference` is a concentration the sensor can be calibrated
+/// against.
+pub fn check_reference(reference: wire::Concentration) -> Result<()> {
+    let ppm = reference.ppm();
+    if !(MIN_REFERENCE_PPM..=MAX_REFERENCE_PPM).contains(&ppm) {
+        return Err(Error(format!(
+            "reference {} ppm is outside {}-{} ppm",
+            ppm, MIN_REFERENCE_PPM, MAX_REFERENCE_PPM
+        )));
+    }
+    return Ok(());
+}
+
+/// Options configures how a calibration job runs.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Options {
+    /// Check the readings have settled before calibrating, if set.
+    pub stability: Option<Stability>,
+    /// How long the device may take to calibrate before the job gives up.
+    pub timeout: time::Duration,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        return Options {
+            stability: None,
+            timeout: device::CALIBRATION_TIMEOUT,
+        };
+    }
+}
+
+/// Stability configures the check, before calibrating, that the readings
+/// have settled, e.g., once the sensor has been carried outdoors or the
+/// reference gas has flushed its chamber.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Stability {
+    /// How many readings to take. At least 2.
+    pub samples: usize,
+    /// How long to wait between readings.
+    pub interval: time::Duration,
+    /// How far apart the readings may be, in ppm.
+    pub tolerance_ppm: u16,
+}
+
+impl Default for Stability {
+    fn default() -> Stability {
+        return Stability {
+            samples: 5,
+            interval: time::Duration::from_secs(10),
+            tolerance_ppm: 10,
+        };
+    }
+}
+
+impl Stability {
+    /// Check that `readings` are within the tolerance of each other.
+    pub fn check(&self, readings: &[wire::Concentration]) -> Result<()> {
+        let ppms = readings.iter().map(|c| c.ppm());
+        let (min, max) = match (ppms.clone().min(), ppms.max()) {
+            (Some(min), Some(max)) => (min, max),
+            _ => return Err(Error::from("no readings to check")),
+        };
+        if max - min > self.tolerance_ppm {
+            return Err(Error(format!(
+                "readings are still moving: {}-{} ppm across {} readings, more than the {} ppm tolerance",
+                min,
+                max,
+                readings.len(),
+                self.tolerance_ppm
+            )));
+        }
+        return Ok(());
+    }
+}
+
+/// State is how far along a calibration job is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    /// Waiting for the device to be free.
+    Requested,
+    /// Checking the readings have settled, see `Stability`.
+    CheckingStability,
+    /// Setting the reference concentration, and checking the device took it.
+    VerifyingReference,
+    /// Waiting for the device to enter calibration.
+    WaitingForCalibration,
+    /// The device is calibrating.
+    Calibrating,
+    Succeeded,
+    /// The job failed, see `Job::reason`.
+    Failed,
+    /// The job was cancelled before it finished.
+    Cancelled,
+}
+
+impl State {
+    /// Returns `true` once the job has succeeded, failed or been cancelled.
+    pub fn is_finished(&self) -> bool {
+        return matches!(self, State::Succeeded | State::Failed | State::Cancelled);
+    }
+}
+
+impl From<device::CalibrationStage> for State {
+    fn from(s: device::CalibrationStage) -> State {
+        return match s {
+            device::CalibrationStage::VerifyingReference => State::VerifyingReference,
+            device::CalibrationStage::WaitingForCalibration => State::WaitingForCalibration,
+            device::CalibrationStage::Calibrating => State::Calibrating,
+        };
+    }
+}
+
+/// Job is a single calibration of the device.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Job {
+    pub id: u64,
+    /// The concentration the device is calibrated against.
+    pub reference_ppm: u16,
+    pub state: State,
+    /// Why the job failed, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub requested_at: chrono::DateTime<chrono::Utc>,
+    /// When the device started on the job.
+    pub started_at: Option<chrono::DateTime<chrono::Utc>>,
+    pub finished_at: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Summary is the job in progress, if any, and the most recent finished
+/// jobs, newest first.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Summary {
+    pub current: Option<Job>,
+    pub history: Vec<Job>,
+}
+
+/// Tracker keeps track of the calibration job in progress, and the results
+/// of finished jobs.
+#[derive(Debug, Default)]
+pub struct Tracker {
+    current: Option<Job>,
+    // Ordered from oldest to newest.
+    history: VecDeque<Job>,
+    next_id: u64,
+    path: Option<path::PathBuf>,
+}
+
+impl Tracker {
+    /// A tracker that only keeps results in memory.
+    pub fn new() -> Tracker {
+        return Tracker::default();
+    }
+
+    /// A tracker that persists results to the file at `p`, loading any
+    /// results already there. Lines that fail to parse, e.g., one torn by a
+    /// crash, are skipped.
+    pub fn open<P: Into<path::PathBuf>>(p: P) -> Result<Tracker> {
+        let p = p.into();
+        let mut history = VecDeque::new();
+        let contents = match fs::read_to_string(&p) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(Error(format!("failed to read {}: {}", p.display(), e))),
+        };
+        for line in contents.lines().filter(|l| !l.trim().is_empty()) {
+            match serde_json::from_str::<Job>(line) {
+                Ok(job) => history.push_back(job),
+                Err(e) => warn!("skipping calibration result in {}: {}", p.display(), e),
+            }
+        }
+        while history.len() > HISTORY_LEN {
+            history.pop_front();
+        }
+
+        // Compact the file, by writing out the retained history and moving
+        // it into place.
+        let tmp = p.with_extension("tmp");
+        let mut f = fs::File::create(&tmp)?;
+        for job in history.iter() {
+            writeln!(f, "{}", serde_json::to_string(job)?)?;
+        }
+        f.sync_all()?;
+        fs::rename(&tmp, &p)?;
+
+        let next_id = history.iter().map(|j| j.id + 1).max().unwrap_or(0);
+        return Ok(Tracker {
+            current: None,
+            history: history,
+            next_id: next_id,
+            path: Some(p),
+        });
+    }
+
+    /// The job in progress, if any.
+    pub fn current(&self) -> Option<&Job> {
+        return self.current.as_ref();
+    }
+
+    /// The most recently finished job, if any.
+    pub fn last(&self) -> Option<&Job> {
+        return self.history.back();
+    }
+
+    pub fn summary(&self) -> Summary {
+        return Summary {
+            current: self.current.clone(),
+            history: self.history.iter().rev().cloned().collect(),
+        };
+    }
+
+    /// Start tracking a new job against `reference`, requested at `at`.
+    /// Returns `None` if a job is already in progress.
+    pub fn request(
+        &mut self,
+        reference: wire::Concentration,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> Option<Job> {
+        if self.current.is_some() {
+            return None;
+        }
+        let job = Job {
+            id: self.next_id,
+            reference_ppm: reference.ppm(),
+            state: State::Requested,
+            reason: None,
+            requested_at: at,
+            started_at: None,
+            finished_at: None,
+        };
+        self.next_id += 1;
+        self.current = Some(job.clone());
+        return Some(job);
+    }
+
+    /// Move the job in progress to `state` at `at`. Finishing a job is done
+    /// with `finish`.
+    pub fn advance(&mut self, state: State, at: chrono::DateTime<chrono::Utc>) {
+        assert!(!state.is_finished(), "jobs are finished with `finish`");
+        if let Some(job) = self.current.as_mut() {
+            if job.started_at.is_none() && state != State::Requested {
+                job.started_at = Some(at);
+            }
+            job.state = state;
+        }
+    }
+
+    /// Finish the job in progress at `at`, failed with a reason if `result`
+    /// is an error, and record it in the history. Returns the finished job,
+    /// or `None` if no job was in progress. The job is recorded in memory
+    /// even if persisting it fails.
+    pub fn finish(
+        &mut self,
+        result: result::Result<(), String>,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Job>> {
+        return match result {
+            Ok(()) => self.close(State::Succeeded, None, at),
+            Err(reason) => self.close(State::Failed, Some(reason), at),
+        };
+    }
+
+    /// Like `finish`, but records the job in progress as cancelled.
+    pub fn finish_cancelled(&mut self, at: chrono::DateTime<chrono::Utc>) -> Result<Option<Job>> {
+        return self.close(State::Cancelled, None, at);
+    }
+
+    fn close(
+        &mut self,
+        state: State,
+        reason: Option<String>,
+        at: chrono::DateTime<chrono::Utc>,
+    ) -> Result<Option<Job>> {
+        let mut job = match self.current.take() {
+            Some(job) => job,
+            None => return Ok(None),
+        };
+        job.state = state;
+        job.reason = reason;
+        job.started_at.get_or_insert(at);
+        job.finished_at = Some(at);
+        self.history.push_back(job.clone());
+        while self.history.len() > HISTORY_LEN {
+            self.history.pop_front();
+        }
+        if let Some(p) = &self.path {
+            let mut f = fs::OpenOptions::new().create(true).append(true).open(p)?;
+            writeln!(f, "{}", serde_json::to_string(&job)?)?;
+            f.sync_all()?;
+        }
+        return Ok(Some(job));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use chrono::TimeZone;
+
+    fn at(secs: i64) -> chrono::DateTime<chrono::Utc> {
+        return chrono::Utc.timestamp(1_600_000_000 + secs, 0);
+    }
+
+    #[test]
+    fn test_job_lifecycle() {
+        let mut t = Tracker::new();
+        let job = t.request(wire::Concentration::PPM(410), at(0)).unwrap();
+        assert_eq!(job.state, State::Requested);
+        assert_eq!(job.started_at, None);
+        // Only one job runs at a time.
+        assert_eq!(t.request(wire::Concentration::PPM(410), at(1)), None);
+
+        t.advance(State::VerifyingReference, at(2));
+        t.advance(State::Calibrating, at(3));
+        let current = t.current().unwrap();
+        assert_eq!(current.state, State::Calibrating);
+        assert_eq!(current.started_at, Some(at(2)));
+
+        let done = t.finish(Ok(()), at(60)).unwrap().unwrap();
+        assert_eq!(done.state, State::Succeeded);
+        assert_eq!(done.finished_at, Some(at(60)));
+        assert_eq!(t.current(), None);
+        assert_eq!(t.last(), Some(&done));
+
+        let second = t.request(wire::Concentration::PPM(420), at(61)).unwrap();
+        assert_eq!(second.id, job.id + 1);
+        let failed = t
+            .finish(Err(String::from("timed out")), at(62))
+            .unwrap()
+            .unwrap();
+        assert_eq!(failed.state, State::Failed);
+        assert_eq!(failed.reason.as_deref(), Some("timed out"));
+
+        t.request(wire::Concentration::PPM(420), at(63)).unwrap();
+        let cancelled = t.finish_cancelled(at(64)).unwrap().unwrap();
+        assert_eq!(cancelled.state, State::Cancelled);
+        assert!(cancelled.state.is_finished());
+        assert_eq!(t.current(), None);
+        assert_eq!(
+            t.summary()
+                .history
+                .iter()
+                .map(|j| j.id)
+                .collect::<Vec<u64>>(),
+            vec![cancelled.id, second.id, job.id]
+        );
+    }
+
+    #[test]
+    fn test_check_reference() {
+        assert!(check_reference(wire::Concentration::PPM(425)).is_ok());
+        // Bottled reference gas.
+        assert!(check_reference(wire::Concentration::PPM(2000)).is_ok());
+        assert!(check_reference(wire::Concentration::PPM(MIN_REFERENCE_PPM)).is_ok());
+        assert!(check_reference(wire::Concentration::PPM(MAX_REFERENCE_PPM)).is_ok());
+        assert!(check_reference(wire::Concentration::PPM(0)).is_err());
+        assert!(check_reference(wire::Concentration::PPM(41)).is_err());
+        assert!(check_reference(wire::Concentration::PPM(MAX_REFERENCE_PPM + 1)).is_err());
+    }
+
+    #[test]
+    fn test_stability_check() {
+        let s = Stability::default();
+        let ppm = |v: &[u16]| -> Vec<wire::Concentration> {
+            return v.iter().map(|p| wire::Concentration::PPM(*p)).collect();
+        };
+        assert!(s.check(&ppm(&[420, 425, 418, 422, 428])).is_ok());
+        // Still settling after being carried outdoors.
+        let err = s.check(&ppm(&[900, 700, 560, 470, 440])).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "readings are still moving: 440-900 ppm across 5 readings, more than the 10 ppm tolerance"
+        );
+        assert!(s.check(&[]).is_err());
+    }
+
+    #[test]
+    fn test_history_survives_restart() {
+        let dir = TempDir::new("calibration-history");
+        let p = dir.path().join("calibrations.jsonl");
+
+        let mut t = Tracker::open(&p).unwrap();
+        for i in 0..(HISTORY_LEN as i64 + 5) {
+            t.request(wire::Concentration::PPM(410), at(i)).unwrap();
+            t.finish(Ok(()), at(i)).unwrap();
+        }
+        let want = t.summary();
+        assert_eq!(want.history.len(), HISTORY_LEN);
+        drop(t);
+
+        // A torn line at the end of the file is skipped.
+        let mut f = fs::OpenOptions::new().append(true).open(&p).unwrap();
+        write!(f, "{{\"id\":").unwrap();
+        drop(f);
+
+        let mut t = Tracker::open(&p).unwrap();
+        assert_eq!(t.summary(), want);
+        let next = t.request(wire::Concentration::PPM(410), at(100)).unwrap();
+        assert_eq!(next.id, HISTORY_LEN as u64 + 5);
+        // The file was compacted to the retained history.
+        assert_eq!(fs::read_to_string(&p).unwrap().lines().count(), HISTORY_LEN);
+    }
+}
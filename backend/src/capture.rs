@@ -0,0 +1,535 @@
+//! Recording and replaying of serial sessions with a sensor.
+//!
+//! `Recording` wraps the serial port a `device::T6615` talks over, and logs
+//! every request it sends and every raw chunk of bytes it receives (line
+//! noise included) to a capture file, along with when it happened. A
+//! `Capture` loaded from that file can be `replay`ed: the resulting port
+//! serves the recorded bytes back to a `T6615` in the same order, failing if
+//! the requests stray from the recording. Replays don't wait, so tests built
+//! from captures are fast and deterministic.
+//!
+//! Capture files are newline-delimited JSON, one `Event` per line, e.g.:
+//!
+//! ```text
+//! {"at_us":0,"dir":"tx","bytes":"FF FE 02 02 03","payload":"02 03"}
+//! {"at_us":8421,"dir":"rx","bytes":"FF FA 02 01 C2"}
+//! ```
+use crate::device;
+use crate::wire;
+use serde;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path;
+use std::result;
+use std::time;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        return self.0.fmt(f);
+    }
+}
+
+impl From<&str> for Error {
+    fn from(e: &str) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Error {
+        return Error(e);
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Format `bs` as space-separated, upper-case hex bytes.
+pub fn to_hex(bs: &[u8]) -> String {
+    return bs
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect::<Vec<String>>()
+        .join(" ");
+}
+
+/// Parse bytes formatted by `to_hex`. Whitespace between bytes is optional.
+pub fn from_hex(s: &str) -> Result<Vec<u8>> {
+    let digits: Vec<char> = s.chars().filter(|c| !c.is_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(Error(format!("odd number of hex digits in {:?}", s)));
+    }
+    return digits
+        .chunks(2)
+        .map(|pair| {
+            let byte: String = pair.iter().collect();
+            u8::from_str_radix(&byte, 16)
+                .map_err(|e| Error(format!("bad hex byte {:?}: {}", byte, e)))
+        })
+        .collect();
+}
+
+/// Direction is which way bytes travelled over the serial port.
+#[derive(Debug, PartialEq, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    /// Sent to the sensor.
+    Tx,
+    /// Received from the sensor.
+    Rx,
+}
+
+/// Event is a single entry in a capture.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Event {
+    /// When the event happened, in microseconds since recording started.
+    pub at_us: u64,
+    pub dir: Direction,
+    /// The raw bytes sent or received, formatted by `to_hex`.
+    pub bytes: String,
+    /// For requests, the payload of the request frame, if it decoded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub payload: Option<String>,
+    /// Set if receiving failed (e.g., timed out) instead of returning bytes.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Recording is a serial port that logs all traffic through the port `P` to
+/// the capture file `W`. Each event is written out as it happens, so a
+/// capture survives the process crashing.
+pub struct Recording<P, W> {
+    port: P,
+    sink: W,
+    started: time::Instant,
+}
+
+impl<P: Read + Write, W: Write> Recording<P, W> {
+    pub fn new(port: P, sink: W) -> Recording<P, W> {
+        return Recording {
+            port: port,
+            sink: sink,
+            started: time::Instant::now(),
+        };
+    }
+
+    /// Time events from `started`, rather than from when the recording was
+    /// created. Recordings of successive connections to the same sensor
+    /// share a timeline this way, when they're written to the same capture.
+    pub fn with_start(mut self, started: time::Instant) -> Self {
+        self.started = started;
+        return self;
+    }
+
+    fn record(&mut self, dir: Direction, bs: &[u8], error: Option<String>) -> io::Result<()> {
+        let payload = match dir {
+            Direction::Tx => {
+                let mut d = wire::Decoder::with_address(wire::REQUEST_ADDRESS);
+                d.push(bs);
+                d.next_payload().map(|p| to_hex(&p))
+            }
+            Direction::Rx => None,
+        };
+        let event = Event {
+            at_us: self.started.elapsed().as_micros() as u64,
+            dir: dir,
+            bytes: to_hex(bs),
+            payload: payload,
+            error: error,
+        };
+        let line = serde_json::to_string(&event)?;
+        writeln!(self.sink, "{}", line)?;
+        return self.sink.flush();
+    }
+}
+
+impl<P: Read + Write, W: Write> Read for Recording<P, W> {
+    fn read(&mut self, bs: &mut [u8]) -> io::Result<usize> {
+        match self.port.read(bs) {
+            Ok(n) => {
+                self.record(Direction::Rx, &bs[..n], None)?;
+                return Ok(n);
+            }
+            Err(e) => {
+                self.record(Direction::Rx, &[], Some(e.to_string()))?;
+                return Err(e);
+            }
+        }
+    }
+}
+
+impl<P: Read + Write, W: Write> Write for Recording<P, W> {
+    fn write(&mut self, bs: &[u8]) -> io::Result<usize> {
+        let n = self.port.write(bs)?;
+        self.record(Direction::Tx, &bs[..n], None)?;
+        return Ok(n);
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return self.port.flush();
+    }
+}
+
+// Bytes discarded from the port were never read, so they aren't recorded.
+impl<P: device::Port, W: Write> device::Port for Recording<P, W> {
+    fn clear_input(&mut self) -> device::Result<()> {
+        return self.port.clear_input();
+    }
+}
+
+/// Capture is a recorded serial session.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Capture {
+    pub events: Vec<Event>,
+}
+
+impl Capture {
+    /// Parse the contents of a capture file. Blank lines are ignored.
+    pub fn parse(s: &str) -> Result<Capture> {
+        let mut events = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let e: Event =
+                serde_json::from_str(line).map_err(|e| Error(format!("line {}: {}", i + 1, e)))?;
+            from_hex(&e.bytes).map_err(|e| Error(format!("line {}: {}", i + 1, e)))?;
+            events.push(e);
+        }
+        return Ok(Capture { events: events });
+    }
+
+    /// Load the capture file at `p`.
+    pub fn load<P: AsRef<path::Path>>(p: P) -> Result<Capture> {
+        let p = p.as_ref();
+        let s = fs::read_to_string(p)
+            .map_err(|e| Error(format!("failed to read {}: {}", p.display(), e)))?;
+        return Capture::parse(&s).map_err(|e| Error(format!("{}: {}", p.display(), e)));
+    }
+
+    /// All bytes sent and received during the session, in the order they
+    /// crossed the wire.
+    pub fn bytes(&self) -> Vec<u8> {
+        return self
+            .events
+            .iter()
+            // Validated when the capture was parsed.
+            .flat_map(|e| from_hex(&e.bytes).unwrap())
+            .collect();
+    }
+
+    /// Returns a port that plays back this capture. Wrap it in a
+    /// `device::T6615` to replay the session.
+    pub fn replay(&self) -> Replay {
+        return Replay {
+            events: self.events.iter().cloned().collect(),
+            tx: Vec::new(),
+            rx: VecDeque::new(),
+        };
+    }
+}
+
+/// Replay is a serial port that plays back a `Capture`. Writes must match
+/// the recorded requests, and reads return the recorded replies.
+pub struct Replay {
+    // Events that have not been played back yet.
+    events: VecDeque<Event>,
+    // Written bytes that have not yet been matched to a recorded request.
+    tx: Vec<u8>,
+    // Received bytes that have not yet been read.
+    rx: VecDeque<u8>,
+}
+
+fn mismatch(msg: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, msg);
+}
+
+impl Replay {
+    /// Returns `true` once every recorded event has been played back.
+    pub fn is_done(&self) -> bool {
+        return self.events.is_empty() && self.rx.is_empty();
+    }
+}
+
+impl Read for Replay {
+    fn read(&mut self, bs: &mut [u8]) -> io::Result<usize> {
+        if self.rx.is_empty() {
+            let e = match self.events.front() {
+                Some(e) if e.dir == Direction::Rx => self.events.pop_front().unwrap(),
+                Some(e) => {
+                    return Err(mismatch(format!(
+                        "read, but the capture expects the request {} next",
+                        e.bytes
+                    )))
+                }
+                None => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "capture has been played back",
+                    ))
+                }
+            };
+            if let Some(err) = e.error {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, err));
+            }
+            // Validated when the capture was parsed.
+            self.rx.extend(from_hex(&e.bytes).unwrap());
+        }
+        let n = bs.len().min(self.rx.len());
+        for (i, b) in self.rx.drain(..n).enumerate() {
+            bs[i] = b;
+        }
+        return Ok(n);
+    }
+}
+
+impl Write for Replay {
+    fn write(&mut self, bs: &[u8]) -> io::Result<usize> {
+        self.tx.extend_from_slice(bs);
+        // Match the written bytes against recorded requests. Requests may be
+        // written in different sized chunks than they were recorded in.
+        while !self.tx.is_empty() {
+            let want = match self.events.front() {
+                Some(e) if e.dir == Direction::Tx => from_hex(&e.bytes).unwrap(),
+                Some(_) => {
+                    return Err(mismatch(format!(
+                        "wrote {}, but the capture expects a reply next",
+                        to_hex(&self.tx)
+                    )))
+                }
+                None => {
+                    return Err(mismatch(format!(
+                        "wrote {} past the end of the capture",
+                        to_hex(&self.tx)
+                    )))
+                }
+            };
+            let n = want.len().min(self.tx.len());
+            if want[..n] != self.tx[..n] {
+                return Err(mismatch(format!(
+                    "wrote {}, but the capture expects {}",
+                    to_hex(&self.tx),
+                    to_hex(&want)
+                )));
+            }
+            if n < want.len() {
+                // Wait for the rest of the request.
+                break;
+            }
+            self.tx.drain(..n);
+            self.events.pop_front();
+        }
+        return Ok(bs.len());
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        return Ok(());
+    }
+}
+
+impl device::Port for Replay {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device;
+    use crate::device::Device;
+    use crate::sim;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A capture file sink that can be read back while still being written.
+    #[derive(Clone, Default)]
+    struct Sink(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Sink {
+        fn write(&mut self, bs: &[u8]) -> io::Result<usize> {
+            return self.0.borrow_mut().write(bs);
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(to_hex(&[0xFF, 0xFA, 0x02]), "FF FA 02");
+        assert_eq!(from_hex("FF FA 02").unwrap(), vec![0xFF, 0xFA, 0x02]);
+        assert_eq!(from_hex("fffa02").unwrap(), vec![0xFF, 0xFA, 0x02]);
+        assert!(from_hex("FF F").is_err());
+        assert!(from_hex("GG").is_err());
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let simulator = sim::Simulator::start(
+            sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(sim::Timing {
+                warmup: time::Duration::from_secs(0),
+                ..sim::Timing::default()
+            }),
+        )
+        .unwrap();
+        let mut opts = device::SerialOptions::new(simulator.path());
+        opts.timeout = time::Duration::from_secs(5);
+        let sink = Sink::default();
+        let mut d =
+            device::T6615::with_port(Recording::new(opts.open_port().unwrap(), sink.clone()));
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        d.set_elevation(wire::Distance::Feet(1500)).unwrap();
+        assert_eq!(d.read_elevation().unwrap(), wire::Distance::Feet(1500));
+        drop(d);
+
+        let recorded = String::from_utf8(sink.0.borrow().clone()).unwrap();
+        let capture = Capture::parse(&recorded).unwrap();
+        assert_eq!(capture.events[0].dir, Direction::Tx);
+        assert_eq!(capture.events[0].bytes, "FF FE 02 02 03");
+        assert_eq!(capture.events[0].payload, Some(String::from("02 03")));
+        assert!(capture.events.iter().any(|e| e.dir == Direction::Rx));
+
+        // The same session plays back without the simulator.
+        drop(simulator);
+        let mut d = device::T6615::with_port(capture.replay());
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        d.set_elevation(wire::Distance::Feet(1500)).unwrap();
+        assert_eq!(d.read_elevation().unwrap(), wire::Distance::Feet(1500));
+
+        // Straying from the recorded session fails.
+        let mut d = device::T6615::with_port(capture.replay());
+        assert!(d.read_elevation().is_err());
+    }
+
+    #[test]
+    fn test_replay_noisy_reply() {
+        // A synthetic capture, written by hand to look like a unit with a
+        // flaky level shifter: line noise and a stray flag byte arrive
+        // before the reply, which is split over several reads.
+        let capture = Capture::parse(include_str!("../testdata/noisy_reply.capture")).unwrap();
+        let mut port = capture.replay();
+        let mut d = device::T6615::with_port(&mut port);
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(706));
+        drop(d);
+        assert!(port.is_done());
+    }
+
+    #[test]
+    fn test_replay_timeout() {
+        let capture = Capture::parse(
+            "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":15000000,\"dir\":\"rx\",\"bytes\":\"\",\"error\":\"Operation timed out\"}\n",
+        )
+        .unwrap();
+        let mut d = device::T6615::with_port(capture.replay()).with_retry(device::Retry::none());
+        assert!(matches!(d.read_status(), Err(device::Error::Timeout(_))));
+    }
+
+    #[test]
+    fn test_replay_garbled() {
+        // A status reply that picked up an extra byte.
+        let capture = Capture::parse(
+            "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":8000,\"dir\":\"rx\",\"bytes\":\"FF FA 02 00 00\"}\n",
+        )
+        .unwrap();
+        let mut d = device::T6615::with_port(capture.replay()).with_retry(device::Retry::none());
+        assert_eq!(
+            d.read_status(),
+            Err(device::Error::Garbled(wire::ParseError::Length {
+                expected: 1,
+                got: 2
+            }))
+        );
+    }
+
+    #[test]
+    fn test_replay_retry() {
+        // A reading that lost a byte, and came through when asked again.
+        let capture = Capture::parse(
+            "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 02 02 03\"}\n\
+             {\"at_us\":8000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 C2\"}\n\
+             {\"at_us\":9000,\"dir\":\"tx\",\"bytes\":\"FF FE 02 02 03\"}\n\
+             {\"at_us\":17000,\"dir\":\"rx\",\"bytes\":\"FF FA 02 01 C2\"}\n",
+        )
+        .unwrap();
+        let mut port = capture.replay();
+        let mut d = device::T6615::with_port(&mut port).with_retry(device::Retry {
+            attempts: 3,
+            backoff: time::Duration::from_secs(0),
+        });
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        let counts = d.stats().get("Read(GasPPM)");
+        assert_eq!(
+            counts,
+            device::CommandCounts {
+                successes: 1,
+                retries: 1,
+                failures: 0,
+            }
+        );
+        drop(d);
+        assert!(port.is_done());
+    }
+
+    #[test]
+    fn test_replay_calibration_lost_ack() {
+        // The ack to starting calibration was lost, but the device started
+        // calibrating, so it's left to finish rather than started again.
+        let capture = Capture::parse(
+            "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 04 03 11 01 90\"}\n\
+             {\"at_us\":1000,\"dir\":\"rx\",\"bytes\":\"FF FA 00\"}\n\
+             {\"at_us\":2000,\"dir\":\"tx\",\"bytes\":\"FF FE 02 02 11\"}\n\
+             {\"at_us\":3000,\"dir\":\"rx\",\"bytes\":\"FF FA 02 01 90\"}\n\
+             {\"at_us\":4000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 9B\"}\n\
+             {\"at_us\":15004000,\"dir\":\"rx\",\"bytes\":\"\",\"error\":\"Operation timed out\"}\n\
+             {\"at_us\":15005000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":15006000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 04\"}\n\
+             {\"at_us\":15007000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":15008000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 04\"}\n\
+             {\"at_us\":30008000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":30009000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 00\"}\n\
+             {\"at_us\":30010000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":30011000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 00\"}\n",
+        )
+        .unwrap();
+        let mut port = capture.replay();
+        let mut d = device::T6615::with_port(&mut port);
+        d.calibrate_co2(
+            wire::Concentration::PPM(400),
+            |_d| {},
+            &device::Limit::none(),
+        )
+        .unwrap();
+        let counts = d.stats().get("StartSinglePointCalibration");
+        assert_eq!(counts.retries, 0);
+        assert_eq!(counts.failures, 1);
+        drop(d);
+        assert!(port.is_done());
+    }
+
+    #[test]
+    fn test_replay_calibration_not_started() {
+        // Without an ack, and without the device calibrating, starting
+        // calibration fails rather than being sent again.
+        let capture = Capture::parse(
+            "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 01 9B\"}\n\
+             {\"at_us\":15000000,\"dir\":\"rx\",\"bytes\":\"\",\"error\":\"Operation timed out\"}\n",
+        )
+        .unwrap();
+        let mut d = device::T6615::with_port(capture.replay());
+        assert!(matches!(
+            d.execute_ack(wire::command::StartSinglePointCalibration),
+            Err(device::Error::Timeout(_))
+        ));
+    }
+}
@@ -0,0 +1,899 @@
+//! The `co2` configuration file.
+//!
+//! The configuration file is TOML. Every setting is optional, and falls back
+//! to its default when omitted:
+//!
+//! ```toml
+//! # Where the frontend is served from.
+//! static_dir = "./frontend"
+//! # Where measurements are durably logged. Not logged if omitted.
+//! data_dir = "./data"
+//!
+//! [http]
+//! listen = "0.0.0.0:80"
+//! # The token clients of the control channel authenticate with, also
+//! # required as a bearer token to change the sensor's configuration over
+//! # HTTP. The control channel is disabled, and changes are open to anyone,
+//! # if omitted.
+//! control_token = "correct-horse-battery-staple"
+//!
+//! [serial]
+//! device = "/dev/serial0"
+//! baud_rate = 19200
+//! timeout_secs = 15
+//!
+//! [sampling]
+//! interval_secs = 15
+//! history_window_secs = 86400
+//!
+//! [calibration]
+//! reference_ppm = 425
+//! # Give up on a calibration that takes longer than this.
+//! timeout_secs = 900
+//!
+//! # Check the readings have settled before calibrating. Not checked if
+//! # omitted.
+//! [calibration.stability]
+//! samples = 5
+//! interval_secs = 10
+//! tolerance_ppm = 10
+//!
+//! [sensor]
+//! # Configure the sensor's elevation on boot. Left as-is if omitted.
+//! elevation_ft = 1500
+//! # Give up on a sensor that takes longer than this to warm up on boot.
+//! warmup_timeout_secs = 600
+//!
+//! # Publish measurements to an MQTT broker. Not published if omitted.
+//! [mqtt]
+//! host = "localhost"
+//! port = 1883
+//! client_id = "co2"
+//! # Log in to the broker. Anonymous if omitted.
+//! username = "co2"
+//! password = "hunter2"
+//! topic_prefix = "co2"
+//! keep_alive_secs = 30
+//! # Announce the sensor to Home Assistant.
+//! discovery = true
+//! discovery_prefix = "homeassistant"
+//!
+//! # Push measurements to a collector. May be repeated. Not pushed if omitted.
+//! [[exporter]]
+//! # One of "influx_http", "influx_udp" or "webhook".
+//! type = "influx_http"
+//! # Names the exporter in logs, and its spool. Defaults to the type.
+//! name = "influx"
+//! # The write endpoint (influx_http), or the endpoint to POST to (webhook).
+//! url = "http://influx.local:8086/api/v2/write?org=home&bucket=co2"
+//! # The `host:port` to send to (influx_udp).
+//! address = "influx.local:8089"
+//! # Sent as `Authorization: Token ...` (influx_http).
+//! token = "s3cret"
+//! # The measurement and tags points are written with (influx_*).
+//! measurement = "co2"
+//! tags = { site = "office" }
+//! # Added to every request (webhook).
+//! headers = { Authorization = "Bearer s3cret" }
+//! batch_size = 100
+//! flush_interval_secs = 60
+//! retries = 3
+//! # Readings that can't be sent are spooled to `data_dir`, if set, up to
+//! # this size.
+//! spool_max_bytes = 16777216
+//! ```
+use crate::calibration;
+use crate::device;
+use crate::mqtt;
+use crate::push;
+use crate::server;
+use crate::store;
+use crate::wire;
+use serde;
+use std::collections;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net;
+use std::path;
+use std::result;
+use std::time;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        return self.0.fmt(f);
+    }
+}
+
+impl From<&str> for Error {
+    fn from(e: &str) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Error {
+        return Error(e);
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<toml::de::Error> for Error {
+    fn from(e: toml::de::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The static directory used when none is configured.
+pub const DEFAULT_STATIC_DIR: &str = "./frontend";
+
+/// The serial device used when none is configured. This is the primary UART
+/// on a Raspberry Pi.
+pub const DEFAULT_SERIAL_DEVICE: &str = "/dev/serial0";
+
+/// Http configures the web server.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Http {
+    /// The address and port to serve on.
+    pub listen: net::SocketAddr,
+    /// The token clients of the control channel authenticate with, and
+    /// requests that change the sensor's configuration must carry as a bearer
+    /// token. The control channel is disabled if `None`.
+    pub control_token: Option<String>,
+}
+
+impl Default for Http {
+    fn default() -> Http {
+        return Http {
+            listen: net::SocketAddr::from(([0, 0, 0, 0], 80)),
+            control_token: None,
+        };
+    }
+}
+
+/// Serial configures the serial port the sensor is attached to.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Serial {
+    pub device: String,
+    pub baud_rate: u32,
+    /// How long to wait for the sensor to reply, in seconds.
+    pub timeout_secs: u64,
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        return Serial {
+            device: String::from(DEFAULT_SERIAL_DEVICE),
+            baud_rate: device::DEFAULT_BAUD_RATE,
+            timeout_secs: device::DEFAULT_TIMEOUT.as_secs(),
+        };
+    }
+}
+
+/// Sampling configures how often the sensor is sampled, and how much
+/// history is kept in memory.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sampling {
+    pub interval_secs: u64,
+    pub history_window_secs: u64,
+}
+
+impl Default for Sampling {
+    fn default() -> Sampling {
+        return Sampling {
+            interval_secs: server::DEFAULT_SAMPLE_INTERVAL.as_secs(),
+            history_window_secs: server::DEFAULT_HISTORY_WINDOW.as_secs(),
+        };
+    }
+}
+
+/// Calibration configures sensor calibration.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    /// The concentration of the air the sensor is calibrated against.
+    pub reference_ppm: u16,
+    /// How long the sensor may take to calibrate, in seconds.
+    pub timeout_secs: u64,
+    /// Check the readings have settled before calibrating. Not checked if
+    /// `None`.
+    pub stability: Option<Stability>,
+}
+
+impl Default for Calibration {
+    fn default() -> Calibration {
+        return Calibration {
+            reference_ppm: server::AMBIENT_CONCENTRATION.ppm(),
+            timeout_secs: device::CALIBRATION_TIMEOUT.as_secs(),
+            stability: None,
+        };
+    }
+}
+
+/// Stability configures the check that readings have settled before
+/// calibrating.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Stability {
+    /// How many readings to take.
+    pub samples: usize,
+    /// How long to wait between readings, in seconds.
+    pub interval_secs: u64,
+    /// How far apart the readings may be, in ppm.
+    pub tolerance_ppm: u16,
+}
+
+impl Default for Stability {
+    fn default() -> Stability {
+        let s = calibration::Stability::default();
+        return Stability {
+            samples: s.samples,
+            interval_secs: s.interval.as_secs(),
+            tolerance_ppm: s.tolerance_ppm,
+        };
+    }
+}
+
+/// Sensor holds settings applied to the sensor on boot.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sensor {
+    /// The elevation to configure, in feet. Left as-is if `None`.
+    pub elevation_ft: Option<u16>,
+    /// How long the sensor may take to warm up, in seconds.
+    pub warmup_timeout_secs: u64,
+}
+
+impl Default for Sensor {
+    fn default() -> Sensor {
+        return Sensor {
+            elevation_ft: None,
+            warmup_timeout_secs: device::WARMUP_TIMEOUT.as_secs(),
+        };
+    }
+}
+
+/// Mqtt configures publishing measurements to an MQTT broker.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Mqtt {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// The username to log in with. Anonymous if `None`.
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// The prefix of every topic published to.
+    pub topic_prefix: String,
+    /// How often to ping the broker while idle, in seconds.
+    pub keep_alive_secs: u64,
+    /// Whether to announce the sensor to Home Assistant.
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for Mqtt {
+    fn default() -> Mqtt {
+        let opts = mqtt::Options::new("localhost");
+        return Mqtt {
+            host: opts.host,
+            port: opts.port,
+            client_id: opts.client_id,
+            username: None,
+            password: None,
+            topic_prefix: opts.topic_prefix,
+            keep_alive_secs: opts.keep_alive.as_secs(),
+            discovery: true,
+            discovery_prefix: String::from(mqtt::DEFAULT_DISCOVERY_PREFIX),
+        };
+    }
+}
+
+/// ExporterType is the kind of collector an exporter pushes to.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExporterType {
+    InfluxHttp,
+    InfluxUdp,
+    Webhook,
+}
+
+impl ExporterType {
+    fn name(&self) -> &'static str {
+        return match self {
+            ExporterType::InfluxHttp => "influx_http",
+            ExporterType::InfluxUdp => "influx_udp",
+            ExporterType::Webhook => "webhook",
+        };
+    }
+}
+
+/// Exporter configures pushing measurements to a collector. Which settings
+/// apply depends on the type.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Exporter {
+    #[serde(rename = "type")]
+    pub kind: ExporterType,
+    /// Names the exporter in logs, and its spool. Defaults to the type.
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub url: Option<String>,
+    #[serde(default)]
+    pub address: Option<String>,
+    #[serde(default)]
+    pub token: Option<String>,
+    #[serde(default)]
+    pub measurement: Option<String>,
+    #[serde(default)]
+    pub tags: collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub headers: collections::BTreeMap<String, String>,
+    #[serde(default)]
+    pub batch_size: Option<usize>,
+    #[serde(default)]
+    pub flush_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub retries: Option<u32>,
+    #[serde(default)]
+    pub spool_max_bytes: Option<u64>,
+}
+
+impl Exporter {
+    pub fn name(&self) -> &str {
+        return self.name.as_deref().unwrap_or_else(|| self.kind.name());
+    }
+
+    /// The collector the exporter pushes to.
+    pub fn collector(&self) -> push::Collector {
+        let line = push::LineProtocol {
+            measurement: self
+                .measurement
+                .clone()
+                .unwrap_or_else(|| String::from(push::DEFAULT_MEASUREMENT)),
+            tags: self.tags.clone(),
+        };
+        let url = self.url.clone().unwrap_or_default();
+        return match self.kind {
+            ExporterType::InfluxHttp => push::Collector::InfluxHttp {
+                url: url,
+                token: self.token.clone(),
+                line: line,
+            },
+            ExporterType::InfluxUdp => push::Collector::InfluxUdp {
+                address: self.address.clone().unwrap_or_default(),
+                line: line,
+            },
+            ExporterType::Webhook => push::Collector::Webhook {
+                url: url,
+                headers: self.headers.clone(),
+            },
+        };
+    }
+}
+
+/// Config is the full configuration of the `co2` server.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub static_dir: path::PathBuf,
+    pub data_dir: Option<path::PathBuf>,
+    pub http: Http,
+    pub serial: Serial,
+    pub sampling: Sampling,
+    pub calibration: Calibration,
+    pub sensor: Sensor,
+    /// Publish measurements to an MQTT broker. Not published if `None`.
+    pub mqtt: Option<Mqtt>,
+    /// Push measurements to collectors.
+    pub exporter: Vec<Exporter>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        return Config {
+            static_dir: path::PathBuf::from(DEFAULT_STATIC_DIR),
+            data_dir: None,
+            http: Http::default(),
+            serial: Serial::default(),
+            sampling: Sampling::default(),
+            calibration: Calibration::default(),
+            sensor: Sensor::default(),
+            mqtt: None,
+            exporter: Vec::new(),
+        };
+    }
+}
+
+impl Config {
+    /// Parse a configuration from the contents of a configuration file.
+    pub fn parse(s: &str) -> Result<Config> {
+        let config: Config = toml::from_str(s)?;
+        config.validate()?;
+        return Ok(config);
+    }
+
+    /// Load the configuration file at `p`.
+    pub fn load<P: AsRef<path::Path>>(p: P) -> Result<Config> {
+        let p = p.as_ref();
+        let s = fs::read_to_string(p)
+            .map_err(|e| Error(format!("failed to read {}: {}", p.display(), e)))?;
+        return Config::parse(&s).map_err(|e| Error(format!("{}: {}", p.display(), e)));
+    }
+
+    /// Check that the configuration makes sense.
+    pub fn validate(&self) -> Result<()> {
+        if self.http.control_token.as_deref() == Some("") {
+            return Err(Error::from("http.control_token must not be empty"));
+        }
+        if self.serial.baud_rate == 0 {
+            return Err(Error::from("serial.baud_rate must be positive"));
+        }
+        if self.sampling.interval_secs == 0 {
+            return Err(Error::from("sampling.interval_secs must be positive"));
+        }
+        if self.history_window() > server::MAX_HISTORY_WINDOW {
+            return Err(Error(format!(
+                "sampling.history_window_secs must be at most {}",
+                server::MAX_HISTORY_WINDOW.as_secs()
+            )));
+        }
+        if self.calibration.timeout_secs == 0 {
+            return Err(Error::from("calibration.timeout_secs must be positive"));
+        }
+        if self.sensor.warmup_timeout_secs == 0 {
+            return Err(Error::from("sensor.warmup_timeout_secs must be positive"));
+        }
+        calibration::check_reference(self.calibration_reference())
+            .map_err(|e| Error(format!("calibration.reference_ppm: {}", e)))?;
+        if let Some(d) = self.elevation() {
+            if d.feet() > server::MT_EVEREST_HEIGHT.feet() {
+                return Err(Error(format!(
+                    "sensor.elevation_ft: {} ft. is higher than Mt. Everest",
+                    d.feet()
+                )));
+            }
+        }
+        if let Some(s) = &self.calibration.stability {
+            if s.samples < 2 {
+                return Err(Error::from(
+                    "calibration.stability.samples must be at least 2",
+                ));
+            }
+            if s.interval_secs == 0 {
+                return Err(Error::from(
+                    "calibration.stability.interval_secs must be positive",
+                ));
+            }
+        }
+        if let Some(m) = &self.mqtt {
+            validate_mqtt(m)?;
+        }
+        for (i, e) in self.exporter.iter().enumerate() {
+            validate_exporter(e).map_err(|err| Error(format!("exporter {}: {}", e.name(), err)))?;
+            if self.exporter[..i].iter().any(|o| o.name() == e.name()) {
+                return Err(Error(format!(
+                    "exporter {}: name is used by another exporter",
+                    e.name()
+                )));
+            }
+        }
+        return Ok(());
+    }
+
+    /// The serial settings to open the sensor with.
+    pub fn serial_options(&self) -> device::SerialOptions {
+        let mut opts = device::SerialOptions::new(&self.serial.device);
+        opts.baud_rate = self.serial.baud_rate;
+        opts.timeout = time::Duration::from_secs(self.serial.timeout_secs);
+        return opts;
+    }
+
+    /// The storage settings, if measurements should be logged to disk.
+    pub fn storage(&self) -> Option<store::Options> {
+        return self.data_dir.as_ref().map(store::Options::new);
+    }
+
+    pub fn sample_interval(&self) -> time::Duration {
+        return time::Duration::from_secs(self.sampling.interval_secs);
+    }
+
+    pub fn history_window(&self) -> time::Duration {
+        return time::Duration::from_secs(self.sampling.history_window_secs);
+    }
+
+    pub fn calibration_reference(&self) -> wire::Concentration {
+        return wire::Concentration::PPM(self.calibration.reference_ppm);
+    }
+
+    /// How long the sensor may take to calibrate.
+    pub fn calibration_timeout(&self) -> time::Duration {
+        return time::Duration::from_secs(self.calibration.timeout_secs);
+    }
+
+    /// The check to run before calibrating, if any.
+    pub fn calibration_stability(&self) -> Option<calibration::Stability> {
+        return self
+            .calibration
+            .stability
+            .as_ref()
+            .map(|s| calibration::Stability {
+                samples: s.samples,
+                interval: time::Duration::from_secs(s.interval_secs),
+                tolerance_ppm: s.tolerance_ppm,
+            });
+    }
+
+    /// The elevation to configure the sensor with on boot, if any.
+    pub fn elevation(&self) -> Option<wire::Distance> {
+        return self.sensor.elevation_ft.map(wire::Distance::Feet);
+    }
+
+    /// How long the sensor may take to warm up on boot.
+    pub fn warmup_timeout(&self) -> time::Duration {
+        return time::Duration::from_secs(self.sensor.warmup_timeout_secs);
+    }
+
+    /// The collectors to push measurements to. Readings that can't be sent
+    /// are spooled to `data_dir/spool`, if set.
+    pub fn exporters(&self) -> Vec<push::Target> {
+        return self
+            .exporter
+            .iter()
+            .map(|e| {
+                let defaults = push::Options::default();
+                let options = push::Options {
+                    batch_size: e.batch_size.unwrap_or(defaults.batch_size),
+                    flush_interval: e
+                        .flush_interval_secs
+                        .map(time::Duration::from_secs)
+                        .unwrap_or(defaults.flush_interval),
+                    retries: e.retries.unwrap_or(defaults.retries),
+                    spool: self
+                        .data_dir
+                        .as_ref()
+                        .map(|d| d.join("spool").join(format!("{}.jsonl", e.name()))),
+                    spool_max_bytes: e.spool_max_bytes.unwrap_or(defaults.spool_max_bytes),
+                    ..defaults
+                };
+                return push::Target {
+                    name: String::from(e.name()),
+                    collector: e.collector(),
+                    options: options,
+                };
+            })
+            .collect();
+    }
+
+    /// The MQTT settings, if measurements should be published to a broker.
+    pub fn mqtt(&self) -> Option<mqtt::Options> {
+        return self.mqtt.as_ref().map(|m| {
+            let mut opts = mqtt::Options::new(&m.host);
+            opts.port = m.port;
+            opts.client_id = m.client_id.clone();
+            opts.credentials = m
+                .username
+                .clone()
+                .map(|u| (u, m.password.clone().unwrap_or_default()));
+            opts.topic_prefix = m.topic_prefix.clone();
+            opts.keep_alive = time::Duration::from_secs(m.keep_alive_secs);
+            opts.discovery_prefix = if m.discovery {
+                Some(m.discovery_prefix.clone())
+            } else {
+                None
+            };
+            return opts;
+        });
+    }
+}
+
+fn validate_exporter(e: &Exporter) -> Result<()> {
+    let name_ok = e
+        .name()
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if e.name().is_empty() || !name_ok {
+        return Err(Error::from(
+            "name must only contain letters, digits, '_' and '-'",
+        ));
+    }
+    // Settings that don't apply to the type are most likely a mistake.
+    let influx = e.kind != ExporterType::Webhook;
+    let needs_url = e.kind != ExporterType::InfluxUdp;
+    let needs_address = e.kind == ExporterType::InfluxUdp;
+    let checks = [
+        ("url", e.url.is_some(), needs_url),
+        ("address", e.address.is_some(), needs_address),
+        (
+            "token",
+            e.token.is_some(),
+            e.kind == ExporterType::InfluxHttp,
+        ),
+        ("measurement", e.measurement.is_some(), influx),
+        ("tags", !e.tags.is_empty(), influx),
+        ("headers", !e.headers.is_empty(), !influx),
+    ];
+    for (field, set, applies) in checks.iter() {
+        if *set && !*applies {
+            return Err(Error(format!(
+                "{} doesn't apply to {} exporters",
+                field,
+                e.kind.name()
+            )));
+        }
+    }
+    if needs_url && e.url.is_none() {
+        return Err(Error::from("url is required"));
+    }
+    if needs_address && e.address.is_none() {
+        return Err(Error::from("address is required"));
+    }
+    if e.batch_size == Some(0) {
+        return Err(Error::from("batch_size must be positive"));
+    }
+    if e.flush_interval_secs == Some(0) {
+        return Err(Error::from("flush_interval_secs must be positive"));
+    }
+    // Line protocol has no way to escape a line break.
+    let line_breaks = e
+        .measurement
+        .iter()
+        .chain(e.tags.keys())
+        .chain(e.tags.values())
+        .any(|s| s.contains(['\n', '\r']));
+    if line_breaks {
+        return Err(Error::from(
+            "measurement and tags must not contain line breaks",
+        ));
+    }
+    if let Some(url) = &e.url {
+        if !url.starts_with("http://") {
+            return Err(Error::from("url must start with http://"));
+        }
+    }
+    return Ok(());
+}
+
+fn validate_mqtt(m: &Mqtt) -> Result<()> {
+    if m.host.is_empty() {
+        return Err(Error::from("mqtt.host must not be empty"));
+    }
+    if m.port == 0 {
+        return Err(Error::from("mqtt.port must be positive"));
+    }
+    if m.client_id.is_empty() || m.client_id.starts_with(' ') {
+        return Err(Error::from(
+            "mqtt.client_id must not be empty or start with a space",
+        ));
+    }
+    if m.password.is_some() && m.username.is_none() {
+        return Err(Error::from("mqtt.password requires mqtt.username"));
+    }
+    // Wildcards are only valid when subscribing.
+    for (name, prefix) in [
+        ("topic_prefix", &m.topic_prefix),
+        ("discovery_prefix", &m.discovery_prefix),
+    ] {
+        if prefix.is_empty() || prefix.contains(['+', '#']) {
+            return Err(Error(format!(
+                "mqtt.{} must be a non-empty topic without wildcards",
+                name
+            )));
+        }
+    }
+    // The client library requires at least 5 seconds.
+    if m.keep_alive_secs < 5 {
+        return Err(Error::from("mqtt.keep_alive_secs must be at least 5"));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_parse_full() {
+        let config = Config::parse(
+            r#"
+            static_dir = "/srv/co2"
+            data_dir = "/var/lib/co2"
+
+            [http]
+            listen = "127.0.0.1:8080"
+            control_token = "hunter2"
+
+            [serial]
+            device = "/dev/ttyUSB0"
+            baud_rate = 9600
+            timeout_secs = 5
+
+            [sampling]
+            interval_secs = 30
+            history_window_secs = 3600
+
+            [calibration]
+            reference_ppm = 2000
+            timeout_secs = 600
+
+            [calibration.stability]
+            samples = 3
+            interval_secs = 20
+            tolerance_ppm = 25
+
+            [sensor]
+            elevation_ft = 1500
+            warmup_timeout_secs = 300
+
+            [mqtt]
+            host = "broker.local"
+            port = 8883
+            client_id = "office"
+            username = "co2"
+            password = "hunter2"
+            topic_prefix = "home/office/co2"
+            keep_alive_secs = 60
+            discovery_prefix = "ha"
+
+            [[exporter]]
+            type = "influx_udp"
+            address = "influx.local:8089"
+            tags = { site = "office" }
+            batch_size = 10
+
+            [[exporter]]
+            type = "webhook"
+            name = "hook"
+            url = "http://example.com/hook"
+            headers = { Authorization = "Bearer s3cret" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.static_dir, path::PathBuf::from("/srv/co2"));
+        assert_eq!(
+            config.storage().map(|o| o.dir),
+            Some(path::PathBuf::from("/var/lib/co2"))
+        );
+        assert_eq!(
+            config.http.listen,
+            "127.0.0.1:8080".parse::<net::SocketAddr>().unwrap()
+        );
+        assert_eq!(config.http.control_token.as_deref(), Some("hunter2"));
+        assert_eq!(
+            config.serial_options(),
+            device::SerialOptions {
+                path: String::from("/dev/ttyUSB0"),
+                baud_rate: 9600,
+                timeout: time::Duration::from_secs(5),
+            }
+        );
+        assert_eq!(config.sample_interval(), time::Duration::from_secs(30));
+        assert_eq!(config.history_window(), time::Duration::from_secs(3600));
+        assert_eq!(
+            config.calibration_reference(),
+            wire::Concentration::PPM(2000)
+        );
+        assert_eq!(
+            config.calibration_stability(),
+            Some(calibration::Stability {
+                samples: 3,
+                interval: time::Duration::from_secs(20),
+                tolerance_ppm: 25,
+            })
+        );
+        assert_eq!(config.calibration_timeout(), time::Duration::from_secs(600));
+        assert_eq!(config.elevation(), Some(wire::Distance::Feet(1500)));
+        assert_eq!(config.warmup_timeout(), time::Duration::from_secs(300));
+        assert_eq!(
+            config.mqtt(),
+            Some(mqtt::Options {
+                host: String::from("broker.local"),
+                port: 8883,
+                client_id: String::from("office"),
+                credentials: Some((String::from("co2"), String::from("hunter2"))),
+                keep_alive: time::Duration::from_secs(60),
+                topic_prefix: String::from("home/office/co2"),
+                discovery_prefix: Some(String::from("ha")),
+            })
+        );
+        let exporters = config.exporters();
+        assert_eq!(exporters.len(), 2);
+        let mut tags = collections::BTreeMap::new();
+        tags.insert(String::from("site"), String::from("office"));
+        assert_eq!(
+            exporters[0],
+            push::Target {
+                name: String::from("influx_udp"),
+                collector: push::Collector::InfluxUdp {
+                    address: String::from("influx.local:8089"),
+                    line: push::LineProtocol {
+                        measurement: String::from("co2"),
+                        tags: tags,
+                    },
+                },
+                options: push::Options {
+                    batch_size: 10,
+                    spool: Some(path::PathBuf::from("/var/lib/co2/spool/influx_udp.jsonl")),
+                    ..push::Options::default()
+                },
+            }
+        );
+        assert_eq!(exporters[1].name, "hook");
+        assert_eq!(
+            exporters[1].options.spool,
+            Some(path::PathBuf::from("/var/lib/co2/spool/hook.jsonl"))
+        );
+    }
+
+    #[test]
+    fn test_parse_mqtt() {
+        assert_eq!(Config::default().mqtt(), None);
+        let config = Config::parse("[mqtt]\ndiscovery = false").unwrap();
+        let mut want = mqtt::Options::new("localhost");
+        want.discovery_prefix = None;
+        assert_eq!(config.mqtt(), Some(want));
+    }
+
+    #[test]
+    fn test_parse_partial() {
+        let config = Config::parse(
+            r#"
+            [serial]
+            device = "/dev/ttyAMA0"
+            "#,
+        )
+        .unwrap();
+        let mut want = Config::default();
+        want.serial.device = String::from("/dev/ttyAMA0");
+        assert_eq!(config, want);
+    }
+
+    #[test]
+    fn test_parse_invalid() {
+        // Typos are caught rather than silently ignored.
+        assert!(Config::parse("[serial]\nbaudrate = 9600").is_err());
+        assert!(Config::parse("[http]\nlisten = \"not an address\"").is_err());
+        assert!(Config::parse("[http]\ncontrol_token = \"\"").is_err());
+        assert!(Config::parse("[sampling]\ninterval_secs = 0").is_err());
+        assert!(Config::parse("[sampling]\nhistory_window_secs = 10000000000000").is_err());
+        assert!(Config::parse("[calibration]\nreference_ppm = 41").is_err());
+        assert!(Config::parse("[calibration.stability]\nsamples = 1").is_err());
+        assert!(Config::parse("[sensor]\nelevation_ft = 30000").is_err());
+        assert!(Config::parse("[mqtt]\nhost = \"\"").is_err());
+        assert!(Config::parse("[mqtt]\ntopic_prefix = \"co2/#\"").is_err());
+        assert!(Config::parse("[mqtt]\npassword = \"hunter2\"").is_err());
+        assert!(Config::parse("[mqtt]\nkeep_alive_secs = 1").is_err());
+        assert!(Config::parse("[[exporter]]\ntype = \"influx_http\"").is_err());
+        assert!(Config::parse("[[exporter]]\ntype = \"graphite\"").is_err());
+        assert!(Config::parse(
+            "[[exporter]]\ntype = \"influx_udp\"\naddress = \"x:1\"\nurl = \"http://x\""
+        )
+        .is_err());
+        assert!(Config::parse("[[exporter]]\ntype = \"webhook\"\nurl = \"ftp://x\"").is_err());
+        assert!(Config::parse(
+            "[[exporter]]\ntype = \"influx_http\"\nurl = \"http://x\"\ntags = { site = \"a\\nb\" }"
+        )
+        .is_err());
+        let twice = "[[exporter]]\ntype = \"webhook\"\nurl = \"http://x\"\n";
+        assert!(Config::parse(&twice.repeat(2)).is_err());
+    }
+}
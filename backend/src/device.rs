@@ -1,48 +1,158 @@
 use crate::wire;
+use log::warn;
 use serialport;
+use std::collections::BTreeMap;
 use std::convert::TryFrom;
+use std::error;
+use std::fmt;
 use std::io;
 use std::io::{Read, Write};
 use std::result;
+use std::sync;
+use std::thread;
 use std::time;
 
-#[derive(Debug, PartialEq)]
-pub struct Error(String);
+/// Error is why talking to a device failed.
+#[derive(Debug)]
+pub enum Error {
+    /// The serial port could not be opened or configured.
+    Serial(serialport::Error),
+    /// Reading from or writing to the device failed.
+    Io(io::Error),
+    /// The device did not reply in time.
+    Timeout(io::Error),
+    /// The serial port was closed.
+    Closed,
+    /// The device replied, but the reply could not be decoded.
+    Garbled(wire::ParseError),
+    /// The device replied with something unexpected, e.g., a calibration
+    /// that didn't take.
+    Unexpected(String),
+    /// The device didn't reach the awaited status before the deadline. Holds
+    /// the last status the device reported.
+    DeadlineExceeded(wire::response::Status),
+    /// The operation was cancelled, see `Cancel`.
+    Cancelled,
+}
+
+impl Error {
+    /// Returns `true` if the device could not be reached, as opposed to
+    /// having replied with something wrong.
+    pub fn is_unavailable(&self) -> bool {
+        return matches!(
+            self,
+            Error::Serial(_)
+                | Error::Io(_)
+                | Error::Timeout(_)
+                | Error::Closed
+                | Error::DeadlineExceeded(_)
+        );
+    }
+
+    /// Returns `true` if an exchange with the device failed in a way that
+    /// may not happen again, i.e., the reply was lost or garbled, so the
+    /// command may be worth sending again.
+    pub fn is_transient(&self) -> bool {
+        return matches!(self, Error::Timeout(_) | Error::Garbled(_));
+    }
 
-impl ToString for Error {
-    fn to_string(&self) -> String {
-        let Error(s) = self;
-        return s.clone();
+    /// A short name for the kind of error, e.g., for metrics.
+    pub fn kind(&self) -> &'static str {
+        return match self {
+            Error::Serial(_) => "serial",
+            Error::Io(_) => "io",
+            Error::Timeout(_) => "timeout",
+            Error::Closed => "closed",
+            Error::Garbled(_) => "garbled",
+            Error::Unexpected(_) => "unexpected",
+            Error::DeadlineExceeded(_) => "deadline_exceeded",
+            Error::Cancelled => "cancelled",
+        };
+    }
+}
+
+// I/O and serial port errors can't be compared, so those are equal if they
+// are of the same kind.
+impl PartialEq for Error {
+    fn eq(&self, other: &Error) -> bool {
+        return match (self, other) {
+            (Error::Serial(a), Error::Serial(b)) => a.kind() == b.kind(),
+            (Error::Io(a), Error::Io(b)) | (Error::Timeout(a), Error::Timeout(b)) => {
+                a.kind() == b.kind()
+            }
+            (Error::Closed, Error::Closed) => true,
+            (Error::Garbled(a), Error::Garbled(b)) => a == b,
+            (Error::Unexpected(a), Error::Unexpected(b)) => a == b,
+            (Error::DeadlineExceeded(a), Error::DeadlineExceeded(b)) => a == b,
+            (Error::Cancelled, Error::Cancelled) => true,
+            _ => false,
+        };
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            Error::Serial(e) => write!(f, "serial port error: {}", e),
+            Error::Io(e) => write!(f, "i/o error: {}", e),
+            Error::Timeout(_) => write!(f, "timed out waiting for the device"),
+            Error::Closed => write!(f, "serial port closed"),
+            Error::Garbled(e) => write!(f, "garbled reply: {}", e),
+            Error::Unexpected(s) => s.fmt(f),
+            Error::DeadlineExceeded(s) => write!(
+                f,
+                "deadline exceeded waiting for the device, last status: {}",
+                s
+            ),
+            Error::Cancelled => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Serial(e) => Some(e),
+            Error::Io(e) | Error::Timeout(e) => Some(e),
+            Error::Garbled(e) => Some(e),
+            Error::Closed
+            | Error::Unexpected(_)
+            | Error::DeadlineExceeded(_)
+            | Error::Cancelled => None,
+        }
     }
 }
 
 impl From<String> for Error {
     fn from(s: String) -> Error {
-        Error(s)
+        Error::Unexpected(s)
     }
 }
 
 impl From<&str> for Error {
     fn from(s: &str) -> Error {
-        Error(String::from(s))
+        Error::Unexpected(String::from(s))
     }
 }
 
 impl From<serialport::Error> for Error {
     fn from(e: serialport::Error) -> Error {
-        Error(e.to_string())
+        Error::Serial(e)
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        Error(e.to_string())
+        match e.kind() {
+            io::ErrorKind::TimedOut => Error::Timeout(e),
+            _ => Error::Io(e),
+        }
     }
 }
 
 impl From<wire::ParseError> for Error {
     fn from(e: wire::ParseError) -> Error {
-        Error(e.to_string())
+        Error::Garbled(e)
     }
 }
 
@@ -59,14 +169,122 @@ fn round(v: u16, nearest: u16) -> u16 {
     return lower + nearest;
 }
 
+/// Round `d` to the nearest 500 feet, the elevation the device will actually
+/// use.
+pub fn round_elevation(d: wire::Distance) -> wire::Distance {
+    return wire::Distance::Feet(round(d.feet(), 500));
+}
+
+/// How long to wait for the device to warm up by default. The T6615 warms up
+/// in a couple of minutes.
+pub const WARMUP_TIMEOUT: time::Duration = time::Duration::from_secs(10 * 60);
+
+/// How long to wait for the device to calibrate by default. Calibration takes
+/// a few minutes.
+pub const CALIBRATION_TIMEOUT: time::Duration = time::Duration::from_secs(15 * 60);
+
+/// How long to wait for the device's self-test, which takes several seconds.
+pub const SELF_TEST_TIMEOUT: time::Duration = time::Duration::from_secs(2 * 60);
+
+/// Cancel is a token to cancel a long-running device operation, e.g., from
+/// another thread. Clones share the same token.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel {
+    cancelled: sync::Arc<(sync::Mutex<bool>, sync::Condvar)>,
+}
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        return Cancel::default();
+    }
+
+    /// Cancel the operation, and wake up anyone sleeping on this token.
+    pub fn cancel(&self) {
+        let (cancelled, cond) = &*self.cancelled;
+        *cancelled.lock().unwrap() = true;
+        cond.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return *self.cancelled.0.lock().unwrap();
+    }
+
+    /// Sleep for `d`, or until cancelled, whichever is sooner. Can be used
+    /// as the `sleep_fn` of an operation so cancelling it takes effect
+    /// without waiting out a long polling interval.
+    pub fn sleep(&self, d: time::Duration) {
+        let (cancelled, cond) = &*self.cancelled;
+        let guard = cancelled.lock().unwrap();
+        let _ = cond.wait_timeout_while(guard, d, |c| !*c).unwrap();
+    }
+}
+
+/// Limit bounds how long a long-running device operation, e.g., waiting for
+/// the device to warm up, may go on for. The operation fails with
+/// `Error::DeadlineExceeded` once the deadline passes, or `Error::Cancelled`
+/// once cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct Limit {
+    deadline: Option<time::Instant>,
+    cancel: Option<Cancel>,
+}
+
+impl Limit {
+    /// No limit, the operation may go on forever.
+    pub fn none() -> Limit {
+        return Limit::default();
+    }
+
+    /// A limit of `d` from now.
+    pub fn timeout(d: time::Duration) -> Limit {
+        return Limit::none().with_deadline(time::Instant::now() + d);
+    }
+
+    pub fn with_deadline(mut self, deadline: time::Instant) -> Limit {
+        self.deadline = Some(deadline);
+        return self;
+    }
+
+    pub fn with_cancel(mut self, cancel: Cancel) -> Limit {
+        self.cancel = Some(cancel);
+        return self;
+    }
+
+    pub fn is_expired(&self) -> bool {
+        return self.deadline.is_some_and(|d| time::Instant::now() >= d);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancel.as_ref().is_some_and(Cancel::is_cancelled);
+    }
+
+    /// Fails with `Error::Cancelled` if cancelled.
+    pub fn check_cancelled(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        return Ok(());
+    }
+}
+
+/// CalibrationStage is how far along `Device::calibrate_co2` is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CalibrationStage {
+    /// Setting the reference concentration, and checking the device took it.
+    VerifyingReference,
+    /// Waiting for the device to enter calibration.
+    WaitingForCalibration,
+    /// The device is calibrating.
+    Calibrating,
+}
+
 /// Device represents a device that can execute commands. This is useful
 /// for testing purposes.
 pub trait Device {
-    fn execute<S, T, E>(&mut self, s: S) -> Result<T>
+    fn execute<S, T>(&mut self, s: S) -> Result<T>
     where
         S: Into<wire::Payload>,
-        E: ToString,
-        T: TryFrom<wire::Payload, Error = E>;
+        T: TryFrom<wire::Payload, Error = wire::ParseError>;
 
     /// A special case of `execute`. Assumes that the given command receives
     /// an ACK reply. Since ACK's don't contain any interesting information,
@@ -76,6 +294,13 @@ pub trait Device {
         return Ok(());
     }
 
+    /// How many times the connection to the device has been re-established,
+    /// for devices that reconnect on their own. Each time, it may have come
+    /// back as a different sensor.
+    fn reconnects(&self) -> u64 {
+        return 0;
+    }
+
     /// Read a co2 measurement from the sensor.
     fn read_co2(&mut self) -> Result<wire::Concentration> {
         let r: wire::response::GasPPM =
@@ -83,6 +308,26 @@ pub trait Device {
         return Ok(r.concentration());
     }
 
+    /// Read the device's serial number and software version.
+    fn read_identity(&mut self) -> Result<Identity> {
+        let serial: wire::response::SerialNumber =
+            self.execute(wire::command::Read(wire::Variable::SerialNumber))?;
+        let subvol: wire::response::CompileSubvol =
+            self.execute(wire::command::Read(wire::Variable::CompileSubvol))?;
+        let wire::response::CompileDate(date) =
+            self.execute(wire::command::Read(wire::Variable::CompileDate))?;
+        return Ok(Identity {
+            serial_number: serial.to_string(),
+            compile_subvol: subvol.to_string(),
+            compile_date: date,
+        });
+    }
+
+    /// Read the device's current status.
+    fn read_status(&mut self) -> Result<wire::response::Status> {
+        return self.execute(wire::command::Status);
+    }
+
     /// Read the configured elevation from the sensor.
     fn read_elevation(&mut self) -> Result<wire::Distance> {
         let wire::response::Elevation(d) =
@@ -93,44 +338,70 @@ pub trait Device {
     /// Configure the device to operate at elevation `d`. May be rounded to
     /// nearest 500 feet.
     fn set_elevation(&mut self, d: wire::Distance) -> Result<()> {
-        let e = wire::Distance::Feet(round(d.feet(), 500));
+        let e = round_elevation(d);
         let wire::response::Ack = self.execute(wire::command::UpdateElevation(e))?;
         return Ok(());
     }
 
     /// Wait for the device to enter a particular status. This function will
     /// continuously poll the device until the given predicate function
-    /// (which accepts a status) returns true.
-    fn wait_status<P, T>(&mut self, pred: P, sleep_fn: T) -> Result<()>
+    /// (which accepts a status) returns true, or `limit` is reached.
+    fn wait_status<P, T>(&mut self, pred: P, sleep_fn: T, limit: &Limit) -> Result<()>
     where
         P: Fn(wire::response::Status) -> bool,
         T: Fn(),
     {
         loop {
+            limit.check_cancelled()?;
             let r: wire::response::Status = self.execute(wire::command::Status)?;
             if pred(r) {
                 return Ok(());
             }
+            if limit.is_expired() {
+                return Err(Error::DeadlineExceeded(r));
+            }
             sleep_fn();
         }
     }
 
     /// Wait for the device to finish warmup. Should be called before
     /// taking co2 measurements. `sleep_fn` is called between polling cycles.
-    fn wait_warmup<T: Fn(time::Duration)>(&mut self, sleep_fn: T) -> Result<()> {
+    fn wait_warmup<T: Fn(time::Duration)>(&mut self, sleep_fn: T, limit: &Limit) -> Result<()> {
         return self.wait_status(
             |s| !s.in_warmup(),
             || sleep_fn(time::Duration::from_secs(5)),
+            limit,
         );
     }
 
     /// Calibrate the device's co2 readings to a reference concentration.
     /// This function is very heavyweight, it may take a minute or longer.
+    /// If `limit` is reached while waiting on the device, the device may be
+    /// left calibrating.
     fn calibrate_co2<T: Fn(time::Duration)>(
         &mut self,
         reference: wire::Concentration,
         sleep_fn: T,
+        limit: &Limit,
     ) -> Result<()> {
+        return self.calibrate_co2_with_progress(reference, sleep_fn, limit, |_| {});
+    }
+
+    /// Like `calibrate_co2`, but `progress` is called as calibration enters
+    /// each stage.
+    fn calibrate_co2_with_progress<T, P>(
+        &mut self,
+        reference: wire::Concentration,
+        sleep_fn: T,
+        limit: &Limit,
+        progress: P,
+    ) -> Result<()>
+    where
+        T: Fn(time::Duration),
+        P: Fn(CalibrationStage),
+    {
+        limit.check_cancelled()?;
+        progress(CalibrationStage::VerifyingReference);
         self.execute_ack(wire::command::SetSinglePointPPM(reference))?;
         let got: wire::response::GasPPM =
             self.execute(wire::command::VerifySinglePointCalibration)?;
@@ -140,17 +411,34 @@ pub trait Device {
                 got, reference
             )));
         }
-        // Start the actual calibration.
-        self.execute_ack(wire::command::StartSinglePointCalibration)?;
+        // Start the actual calibration. This is the last chance to cancel
+        // without leaving the device calibrating.
+        limit.check_cancelled()?;
+        progress(CalibrationStage::WaitingForCalibration);
+        match self.execute_ack(wire::command::StartSinglePointCalibration) {
+            Ok(()) => {}
+            // If only the ack was lost, the device is calibrating already,
+            // and starting it again would restart the calibration. So rather
+            // than sending the command again, check whether it took.
+            Err(e) if e.is_transient() => {
+                if !self.read_status()?.in_calibration() {
+                    return Err(e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
         // Wait for the device to enter calibration mode, polling every 5s.
         self.wait_status(
             |s| s.in_calibration(),
             || sleep_fn(time::Duration::from_secs(5)),
+            limit,
         )?;
         // Wait for the device to exit calibration mode, polling every 15s.
+        progress(CalibrationStage::Calibrating);
         self.wait_status(
             |s| !s.in_calibration(),
             || sleep_fn(time::Duration::from_secs(15)),
+            limit,
         )?;
 
         let status: wire::response::Status = self.execute(wire::command::Status)?;
@@ -160,73 +448,502 @@ pub trait Device {
         return Ok(());
     }
 
-    fn disable_abc(&mut self) -> Result<()> {
-        let r: wire::response::ABCState =
-            self.execute(wire::command::SetABCLogic(wire::Toggle::Off))?;
-        if r != wire::response::ABCState::Off {
-            return Err(Error::from("ABC state failed toggle off."));
+    /// Run the device's built-in self-test, and return the result. The test
+    /// takes several seconds; `sleep_fn` is called between polling cycles.
+    /// Gives up after `SELF_TEST_TIMEOUT`.
+    fn run_self_test<T: Fn(time::Duration)>(
+        &mut self,
+        sleep_fn: T,
+    ) -> Result<wire::response::SelfTest> {
+        let limit = Limit::timeout(SELF_TEST_TIMEOUT);
+        self.execute_ack(wire::command::StartSelfTest)?;
+        // Give the device a moment to enter the self-test, and then poll
+        // every 5s until it's done.
+        sleep_fn(time::Duration::from_secs(5));
+        self.wait_status(
+            |s| !s.in_self_test(),
+            || sleep_fn(time::Duration::from_secs(5)),
+            &limit,
+        )?;
+        return self.execute(wire::command::SelfTestResults);
+    }
+
+    /// Read whether automatic baseline correction (ABC) is enabled.
+    fn read_abc(&mut self) -> Result<wire::response::ABCState> {
+        return self.execute(wire::command::ABCLogic);
+    }
+
+    /// Turn automatic baseline correction on or off.
+    fn set_abc(&mut self, t: wire::Toggle) -> Result<()> {
+        let want = match t {
+            wire::Toggle::On => wire::response::ABCState::On,
+            wire::Toggle::Off => wire::response::ABCState::Off,
+        };
+        let r: wire::response::ABCState = self.execute(wire::command::SetABCLogic(t))?;
+        if r != want {
+            return Err(Error::from(format!("ABC state failed to toggle {:?}.", t)));
         }
         return Ok(());
     }
+
+    fn enable_abc(&mut self) -> Result<()> {
+        return self.set_abc(wire::Toggle::On);
+    }
+
+    fn disable_abc(&mut self) -> Result<()> {
+        return self.set_abc(wire::Toggle::Off);
+    }
+
+    /// Reset the automatic baseline correction history, discarding the
+    /// baseline the device has learned so far.
+    fn reset_abc(&mut self) -> Result<()> {
+        return self.execute_ack(wire::command::ResetABCLogic);
+    }
 }
 
-/// T6615 implements the `Device` trait for the Telaire T6615 CO2 module.
-pub struct T6615 {
-    port: serialport::TTYPort,
+/// Identity is what a device reports about itself.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Identity {
+    pub serial_number: String,
+    pub compile_subvol: String,
+    pub compile_date: chrono::NaiveDate,
 }
 
-impl T6615 {
-    /// Construct a new T6615 instance from a TTY path.
-    pub fn new(path: &str) -> Result<T6615> {
-        let port = serialport::TTYPort::open(
-            &serialport::new(path, 19200)
+/// A CO2 measurement along with the time it was received.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Reading {
+    pub concentration: wire::Concentration,
+    pub at: chrono::DateTime<chrono::Utc>,
+    /// The device's status when the measurement was taken, if known.
+    pub status: Option<wire::response::Status>,
+    /// The elevation the device was configured for, if known.
+    pub elevation: Option<wire::Distance>,
+}
+
+impl Reading {
+    /// A reading of `concentration` at time `at`, with no other context.
+    pub fn new(concentration: wire::Concentration, at: chrono::DateTime<chrono::Utc>) -> Reading {
+        return Reading {
+            concentration: concentration,
+            at: at,
+            status: None,
+            elevation: None,
+        };
+    }
+}
+
+// The number of frames to drain while waiting for the device to acknowledge
+// the end of stream mode before giving up.
+const MAX_STREAM_DRAIN: usize = 16;
+
+/// StreamDevice is a `Device` that can also push measurements on its own,
+/// without being polled.
+pub trait StreamDevice: Device {
+    /// Send a command to the device without waiting for a reply.
+    fn send<S: Into<wire::Payload>>(&mut self, s: S) -> Result<()>;
+
+    /// Wait for the next frame sent by the device.
+    fn receive(&mut self) -> Result<wire::Payload>;
+
+    /// Put the device into stream mode. The returned `Stream` yields
+    /// readings as the device pushes them, and takes the device back out of
+    /// stream mode when stopped or dropped.
+    fn stream_co2(&mut self) -> Result<Stream<'_, Self>>
+    where
+        Self: Sized,
+    {
+        self.send(wire::command::StreamData)?;
+        return Ok(Stream {
+            device: self,
+            stopped: false,
+        });
+    }
+}
+
+/// Stream is an iterator over readings pushed by a device in stream mode.
+/// See `StreamDevice::stream_co2`.
+pub struct Stream<'a, D: StreamDevice> {
+    device: &'a mut D,
+    stopped: bool,
+}
+
+impl<'a, D: StreamDevice> Stream<'a, D> {
+    /// Take the device out of stream mode. Any other command ends stream
+    /// mode, so a status request is sent, and streamed frames are drained
+    /// until its reply shows up.
+    pub fn stop(mut self) -> Result<()> {
+        return self.stop_streaming();
+    }
+
+    fn stop_streaming(&mut self) -> Result<()> {
+        if self.stopped {
+            return Ok(());
+        }
+        self.stopped = true;
+        self.device.send(wire::command::Status)?;
+        for _ in 0..MAX_STREAM_DRAIN {
+            let p = self.device.receive()?;
+            if wire::response::Status::try_from(p).is_ok() {
+                return Ok(());
+            }
+        }
+        return Err(Error::from("device did not leave stream mode"));
+    }
+}
+
+impl<'a, D: StreamDevice> Iterator for Stream<'a, D> {
+    type Item = Result<Reading>;
+
+    fn next(&mut self) -> Option<Result<Reading>> {
+        if self.stopped {
+            return None;
+        }
+        let r = self.device.receive().and_then(|p| {
+            let d = wire::response::StreamData::try_from(p)?;
+            return Ok(Reading::new(d.concentration(), chrono::Utc::now()));
+        });
+        return Some(r);
+    }
+}
+
+impl<'a, D: StreamDevice> Drop for Stream<'a, D> {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_streaming() {
+            warn!("failed to leave stream mode: {}", e);
+        }
+    }
+}
+
+/// The baud rate the T6615 uses out of the box.
+pub const DEFAULT_BAUD_RATE: u32 = 19200;
+
+/// How long to wait for the T6615 to reply by default.
+pub const DEFAULT_TIMEOUT: time::Duration = time::Duration::from_secs(15);
+
+/// SerialOptions configures the serial port used to talk to a `T6615`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SerialOptions {
+    /// The path of the TTY the device is attached to.
+    pub path: String,
+    pub baud_rate: u32,
+    /// How long to wait for a reply before giving up.
+    pub timeout: time::Duration,
+}
+
+impl SerialOptions {
+    /// Options with the device's default settings for the TTY at `path`.
+    pub fn new(path: &str) -> SerialOptions {
+        return SerialOptions {
+            path: String::from(path),
+            baud_rate: DEFAULT_BAUD_RATE,
+            timeout: DEFAULT_TIMEOUT,
+        };
+    }
+
+    /// Open the serial port described by these options.
+    pub fn open_port(&self) -> Result<serialport::TTYPort> {
+        return Ok(serialport::TTYPort::open(
+            &serialport::new(&self.path, self.baud_rate)
                 .parity(serialport::Parity::None)
                 .data_bits(serialport::DataBits::Eight)
                 .stop_bits(serialport::StopBits::One)
-                .timeout(time::Duration::from_secs(15)),
-        )?;
+                .timeout(self.timeout),
+        )?);
+    }
+}
+
+/// Port is a serial port a `T6615` can be attached to.
+pub trait Port: Read + Write {
+    /// Discard anything received but not read yet, e.g., the rest of a
+    /// reply that was given up on. Ports that can't have anything left
+    /// over, e.g., in-memory ones, have nothing to do.
+    fn clear_input(&mut self) -> Result<()> {
+        return Ok(());
+    }
+}
+
+impl Port for serialport::TTYPort {
+    fn clear_input(&mut self) -> Result<()> {
+        use serialport::SerialPort;
+        self.clear(serialport::ClearBuffer::Input)?;
+        return Ok(());
+    }
+}
 
-        return Ok(T6615 { port: port });
+impl<P: Port + ?Sized> Port for &mut P {
+    fn clear_input(&mut self) -> Result<()> {
+        return (**self).clear_input();
     }
 }
 
-impl Device for T6615 {
-    fn execute<S, T, E>(&mut self, s: S) -> Result<T>
+/// Retry is how many times a `T6615` tries an idempotent command whose reply
+/// is lost or garbled, e.g., because of a dropped byte. Commands that aren't
+/// idempotent are only ever sent once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Retry {
+    /// How many times to try a command, including the first.
+    pub attempts: u32,
+    /// How long to wait between attempts.
+    pub backoff: time::Duration,
+}
+
+impl Retry {
+    /// Never retry.
+    pub fn none() -> Retry {
+        return Retry {
+            attempts: 1,
+            backoff: time::Duration::from_secs(0),
+        };
+    }
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        return Retry {
+            attempts: 3,
+            backoff: time::Duration::from_millis(100),
+        };
+    }
+}
+
+/// Outcome is how a single attempt at a command went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// The command got a good reply.
+    Success,
+    /// The reply was bad, and the command will be sent again.
+    Retry,
+    /// The reply was bad, and the command failed.
+    Failure,
+}
+
+impl Outcome {
+    /// A short name for the outcome, e.g., for metrics.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Outcome::Success => "success",
+            Outcome::Retry => "retry",
+            Outcome::Failure => "failure",
+        };
+    }
+}
+
+/// Exchange is a single attempt at sending a command to a device, and
+/// receiving its reply.
+#[derive(Debug)]
+pub struct Exchange<'a> {
+    /// The command's name, see `wire::command::Info`.
+    pub command: &'static str,
+    /// How long the attempt took.
+    pub took: time::Duration,
+    pub outcome: Outcome,
+    /// Why the attempt failed, if it did.
+    pub error: Option<&'a Error>,
+}
+
+/// CommandCounts is how sending a command to a device has gone.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandCounts {
+    /// How many times the command got a good reply.
+    pub successes: u64,
+    /// How many times the command was sent again after a bad reply.
+    pub retries: u64,
+    /// How many times the command failed, after any retries.
+    pub failures: u64,
+}
+
+type Observer = Box<dyn Fn(&Exchange<'_>) + Send>;
+
+/// CommandStats counts how sending each command to a device has gone, by
+/// command name (see `wire::command::Info`). Clones share the same counts.
+#[derive(Clone, Default)]
+pub struct CommandStats {
+    counts: sync::Arc<sync::Mutex<BTreeMap<&'static str, CommandCounts>>>,
+    observers: sync::Arc<sync::Mutex<Vec<Observer>>>,
+}
+
+impl CommandStats {
+    /// The counts for the command named `name`.
+    pub fn get(&self, name: &str) -> CommandCounts {
+        let counts = self.counts.lock().unwrap();
+        return counts.get(name).copied().unwrap_or_default();
+    }
+
+    /// The counts for every command sent so far, by name.
+    pub fn all(&self) -> Vec<(&'static str, CommandCounts)> {
+        let counts = self.counts.lock().unwrap();
+        return counts.iter().map(|(k, v)| (*k, *v)).collect();
+    }
+
+    /// Call `f` after every attempt at a command, e.g., to export latencies
+    /// as metrics.
+    pub fn observe<F: Fn(&Exchange<'_>) + Send + 'static>(&self, f: F) {
+        self.observers.lock().unwrap().push(Box::new(f));
+    }
+
+    fn record(&self, e: &Exchange<'_>) {
+        {
+            let mut counts = self.counts.lock().unwrap();
+            let c = counts.entry(e.command).or_default();
+            match e.outcome {
+                Outcome::Success => c.successes += 1,
+                Outcome::Retry => c.retries += 1,
+                Outcome::Failure => c.failures += 1,
+            }
+        }
+        for f in self.observers.lock().unwrap().iter() {
+            f(e);
+        }
+    }
+}
+
+/// T6615 implements the `Device` trait for the Telaire T6615 CO2 module,
+/// attached to the serial port `P`.
+pub struct T6615<P = serialport::TTYPort> {
+    port: P,
+    decoder: wire::Decoder,
+    retry: Retry,
+    stats: CommandStats,
+}
+
+impl T6615 {
+    /// Construct a new T6615 instance from a TTY path, using the default
+    /// serial settings.
+    pub fn new(path: &str) -> Result<T6615> {
+        return T6615::open(&SerialOptions::new(path));
+    }
+
+    /// Construct a new T6615 instance on the serial port described by
+    /// `opts`.
+    pub fn open(opts: &SerialOptions) -> Result<T6615> {
+        return Ok(T6615::with_port(opts.open_port()?));
+    }
+}
+
+impl<P: Port> T6615<P> {
+    /// Construct a new T6615 instance talking over an already open port.
+    pub fn with_port(port: P) -> T6615<P> {
+        return T6615 {
+            port: port,
+            decoder: wire::Decoder::new(),
+            retry: Retry::default(),
+            stats: CommandStats::default(),
+        };
+    }
+
+    pub fn with_retry(mut self, retry: Retry) -> Self {
+        self.retry = retry;
+        return self;
+    }
+
+    /// Count commands in `stats`, e.g., to share them with a device that
+    /// replaces this one.
+    pub fn with_stats(mut self, stats: CommandStats) -> Self {
+        self.stats = stats;
+        return self;
+    }
+
+    /// How sending each command has gone.
+    pub fn stats(&self) -> CommandStats {
+        return self.stats.clone();
+    }
+
+    // Send `p` and receive the reply, once.
+    fn exchange<T>(&mut self, p: &wire::Payload, info: &wire::command::Info) -> Result<T>
+    where
+        T: TryFrom<wire::Payload, Error = wire::ParseError>,
+    {
+        // Drop anything left over from a previous exchange, so it can't be
+        // mistaken for the reply to this command.
+        self.decoder = wire::Decoder::new();
+        self.port.clear_input()?;
+        self.send(p.clone())?;
+        let body = self.receive()?;
+        if let Some(len) = info.reply_len {
+            if body.len() != len {
+                return Err(Error::Garbled(wire::ParseError::Length {
+                    expected: len,
+                    got: body.len(),
+                }));
+            }
+        }
+
+        // And unmarshal the reply body into a reply type.
+        return Ok(T::try_from(body)?);
+    }
+}
+
+impl<P: Port> Device for T6615<P> {
+    fn execute<S, T>(&mut self, s: S) -> Result<T>
     where
         S: Into<wire::Payload>,
-        E: ToString,
-        T: TryFrom<wire::Payload, Error = E>,
+        T: TryFrom<wire::Payload, Error = wire::ParseError>,
     {
+        let p: wire::Payload = s.into();
+        let info = wire::command::info(&p);
+        let attempts = if info.idempotent {
+            self.retry.attempts.max(1)
+        } else {
+            1
+        };
+        let mut attempt = 1;
+        loop {
+            let started = time::Instant::now();
+            let r = self.exchange(&p, &info);
+            let outcome = match &r {
+                Ok(_) => Outcome::Success,
+                Err(e) if attempt < attempts && e.is_transient() => Outcome::Retry,
+                Err(_) => Outcome::Failure,
+            };
+            self.stats.record(&Exchange {
+                command: info.name,
+                took: started.elapsed(),
+                outcome: outcome,
+                error: r.as_ref().err(),
+            });
+            match r {
+                Err(e) if outcome == Outcome::Retry => {
+                    warn!("{} failed, retrying: {}", info.name, e);
+                }
+                r => return r,
+            }
+            attempt += 1;
+            thread::sleep(self.retry.backoff);
+        }
+    }
+}
+
+impl<P: Port> StreamDevice for T6615<P> {
+    fn send<S: Into<wire::Payload>>(&mut self, s: S) -> Result<()> {
         let msg = wire::Message::from(s.into());
         self.port.write_all(&msg)?;
+        return Ok(());
+    }
 
-        // Read out the reply header.
-        let mut hdr: [u8; 3] = Default::default();
-        self.port.read_exact(&mut hdr)?;
-        if hdr[0] != 0xFF {
-            return Err(Error::from(format!(
-                "incorrect Tsunami flag: {:#X}",
-                hdr[0]
-            )));
-        }
-        if hdr[1] != 0xFA {
-            return Err(Error::from(format!(
-                "incorrect Tsunami address: {:#X}",
-                hdr[1]
-            )));
+    fn receive(&mut self) -> Result<wire::Payload> {
+        // Read until the decoder has re-assembled a full frame. Any noise on
+        // the line before the frame is skipped by the decoder.
+        let discarded = self.decoder.discarded();
+        let mut chunk: [u8; 64] = [0; 64];
+        let body = loop {
+            if let Some(p) = self.decoder.next_payload() {
+                break p;
+            }
+            let n = match self.port.read(&mut chunk) {
+                Ok(0) => return Err(Error::Closed),
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Error::from(e)),
+            };
+            self.decoder.push(&chunk[..n]);
+        };
+        if self.decoder.discarded() > discarded {
+            warn!(
+                "skipped {} bytes of noise while reading reply",
+                self.decoder.discarded() - discarded
+            );
         }
-        let length: usize = hdr[2] as usize;
-
-        // Read out the body.
-        let mut body: Vec<u8> = Vec::with_capacity(length);
-        // Though body has 'length' capacity, it is still "empty", so it
-        // is coereced to an empty slice. Here we reserve 'length'
-        // bytes so it will have non-zero size.
-        body.resize(length, 0);
-        self.port.read_exact(&mut body)?;
-
-        // And unmarshal the reply body into a reply type.
-        return Ok(T::try_from(wire::Payload(body)).map_err(|e| e.to_string())?);
+        return Ok(body);
     }
 }
 
@@ -248,6 +965,10 @@ mod tests {
         status_notify: Option<mpsc::Sender<()>>,
         reference: wire::Concentration,
         in_calibration: sync::Arc<atomic::AtomicBool>,
+        in_self_test: sync::Arc<atomic::AtomicBool>,
+        self_test_result: Option<wire::Payload>,
+        abc: wire::response::ABCState,
+        abc_resets: usize,
     }
 
     impl Default for Fake {
@@ -259,6 +980,10 @@ mod tests {
                 status_notify: None,
                 reference: wire::Concentration::PPM(0),
                 in_calibration: sync::Arc::new(atomic::AtomicBool::new(false)),
+                in_self_test: sync::Arc::new(atomic::AtomicBool::new(false)),
+                self_test_result: None,
+                abc: wire::response::ABCState::On,
+                abc_resets: 0,
             };
         }
     }
@@ -284,11 +1009,10 @@ mod tests {
     }
 
     impl Device for Fake {
-        fn execute<S, T, E>(&mut self, s: S) -> Result<T>
+        fn execute<S, T>(&mut self, s: S) -> Result<T>
         where
             S: Into<wire::Payload>,
-            E: ToString,
-            T: TryFrom<wire::Payload, Error = E>,
+            T: TryFrom<wire::Payload, Error = wire::ParseError>,
         {
             let p: wire::Payload = s.into();
             let r: wire::Payload;
@@ -300,6 +1024,7 @@ mod tests {
                 let mut flags = wire::response::StatusFlags::default();
                 flags.in_warmup = self.in_warmup.load(atomic::Ordering::SeqCst);
                 flags.in_calibration = self.in_calibration.load(atomic::Ordering::SeqCst);
+                flags.in_self_test = self.in_self_test.load(atomic::Ordering::SeqCst);
                 r = wire::response::Status::from(flags).into();
                 if let Some(notify) = &self.status_notify {
                     let _r = notify.send(());
@@ -315,15 +1040,125 @@ mod tests {
                 let wire::command::SetSinglePointPPM(c) = ssp;
                 self.reference = c;
                 r = wire::response::Ack.into();
-            } else if let Ok(_) = wire::command::VerifySinglePointCalibration::try_from(p.clone()) {
+            } else if wire::command::VerifySinglePointCalibration::try_from(p.clone()).is_ok() {
                 r = wire::response::GasPPM::with_ppm(self.reference.ppm()).into();
+            } else if p == wire::Payload::from(wire::command::ABCLogic) {
+                r = self.abc.into();
+            } else if p == wire::Payload::from(wire::command::SetABCLogic(wire::Toggle::On)) {
+                self.abc = wire::response::ABCState::On;
+                r = self.abc.into();
+            } else if p == wire::Payload::from(wire::command::SetABCLogic(wire::Toggle::Off)) {
+                self.abc = wire::response::ABCState::Off;
+                r = self.abc.into();
+            } else if p == wire::Payload::from(wire::command::ResetABCLogic) {
+                self.abc_resets += 1;
+                r = wire::response::Ack.into();
+            } else if p == wire::Payload::from(wire::command::StartSelfTest) {
+                self.in_self_test.store(true, atomic::Ordering::SeqCst);
+                r = wire::response::Ack.into();
+            } else if p == wire::Payload::from(wire::command::SelfTestResults) {
+                r = match &self.self_test_result {
+                    Some(res) => res.clone(),
+                    None => return Err(Error::from("no self-test result set on fake")),
+                };
             } else {
                 return Err(Error::from(format!("fake not implemented: {:?}", p)));
             }
-            return T::try_from(r).map_err(|e| Error::from(e.to_string()));
+            return Ok(T::try_from(r)?);
         }
     }
 
+    /// StreamFake implements `StreamDevice`. While streaming, it replies with
+    /// each of the concentrations in `readings` in turn.
+    #[derive(Default)]
+    struct StreamFake {
+        readings: Vec<u16>,
+        streaming: bool,
+        pending: Vec<wire::Payload>,
+        sent: Vec<wire::Payload>,
+    }
+
+    impl Device for StreamFake {
+        fn execute<S, T>(&mut self, s: S) -> Result<T>
+        where
+            S: Into<wire::Payload>,
+            T: TryFrom<wire::Payload, Error = wire::ParseError>,
+        {
+            self.send(s)?;
+            let r = self.receive()?;
+            return Ok(T::try_from(r)?);
+        }
+    }
+
+    impl StreamDevice for StreamFake {
+        fn send<S: Into<wire::Payload>>(&mut self, s: S) -> Result<()> {
+            let p: wire::Payload = s.into();
+            self.sent.push(p.clone());
+            if p == wire::Payload::from(wire::command::StreamData) {
+                self.streaming = true;
+            } else if p == wire::Payload::from(wire::command::Status) {
+                // Leave a streamed frame in flight, like the real device.
+                self.streaming = false;
+                self.pending
+                    .push(wire::response::StreamData::with_ppm(1).into());
+                self.pending.push(
+                    wire::response::Status::from(wire::response::StatusFlags::default()).into(),
+                );
+            }
+            return Ok(());
+        }
+
+        fn receive(&mut self) -> Result<wire::Payload> {
+            if !self.pending.is_empty() {
+                return Ok(self.pending.remove(0));
+            }
+            if self.streaming && !self.readings.is_empty() {
+                let ppm = self.readings.remove(0);
+                return Ok(wire::response::StreamData::with_ppm(ppm).into());
+            }
+            return Err(Error::from(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no more readings on fake",
+            )));
+        }
+    }
+
+    #[test]
+    fn test_stream_co2() {
+        let mut f = StreamFake::default();
+        f.readings = vec![400, 410, 420, 430];
+
+        let mut stream = f.stream_co2().unwrap();
+        let got: Vec<u16> = stream
+            .by_ref()
+            .take(3)
+            .map(|r| r.unwrap().concentration.ppm())
+            .collect();
+        assert_eq!(got, vec![400, 410, 420]);
+        stream.stop().unwrap();
+
+        assert!(!f.streaming);
+        assert_eq!(
+            f.sent,
+            vec![
+                wire::Payload::from(wire::command::StreamData),
+                wire::Payload::from(wire::command::Status),
+            ],
+        );
+    }
+
+    #[test]
+    fn test_stream_co2_stops_on_drop() {
+        let mut f = StreamFake::default();
+        f.readings = vec![400];
+        {
+            let mut stream = f.stream_co2().unwrap();
+            assert!(stream.next().unwrap().is_ok());
+        }
+        assert!(!f.streaming);
+        assert!(f.pending.is_empty());
+    }
+
     #[test]
     fn test_read_co2() {
         assert_eq!(
@@ -368,9 +1203,12 @@ mod tests {
         // warmup status.
         thread::spawn(move || {
             warmup_done_send
-                .send(f.wait_warmup(|_d| {
-                    thread::sleep(time::Duration::from_millis(100));
-                }))
+                .send(f.wait_warmup(
+                    |_d| {
+                        thread::sleep(time::Duration::from_millis(100));
+                    },
+                    &Limit::none(),
+                ))
                 .unwrap();
         });
 
@@ -397,6 +1235,64 @@ mod tests {
         assert!(!in_warm_status.load(atomic::Ordering::SeqCst));
     }
 
+    #[test]
+    fn test_wait_warmup_deadline() {
+        // A sensor stuck in warmup gives up at the deadline, rather than
+        // polling forever.
+        let mut f = Fake::default();
+        f.in_warmup.store(true, atomic::Ordering::SeqCst);
+        let limit = Limit::none().with_deadline(time::Instant::now());
+        match f.wait_warmup(|_d| {}, &limit) {
+            Err(Error::DeadlineExceeded(s)) => assert!(s.in_warmup()),
+            r => panic!("expected the deadline to be exceeded, got {:?}", r),
+        }
+
+        let polls = atomic::AtomicUsize::new(0);
+        let limit = Limit::timeout(time::Duration::from_millis(50));
+        let r = f.wait_warmup(
+            |_d| {
+                polls.fetch_add(1, atomic::Ordering::SeqCst);
+                thread::sleep(time::Duration::from_millis(5));
+            },
+            &limit,
+        );
+        assert!(matches!(r, Err(Error::DeadlineExceeded(_))));
+        assert!(polls.load(atomic::Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn test_calibrate_co2_cancel() {
+        let mut f = Fake::default();
+        let cancel = Cancel::new();
+        let limit = Limit::none().with_cancel(cancel.clone());
+
+        // Cancelling while the device calibrates stops waiting on it. The
+        // fake stays in calibration until told otherwise.
+        let r = f.calibrate_co2(wire::Concentration::PPM(400), |_d| cancel.cancel(), &limit);
+        assert_eq!(r, Err(Error::Cancelled));
+        assert_eq!(f.reference, wire::Concentration::PPM(400));
+
+        // Once cancelled, calibration doesn't start at all.
+        let mut f = Fake::default();
+        let r = f.calibrate_co2(wire::Concentration::PPM(400), |_d| {}, &limit);
+        assert_eq!(r, Err(Error::Cancelled));
+        assert_eq!(f.reference, wire::Concentration::PPM(0));
+    }
+
+    #[test]
+    fn test_cancel_wakes_sleep() {
+        let cancel = Cancel::new();
+        let sleeping = cancel.clone();
+        let (done_tx, done_rx) = mpsc::channel();
+        thread::spawn(move || {
+            sleeping.sleep(time::Duration::from_secs(60));
+            done_tx.send(()).unwrap();
+        });
+        cancel.cancel();
+        assert!(cancel.is_cancelled());
+        assert!(done_rx.recv_timeout(time::Duration::from_secs(5)).is_ok());
+    }
+
     #[test]
     fn test_set_elevation() {
         let mut f = Fake::default();
@@ -428,7 +1324,7 @@ mod tests {
                 // calibration mode.
                 in_calibration.store(false, atomic::Ordering::SeqCst);
             };
-            f.calibrate_co2(wire::Concentration::PPM(400), sleep_fn)
+            f.calibrate_co2(wire::Concentration::PPM(400), sleep_fn, &Limit::none())
                 .unwrap();
             calibrated_tx.send(f).unwrap();
         });
@@ -442,4 +1338,57 @@ mod tests {
             wire::Concentration::PPM(400),
         );
     }
+
+    #[test]
+    fn test_calibrate_co2_progress() {
+        let mut f = Fake::default();
+        let in_calibration = f.in_calibration.clone();
+        let stages = sync::Mutex::new(vec![]);
+        f.calibrate_co2_with_progress(
+            wire::Concentration::PPM(400),
+            |_d| in_calibration.store(false, atomic::Ordering::SeqCst),
+            &Limit::none(),
+            |stage| stages.lock().unwrap().push(stage),
+        )
+        .unwrap();
+        assert_eq!(
+            stages.into_inner().unwrap(),
+            vec![
+                CalibrationStage::VerifyingReference,
+                CalibrationStage::WaitingForCalibration,
+                CalibrationStage::Calibrating,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_run_self_test() {
+        let mut f = Fake::default();
+        let in_self_test = f.in_self_test.clone();
+        f.self_test_result = Some(wire::Payload(vec![0x0F, 0x01, 12, 12]));
+
+        let result = f
+            .run_self_test(|_d| {
+                // Finish the test the first time we're asked to wait.
+                in_self_test.store(false, atomic::Ordering::SeqCst);
+            })
+            .unwrap();
+        assert!(result.passed());
+        assert_eq!(result.total_dsp_cycles(), 12);
+    }
+
+    #[test]
+    fn test_abc() {
+        let mut f = Fake::default();
+        assert_eq!(f.read_abc(), Ok(wire::response::ABCState::On));
+
+        f.disable_abc().unwrap();
+        assert_eq!(f.read_abc(), Ok(wire::response::ABCState::Off));
+
+        f.enable_abc().unwrap();
+        assert_eq!(f.read_abc(), Ok(wire::response::ABCState::On));
+
+        f.reset_abc().unwrap();
+        assert_eq!(f.abc_resets, 1);
+    }
 }
@@ -0,0 +1,139 @@
+//! Live events, e.g., new measurements, published once and fanned out to
+//! every subscriber.
+//!
+//! Events are published by whatever owns the device, so the device is read
+//! at the same rate no matter how many subscribers are watching.
+use crate::api;
+use log::debug;
+use std::sync;
+use tokio::sync::broadcast;
+
+/// How many events are buffered for each subscriber. A subscriber that falls
+/// further behind than this misses the oldest events.
+pub const CAPACITY: usize = 64;
+
+/// Hub is where events are published. Clones publish to the same
+/// subscribers.
+#[derive(Debug, Clone)]
+pub struct Hub {
+    // Behind a mutex so the hub can be shared with gotham handlers, which
+    // must be unwind safe.
+    sender: sync::Arc<sync::Mutex<broadcast::Sender<api::Event>>>,
+}
+
+impl Hub {
+    pub fn new() -> Hub {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        return Hub {
+            sender: sync::Arc::new(sync::Mutex::new(sender)),
+        };
+    }
+
+    /// Send `e` to every current subscriber. Events published while there
+    /// are no subscribers are dropped.
+    pub fn publish(&self, e: api::Event) {
+        // Sending only fails if there are no subscribers.
+        let _ = self.sender.lock().unwrap().send(e);
+    }
+
+    /// Subscribe to every event published from now on.
+    pub fn subscribe(&self) -> Subscription {
+        return Subscription {
+            receiver: self.sender.lock().unwrap().subscribe(),
+        };
+    }
+
+    /// How many subscribers there are.
+    pub fn subscribers(&self) -> usize {
+        return self.sender.lock().unwrap().receiver_count();
+    }
+}
+
+impl Default for Hub {
+    fn default() -> Hub {
+        return Hub::new();
+    }
+}
+
+/// Subscription is a subscriber's view of the events published to a `Hub`.
+/// A subscriber that falls behind skips the events it missed.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: broadcast::Receiver<api::Event>,
+}
+
+impl Subscription {
+    /// Wait for the next event. Returns `None` once every `Hub` it was
+    /// subscribed to has been dropped.
+    pub async fn next(&mut self) -> Option<api::Event> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(e) => return Some(e),
+                Err(broadcast::error::RecvError::Lagged(n)) => {
+                    debug!("subscriber fell behind, skipped {} events", n);
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+
+    /// Returns the next event, if one has already been published.
+    pub fn try_next(&mut self) -> Option<api::Event> {
+        loop {
+            match self.receiver.try_recv() {
+                Ok(e) => return Some(e),
+                Err(broadcast::error::TryRecvError::Lagged(n)) => {
+                    debug!("subscriber fell behind, skipped {} events", n);
+                }
+                Err(_) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+
+    fn status(warmup: bool) -> api::Event {
+        let mut flags = wire::response::StatusFlags::default();
+        flags.in_warmup = warmup;
+        return api::Event::Status(api::Status::from(wire::response::Status::from(flags)));
+    }
+
+    #[test]
+    fn test_fan_out() {
+        let hub = Hub::new();
+        // Nobody is listening yet.
+        hub.publish(status(true));
+
+        let mut a = hub.subscribe();
+        let mut b = hub.clone().subscribe();
+        assert_eq!(hub.subscribers(), 2);
+        hub.publish(status(false));
+        assert_eq!(a.try_next(), Some(status(false)));
+        assert_eq!(a.try_next(), None);
+        assert_eq!(b.try_next(), Some(status(false)));
+
+        drop(b);
+        assert_eq!(hub.subscribers(), 1);
+    }
+
+    #[test]
+    fn test_lagging_subscriber() {
+        let hub = Hub::new();
+        let mut s = hub.subscribe();
+        for _ in 0..CAPACITY {
+            hub.publish(status(true));
+        }
+        hub.publish(status(false));
+        // The oldest event was dropped, the rest are still there.
+        let mut got = Vec::new();
+        while let Some(e) = s.try_next() {
+            got.push(e);
+        }
+        assert_eq!(got.len(), CAPACITY);
+        assert_eq!(got.last(), Some(&status(false)));
+    }
+}
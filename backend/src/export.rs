@@ -0,0 +1,157 @@
+//! Conversion of recorded readings into formats that are easy to pull into
+//! other tools (e.g., spreadsheets).
+use crate::device;
+use crate::wire;
+use serde;
+
+/// The CSV header written by `csv`.
+pub const CSV_HEADER: &str =
+    "time,co2_ppm,status_error,status_warmup,status_calibration,status_idle,status_self_test,elevation_ft";
+
+/// Status flags of a reading, as written by `jsonl`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct StatusRecord {
+    pub error: bool,
+    pub warmup: bool,
+    pub calibration: bool,
+    pub idle: bool,
+    pub self_test: bool,
+}
+
+impl From<&wire::response::Status> for StatusRecord {
+    fn from(s: &wire::response::Status) -> StatusRecord {
+        return StatusRecord {
+            error: s.is_err(),
+            warmup: s.in_warmup(),
+            calibration: s.in_calibration(),
+            idle: s.in_idle(),
+            self_test: s.in_self_test(),
+        };
+    }
+}
+
+/// A single reading, as written by `jsonl`.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Record {
+    pub time: chrono::DateTime<chrono::Utc>,
+    pub co2_ppm: u16,
+    pub status: Option<StatusRecord>,
+    pub elevation_ft: Option<u16>,
+}
+
+impl From<&device::Reading> for Record {
+    fn from(r: &device::Reading) -> Record {
+        return Record {
+            time: r.at,
+            co2_ppm: r.concentration.ppm(),
+            status: r.status.as_ref().map(StatusRecord::from),
+            elevation_ft: r.elevation.map(|e| e.feet()),
+        };
+    }
+}
+
+/// Render `readings` as CSV, with a header row. Unknown status flags or
+/// elevations are left empty.
+pub fn csv(readings: &[device::Reading]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    out.push_str(&csv_rows(readings));
+    return out;
+}
+
+/// Render `readings` as CSV rows, without the header row, e.g., to continue
+/// what `csv` started.
+pub fn csv_rows(readings: &[device::Reading]) -> String {
+    let mut out = String::new();
+    for r in readings.iter() {
+        let flags = match &r.status {
+            Some(s) => [
+                s.is_err(),
+                s.in_warmup(),
+                s.in_calibration(),
+                s.in_idle(),
+                s.in_self_test(),
+            ]
+            .iter()
+            .map(|f| f.to_string())
+            .collect::<Vec<String>>()
+            .join(","),
+            None => String::from(",,,,"),
+        };
+        let elevation = match r.elevation {
+            Some(e) => e.feet().to_string(),
+            None => String::new(),
+        };
+        out.push_str(&format!(
+            "{},{},{},{}\n",
+            r.at.to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            r.concentration.ppm(),
+            flags,
+            elevation
+        ));
+    }
+    return out;
+}
+
+/// Render `readings` as newline-delimited JSON, one `Record` per line.
+pub fn jsonl(readings: &[device::Reading]) -> serde_json::Result<String> {
+    let mut out = String::new();
+    for r in readings.iter() {
+        out.push_str(&serde_json::to_string(&Record::from(r))?);
+        out.push('\n');
+    }
+    return Ok(out);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn readings() -> Vec<device::Reading> {
+        let mut with_context = device::Reading::new(
+            wire::Concentration::PPM(410),
+            chrono::Utc.timestamp(1_600_000_015, 0),
+        );
+        with_context.status = Some(wire::response::Status::from(wire::response::StatusFlags {
+            in_calibration: true,
+            ..wire::response::StatusFlags::default()
+        }));
+        with_context.elevation = Some(wire::Distance::Feet(1500));
+        return vec![
+            device::Reading::new(
+                wire::Concentration::PPM(400),
+                chrono::Utc.timestamp(1_600_000_000, 0),
+            ),
+            with_context,
+        ];
+    }
+
+    #[test]
+    fn test_csv() {
+        let got = csv(&readings());
+        let lines: Vec<&str> = got.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                CSV_HEADER,
+                "2020-09-13T12:26:40.000Z,400,,,,,,",
+                "2020-09-13T12:26:55.000Z,410,false,false,true,false,false,1500",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_jsonl() {
+        let got = jsonl(&readings()).unwrap();
+        let records: Vec<Record> = got
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].co2_ppm, 400);
+        assert_eq!(records[0].status, None);
+        assert_eq!(records[1].elevation_ft, Some(1500));
+        assert!(records[1].status.as_ref().unwrap().calibration);
+    }
+}
@@ -0,0 +1,276 @@
+use crate::device;
+use serde;
+use std::collections::VecDeque;
+use std::error;
+use std::fmt;
+use std::result;
+
+/// The most buckets `History::downsample` will summarize a span of time
+/// into, whether or not they end up holding any readings.
+pub const MAX_BUCKETS: i64 = 100_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// The span of time asked for is too long for the step, i.e., it would
+    /// take more than `MAX_BUCKETS` buckets to cover.
+    TooManyBuckets(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            Error::TooManyBuckets(s) => write!(f, "too many buckets: {}", s),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// Bucket summarizes the readings taken within a span of time.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Bucket {
+    pub start: chrono::DateTime<chrono::Utc>,
+    pub end: chrono::DateTime<chrono::Utc>,
+    pub min: u16,
+    pub max: u16,
+    pub mean: f64,
+    pub count: usize,
+}
+
+impl Bucket {
+    fn of(r: &device::Reading) -> Bucket {
+        let ppm = r.concentration.ppm();
+        return Bucket {
+            start: r.at,
+            end: r.at,
+            min: ppm,
+            max: ppm,
+            mean: ppm as f64,
+            count: 1,
+        };
+    }
+
+    fn add(&mut self, r: &device::Reading) {
+        let ppm = r.concentration.ppm();
+        self.min = self.min.min(ppm);
+        self.max = self.max.max(ppm);
+        self.mean += (ppm as f64 - self.mean) / ((self.count + 1) as f64);
+        self.count += 1;
+    }
+}
+
+/// History is a bounded, in-memory buffer of readings. Readings older than
+/// `window` (relative to the newest reading) are dropped as new readings
+/// are added.
+#[derive(Debug)]
+pub struct History {
+    window: chrono::Duration,
+    readings: VecDeque<device::Reading>,
+}
+
+impl History {
+    pub fn new(window: chrono::Duration) -> History {
+        return History {
+            window: window,
+            readings: VecDeque::new(),
+        };
+    }
+
+    /// Returns the span of time covered by this history.
+    pub fn window(&self) -> chrono::Duration {
+        return self.window;
+    }
+
+    /// Change the span of time covered by this history. Readings that fall
+    /// outside of the new window are dropped.
+    pub fn set_window(&mut self, window: chrono::Duration) {
+        self.window = window;
+        self.expire();
+    }
+
+    /// Add a reading. Readings are expected to be pushed in time order.
+    pub fn push(&mut self, r: device::Reading) {
+        self.readings.push_back(r);
+        self.expire();
+    }
+
+    fn expire(&mut self) {
+        let newest = match self.readings.back() {
+            Some(r) => r.at,
+            None => return,
+        };
+        while let Some(oldest) = self.readings.front() {
+            if newest - oldest.at <= self.window {
+                return;
+            }
+            self.readings.pop_front();
+        }
+    }
+
+    /// Returns the most recent reading, if any.
+    pub fn latest(&self) -> Option<device::Reading> {
+        return self.readings.back().copied();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.readings.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.readings.is_empty();
+    }
+
+    /// Returns the readings taken in [since, until].
+    pub fn range(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+        until: chrono::DateTime<chrono::Utc>,
+    ) -> Vec<device::Reading> {
+        return self
+            .readings
+            .iter()
+            .filter(|r| r.at >= since && r.at <= until)
+            .copied()
+            .collect();
+    }
+
+    /// Summarize the readings taken in [since, until] into buckets `step`
+    /// wide, starting at `since`. Empty buckets are omitted. If `step` is
+    /// `None`, every reading gets its own bucket. Fails if covering the span
+    /// would take more than `MAX_BUCKETS` buckets.
+    pub fn downsample(
+        &self,
+        since: chrono::DateTime<chrono::Utc>,
+        until: chrono::DateTime<chrono::Utc>,
+        step: Option<chrono::Duration>,
+    ) -> Result<Vec<Bucket>> {
+        let readings = self.range(since, until);
+        let step_ms = match step.map(|s| s.num_milliseconds()) {
+            Some(ms) if ms > 0 => ms,
+            _ => return Ok(readings.iter().map(Bucket::of).collect()),
+        };
+        let span_ms = (until - since).num_milliseconds();
+        if span_ms / step_ms >= MAX_BUCKETS {
+            return Err(Error::TooManyBuckets(format!(
+                "{}ms in steps of {}ms would take more than {}",
+                span_ms, step_ms, MAX_BUCKETS
+            )));
+        }
+        // Where the `idx`th bucket starts or, for `idx + 1`, ends.
+        let edge = |idx: i64| {
+            return step_ms
+                .checked_mul(idx)
+                .and_then(|ms| since.checked_add_signed(chrono::Duration::milliseconds(ms)))
+                .ok_or_else(|| Error::TooManyBuckets(format!("bucket {} is out of range", idx)));
+        };
+        let mut buckets: Vec<Bucket> = Vec::new();
+        for r in readings.iter() {
+            // Readings are in time order, so only the last bucket can
+            // still be growing.
+            let idx = (r.at - since).num_milliseconds() / step_ms;
+            let start = edge(idx)?;
+            match buckets.last_mut() {
+                Some(b) if b.start == start => b.add(r),
+                _ => {
+                    let mut b = Bucket::of(r);
+                    b.start = start;
+                    b.end = edge(idx + 1)?;
+                    buckets.push(b);
+                }
+            }
+        }
+        return Ok(buckets);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+    use chrono::TimeZone;
+
+    fn reading(secs: i64, ppm: u16) -> device::Reading {
+        return device::Reading::new(
+            wire::Concentration::PPM(ppm),
+            chrono::Utc.timestamp(secs, 0),
+        );
+    }
+
+    #[test]
+    fn test_push_expires() {
+        let mut h = History::new(chrono::Duration::seconds(60));
+        h.push(reading(0, 400));
+        h.push(reading(30, 410));
+        h.push(reading(60, 420));
+        assert_eq!(h.len(), 3);
+
+        // Pushing a reading 61s after the first one drops it.
+        h.push(reading(61, 430));
+        assert_eq!(h.len(), 3);
+        assert_eq!(
+            h.range(chrono::Utc.timestamp(0, 0), chrono::Utc.timestamp(100, 0)),
+            vec![reading(30, 410), reading(60, 420), reading(61, 430)],
+        );
+        assert_eq!(h.latest(), Some(reading(61, 430)));
+
+        // Shrinking the window drops anything outside of it.
+        h.set_window(chrono::Duration::seconds(1));
+        assert_eq!(h.len(), 2);
+    }
+
+    #[test]
+    fn test_downsample() {
+        let mut h = History::new(chrono::Duration::hours(1));
+        for (secs, ppm) in [(0, 400), (5, 500), (9, 600), (25, 700)] {
+            h.push(reading(secs, ppm));
+        }
+        let since = chrono::Utc.timestamp(0, 0);
+        let until = chrono::Utc.timestamp(30, 0);
+
+        let buckets = h
+            .downsample(since, until, Some(chrono::Duration::seconds(10)))
+            .unwrap();
+        assert_eq!(
+            buckets,
+            vec![
+                Bucket {
+                    start: chrono::Utc.timestamp(0, 0),
+                    end: chrono::Utc.timestamp(10, 0),
+                    min: 400,
+                    max: 600,
+                    mean: 500.0,
+                    count: 3,
+                },
+                // The empty 10s-20s bucket is skipped.
+                Bucket {
+                    start: chrono::Utc.timestamp(20, 0),
+                    end: chrono::Utc.timestamp(30, 0),
+                    min: 700,
+                    max: 700,
+                    mean: 700.0,
+                    count: 1,
+                },
+            ],
+        );
+
+        // Without a step, each reading is reported on its own.
+        let raw = h
+            .downsample(since, chrono::Utc.timestamp(5, 0), None)
+            .unwrap();
+        assert_eq!(
+            raw,
+            vec![Bucket::of(&reading(0, 400)), Bucket::of(&reading(5, 500))]
+        );
+
+        // Summarizing a long span in small steps is refused, even though
+        // most of the buckets would be empty.
+        let year = chrono::Duration::days(365);
+        let long = h.downsample(since - year, until, Some(chrono::Duration::seconds(1)));
+        assert!(matches!(long, Err(Error::TooManyBuckets(_))));
+        let far = chrono::Utc.timestamp(i32::MAX as i64 * 1000, 0);
+        let huge = h.downsample(since, far, Some(chrono::Duration::seconds(u32::MAX as i64)));
+        assert_eq!(huge.unwrap().len(), 1);
+    }
+}
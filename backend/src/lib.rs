@@ -0,0 +1,20 @@
+pub mod analyze;
+pub mod api;
+pub mod calibration;
+pub mod capture;
+pub mod config;
+pub mod device;
+pub mod events;
+pub mod export;
+pub mod history;
+pub mod metrics;
+pub mod mqtt;
+pub mod push;
+pub mod reconnect;
+pub mod server;
+pub mod sim;
+pub mod store;
+#[cfg(test)]
+mod testing;
+pub mod tools;
+pub mod wire;
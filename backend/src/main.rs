@@ -1,43 +1,290 @@
-use std::env;
-use std::net;
-use std::process;
-mod device;
-mod server;
-mod wire;
-use device::Device;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use co2::analyze;
+use co2::calibration;
+use co2::capture;
+use co2::config;
+use co2::device;
+use co2::device::Device;
+use co2::reconnect;
+use co2::server;
+use co2::sim;
+use co2::tools;
+use co2::wire;
+use futures_util::future;
 use gotham;
-use log::error;
+use log::{error, warn};
 use pretty_env_logger;
 use std::default::Default;
+use std::env;
+use std::ffi;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::net;
+use std::path;
+use std::process;
 use std::thread;
+use std::time;
+use tokio::signal;
 
-fn print_device(d: &mut device::T6615) -> device::Result<()> {
-    let serial: wire::response::SerialNumber =
-        d.execute(wire::command::Read(wire::Variable::SerialNumber))?;
-    let subvol: wire::response::CompileSubvol =
-        d.execute(wire::command::Read(wire::Variable::CompileSubvol))?;
-    let date: wire::response::CompileDate =
-        d.execute(wire::command::Read(wire::Variable::CompileDate))?;
-    println!("Device: Telaire T6615");
-    println!("  Serial: {}", serial);
-    println!("  Software Version: {}.{}", subvol, date);
-    return Ok(());
+/// Serve CO2 measurements from a Telaire T6615 over HTTP, or service the
+/// sensor directly.
+///
+/// Settings are taken from the flags below, then the configuration file (if
+/// given), then the built-in defaults, in that order of precedence. Flags
+/// may be given before or after the command.
+///
+/// Earlier versions took the static directory and serial device as
+/// positional arguments, `co2 <static-dir> <serial-device>`. That's still
+/// accepted, as `co2 serve --static-dir <static-dir> --serial-device
+/// <serial-device>`, but warned about.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Flags {
+    /// Read settings from this TOML configuration file.
+    #[arg(short, long, global = true)]
+    config: Option<path::PathBuf>,
+
+    /// The address and port to serve on [default: 0.0.0.0:80].
+    #[arg(long, global = true)]
+    listen: Option<net::SocketAddr>,
+
+    /// The directory the frontend is served from [default: ./frontend].
+    #[arg(long, global = true)]
+    static_dir: Option<path::PathBuf>,
+
+    /// Durably log measurements to this directory.
+    #[arg(long, global = true)]
+    data_dir: Option<path::PathBuf>,
+
+    /// The serial device the sensor is attached to [default: /dev/serial0].
+    #[arg(long, global = true)]
+    serial_device: Option<String>,
+
+    /// The baud rate of the serial device [default: 19200].
+    #[arg(long, global = true)]
+    baud_rate: Option<u32>,
+
+    /// How long to wait for the sensor to reply, in seconds [default: 15].
+    #[arg(long, global = true)]
+    serial_timeout: Option<u64>,
+
+    /// How often to sample the sensor, in seconds [default: 15].
+    #[arg(long, global = true)]
+    sample_interval: Option<u64>,
+
+    /// The concentration calibration is done against, in ppm [default: 425].
+    #[arg(long, global = true)]
+    calibration_reference: Option<u16>,
+
+    /// Configure the sensor's elevation, in feet, on boot.
+    #[arg(long, global = true)]
+    elevation: Option<u16>,
+
+    /// Record all traffic with the sensor to this capture file, e.g., to
+    /// turn a misbehaving sensor's session into a test.
+    #[arg(long, global = true)]
+    record: Option<path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() {
-    pretty_env_logger::init();
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        error!("Must supply <static-dir> <serial-device>");
-        process::exit(1);
+/// Command is a subcommand of the `co2` binary. Every command other than
+/// `serve` talks to the sensor directly, and must not be run while the
+/// server is using the sensor.
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve measurements over HTTP. This is the default.
+    Serve,
+    /// Print the sensor's serial number and software version.
+    Info,
+    /// Print a single CO2 measurement.
+    Read,
+    /// Continuously print CO2 measurements.
+    Watch {
+        /// How often to take a measurement, in seconds.
+        #[arg(long, default_value_t = 5)]
+        interval: u64,
+        /// Stop after this many measurements.
+        #[arg(long)]
+        count: Option<usize>,
+    },
+    /// Print the sensor's status flags.
+    Status,
+    /// Calibrate the sensor. Blocks until calibration is finished.
+    Calibrate {
+        /// The concentration of the air the sensor is exposed to, in ppm.
+        /// Defaults to the configured calibration reference.
+        #[arg(long)]
+        reference: Option<u16>,
+    },
+    /// Read or configure the sensor's elevation.
+    Elevation {
+        #[command(subcommand)]
+        action: ElevationAction,
+    },
+    /// Read or configure automatic baseline correction (ABC).
+    Abc {
+        #[command(subcommand)]
+        action: ABCAction,
+    },
+    /// Run the sensor's self-test.
+    Selftest,
+    /// Check the connection to the sensor, by having it echo a test
+    /// pattern back.
+    Loopback,
+    /// Simulate a sensor on a pseudo-terminal, for use without hardware.
+    /// Point `--serial-device` at the printed path to use it.
+    Simulate {
+        /// The concentrations to report: constant:<ppm>,
+        /// sine:<mean>:<amplitude>:<period-secs> or
+        /// sequence:<step-secs>:<ppm>,<ppm>,...
+        #[arg(long, default_value = "sine:450:50:600")]
+        waveform: sim::Waveform,
+        /// How long warmup takes, in seconds.
+        #[arg(long, default_value_t = 30)]
+        warmup: u64,
+        /// How long calibration takes, in seconds.
+        #[arg(long, default_value_t = 60)]
+        calibration: u64,
+        /// How long the self-test takes, in seconds.
+        #[arg(long, default_value_t = 10)]
+        self_test: u64,
+    },
+    /// Annotate each frame in a dump of serial traffic with its decoded
+    /// command or response, and flag malformed frames. Does not use the
+    /// sensor.
+    Decode {
+        /// The dump to decode. Read from stdin if omitted.
+        file: Option<path::PathBuf>,
+        /// The format of the dump: hex bytes (optionally prefixed with `0x`
+        /// and separated by commas), raw binary, or a `--record` capture.
+        #[arg(long, value_enum, default_value_t = DumpFormat::Hex)]
+        format: DumpFormat,
+    },
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+enum DumpFormat {
+    Hex,
+    Binary,
+    Capture,
+}
+
+#[derive(Debug, Subcommand)]
+enum ElevationAction {
+    /// Print the configured elevation.
+    Get,
+    /// Configure the elevation. Rounded to the nearest 500ft by the sensor.
+    Set {
+        /// The elevation, in feet.
+        feet: u16,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum ABCAction {
+    /// Print whether ABC is enabled.
+    Get,
+    /// Enable ABC.
+    On,
+    /// Disable ABC.
+    Off,
+    /// Discard the baseline learned so far.
+    Reset,
+}
+
+// Translate the positional `co2 <static-dir> <serial-device>` invocation of
+// earlier versions into flags, so that existing setups keep working. Any
+// other arguments are returned as they are.
+fn upgrade_legacy_args(args: Vec<ffi::OsString>) -> Vec<ffi::OsString> {
+    let mut cmd = Flags::command();
+    cmd.build();
+    let is_positional = |a: &ffi::OsString| {
+        return a
+            .to_str()
+            .is_none_or(|s| !s.starts_with('-') && cmd.find_subcommand(s).is_none());
+    };
+    if args.len() != 3 || !args[1..].iter().all(is_positional) {
+        return args;
+    }
+    let (static_dir, serial_device) = (&args[1], &args[2]);
+    warn!(
+        "Positional arguments are deprecated, run: {} serve --static-dir {} --serial-device {}",
+        args[0].to_string_lossy(),
+        static_dir.to_string_lossy(),
+        serial_device.to_string_lossy()
+    );
+    return vec![
+        args[0].clone(),
+        ffi::OsString::from("serve"),
+        ffi::OsString::from("--static-dir"),
+        static_dir.clone(),
+        ffi::OsString::from("--serial-device"),
+        serial_device.clone(),
+    ];
+}
+
+impl Flags {
+    // Build the configuration, with any flags overriding the configuration
+    // file.
+    fn config(&self) -> config::Result<config::Config> {
+        let mut config = match &self.config {
+            Some(p) => config::Config::load(p)?,
+            None => config::Config::default(),
+        };
+        if let Some(listen) = self.listen {
+            config.http.listen = listen;
+        }
+        if let Some(dir) = &self.static_dir {
+            config.static_dir = dir.clone();
+        }
+        if let Some(dir) = &self.data_dir {
+            config.data_dir = Some(dir.clone());
+        }
+        if let Some(device) = &self.serial_device {
+            config.serial.device = device.clone();
+        }
+        if let Some(baud_rate) = self.baud_rate {
+            config.serial.baud_rate = baud_rate;
+        }
+        if let Some(timeout) = self.serial_timeout {
+            config.serial.timeout_secs = timeout;
+        }
+        if let Some(interval) = self.sample_interval {
+            config.sampling.interval_secs = interval;
+        }
+        if let Some(reference) = self.calibration_reference {
+            config.calibration.reference_ppm = reference;
+        }
+        if let Some(elevation) = self.elevation {
+            config.sensor.elevation_ft = Some(elevation);
+        }
+        config.validate()?;
+        return Ok(config);
     }
-    let (static_dir, serial_device_path) = (&args[1], &args[2]);
-    let mut sensor = device::T6615::new(serial_device_path).expect("unable to connect to sensor");
+}
 
-    print_device(&mut sensor).expect("failed to read device metadata");
+// Serve the sensor on the port opened by `open`, which is called again to
+// reconnect to the sensor if the connection is lost.
+fn serve<P, F>(config: &config::Config, open: F) -> device::Result<()>
+where
+    P: device::Port + Send + 'static,
+    F: FnMut() -> device::Result<P> + Send + 'static,
+{
+    let mut sensor =
+        reconnect::Reconnecting::open(open)?.with_warmup_timeout(config.warmup_timeout());
+    tools::info(&mut sensor, &mut io::stdout()).expect("failed to read device metadata");
 
     println!("Waiting for warmup...");
-    sensor.wait_warmup(thread::sleep).unwrap();
+    if let Err(e) = sensor.wait_warmup(
+        thread::sleep,
+        &device::Limit::timeout(config.warmup_timeout()),
+    ) {
+        error!("Error: Sensor failed to warm up: {}", e);
+        process::exit(1);
+    }
 
     let status: wire::response::Status = sensor
         .execute(wire::command::Status)
@@ -47,12 +294,216 @@ fn main() {
         process::exit(1);
     }
 
+    if let Some(elevation) = config.elevation() {
+        println!("Configuring elevation to {}ft...", elevation.feet());
+        sensor
+            .set_elevation(elevation)
+            .expect("failed to configure elevation");
+    }
+
     println!("Booting server...");
     let mut server_builder = server::Builder::default();
-    server_builder.device(sensor);
-    server_builder.static_dir(static_dir);
+    server_builder
+        .connection(sensor.connection())
+        .command_stats(sensor.stats())
+        .device(sensor)
+        .static_dir(&config.static_dir.to_string_lossy())
+        .sample_interval(config.sample_interval())
+        .history_window(config.history_window())
+        .calibration_reference(config.calibration_reference())
+        .calibration_timeout(config.calibration_timeout());
+    if let Some(s) = config.calibration_stability() {
+        server_builder.calibration_stability(s);
+    }
+    if let Some(opts) = config.storage() {
+        server_builder.storage(opts);
+    }
+    if let Some(token) = &config.http.control_token {
+        server_builder.control_token(token);
+    }
+    if let Some(opts) = config.mqtt() {
+        server_builder.mqtt(opts);
+    }
+    for target in config.exporters() {
+        server_builder.exporter(target);
+    }
     let server = server_builder.build().expect("failed to build server");
 
-    println!("Serving on 0.0.0.0:80");
-    gotham::start((net::Ipv4Addr::new(0, 0, 0, 0), 80), server.routes());
+    println!("Serving on {}", config.http.listen);
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(future::select(
+        Box::pin(gotham::init_server(config.http.listen, server.routes())),
+        Box::pin(shutdown_requested()),
+    ));
+    println!("Shutting down...");
+    server.shutdown();
+    return Ok(());
+}
+
+// Wait until the process is asked to stop, by SIGINT (e.g., Ctrl-C) or
+// SIGTERM (e.g., by systemd).
+async fn shutdown_requested() {
+    let terminate = async {
+        match signal::unix::signal(signal::unix::SignalKind::terminate()) {
+            Ok(mut s) => {
+                s.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                future::pending::<()>().await;
+            }
+        }
+    };
+    future::select(Box::pin(signal::ctrl_c()), Box::pin(terminate)).await;
+}
+
+// Run `command`, other than `serve`, against the sensor attached to `port`.
+fn run<P>(config: &config::Config, command: &Command, port: P) -> device::Result<()>
+where
+    P: device::Port + Send + 'static,
+{
+    let out = &mut io::stdout();
+    let d = &mut device::T6615::with_port(port);
+    return match command {
+        Command::Serve | Command::Simulate { .. } | Command::Decode { .. } => unreachable!(),
+        Command::Info => tools::info(d, out),
+        Command::Read => tools::read(d, out),
+        Command::Watch { interval, count } => tools::watch(
+            d,
+            out,
+            time::Duration::from_secs(*interval),
+            *count,
+            thread::sleep,
+        ),
+        Command::Status => tools::status(d, out),
+        Command::Calibrate { reference } => {
+            let reference = match reference {
+                Some(ppm) => wire::Concentration::PPM(*ppm),
+                None => config.calibration_reference(),
+            };
+            calibration::check_reference(reference)
+                .map_err(|e| device::Error::from(e.to_string()))?;
+            let timeout = config.calibration_timeout();
+            tools::calibrate(d, out, reference, timeout, thread::sleep)
+        }
+        Command::Elevation { action } => match action {
+            ElevationAction::Get => tools::get_elevation(d, out),
+            ElevationAction::Set { feet } => {
+                tools::set_elevation(d, out, wire::Distance::Feet(*feet))
+            }
+        },
+        Command::Abc { action } => match action {
+            ABCAction::Get => tools::get_abc(d, out),
+            ABCAction::On => tools::set_abc(d, out, wire::Toggle::On),
+            ABCAction::Off => tools::set_abc(d, out, wire::Toggle::Off),
+            ABCAction::Reset => tools::reset_abc(d, out),
+        },
+        Command::Selftest => tools::self_test(d, out, thread::sleep),
+        Command::Loopback => tools::loopback(d, out),
+    };
+}
+
+// Serve a simulated sensor until the process is killed.
+fn simulate(waveform: &sim::Waveform, warmup: u64, calibration: u64, self_test: u64) {
+    let mut timing = sim::Timing::default();
+    timing.warmup = time::Duration::from_secs(warmup);
+    timing.calibration = time::Duration::from_secs(calibration);
+    timing.self_test = time::Duration::from_secs(self_test);
+    let sensor = sim::Sensor::new(waveform.clone()).with_timing(timing);
+    let simulator = sim::Simulator::start(sensor).expect("failed to start simulator");
+    println!("Simulating a T6615 on {}", simulator.path());
+    loop {
+        thread::park();
+    }
+}
+
+// Annotate the serial traffic dumped to `file`, or stdin if `None`.
+fn decode(file: &Option<path::PathBuf>, format: &DumpFormat) -> capture::Result<()> {
+    let raw = match file {
+        Some(p) => fs::read(p)
+            .map_err(|e| capture::Error::from(format!("failed to read {}: {}", p.display(), e)))?,
+        None => {
+            let mut raw = Vec::new();
+            io::stdin().read_to_end(&mut raw)?;
+            raw
+        }
+    };
+    let text = |raw: Vec<u8>| {
+        return String::from_utf8(raw)
+            .map_err(|_| capture::Error::from("dump is not text, try --format=binary"));
+    };
+    let bytes = match format {
+        DumpFormat::Hex => analyze::parse_hex(&text(raw)?)?,
+        DumpFormat::Binary => raw,
+        DumpFormat::Capture => capture::Capture::parse(&text(raw)?)?.bytes(),
+    };
+    analyze::report(&analyze::analyze(&bytes), &mut io::stdout())?;
+    return Ok(());
+}
+
+fn main() {
+    pretty_env_logger::init();
+    let flags = Flags::parse_from(upgrade_legacy_args(env::args_os().collect()));
+    let config = match flags.config() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Invalid configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    let command = flags.command.as_ref().unwrap_or(&Command::Serve);
+    if let Command::Simulate {
+        waveform,
+        warmup,
+        calibration,
+        self_test,
+    } = command
+    {
+        simulate(waveform, *warmup, *calibration, *self_test);
+        return;
+    }
+    if let Command::Decode { file, format } = command {
+        if let Err(e) = decode(file, format) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let opts = config.serial_options();
+    let sink = match &flags.record {
+        Some(path) => match fs::File::create(path) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                eprintln!("Error: failed to create {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        },
+        None => None,
+    };
+    let result = match (command, sink) {
+        // The server reopens the port if the connection is lost, and keeps
+        // recording to the same capture, on the same timeline.
+        (Command::Serve, Some(f)) => {
+            let started = time::Instant::now();
+            serve(&config, move || {
+                let sink = f.try_clone()?;
+                let port = opts.open_port()?;
+                return Ok(capture::Recording::new(port, sink).with_start(started));
+            })
+        }
+        (Command::Serve, None) => serve(&config, move || opts.open_port()),
+        (_, Some(f)) => opts
+            .open_port()
+            .and_then(|port| run(&config, command, capture::Recording::new(port, f))),
+        (_, None) => opts
+            .open_port()
+            .and_then(|port| run(&config, command, port)),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,438 @@
+//! Prometheus metrics about the sensor, served at `/metrics`.
+//!
+//! Most metrics describe the state of the sensor as last seen by the
+//! server, and are brought up to date from a `Snapshot` whenever they are
+//! scraped. Serial command latencies, outcomes and errors are recorded as
+//! the commands happen instead, see `Metrics::watch_commands`.
+use crate::api;
+use crate::calibration;
+use crate::device;
+use crate::reconnect;
+use crate::wire;
+use prometheus;
+use prometheus::Encoder;
+use std::result;
+
+/// The upper bounds of the serial command latency buckets, in seconds. A
+/// healthy sensor replies within tens of milliseconds, and gives up after
+/// the serial timeout, 15 seconds by default.
+const LATENCY_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 15.0,
+];
+
+/// Snapshot is the state of the sensor the metrics are taken from.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// The most recent measurement, if any.
+    pub reading: Option<device::Reading>,
+    /// The outcome of the last self-test, if one has finished: its result,
+    /// or why it couldn't be run.
+    pub self_test: Option<result::Result<wire::response::SelfTest, String>>,
+    pub abc: Option<wire::response::ABCState>,
+    pub identity: Option<device::Identity>,
+    /// The most recently finished calibration, if any.
+    pub last_calibration: Option<calibration::Job>,
+    pub now: chrono::DateTime<chrono::Utc>,
+}
+
+fn bool_value(b: bool) -> f64 {
+    return if b { 1.0 } else { 0.0 };
+}
+
+// ConnectionMetrics exports the state of the connection to the device.
+#[derive(Clone)]
+struct ConnectionMetrics {
+    connection: reconnect::Connection,
+    connected: prometheus::IntGauge,
+    reconnects: prometheus::IntCounter,
+}
+
+impl ConnectionMetrics {
+    fn register(
+        connection: reconnect::Connection,
+        registry: &prometheus::Registry,
+    ) -> prometheus::Result<ConnectionMetrics> {
+        let connected = prometheus::IntGauge::new(
+            "co2_serial_connected",
+            "1 if the sensor's serial port is connected, 0 while reconnecting.",
+        )?;
+        registry.register(Box::new(connected.clone()))?;
+        let reconnects = prometheus::IntCounter::new(
+            "co2_serial_reconnects_total",
+            "How many times the sensor's serial port has been reconnected.",
+        )?;
+        registry.register(Box::new(reconnects.clone()))?;
+        let metrics = ConnectionMetrics {
+            connection: connection,
+            connected: connected,
+            reconnects: reconnects,
+        };
+        metrics.update();
+        return Ok(metrics);
+    }
+
+    // Bring the metrics up to date with the connection.
+    fn update(&self) {
+        self.connected
+            .set(if self.connection.is_connected() { 1 } else { 0 });
+        let seen = self.reconnects.get();
+        let reconnects = self.connection.reconnects();
+        if reconnects > seen {
+            self.reconnects.inc_by(reconnects - seen);
+        }
+    }
+}
+
+/// Metrics is the registry of every metric about the sensor. Clones share
+/// the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: prometheus::Registry,
+    co2: prometheus::Gauge,
+    self_test: prometheus::Gauge,
+    status: prometheus::IntGaugeVec,
+    elevation: prometheus::Gauge,
+    abc: prometheus::Gauge,
+    sample_age: prometheus::Gauge,
+    calibration_time: prometheus::Gauge,
+    calibration_success: prometheus::Gauge,
+    info: prometheus::IntGaugeVec,
+    command_latency: prometheus::HistogramVec,
+    commands: prometheus::IntCounterVec,
+    errors: prometheus::IntCounterVec,
+    connection: Option<ConnectionMetrics>,
+}
+
+impl Metrics {
+    pub fn new() -> prometheus::Result<Metrics> {
+        let registry = prometheus::Registry::new();
+        let gauge = |name: &str, help: &str| -> prometheus::Result<prometheus::Gauge> {
+            let g = prometheus::Gauge::new(name, help)?;
+            // Unknown until there's something to report.
+            g.set(f64::NAN);
+            registry.register(Box::new(g.clone()))?;
+            return Ok(g);
+        };
+        let co2 = gauge(
+            "co2_ppm",
+            "The current concentration of CO2 in the air in parts per million",
+        )?;
+        let self_test = gauge(
+            "co2_selftest_passed",
+            "1 if the last sensor self-test passed, 0 if it failed. NaN if no \
+             self-test has completed yet.",
+        )?;
+        let elevation = gauge(
+            "co2_elevation_feet",
+            "The elevation the sensor is configured for, in feet.",
+        )?;
+        let abc = gauge(
+            "co2_abc_enabled",
+            "1 if the sensor's automatic baseline correction is on, 0 if off.",
+        )?;
+        let sample_age = gauge(
+            "co2_last_sample_age_seconds",
+            "How long ago the most recent measurement was taken.",
+        )?;
+        let calibration_time = gauge(
+            "co2_last_calibration_timestamp_seconds",
+            "When the most recent calibration finished, as a Unix timestamp.",
+        )?;
+        let calibration_success = gauge(
+            "co2_last_calibration_success",
+            "1 if the most recent calibration succeeded, 0 if it failed or \
+             was cancelled.",
+        )?;
+
+        let status = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new(
+                "co2_status",
+                "1 if the sensor's most recent status has the flag set, 0 if not.",
+            ),
+            &["flag"],
+        )?;
+        registry.register(Box::new(status.clone()))?;
+        let info = prometheus::IntGaugeVec::new(
+            prometheus::Opts::new("co2_sensor_info", "The sensor's identity, always 1."),
+            &["serial_number", "compile_subvol", "compile_date"],
+        )?;
+        registry.register(Box::new(info.clone()))?;
+        let command_latency = prometheus::HistogramVec::new(
+            prometheus::HistogramOpts::new(
+                "co2_serial_command_duration_seconds",
+                "How long each attempt at a serial command took, including \
+                 waiting for the reply.",
+            )
+            .buckets(LATENCY_BUCKETS.to_vec()),
+            &["command"],
+        )?;
+        registry.register(Box::new(command_latency.clone()))?;
+        let commands = prometheus::IntCounterVec::new(
+            prometheus::Opts::new(
+                "co2_serial_commands_total",
+                "Attempts at serial commands, by whether they succeeded, will \
+                 be retried, or failed.",
+            ),
+            &["command", "outcome"],
+        )?;
+        registry.register(Box::new(commands.clone()))?;
+        let errors = prometheus::IntCounterVec::new(
+            prometheus::Opts::new(
+                "co2_serial_errors_total",
+                "Failed attempts at serial commands, by kind of error.",
+            ),
+            &["kind"],
+        )?;
+        registry.register(Box::new(errors.clone()))?;
+
+        return Ok(Metrics {
+            registry: registry,
+            co2: co2,
+            self_test: self_test,
+            status: status,
+            elevation: elevation,
+            abc: abc,
+            sample_age: sample_age,
+            calibration_time: calibration_time,
+            calibration_success: calibration_success,
+            info: info,
+            command_latency: command_latency,
+            commands: commands,
+            errors: errors,
+            connection: None,
+        });
+    }
+
+    /// Export the state of `connection`.
+    pub fn watch_connection(
+        &mut self,
+        connection: reconnect::Connection,
+    ) -> prometheus::Result<()> {
+        self.connection = Some(ConnectionMetrics::register(connection, &self.registry)?);
+        return Ok(());
+    }
+
+    /// Record the latency, outcome and any error of every command counted
+    /// by `stats`.
+    pub fn watch_commands(&self, stats: &device::CommandStats) {
+        let latency = self.command_latency.clone();
+        let commands = self.commands.clone();
+        let errors = self.errors.clone();
+        stats.observe(move |e| {
+            latency
+                .with_label_values(&[e.command])
+                .observe(e.took.as_secs_f64());
+            commands
+                .with_label_values(&[e.command, e.outcome.name()])
+                .inc();
+            if let Some(err) = e.error {
+                errors.with_label_values(&[err.kind()]).inc();
+            }
+        });
+    }
+
+    /// Bring the metrics up to date with `s`.
+    pub fn update(&self, s: &Snapshot) {
+        if let Some(c) = &self.connection {
+            c.update();
+        }
+        match &s.reading {
+            Some(r) => {
+                self.co2.set(r.concentration.ppm() as f64);
+                let age = s.now - r.at;
+                self.sample_age
+                    .set(age.num_milliseconds().max(0) as f64 / 1000.0);
+            }
+            None => {
+                self.co2.set(f64::NAN);
+                self.sample_age.set(f64::NAN);
+            }
+        }
+        match s.reading.as_ref().and_then(|r| r.status) {
+            Some(status) => {
+                for (flag, is_set) in api::Status::from(status).flags().iter() {
+                    self.status
+                        .with_label_values(&[flag])
+                        .set(if *is_set { 1 } else { 0 });
+                }
+            }
+            None => self.status.reset(),
+        }
+        match s.reading.as_ref().and_then(|r| r.elevation) {
+            Some(d) => self.elevation.set(d.feet() as f64),
+            None => self.elevation.set(f64::NAN),
+        }
+        match s.abc {
+            Some(a) => self.abc.set(bool_value(a == wire::response::ABCState::On)),
+            None => self.abc.set(f64::NAN),
+        }
+        // A self-test that couldn't be run didn't pass either, e.g., on a
+        // dying sensor.
+        match &s.self_test {
+            Some(Ok(r)) => self.self_test.set(bool_value(r.passed())),
+            Some(Err(_)) => self.self_test.set(0.0),
+            None => {}
+        }
+        match &s.last_calibration {
+            Some(job) => {
+                let at = job.finished_at.unwrap_or(job.requested_at);
+                self.calibration_time
+                    .set(at.timestamp_millis() as f64 / 1000.0);
+                self.calibration_success
+                    .set(bool_value(job.state == calibration::State::Succeeded));
+            }
+            None => {
+                self.calibration_time.set(f64::NAN);
+                self.calibration_success.set(f64::NAN);
+            }
+        }
+        self.info.reset();
+        if let Some(id) = &s.identity {
+            self.info
+                .with_label_values(&[
+                    &id.serial_number,
+                    &id.compile_subvol,
+                    &id.compile_date.to_string(),
+                ])
+                .set(1);
+        }
+    }
+
+    /// Encode every metric in the Prometheus text format.
+    pub fn encode(&self) -> prometheus::Result<Vec<u8>> {
+        let mut out: Vec<u8> = Vec::new();
+        prometheus::TextEncoder::new().encode(&self.registry.gather(), &mut out)?;
+        return Ok(out);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::capture;
+    use crate::device::Device;
+    use chrono::TimeZone;
+    use std::convert::TryFrom;
+    use std::time;
+
+    fn encoded(m: &Metrics) -> String {
+        return String::from_utf8(m.encode().unwrap()).unwrap();
+    }
+
+    fn empty(now: chrono::DateTime<chrono::Utc>) -> Snapshot {
+        return Snapshot {
+            reading: None,
+            self_test: None,
+            abc: None,
+            identity: None,
+            last_calibration: None,
+            now: now,
+        };
+    }
+
+    #[test]
+    fn test_update() {
+        let m = Metrics::new().unwrap();
+        let now = chrono::Utc.timestamp(1_600_000_030, 0);
+        let mut flags = wire::response::StatusFlags::default();
+        flags.in_warmup = true;
+        let mut reading = device::Reading::new(
+            wire::Concentration::PPM(612),
+            chrono::Utc.timestamp(1_600_000_000, 0),
+        );
+        reading.status = Some(flags.into());
+        reading.elevation = Some(wire::Distance::Feet(1500));
+        m.update(&Snapshot {
+            reading: Some(reading),
+            self_test: None,
+            abc: Some(wire::response::ABCState::Off),
+            identity: Some(device::Identity {
+                serial_number: String::from("1234"),
+                compile_subvol: String::from("A01"),
+                compile_date: chrono::NaiveDate::from_ymd(2019, 3, 5),
+            }),
+            last_calibration: Some(calibration::Job {
+                id: 1,
+                reference_ppm: 425,
+                state: calibration::State::Failed,
+                reason: Some(String::from("no")),
+                requested_at: chrono::Utc.timestamp(1_500_000_000, 0),
+                started_at: None,
+                finished_at: Some(chrono::Utc.timestamp(1_500_000_100, 0)),
+            }),
+            now: now,
+        });
+
+        let body = encoded(&m);
+        assert!(body.contains("co2_ppm 612"));
+        assert!(body.contains("co2_last_sample_age_seconds 30"));
+        assert!(body.contains("co2_status{flag=\"warmup\"} 1"));
+        assert!(body.contains("co2_status{flag=\"calibration\"} 0"));
+        assert!(body.contains("co2_elevation_feet 1500"));
+        assert!(body.contains("co2_abc_enabled 0"));
+        assert!(body.contains("co2_last_calibration_timestamp_seconds 1500000100"));
+        assert!(body.contains("co2_last_calibration_success 0"));
+        assert!(body.contains(
+            "co2_sensor_info{compile_date=\"2019-03-05\",compile_subvol=\"A01\",serial_number=\"1234\"} 1"
+        ));
+
+        // Once the state is unknown, so are the metrics.
+        m.update(&empty(now));
+        let body = encoded(&m);
+        assert!(body.contains("co2_ppm NaN"));
+        assert!(!body.contains("co2_status{"));
+        assert!(!body.contains("co2_sensor_info{"));
+    }
+
+    #[test]
+    fn test_update_self_test() {
+        let m = Metrics::new().unwrap();
+        let now = chrono::Utc.timestamp(1_600_000_030, 0);
+        let passed = wire::response::SelfTest::try_from(wire::Payload(vec![0x0F, 0x01, 12, 12]));
+        m.update(&Snapshot {
+            self_test: Some(Ok(passed.unwrap())),
+            ..empty(now)
+        });
+        assert!(encoded(&m).contains("co2_selftest_passed 1"));
+
+        // A later self-test that couldn't be run doesn't leave the last
+        // pass standing.
+        m.update(&Snapshot {
+            self_test: Some(Err(String::from("timed out"))),
+            ..empty(now)
+        });
+        assert!(encoded(&m).contains("co2_selftest_passed 0"));
+    }
+
+    #[test]
+    fn test_watch_commands() {
+        let m = Metrics::new().unwrap();
+        let stats = device::CommandStats::default();
+        m.watch_commands(&stats);
+
+        let mut d = device::T6615::with_port(
+            capture::Capture::parse(
+                "{\"at_us\":0,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":15000000,\"dir\":\"rx\",\"bytes\":\"\",\"error\":\"Operation timed out\"}\n\
+             {\"at_us\":15001000,\"dir\":\"tx\",\"bytes\":\"FF FE 01 B6\"}\n\
+             {\"at_us\":15002000,\"dir\":\"rx\",\"bytes\":\"FF FA 01 00\"}\n",
+            )
+            .unwrap()
+            .replay(),
+        )
+        .with_retry(device::Retry {
+            attempts: 2,
+            backoff: time::Duration::from_secs(0),
+        })
+        .with_stats(stats);
+        d.read_status().unwrap();
+
+        let body = encoded(&m);
+        assert!(body.contains("co2_serial_commands_total{command=\"Status\",outcome=\"retry\"} 1"));
+        assert!(
+            body.contains("co2_serial_commands_total{command=\"Status\",outcome=\"success\"} 1")
+        );
+        assert!(body.contains("co2_serial_errors_total{kind=\"timeout\"} 1"));
+        assert!(body.contains("co2_serial_command_duration_seconds_count{command=\"Status\"} 2"));
+    }
+}
@@ -0,0 +1,421 @@
+//! Publishing measurements to an MQTT broker, e.g., for Home Assistant.
+//!
+//! Every event (see `api::Event`) is published as JSON to a topic named
+//! after it under `Options::topic_prefix`, e.g., `co2/reading`. Messages are
+//! retained, so a new subscriber sees the latest of each right away. The
+//! `availability` topic is `online` while connected, and is set to `offline`
+//! on shutdown, or by the broker if the connection is lost. With Home Assistant discovery
+//! enabled, the sensor is announced on every connect, so it shows up in Home
+//! Assistant without any configuration there.
+use crate::api;
+use crate::device;
+use crate::events;
+use crate::reconnect;
+use log::{error, info};
+use rumqttc;
+use serde;
+use std::io;
+use std::thread;
+use std::time;
+
+/// The port MQTT brokers listen on by default.
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// The prefix of the topics published to by default.
+pub const DEFAULT_TOPIC_PREFIX: &str = "co2";
+
+/// The prefix Home Assistant looks for discovery configs under by default.
+pub const DEFAULT_DISCOVERY_PREFIX: &str = "homeassistant";
+
+/// How often the broker is pinged while idle by default.
+pub const DEFAULT_KEEP_ALIVE: time::Duration = time::Duration::from_secs(30);
+
+// The topic, under the prefix, that says whether the server is connected.
+const AVAILABILITY_TOPIC: &str = "availability";
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+// How many messages can be waiting to be sent to the broker, e.g., while
+// reconnecting, before publishing more waits.
+const CAPACITY: usize = 64;
+
+/// Options configures the connection to the broker, and what's published.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub host: String,
+    pub port: u16,
+    pub client_id: String,
+    /// The username and password to log in with, if any.
+    pub credentials: Option<(String, String)>,
+    pub keep_alive: time::Duration,
+    /// The prefix of every topic published to, e.g., `co2`.
+    pub topic_prefix: String,
+    /// The prefix Home Assistant looks for discovery configs under. The
+    /// sensor isn't announced if `None`.
+    pub discovery_prefix: Option<String>,
+}
+
+impl Options {
+    /// The default options for the broker at `host`.
+    pub fn new(host: &str) -> Options {
+        return Options {
+            host: String::from(host),
+            port: DEFAULT_PORT,
+            client_id: String::from("co2"),
+            credentials: None,
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            topic_prefix: String::from(DEFAULT_TOPIC_PREFIX),
+            discovery_prefix: Some(String::from(DEFAULT_DISCOVERY_PREFIX)),
+        };
+    }
+
+    /// The full name of the topic `name`, under the prefix.
+    pub fn topic(&self, name: &str) -> String {
+        return format!("{}/{}", self.topic_prefix, name);
+    }
+}
+
+/// Discovery is a Home Assistant MQTT discovery config, which describes the
+/// sensor to Home Assistant. See
+/// https://www.home-assistant.io/integrations/sensor.mqtt/.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct Discovery {
+    pub name: String,
+    pub unique_id: String,
+    pub device_class: String,
+    pub state_class: String,
+    pub unit_of_measurement: String,
+    pub state_topic: String,
+    pub value_template: String,
+    pub availability_topic: String,
+    pub device: DiscoveryDevice,
+}
+
+/// DiscoveryDevice is the device a Home Assistant entity belongs to.
+#[derive(Debug, PartialEq, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DiscoveryDevice {
+    pub identifiers: Vec<String>,
+    pub name: String,
+    pub manufacturer: String,
+    pub model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sw_version: Option<String>,
+}
+
+impl Discovery {
+    /// The discovery config for the CO2 concentration measured by the sensor
+    /// with `identity`, as published with `opts`.
+    pub fn new(opts: &Options, identity: Option<&device::Identity>) -> Discovery {
+        let id = node_id(opts, identity);
+        return Discovery {
+            name: String::from("CO2"),
+            unique_id: format!("{}_co2", id),
+            device_class: String::from("carbon_dioxide"),
+            state_class: String::from("measurement"),
+            unit_of_measurement: String::from("ppm"),
+            state_topic: opts.topic("reading"),
+            value_template: String::from("{{ value_json.ppm }}"),
+            availability_topic: opts.topic(AVAILABILITY_TOPIC),
+            device: DiscoveryDevice {
+                identifiers: vec![id],
+                name: String::from("CO2 Sensor"),
+                manufacturer: String::from("Telaire"),
+                model: String::from("T6615"),
+                serial_number: identity.map(|i| i.serial_number.clone()),
+                sw_version: identity.map(|i| format!("{} ({})", i.compile_subvol, i.compile_date)),
+            },
+        };
+    }
+
+    /// The topic the config is published to, under `prefix`.
+    pub fn topic(&self, prefix: &str) -> String {
+        return format!(
+            "{}/sensor/{}/co2/config",
+            prefix, self.device.identifiers[0]
+        );
+    }
+}
+
+// A name for the sensor that's unique, stable, and safe to use in a topic.
+// Based on the serial number if the sensor reported one, and the client ID
+// otherwise.
+fn node_id(opts: &Options, identity: Option<&device::Identity>) -> String {
+    let name = match identity {
+        Some(i) => format!("co2_{}", i.serial_number),
+        None => opts.client_id.clone(),
+    };
+    return name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+}
+
+/// Start publishing every event from `events` to the broker, as configured
+/// by `opts`. `identity` is what the sensor reported about itself, used to
+/// announce it to Home Assistant. Publishing stops once the `events::Hub`
+/// the subscription is from is dropped.
+pub fn start(
+    opts: Options,
+    identity: Option<device::Identity>,
+    mut events: events::Subscription,
+) -> io::Result<()> {
+    let mut mqtt_opts = rumqttc::MqttOptions::new(&opts.client_id, &opts.host, opts.port);
+    mqtt_opts.set_keep_alive(opts.keep_alive);
+    if let Some((username, password)) = &opts.credentials {
+        mqtt_opts.set_credentials(username, password);
+    }
+    mqtt_opts.set_last_will(rumqttc::LastWill::new(
+        opts.topic(AVAILABILITY_TOPIC),
+        OFFLINE,
+        rumqttc::QoS::AtLeastOnce,
+        true,
+    ));
+    let (client, mut connection) = rumqttc::Client::new(mqtt_opts, CAPACITY);
+
+    // Waiting for the next event is async, so it's done on a runtime of its
+    // own.
+    let runtime = tokio::runtime::Builder::new_current_thread().build()?;
+    let mut publisher = client.clone();
+    let publisher_opts = opts.clone();
+    thread::Builder::new()
+        .name(String::from("mqtt-publisher"))
+        .spawn(move || {
+            while let Some(e) = runtime.block_on(events.next()) {
+                let payload = match event_payload(&e) {
+                    Ok(p) => p,
+                    Err(err) => {
+                        error!("Failed to encode {} event: {}", e.name(), err);
+                        continue;
+                    }
+                };
+                let topic = publisher_opts.topic(e.name());
+                if let Err(err) = publisher.publish(topic, rumqttc::QoS::AtLeastOnce, true, payload)
+                {
+                    error!("Failed to publish {} event: {}", e.name(), err);
+                }
+            }
+            // A clean disconnect doesn't trigger the last will, so mark the
+            // server offline first.
+            let topic = publisher_opts.topic(AVAILABILITY_TOPIC);
+            if let Err(e) = publisher.publish(topic, rumqttc::QoS::AtLeastOnce, true, OFFLINE) {
+                error!("Failed to publish availability: {}", e);
+            }
+            // Let the connection know it's done.
+            let _ = publisher.disconnect();
+        })?;
+
+    thread::Builder::new()
+        .name(String::from("mqtt-connection"))
+        .spawn(move || {
+            let mut backoff = reconnect::MIN_BACKOFF;
+            for notification in connection.iter() {
+                match notification {
+                    Ok(rumqttc::Event::Incoming(rumqttc::Packet::ConnAck(_))) => {
+                        info!("Connected to MQTT broker {}:{}", opts.host, opts.port);
+                        backoff = reconnect::MIN_BACKOFF;
+                        announce(&client, &opts, identity.as_ref());
+                    }
+                    Ok(rumqttc::Event::Outgoing(rumqttc::Outgoing::Disconnect)) => return,
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("MQTT connection failed, retrying in {:?}: {}", backoff, e);
+                        thread::sleep(backoff);
+                        backoff = (backoff * 2).min(reconnect::MAX_BACKOFF);
+                    }
+                }
+            }
+        })?;
+    return Ok(());
+}
+
+// The payload `e` is published with: the JSON of what happened.
+fn event_payload(e: &api::Event) -> serde_json::Result<Vec<u8>> {
+    return match e {
+        api::Event::Reading(r) => serde_json::to_vec(r),
+        api::Event::Status(s) => serde_json::to_vec(s),
+        api::Event::Calibration(j) => serde_json::to_vec(j),
+        api::Event::SelfTest(r) => serde_json::to_vec(r),
+    };
+}
+
+// Mark the server online, and announce the sensor to Home Assistant, if
+// enabled. Called on every connect, as the broker may have restarted and lost
+// retained messages. The messages are queued from a thread of their own, which
+// waits for space in the queue if it's full, e.g., of readings taken while
+// disconnected: the connection only makes progress while the caller isn't
+// blocked.
+fn announce(client: &rumqttc::Client, opts: &Options, identity: Option<&device::Identity>) {
+    let mut messages = vec![(opts.topic(AVAILABILITY_TOPIC), ONLINE.as_bytes().to_vec())];
+    if let Some(prefix) = &opts.discovery_prefix {
+        let discovery = Discovery::new(opts, identity);
+        match serde_json::to_vec(&discovery) {
+            Ok(payload) => messages.push((discovery.topic(prefix), payload)),
+            Err(e) => error!("Failed to encode discovery config: {}", e),
+        }
+    }
+    let mut client = client.clone();
+    let spawned = thread::Builder::new()
+        .name(String::from("mqtt-announce"))
+        .spawn(move || {
+            for (topic, payload) in messages.into_iter() {
+                if let Err(e) = client.publish(&topic, rumqttc::QoS::AtLeastOnce, true, payload) {
+                    error!("Failed to publish to {}: {}", topic, e);
+                }
+            }
+        });
+    if let Err(e) = spawned {
+        error!("Failed to announce the sensor: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::wire;
+    use bytes::BytesMut;
+    use rumqttc::mqttbytes::v4;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::sync::mpsc;
+
+    fn identity() -> device::Identity {
+        return device::Identity {
+            serial_number: String::from("12345"),
+            compile_subvol: String::from("A01"),
+            compile_date: chrono::NaiveDate::from_ymd(2020, 1, 2),
+        };
+    }
+
+    // Accept a single client on `listener`, and pass every packet it sends
+    // to the returned channel, acknowledging them as a broker would.
+    fn fake_broker(listener: net::TcpListener) -> mpsc::Receiver<v4::Packet> {
+        let (packets_in, packets_out) = mpsc::channel();
+        thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut buf = BytesMut::new();
+            let mut chunk = [0u8; 1024];
+            loop {
+                let packet = match v4::read(&mut buf, 1 << 20) {
+                    Ok(p) => p,
+                    Err(rumqttc::mqttbytes::Error::InsufficientBytes(_)) => {
+                        match conn.read(&mut chunk) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+                        }
+                        continue;
+                    }
+                    Err(e) => panic!("bad packet: {:?}", e),
+                };
+                let mut reply = BytesMut::new();
+                match &packet {
+                    v4::Packet::Connect(_) => {
+                        v4::ConnAck::new(v4::ConnectReturnCode::Success, false)
+                            .write(&mut reply)
+                            .unwrap();
+                    }
+                    v4::Packet::Publish(p) if p.qos == rumqttc::QoS::AtLeastOnce => {
+                        v4::PubAck::new(p.pkid).write(&mut reply).unwrap();
+                    }
+                    v4::Packet::PingReq => {
+                        v4::PingResp.write(&mut reply).unwrap();
+                    }
+                    _ => {}
+                }
+                conn.write_all(&reply).unwrap();
+                if packets_in.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+        return packets_out;
+    }
+
+    fn next_publish(packets: &mpsc::Receiver<v4::Packet>) -> v4::Publish {
+        loop {
+            match packets.recv_timeout(time::Duration::from_secs(5)).unwrap() {
+                v4::Packet::Publish(p) => return p,
+                _ => continue,
+            }
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let opts = Options::new("localhost");
+        let d = Discovery::new(&opts, Some(&identity()));
+        assert_eq!(
+            d.topic("homeassistant"),
+            "homeassistant/sensor/co2_12345/co2/config"
+        );
+        assert_eq!(d.device_class, "carbon_dioxide");
+        assert_eq!(d.state_topic, "co2/reading");
+        assert_eq!(d.device.serial_number.as_deref(), Some("12345"));
+        assert_eq!(d.device.sw_version.as_deref(), Some("A01 (2020-01-02)"));
+
+        // Without an identity, the client ID stands in for the serial number.
+        let mut opts = Options::new("localhost");
+        opts.client_id = String::from("living room");
+        let d = Discovery::new(&opts, None);
+        assert_eq!(d.topic("ha"), "ha/sensor/living_room/co2/config");
+        assert_eq!(d.device.serial_number, None);
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut opts = Options::new("127.0.0.1");
+        opts.port = listener.local_addr().unwrap().port();
+        let packets = fake_broker(listener);
+        let hub = events::Hub::new();
+        start(opts, Some(identity()), hub.subscribe()).unwrap();
+
+        // The broker marks the server offline if the connection is lost.
+        let connect = match packets.recv_timeout(time::Duration::from_secs(5)).unwrap() {
+            v4::Packet::Connect(c) => c,
+            p => panic!("expected a connect, got {:?}", p),
+        };
+        let will = connect.last_will.unwrap();
+        assert_eq!(will.topic, "co2/availability");
+        assert_eq!(&will.message[..], b"offline");
+        assert!(will.retain);
+
+        let online = next_publish(&packets);
+        assert_eq!(online.topic, "co2/availability");
+        assert_eq!(&online.payload[..], b"online");
+        assert!(online.retain);
+        let discovery = next_publish(&packets);
+        assert_eq!(discovery.topic, "homeassistant/sensor/co2_12345/co2/config");
+        let config: Discovery = serde_json::from_slice(&discovery.payload).unwrap();
+        assert_eq!(
+            config,
+            Discovery::new(&Options::new("127.0.0.1"), Some(&identity()))
+        );
+
+        let r = device::Reading::new(wire::Concentration::PPM(612), chrono::Utc::now());
+        hub.publish(api::Event::Reading(api::Reading::new(&r, None, r.at)));
+        let reading = next_publish(&packets);
+        assert_eq!(reading.topic, "co2/reading");
+        assert!(reading.retain);
+        let got: api::Reading = serde_json::from_slice(&reading.payload).unwrap();
+        assert_eq!(got.ppm, 612);
+
+        // Publishing stops once there's nothing left to publish, and the
+        // server is marked offline before disconnecting.
+        drop(hub);
+        let offline = next_publish(&packets);
+        assert_eq!(offline.topic, "co2/availability");
+        assert_eq!(&offline.payload[..], b"offline");
+        assert!(offline.retain);
+        loop {
+            match packets.recv_timeout(time::Duration::from_secs(5)).unwrap() {
+                v4::Packet::Disconnect => break,
+                _ => continue,
+            }
+        }
+    }
+}
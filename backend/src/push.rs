@@ -0,0 +1,1120 @@
+//! Pushing readings to collectors, for servers nothing can scrape, e.g.,
+//! behind NAT.
+//!
+//! Each `Exporter` sends batches of readings to a collector: InfluxDB over
+//! HTTP or UDP, or any HTTP endpoint as JSON. A `Pusher` feeds an exporter
+//! from the sampler on a thread of its own. It batches readings, retries
+//! failed batches with backoff, and spools whatever still can't be sent to
+//! disk, to be sent once the collector is back.
+use crate::device;
+use crate::export;
+use crate::reconnect;
+use gotham::hyper;
+use http;
+use log::{error, info, warn};
+use std::collections;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::net;
+use std::net::ToSocketAddrs;
+use std::path;
+use std::result;
+use std::sync::mpsc;
+use std::thread;
+use std::time;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The exporter is misconfigured, e.g., its URL is malformed.
+    Config(String),
+    /// The collector couldn't be reached, or failed to take the batch.
+    /// Worth retrying.
+    Unavailable(String),
+    /// The collector refused the batch, e.g., as malformed. Retrying won't
+    /// help.
+    Rejected(String),
+    /// Reading or writing the spool failed.
+    Io(io::Error),
+}
+
+impl Error {
+    /// Whether sending the same batch again might succeed.
+    pub fn is_retryable(&self) -> bool {
+        return matches!(self, Error::Unavailable(_));
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            Error::Config(s) => write!(f, "invalid exporter: {}", s),
+            Error::Unavailable(s) => write!(f, "collector unavailable: {}", s),
+            Error::Rejected(s) => write!(f, "collector rejected readings: {}", s),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Config(_) | Error::Unavailable(_) | Error::Rejected(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error::Io(e);
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+/// The measurement InfluxDB readings are written to by default.
+pub const DEFAULT_MEASUREMENT: &str = "co2";
+
+// How long a collector has to take a batch over HTTP.
+const HTTP_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// The largest UDP datagram sent. Larger batches are split across datagrams,
+// so they aren't fragmented on a typical network.
+const MAX_DATAGRAM: usize = 1400;
+
+/// Exporter sends readings to a collector.
+pub trait Exporter: Send {
+    /// Send `batch` to the collector. The whole batch is sent again if this
+    /// fails with a retryable error, so collectors should tolerate
+    /// duplicates.
+    fn send(&mut self, batch: &[export::Record]) -> Result<()>;
+}
+
+/// LineProtocol renders readings as InfluxDB line protocol. See
+/// https://docs.influxdata.com/influxdb/v2/reference/syntax/line-protocol/.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineProtocol {
+    pub measurement: String,
+    /// Attached to every point, e.g., to tell sensors apart.
+    pub tags: collections::BTreeMap<String, String>,
+}
+
+impl Default for LineProtocol {
+    fn default() -> LineProtocol {
+        return LineProtocol {
+            measurement: String::from(DEFAULT_MEASUREMENT),
+            tags: collections::BTreeMap::new(),
+        };
+    }
+}
+
+impl LineProtocol {
+    /// Render `r` as a single point, with a nanosecond timestamp.
+    pub fn line(&self, r: &export::Record) -> String {
+        let mut out = escape(&self.measurement, &['\\', ',', ' ']);
+        for (k, v) in self.tags.iter() {
+            out.push_str(&format!(
+                ",{}={}",
+                escape(k, &['\\', ',', '=', ' ']),
+                escape(v, &['\\', ',', '=', ' '])
+            ));
+        }
+        out.push_str(&format!(" ppm={}i", r.co2_ppm));
+        if let Some(e) = r.elevation_ft {
+            out.push_str(&format!(",elevation_ft={}i", e));
+        }
+        if let Some(s) = &r.status {
+            out.push_str(&format!(
+                ",error={},warmup={},calibration={},idle={},self_test={}",
+                s.error, s.warmup, s.calibration, s.idle, s.self_test
+            ));
+        }
+        out.push_str(&format!(" {}", r.time.timestamp_nanos()));
+        return out;
+    }
+}
+
+// Escape every `special` character in `s` with a backslash. Newlines can't
+// be escaped in line protocol at all, so the config rejects them.
+fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    return out;
+}
+
+// Poster POSTs bodies to a URL. hyper's client is async, so it's run on a
+// runtime of its own.
+struct Poster {
+    runtime: tokio::runtime::Runtime,
+    client: hyper::Client<hyper::client::HttpConnector>,
+    url: hyper::Uri,
+    headers: http::HeaderMap,
+}
+
+impl Poster {
+    fn new(url: &str, headers: &collections::BTreeMap<String, String>) -> Result<Poster> {
+        let url: hyper::Uri = url
+            .parse()
+            .map_err(|e| Error::Config(format!("{}: {}", url, e)))?;
+        if url.scheme_str() != Some("http") {
+            return Err(Error::Config(format!(
+                "{}: only http:// URLs are supported",
+                url
+            )));
+        }
+        let mut header_map = http::HeaderMap::new();
+        for (k, v) in headers.iter() {
+            let name = http::header::HeaderName::from_bytes(k.as_bytes())
+                .map_err(|e| Error::Config(format!("header {}: {}", k, e)))?;
+            let value = http::HeaderValue::from_str(v)
+                .map_err(|e| Error::Config(format!("header {}: {}", k, e)))?;
+            header_map.insert(name, value);
+        }
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        return Ok(Poster {
+            runtime: runtime,
+            client: hyper::Client::new(),
+            url: url,
+            headers: header_map,
+        });
+    }
+
+    fn post(&self, content_type: &str, body: Vec<u8>) -> Result<()> {
+        let mut req = hyper::Request::post(self.url.clone())
+            .body(hyper::Body::from(body))
+            .map_err(|e| Error::Config(e.to_string()))?;
+        req.headers_mut().extend(self.headers.clone());
+        req.headers_mut().insert(
+            http::header::CONTENT_TYPE,
+            http::HeaderValue::from_str(content_type).unwrap(),
+        );
+        let client = self.client.clone();
+        return self.runtime.block_on(async move {
+            let resp = tokio::time::timeout(HTTP_TIMEOUT, client.request(req))
+                .await
+                .map_err(|_| Error::Unavailable(format!("timed out after {:?}", HTTP_TIMEOUT)))?
+                .map_err(|e| Error::Unavailable(e.to_string()))?;
+            let status = resp.status();
+            if status.is_success() {
+                return Ok(());
+            }
+            let body = hyper::body::to_bytes(resp.into_body())
+                .await
+                .unwrap_or_default();
+            let message = format!("{}: {}", status, String::from_utf8_lossy(&body).trim());
+            // Only the collector failing, or asking us to back off, is worth
+            // retrying.
+            if status.is_server_error()
+                || status == http::StatusCode::REQUEST_TIMEOUT
+                || status == http::StatusCode::TOO_MANY_REQUESTS
+            {
+                return Err(Error::Unavailable(message));
+            }
+            return Err(Error::Rejected(message));
+        });
+    }
+}
+
+/// InfluxHttp writes readings to InfluxDB's HTTP write endpoint.
+pub struct InfluxHttp {
+    poster: Poster,
+    line: LineProtocol,
+}
+
+impl InfluxHttp {
+    /// Write to `url`, the full URL of the write endpoint, e.g.,
+    /// `http://influx:8086/api/v2/write?org=home&bucket=co2` for InfluxDB 2,
+    /// or `http://influx:8086/write?db=co2` for InfluxDB 1. `token` is sent
+    /// as `Authorization: Token <token>`, if any.
+    pub fn new(url: &str, token: Option<&str>, line: LineProtocol) -> Result<InfluxHttp> {
+        let mut headers = collections::BTreeMap::new();
+        if let Some(t) = token {
+            headers.insert(String::from("authorization"), format!("Token {}", t));
+        }
+        return Ok(InfluxHttp {
+            poster: Poster::new(url, &headers)?,
+            line: line,
+        });
+    }
+}
+
+impl Exporter for InfluxHttp {
+    fn send(&mut self, batch: &[export::Record]) -> Result<()> {
+        let mut body = String::new();
+        for r in batch.iter() {
+            body.push_str(&self.line.line(r));
+            body.push('\n');
+        }
+        return self
+            .poster
+            .post("text/plain; charset=utf-8", body.into_bytes());
+    }
+}
+
+/// InfluxUdp writes readings to InfluxDB's UDP listener, or anything that
+/// speaks line protocol over UDP, e.g., Telegraf.
+pub struct InfluxUdp {
+    address: String,
+    line: LineProtocol,
+}
+
+impl InfluxUdp {
+    /// Write to `address`, a `host:port`. The host is looked up on every
+    /// send, so it needn't resolve yet.
+    pub fn new(address: &str, line: LineProtocol) -> InfluxUdp {
+        return InfluxUdp {
+            address: String::from(address),
+            line: line,
+        };
+    }
+}
+
+impl Exporter for InfluxUdp {
+    fn send(&mut self, batch: &[export::Record]) -> Result<()> {
+        let unavailable = |e: io::Error| Error::Unavailable(format!("{}: {}", self.address, e));
+        let addr = self
+            .address
+            .to_socket_addrs()
+            .map_err(unavailable)?
+            .next()
+            .ok_or_else(|| Error::Unavailable(format!("{}: no addresses", self.address)))?;
+        let local: net::SocketAddr = if addr.is_ipv4() {
+            (net::Ipv4Addr::UNSPECIFIED, 0).into()
+        } else {
+            (net::Ipv6Addr::UNSPECIFIED, 0).into()
+        };
+        let socket = net::UdpSocket::bind(local).map_err(unavailable)?;
+        // Fill each datagram with as many whole lines as fit.
+        let mut datagram = String::new();
+        for r in batch.iter() {
+            let line = self.line.line(r);
+            if !datagram.is_empty() && datagram.len() + line.len() + 1 > MAX_DATAGRAM {
+                socket
+                    .send_to(datagram.as_bytes(), addr)
+                    .map_err(unavailable)?;
+                datagram.clear();
+            }
+            datagram.push_str(&line);
+            datagram.push('\n');
+        }
+        if !datagram.is_empty() {
+            socket
+                .send_to(datagram.as_bytes(), addr)
+                .map_err(unavailable)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Webhook POSTs readings to any HTTP endpoint, as a JSON array of
+/// `export::Record`s.
+pub struct Webhook {
+    poster: Poster,
+}
+
+impl Webhook {
+    /// POST to `url`, with `headers` added to every request, e.g., for
+    /// authentication.
+    pub fn new(url: &str, headers: &collections::BTreeMap<String, String>) -> Result<Webhook> {
+        return Ok(Webhook {
+            poster: Poster::new(url, headers)?,
+        });
+    }
+}
+
+impl Exporter for Webhook {
+    fn send(&mut self, batch: &[export::Record]) -> Result<()> {
+        let body = serde_json::to_vec(batch).map_err(|e| Error::Config(e.to_string()))?;
+        return self.poster.post("application/json", body);
+    }
+}
+
+/// Collector describes where an exporter sends readings, so it can be
+/// configured before it's started.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Collector {
+    InfluxHttp {
+        url: String,
+        token: Option<String>,
+        line: LineProtocol,
+    },
+    InfluxUdp {
+        address: String,
+        line: LineProtocol,
+    },
+    Webhook {
+        url: String,
+        headers: collections::BTreeMap<String, String>,
+    },
+}
+
+impl Collector {
+    /// An exporter sending readings to the collector.
+    pub fn exporter(&self) -> Result<Box<dyn Exporter>> {
+        return Ok(match self {
+            Collector::InfluxHttp { url, token, line } => {
+                Box::new(InfluxHttp::new(url, token.as_deref(), line.clone())?)
+            }
+            Collector::InfluxUdp { address, line } => {
+                Box::new(InfluxUdp::new(address, line.clone()))
+            }
+            Collector::Webhook { url, headers } => Box::new(Webhook::new(url, headers)?),
+        });
+    }
+}
+
+/// Options configures how a `Pusher` batches, retries and spools readings.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    /// Send a batch once it has this many readings...
+    pub batch_size: usize,
+    /// ...or once it's this old, whichever comes first.
+    pub flush_interval: time::Duration,
+    /// How many times a failed batch is retried before it's spooled.
+    pub retries: u32,
+    /// How long to wait before the first retry. Doubled on every retry.
+    pub backoff: time::Duration,
+    /// The file batches that can't be sent are spooled to. Dropped if
+    /// `None`.
+    pub spool: Option<path::PathBuf>,
+    /// Drop the oldest spooled readings once the spool grows past this
+    /// size.
+    pub spool_max_bytes: u64,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        return Options {
+            batch_size: 100,
+            flush_interval: time::Duration::from_secs(60),
+            retries: 3,
+            backoff: reconnect::MIN_BACKOFF,
+            spool: None,
+            spool_max_bytes: 16 << 20,
+        };
+    }
+}
+
+/// Target is a configured exporter: where readings go, and how.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Target {
+    /// Names the exporter in logs.
+    pub name: String,
+    pub collector: Collector,
+    pub options: Options,
+}
+
+impl Target {
+    /// Start pushing readings to the collector.
+    pub fn start(&self) -> Result<Pusher> {
+        return Pusher::start(&self.name, self.collector.exporter()?, self.options.clone());
+    }
+}
+
+/// Pusher feeds readings to an exporter in the background.
+pub struct Pusher {
+    sender: mpsc::Sender<export::Record>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl Pusher {
+    /// Start feeding `exporter` the readings pushed, as configured by
+    /// `opts`. `name` names the exporter in logs. Readings spooled by a
+    /// previous run are sent first.
+    pub fn start(name: &str, exporter: Box<dyn Exporter>, opts: Options) -> Result<Pusher> {
+        let spool = match &opts.spool {
+            Some(p) => Some(Spool::open(p, opts.spool_max_bytes)?),
+            None => None,
+        };
+        let (sender, receiver) = mpsc::channel();
+        let mut worker = Worker {
+            name: String::from(name),
+            exporter: exporter,
+            opts: opts,
+            spool: spool,
+        };
+        let thread = thread::Builder::new()
+            .name(format!("push-{}", name))
+            .spawn(move || worker.run(receiver))?;
+        return Ok(Pusher {
+            sender: sender,
+            thread: thread,
+        });
+    }
+
+    /// Queue `r` to be sent with the next batch. Never blocks.
+    pub fn push(&self, r: &device::Reading) {
+        // Sending only fails if the worker is gone, which it never is while
+        // the pusher is alive.
+        let _ = self.sender.send(export::Record::from(r));
+    }
+
+    /// Send the readings queued so far, without retrying, and wait for the
+    /// pusher to stop. Readings that can't be sent are spooled.
+    pub fn close(self) {
+        drop(self.sender);
+        let _ = self.thread.join();
+    }
+}
+
+struct Worker {
+    name: String,
+    exporter: Box<dyn Exporter>,
+    opts: Options,
+    spool: Option<Spool>,
+}
+
+impl Worker {
+    fn run(&mut self, receiver: mpsc::Receiver<export::Record>) {
+        let mut batch = Vec::new();
+        let mut deadline = time::Instant::now() + self.opts.flush_interval;
+        loop {
+            let wait = deadline.saturating_duration_since(time::Instant::now());
+            match receiver.recv_timeout(wait) {
+                Ok(r) => {
+                    batch.push(r);
+                    if batch.len() < self.opts.batch_size {
+                        continue;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {}
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    self.flush(batch, 0);
+                    return;
+                }
+            }
+            // Flushed even if empty, to retry whatever is spooled.
+            self.flush(std::mem::take(&mut batch), self.opts.retries);
+            deadline = time::Instant::now() + self.opts.flush_interval;
+        }
+    }
+
+    fn flush(&mut self, batch: Vec<export::Record>, retries: u32) {
+        let exporter = self.exporter.as_mut();
+        let (name, opts) = (&self.name, &self.opts);
+        let mut send = |b: &[export::Record]| send_with_retry(exporter, name, opts, b, retries);
+        if let Some(spool) = &mut self.spool {
+            if !spool.is_empty() {
+                // Queue up behind the spooled readings, so they're sent in
+                // order.
+                if let Err(e) = spool.append(&batch) {
+                    error!("{}: failed to spool {} readings: {}", name, batch.len(), e);
+                }
+                match spool.drain(opts.batch_size, &mut send) {
+                    Ok(0) => {}
+                    Ok(n) => info!("{}: sent {} spooled readings", name, n),
+                    Err(e) => warn!(
+                        "{}: {} readings spooled, will retry: {}",
+                        name,
+                        spool.len(),
+                        e
+                    ),
+                }
+                return;
+            }
+        }
+        if batch.is_empty() {
+            return;
+        }
+        match send(&batch) {
+            Ok(()) => {}
+            Err(e) if e.is_retryable() => match &mut self.spool {
+                Some(spool) => match spool.append(&batch) {
+                    Ok(()) => warn!("{}: spooled {} readings: {}", name, batch.len(), e),
+                    Err(se) => error!(
+                        "{}: dropped {} readings: {}, and failed to spool them: {}",
+                        name,
+                        batch.len(),
+                        e,
+                        se
+                    ),
+                },
+                None => error!("{}: dropped {} readings: {}", name, batch.len(), e),
+            },
+            Err(e) => error!("{}: dropped {} readings: {}", name, batch.len(), e),
+        }
+    }
+}
+
+// Send `batch`, retrying up to `retries` times with backoff while the
+// collector is unavailable.
+fn send_with_retry(
+    exporter: &mut dyn Exporter,
+    name: &str,
+    opts: &Options,
+    batch: &[export::Record],
+    retries: u32,
+) -> Result<()> {
+    let mut backoff = opts.backoff;
+    let mut attempt = 0;
+    loop {
+        match exporter.send(batch) {
+            Err(e) if e.is_retryable() && attempt < retries => {
+                warn!("{}: send failed, retrying in {:?}: {}", name, backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(reconnect::MAX_BACKOFF);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Spool is an on-disk queue of readings that couldn't be sent yet, stored
+// as newline-delimited JSON `export::Record`s. Readings are only ever
+// appended to the file, and how much of it has been sent is tracked in an
+// offset file next to it, so draining the spool doesn't rewrite it. It's
+// compacted once more of it has been sent than is left to send. It's also
+// kept in memory, as it's read in full every time it's drained anyway.
+struct Spool {
+    path: path::PathBuf,
+    offset_path: path::PathBuf,
+    max_bytes: u64,
+    records: collections::VecDeque<export::Record>,
+    // The size of `records` in the file.
+    bytes: u64,
+    // How much of the start of the file has been sent, or dropped.
+    offset: u64,
+}
+
+impl Spool {
+    fn open(p: &path::Path, max_bytes: u64) -> Result<Spool> {
+        if let Some(dir) = p.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut spool = Spool {
+            path: p.to_path_buf(),
+            offset_path: p.with_extension("offset"),
+            max_bytes: max_bytes,
+            records: collections::VecDeque::new(),
+            bytes: 0,
+            offset: 0,
+        };
+        let bs = match fs::read(p) {
+            Ok(bs) => bs,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                spool.commit()?;
+                return Ok(spool);
+            }
+            Err(e) => return Err(Error::from(e)),
+        };
+        let offset = match fs::read_to_string(&spool.offset_path) {
+            Ok(o) => match o.trim().parse::<usize>() {
+                Ok(o) if o <= bs.len() => o,
+                // E.g., torn by a crash mid-write. Readings may be sent
+                // twice, but none are lost.
+                _ => {
+                    warn!("{}: ignoring unreadable offset", p.display());
+                    0
+                }
+            },
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(Error::from(e)),
+        };
+        let mut skipped = 0;
+        for line in bs[offset..].split(|b| *b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            match serde_json::from_slice(line) {
+                Ok(r) => spool.records.push_back(r),
+                Err(_) => skipped += 1,
+            }
+        }
+        spool.bytes = spool.records.iter().map(record_len).sum();
+        spool.offset = offset as u64;
+        if skipped > 0 {
+            // E.g., torn by a crash mid-append. Left alone, the next append
+            // would be glued onto it.
+            warn!(
+                "{}: skipped {} unreadable spooled readings",
+                p.display(),
+                skipped
+            );
+            spool.compact()?;
+        } else {
+            spool.commit()?;
+        }
+        if !spool.is_empty() {
+            info!(
+                "{}: {} readings spooled by a previous run",
+                p.display(),
+                spool.len()
+            );
+        }
+        return Ok(spool);
+    }
+
+    fn len(&self) -> usize {
+        return self.records.len();
+    }
+
+    fn is_empty(&self) -> bool {
+        return self.records.is_empty();
+    }
+
+    // Add `batch` to the back of the spool, dropping the oldest readings if
+    // it grows too large.
+    fn append(&mut self, batch: &[export::Record]) -> Result<()> {
+        let mut out = Vec::new();
+        for r in batch.iter() {
+            out.extend(serde_json::to_vec(r).map_err(|e| Error::Config(e.to_string()))?);
+            out.push(b'\n');
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?
+            .write_all(&out)?;
+        self.records.extend(batch.iter().cloned());
+        self.bytes += out.len() as u64;
+        if self.bytes <= self.max_bytes {
+            return Ok(());
+        }
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes && !self.records.is_empty() {
+            self.pop_front(1);
+            dropped += 1;
+        }
+        warn!(
+            "{}: spool is full, dropped the {} oldest readings",
+            self.path.display(),
+            dropped
+        );
+        return self.commit();
+    }
+
+    // Send the spooled readings in batches of up to `batch_size` with
+    // `send`, oldest first, until it's empty or the collector becomes
+    // unavailable. Batches the collector rejects are dropped. Returns how
+    // many readings were sent.
+    fn drain<F>(&mut self, batch_size: usize, send: &mut F) -> Result<usize>
+    where
+        F: FnMut(&[export::Record]) -> Result<()>,
+    {
+        let mut sent = 0;
+        let mut result = Ok(());
+        while !self.records.is_empty() {
+            let n = batch_size.min(self.records.len());
+            match send(&self.records.make_contiguous()[..n]) {
+                Ok(()) => sent += n,
+                Err(e) if e.is_retryable() => {
+                    result = Err(e);
+                    break;
+                }
+                Err(e) => error!(
+                    "{}: dropped {} spooled readings: {}",
+                    self.path.display(),
+                    n,
+                    e
+                ),
+            }
+            self.pop_front(n);
+        }
+        self.commit()?;
+        return result.map(|_| sent);
+    }
+
+    // Take the `n` oldest readings off the spool, leaving the file alone.
+    fn pop_front(&mut self, n: usize) {
+        for r in self.records.drain(..n) {
+            let len = record_len(&r);
+            self.bytes -= len;
+            self.offset += len;
+        }
+    }
+
+    // Record how much of the file has been sent. The file is removed once
+    // there's nothing left to send, and compacted once more of it has been
+    // sent than is left.
+    fn commit(&mut self) -> Result<()> {
+        if self.records.is_empty() {
+            self.bytes = 0;
+            self.offset = 0;
+            for p in [&self.offset_path, &self.path].iter() {
+                match fs::remove_file(p) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::from(e)),
+                    _ => {}
+                }
+            }
+            return Ok(());
+        }
+        if self.offset > self.bytes {
+            return self.compact();
+        }
+        // Losing the offset only means sending some readings twice, so it
+        // isn't synced.
+        fs::write(&self.offset_path, self.offset.to_string())?;
+        return Ok(());
+    }
+
+    // Rewrite the file to hold only what's left to send.
+    fn compact(&mut self) -> Result<()> {
+        // The offset is removed first, so a crash part-way through can't
+        // leave it applied to the rewritten file.
+        match fs::remove_file(&self.offset_path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(Error::from(e)),
+            _ => {}
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut out = Vec::new();
+        for r in self.records.iter() {
+            out.extend(serde_json::to_vec(r).map_err(|e| Error::Config(e.to_string()))?);
+            out.push(b'\n');
+        }
+        let mut f = fs::File::create(&tmp)?;
+        f.write_all(&out)?;
+        f.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        self.bytes = out.len() as u64;
+        self.offset = 0;
+        return Ok(());
+    }
+}
+
+// The size of `r` in the spool.
+fn record_len(r: &export::Record) -> u64 {
+    return serde_json::to_vec(r)
+        .map(|v| v.len() as u64 + 1)
+        .unwrap_or(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use crate::wire;
+    use chrono::TimeZone;
+    use std::io::Read;
+    use std::sync;
+
+    fn reading(ppm: u16) -> device::Reading {
+        return device::Reading::new(
+            wire::Concentration::PPM(ppm),
+            chrono::Utc.timestamp(1_600_000_000 + ppm as i64, 0),
+        );
+    }
+
+    fn record(ppm: u16) -> export::Record {
+        return export::Record::from(&reading(ppm));
+    }
+
+    fn ppms(records: &[export::Record]) -> Vec<u16> {
+        return records.iter().map(|r| r.co2_ppm).collect();
+    }
+
+    // Makes the error a `Fake` fails with.
+    type Failure = fn(String) -> Error;
+
+    // An exporter that records what it's sent, and fails with whatever
+    // error is set.
+    #[derive(Clone, Default)]
+    struct Fake {
+        sent: sync::Arc<sync::Mutex<Vec<Vec<u16>>>>,
+        fail: sync::Arc<sync::Mutex<Option<Failure>>>,
+    }
+
+    impl Exporter for Fake {
+        fn send(&mut self, batch: &[export::Record]) -> Result<()> {
+            if let Some(f) = *self.fail.lock().unwrap() {
+                return Err(f(String::from("nope")));
+            }
+            self.sent.lock().unwrap().push(ppms(batch));
+            return Ok(());
+        }
+    }
+
+    fn options(spool: Option<path::PathBuf>) -> Options {
+        return Options {
+            batch_size: 2,
+            flush_interval: time::Duration::from_secs(3600),
+            retries: 1,
+            backoff: time::Duration::from_millis(1),
+            spool: spool,
+            spool_max_bytes: 1 << 20,
+        };
+    }
+
+    // Serve HTTP requests on a local port, replying with `status`, and pass
+    // each request's head and body to the returned channel.
+    fn fake_collector(status: u16) -> (String, mpsc::Receiver<(String, String)>) {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests_in, requests_out) = mpsc::channel();
+        thread::spawn(move || {
+            for conn in listener.incoming() {
+                let mut conn = conn.unwrap();
+                let mut buf = Vec::new();
+                let mut chunk = [0u8; 4096];
+                let head_len = loop {
+                    let n = conn.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                    if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break i + 4;
+                    }
+                };
+                let head = String::from_utf8_lossy(&buf[..head_len]).to_lowercase();
+                let len: usize = head
+                    .lines()
+                    .find_map(|l| l.strip_prefix("content-length: "))
+                    .map(|l| l.trim().parse().unwrap())
+                    .unwrap_or(0);
+                while buf.len() < head_len + len {
+                    let n = conn.read(&mut chunk).unwrap();
+                    buf.extend_from_slice(&chunk[..n]);
+                }
+                let body = String::from_utf8_lossy(&buf[head_len..]).to_string();
+                write!(
+                    conn,
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                )
+                .unwrap();
+                if requests_in.send((head, body)).is_err() {
+                    return;
+                }
+            }
+        });
+        return (format!("http://{}", addr), requests_out);
+    }
+
+    #[test]
+    fn test_line_protocol() {
+        let mut line = LineProtocol::default();
+        line.tags
+            .insert(String::from("site"), String::from("the office"));
+        line.tags
+            .insert(String::from("room"), String::from("a,b=c"));
+        line.tags
+            .insert(String::from("share"), String::from("\\\\nas\\"));
+        assert_eq!(
+            line.line(&record(612)),
+            "co2,room=a\\,b\\=c,share=\\\\\\\\nas\\\\,site=the\\ office ppm=612i 1600000612000000000"
+        );
+
+        let mut r = reading(400);
+        r.elevation = Some(wire::Distance::Feet(1500));
+        r.status = Some(wire::response::Status::from(wire::response::StatusFlags {
+            in_warmup: true,
+            ..wire::response::StatusFlags::default()
+        }));
+        let line = LineProtocol {
+            measurement: String::from("air quality"),
+            tags: collections::BTreeMap::new(),
+        };
+        assert_eq!(
+            line.line(&export::Record::from(&r)),
+            "air\\ quality ppm=400i,elevation_ft=1500i,error=false,warmup=true,\
+             calibration=false,idle=false,self_test=false 1600000400000000000"
+        );
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = TempDir::new("push-spool");
+        let p = dir.path().join("spool.jsonl");
+        let mut spool = Spool::open(&p, 1 << 20).unwrap();
+        assert!(spool.is_empty());
+        spool.append(&[record(1), record(2), record(3)]).unwrap();
+
+        // Survives a restart, even one that tore the last append.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&p)
+            .unwrap()
+            .write_all(b"{\"time\":")
+            .unwrap();
+        let mut spool = Spool::open(&p, 1 << 20).unwrap();
+        assert_eq!(spool.len(), 3);
+
+        // Stops at the first batch the collector can't take.
+        let mut sent = Vec::new();
+        let err = spool
+            .drain(1, &mut |b: &[export::Record]| {
+                if !sent.is_empty() {
+                    return Err(Error::Unavailable(String::from("down")));
+                }
+                sent.push(ppms(b));
+                return Ok(());
+            })
+            .unwrap_err();
+        assert!(err.is_retryable());
+        assert_eq!(sent, vec![vec![1]]);
+        // What was sent is skipped over, rather than rewritten.
+        let len = fs::metadata(&p).unwrap().len();
+        assert_eq!(len, 3 * record_len(&record(1)));
+        let mut spool = Spool::open(&p, 1 << 20).unwrap();
+        assert_eq!(ppms(spool.records.make_contiguous()), vec![2, 3]);
+        assert_eq!(fs::metadata(&p).unwrap().len(), len);
+
+        // Rejected batches are dropped rather than retried forever.
+        let n = spool
+            .drain(2, &mut |_: &[export::Record]| {
+                return Err(Error::Rejected(String::from("bad")));
+            })
+            .unwrap();
+        assert_eq!(n, 0);
+        assert!(spool.is_empty());
+        assert!(!p.exists());
+        assert!(!p.with_extension("offset").exists());
+
+        // Drops the oldest readings once full.
+        let max = record_len(&record(10)) * 2;
+        let mut spool = Spool::open(&p, max).unwrap();
+        spool.append(&[record(10), record(11), record(12)]).unwrap();
+        assert_eq!(ppms(spool.records.make_contiguous()), vec![11, 12]);
+        let mut spool = Spool::open(&p, max).unwrap();
+        assert_eq!(ppms(spool.records.make_contiguous()), vec![11, 12]);
+
+        // The file is compacted once more of it was sent than is left.
+        spool.append(&[record(13), record(14)]).unwrap();
+        assert_eq!(ppms(spool.records.make_contiguous()), vec![13, 14]);
+        assert_eq!(fs::metadata(&p).unwrap().len(), max);
+        assert!(!p.with_extension("offset").exists());
+    }
+
+    #[test]
+    fn test_pusher() {
+        let dir = TempDir::new("push-pusher");
+        let spool = dir.path().join("fake.jsonl");
+        let fake = Fake::default();
+        let pusher =
+            Pusher::start("fake", Box::new(fake.clone()), options(Some(spool.clone()))).unwrap();
+        // Sent once a batch fills up.
+        pusher.push(&reading(1));
+        pusher.push(&reading(2));
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while fake.sent.lock().unwrap().is_empty() {
+            assert!(time::Instant::now() < deadline, "batch never sent");
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        *fake.fail.lock().unwrap() = Some(Error::Unavailable);
+        pusher.push(&reading(3));
+        // Spooled once the retries run out, and on close.
+        pusher.push(&reading(4));
+        pusher.push(&reading(5));
+        pusher.close();
+        assert_eq!(*fake.sent.lock().unwrap(), vec![vec![1, 2]]);
+        assert_eq!(Spool::open(&spool, 1 << 20).unwrap().len(), 3);
+
+        // Spooled readings are sent first, in order, once the collector is
+        // back.
+        *fake.fail.lock().unwrap() = None;
+        let pusher =
+            Pusher::start("fake", Box::new(fake.clone()), options(Some(spool.clone()))).unwrap();
+        pusher.push(&reading(6));
+        pusher.push(&reading(7));
+        pusher.close();
+        assert_eq!(
+            *fake.sent.lock().unwrap(),
+            vec![vec![1, 2], vec![3, 4], vec![5, 6], vec![7]]
+        );
+        assert!(!spool.exists());
+
+        // Without a spool, rejected and undeliverable batches are dropped.
+        *fake.fail.lock().unwrap() = Some(Error::Rejected);
+        let pusher = Pusher::start("fake", Box::new(fake.clone()), options(None)).unwrap();
+        pusher.push(&reading(8));
+        pusher.close();
+        assert_eq!(fake.sent.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_influx_http() {
+        let (url, requests) = fake_collector(204);
+        let mut line = LineProtocol::default();
+        line.tags
+            .insert(String::from("site"), String::from("office"));
+        let mut exporter = Collector::InfluxHttp {
+            url: format!("{}/api/v2/write?org=home&bucket=co2", url),
+            token: Some(String::from("s3cret")),
+            line: line,
+        }
+        .exporter()
+        .unwrap();
+        exporter.send(&[record(612), record(613)]).unwrap();
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("post /api/v2/write?org=home&bucket=co2 http/1.1"));
+        assert!(head.contains("authorization: token s3cret"));
+        assert_eq!(
+            body,
+            "co2,site=office ppm=612i 1600000612000000000\n\
+             co2,site=office ppm=613i 1600000613000000000\n"
+        );
+
+        // Malformed points are rejected, an overloaded server is retried.
+        let (url, _requests) = fake_collector(400);
+        let mut exporter = InfluxHttp::new(&url, None, LineProtocol::default()).unwrap();
+        assert!(matches!(
+            exporter.send(&[record(1)]),
+            Err(Error::Rejected(_))
+        ));
+        let (url, _requests) = fake_collector(503);
+        let mut exporter = InfluxHttp::new(&url, None, LineProtocol::default()).unwrap();
+        assert!(matches!(
+            exporter.send(&[record(1)]),
+            Err(Error::Unavailable(_))
+        ));
+
+        assert!(matches!(
+            InfluxHttp::new("https://influx", None, LineProtocol::default()),
+            Err(Error::Config(_))
+        ));
+    }
+
+    #[test]
+    fn test_influx_udp() {
+        let socket = net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut exporter = InfluxUdp::new(
+            &socket.local_addr().unwrap().to_string(),
+            LineProtocol::default(),
+        );
+        // Large batches are split into several datagrams of whole lines.
+        let batch: Vec<export::Record> = (0..100).map(record).collect();
+        exporter.send(&batch).unwrap();
+        let mut lines = Vec::new();
+        let mut buf = [0u8; 65536];
+        while lines.len() < batch.len() {
+            let n = socket.recv(&mut buf).unwrap();
+            assert!(n <= MAX_DATAGRAM);
+            let datagram = String::from_utf8_lossy(&buf[..n]).to_string();
+            lines.extend(datagram.lines().map(String::from));
+        }
+        assert_eq!(lines[0], "co2 ppm=0i 1600000000000000000");
+        assert_eq!(lines[99], "co2 ppm=99i 1600000099000000000");
+    }
+
+    #[test]
+    fn test_webhook() {
+        let (url, requests) = fake_collector(200);
+        let mut headers = collections::BTreeMap::new();
+        headers.insert(String::from("Authorization"), String::from("Bearer s3cret"));
+        let mut exporter = Webhook::new(&format!("{}/hook", url), &headers).unwrap();
+        exporter.send(&[record(612)]).unwrap();
+        let (head, body) = requests.recv().unwrap();
+        assert!(head.starts_with("post /hook http/1.1"));
+        assert!(head.contains("authorization: bearer s3cret"));
+        assert!(head.contains("content-type: application/json"));
+        let got: Vec<export::Record> = serde_json::from_str(&body).unwrap();
+        assert_eq!(got, vec![record(612)]);
+
+        headers.insert(String::from("bad header"), String::from("x"));
+        assert!(matches!(
+            Webhook::new(&url, &headers),
+            Err(Error::Config(_))
+        ));
+    }
+}
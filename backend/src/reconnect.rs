@@ -0,0 +1,460 @@
+//! Automatic recovery of the connection to a sensor.
+//!
+//! A USB serial adapter can be unplugged, or the sensor can brown out, and
+//! either way the open port is useless from then on. `Reconnecting` notices
+//! the connection failing, and reopens the port with backoff. Once the port
+//! is reopened, it waits for the sensor to warm up again, and re-applies the
+//! elevation the sensor was configured with.
+use crate::device;
+use crate::device::Device;
+use crate::wire;
+use log::{info, warn};
+use std::convert::TryFrom;
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time;
+
+/// How long to wait before retrying after the first failed attempt to
+/// reconnect. The wait doubles with every failed attempt after that.
+pub const MIN_BACKOFF: time::Duration = time::Duration::from_secs(1);
+
+/// The longest wait between attempts to reconnect.
+pub const MAX_BACKOFF: time::Duration = time::Duration::from_secs(60);
+
+/// How many commands in a row must time out before the connection is
+/// considered lost. A busy sensor may miss the odd reply, and reconnecting
+/// means waiting for it to warm up again, so one timeout isn't enough.
+pub const MAX_CONSECUTIVE_TIMEOUTS: u32 = 3;
+
+#[derive(Debug, Default)]
+struct ConnectionState {
+    connected: atomic::AtomicBool,
+    reconnects: atomic::AtomicU64,
+}
+
+/// Connection is a view of the state of a `Reconnecting` device's
+/// connection, that can be read without access to the device, e.g., for
+/// metrics. Clones share the same state.
+#[derive(Debug, Clone, Default)]
+pub struct Connection {
+    state: sync::Arc<ConnectionState>,
+}
+
+impl Connection {
+    /// Returns `true` if the device is believed to be reachable.
+    pub fn is_connected(&self) -> bool {
+        return self.state.connected.load(atomic::Ordering::SeqCst);
+    }
+
+    /// How many times the device has been reconnected.
+    pub fn reconnects(&self) -> u64 {
+        return self.state.reconnects.load(atomic::Ordering::SeqCst);
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.state
+            .connected
+            .store(connected, atomic::Ordering::SeqCst);
+    }
+}
+
+/// Reconnecting is a T6615 on the port opened by `F`, that is reopened when
+/// the connection is lost. While disconnected, commands fail until the
+/// device is reconnected, which is attempted when a command is executed, at
+/// most once per backoff period.
+pub struct Reconnecting<P, F> {
+    open: F,
+    device: Option<device::T6615<P>>,
+    connection: Connection,
+    // The earliest a reconnect may be attempted, and how long to wait after
+    // that attempt if it fails.
+    retry_at: time::Instant,
+    backoff: time::Duration,
+    // The elevation the device was last configured with, if any.
+    elevation: Option<wire::Distance>,
+    warmup_timeout: time::Duration,
+    sleep_fn: fn(time::Duration),
+    retry: device::Retry,
+    // Shared by every device, so the counts survive reconnecting.
+    stats: device::CommandStats,
+    // How many commands in a row have timed out.
+    timeouts: u32,
+}
+
+impl<P, F> Reconnecting<P, F>
+where
+    P: device::Port,
+    F: FnMut() -> device::Result<P>,
+{
+    /// Connect to the device on the port opened by `open`, which is called
+    /// again every time the device is reconnected. Fails if the port can't
+    /// be opened the first time.
+    pub fn open(mut open: F) -> device::Result<Reconnecting<P, F>> {
+        let port = open()?;
+        let connection = Connection::default();
+        connection.set_connected(true);
+        let stats = device::CommandStats::default();
+        return Ok(Reconnecting {
+            open: open,
+            device: Some(device::T6615::with_port(port).with_stats(stats.clone())),
+            connection: connection,
+            retry_at: time::Instant::now(),
+            backoff: MIN_BACKOFF,
+            elevation: None,
+            warmup_timeout: device::WARMUP_TIMEOUT,
+            sleep_fn: thread::sleep,
+            retry: device::Retry::default(),
+            stats: stats,
+            timeouts: 0,
+        });
+    }
+
+    /// Set how to retry commands whose reply is lost or garbled.
+    pub fn with_retry(mut self, retry: device::Retry) -> Self {
+        self.retry = retry;
+        self.device = self.device.take().map(|d| d.with_retry(retry));
+        return self;
+    }
+
+    /// Set how long to wait for the device to warm up after reconnecting.
+    pub fn with_warmup_timeout(mut self, timeout: time::Duration) -> Self {
+        self.warmup_timeout = timeout;
+        return self;
+    }
+
+    /// Set how to sleep while waiting for the device to warm up.
+    pub fn with_sleep(mut self, sleep_fn: fn(time::Duration)) -> Self {
+        self.sleep_fn = sleep_fn;
+        return self;
+    }
+
+    /// The state of the connection to the device.
+    pub fn connection(&self) -> Connection {
+        return self.connection.clone();
+    }
+
+    /// How sending each command to the device has gone, across reconnects.
+    pub fn stats(&self) -> device::CommandStats {
+        return self.stats.clone();
+    }
+
+    // Returns `true` if `e`, the result of the latest command, means the
+    // connection to the device is gone, as opposed to the device replying
+    // with something wrong. A caller's deadline running out says nothing
+    // about the connection.
+    fn is_connection_lost(&mut self, e: &device::Error) -> bool {
+        return match e {
+            device::Error::Timeout(_) => {
+                self.timeouts += 1;
+                self.timeouts >= MAX_CONSECUTIVE_TIMEOUTS
+            }
+            device::Error::DeadlineExceeded(_) => false,
+            _ => e.is_unavailable(),
+        };
+    }
+
+    fn disconnect(&mut self, e: &device::Error) {
+        warn!("Lost connection to the device: {}", e);
+        self.device = None;
+        self.connection.set_connected(false);
+        self.timeouts = 0;
+        self.retry_at = time::Instant::now();
+        self.backoff = MIN_BACKOFF;
+    }
+
+    fn reconnect(&mut self) -> device::Result<()> {
+        let now = time::Instant::now();
+        if now < self.retry_at {
+            return Err(device::Error::Closed);
+        }
+        return match self.try_reconnect() {
+            Ok(d) => {
+                info!("Reconnected to the device");
+                self.device = Some(d);
+                self.connection.set_connected(true);
+                self.connection
+                    .state
+                    .reconnects
+                    .fetch_add(1, atomic::Ordering::SeqCst);
+                self.backoff = MIN_BACKOFF;
+                Ok(())
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to reconnect to the device, retrying in {:?}: {}",
+                    self.backoff, e
+                );
+                self.retry_at = now + self.backoff;
+                self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                Err(e)
+            }
+        };
+    }
+
+    // Reopen the port, and bring the device back to the state it was in.
+    fn try_reconnect(&mut self) -> device::Result<device::T6615<P>> {
+        let mut d = device::T6615::with_port((self.open)()?)
+            .with_retry(self.retry)
+            .with_stats(self.stats.clone());
+        d.wait_warmup(self.sleep_fn, &device::Limit::timeout(self.warmup_timeout))?;
+        if let Some(e) = self.elevation {
+            d.set_elevation(e)?;
+        }
+        return Ok(d);
+    }
+}
+
+impl<P, F> Device for Reconnecting<P, F>
+where
+    P: device::Port,
+    F: FnMut() -> device::Result<P>,
+{
+    fn execute<S, T>(&mut self, s: S) -> device::Result<T>
+    where
+        S: Into<wire::Payload>,
+        T: TryFrom<wire::Payload, Error = wire::ParseError>,
+    {
+        if self.device.is_none() {
+            self.reconnect()?;
+        }
+        let r = match self.device.as_mut() {
+            Some(d) => d.execute(s),
+            None => Err(device::Error::Closed),
+        };
+        match &r {
+            Ok(_) => self.timeouts = 0,
+            Err(e) if self.is_connection_lost(e) => self.disconnect(e),
+            Err(_) => {}
+        }
+        return r;
+    }
+
+    fn reconnects(&self) -> u64 {
+        return self.connection.reconnects();
+    }
+
+    fn set_elevation(&mut self, d: wire::Distance) -> device::Result<()> {
+        let wire::response::Ack =
+            self.execute(wire::command::UpdateElevation(device::round_elevation(d)))?;
+        self.elevation = Some(d);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim;
+    use std::io;
+    use std::io::{Read, Write};
+
+    // Plug is a simulated sensor on a serial adapter that can be unplugged.
+    #[derive(Clone)]
+    struct Plug {
+        sensor: sync::Arc<sync::Mutex<sim::Sensor>>,
+        plugged: sync::Arc<atomic::AtomicBool>,
+        // Whether the sensor has stopped replying, without the adapter
+        // being unplugged.
+        muted: sync::Arc<atomic::AtomicBool>,
+        started: time::Instant,
+    }
+
+    impl Plug {
+        fn new(sensor: sim::Sensor) -> Plug {
+            return Plug {
+                sensor: sync::Arc::new(sync::Mutex::new(sensor)),
+                plugged: sync::Arc::new(atomic::AtomicBool::new(true)),
+                muted: sync::Arc::new(atomic::AtomicBool::new(false)),
+                started: time::Instant::now(),
+            };
+        }
+
+        fn set_plugged(&self, plugged: bool) {
+            self.plugged.store(plugged, atomic::Ordering::SeqCst);
+        }
+
+        fn set_muted(&self, muted: bool) {
+            self.muted.store(muted, atomic::Ordering::SeqCst);
+        }
+
+        // Open a port to the sensor, which stops working once unplugged.
+        fn open(&self) -> device::Result<Port> {
+            if !self.plugged.load(atomic::Ordering::SeqCst) {
+                return Err(device::Error::from(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no such device",
+                )));
+            }
+            return Ok(Port {
+                plug: self.clone(),
+                replies: Vec::new(),
+                alive: true,
+            });
+        }
+    }
+
+    struct Port {
+        plug: Plug,
+        replies: Vec<u8>,
+        alive: bool,
+    }
+
+    impl Port {
+        fn check(&mut self) -> io::Result<()> {
+            // Once unplugged, a port stays dead even if plugged back in.
+            self.alive = self.alive && self.plug.plugged.load(atomic::Ordering::SeqCst);
+            if !self.alive {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "unplugged"));
+            }
+            return Ok(());
+        }
+    }
+
+    impl device::Port for Port {}
+
+    impl Read for Port {
+        fn read(&mut self, bs: &mut [u8]) -> io::Result<usize> {
+            self.check()?;
+            if self.plug.muted.load(atomic::Ordering::SeqCst) {
+                self.replies.clear();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "muted"));
+            }
+            let n = bs.len().min(self.replies.len());
+            bs[..n].copy_from_slice(&self.replies[..n]);
+            self.replies.drain(..n);
+            return Ok(n);
+        }
+    }
+
+    impl Write for Port {
+        fn write(&mut self, bs: &[u8]) -> io::Result<usize> {
+            self.check()?;
+            let mut decoder = wire::Decoder::with_address(wire::REQUEST_ADDRESS);
+            decoder.push(bs);
+            let mut sensor = self.plug.sensor.lock().unwrap();
+            for req in decoder.by_ref() {
+                if let Some(reply) = sensor.handle(&req, self.plug.started.elapsed()) {
+                    self.replies.extend_from_slice(&wire::Message::reply(reply));
+                }
+            }
+            return Ok(bs.len());
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            return Ok(());
+        }
+    }
+
+    fn fast() -> sim::Timing {
+        return sim::Timing {
+            warmup: time::Duration::from_millis(0),
+            ..sim::Timing::default()
+        };
+    }
+
+    fn no_sleep(_: time::Duration) {}
+
+    #[test]
+    fn test_reconnect() {
+        let plug = Plug::new(sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(fast()));
+        let opener = plug.clone();
+        let mut d = Reconnecting::open(move || opener.open())
+            .unwrap()
+            .with_sleep(no_sleep);
+        let conn = d.connection();
+        d.set_elevation(wire::Distance::Feet(1500)).unwrap();
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        assert!(conn.is_connected());
+
+        // The adapter is unplugged, and the sensor forgets its elevation.
+        plug.set_plugged(false);
+        *plug.sensor.lock().unwrap() =
+            sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(fast());
+        assert!(d.read_co2().unwrap_err().is_unavailable());
+        assert!(!conn.is_connected());
+        // The first attempt to reconnect fails, and the next waits out the
+        // backoff.
+        assert!(d.read_co2().is_err());
+        assert_eq!(d.read_co2(), Err(device::Error::Closed));
+        assert_eq!(conn.reconnects(), 0);
+
+        // Once plugged back in, the device is reconnected and reconfigured.
+        plug.set_plugged(true);
+        d.retry_at = time::Instant::now();
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        assert!(conn.is_connected());
+        assert_eq!(conn.reconnects(), 1);
+        assert_eq!(d.reconnects(), 1);
+        assert_eq!(d.read_elevation().unwrap(), wire::Distance::Feet(1500));
+    }
+
+    #[test]
+    fn test_reconnect_backoff() {
+        let plug = Plug::new(sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(fast()));
+        let opener = plug.clone();
+        let mut d = Reconnecting::open(move || opener.open()).unwrap();
+        plug.set_plugged(false);
+        assert!(d.read_co2().is_err());
+
+        // Every failed attempt doubles the wait, up to a limit.
+        let mut waits = vec![];
+        for _ in 0..8 {
+            d.retry_at = time::Instant::now();
+            waits.push(d.backoff);
+            assert!(d.read_co2().is_err());
+        }
+        assert_eq!(
+            waits.iter().map(|w| w.as_secs()).collect::<Vec<u64>>(),
+            vec![1, 2, 4, 8, 16, 32, 60, 60]
+        );
+    }
+
+    #[test]
+    fn test_timeouts_lose_connection() {
+        let plug = Plug::new(sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(fast()));
+        let opener = plug.clone();
+        let mut d = Reconnecting::open(move || opener.open())
+            .unwrap()
+            .with_retry(device::Retry::none());
+        let conn = d.connection();
+        let timed_out = |r: device::Result<wire::Concentration>| {
+            return matches!(r, Err(device::Error::Timeout(_)));
+        };
+
+        // The odd missed reply doesn't count as losing the connection, and
+        // a reply in between starts the count over.
+        plug.set_muted(true);
+        for _ in 1..MAX_CONSECUTIVE_TIMEOUTS {
+            assert!(timed_out(d.read_co2()));
+        }
+        plug.set_muted(false);
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        plug.set_muted(true);
+        for _ in 1..MAX_CONSECUTIVE_TIMEOUTS {
+            assert!(timed_out(d.read_co2()));
+        }
+        assert!(conn.is_connected());
+
+        // Too many in a row do.
+        assert!(timed_out(d.read_co2()));
+        assert!(!conn.is_connected());
+        plug.set_muted(false);
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+        assert_eq!(conn.reconnects(), 1);
+    }
+
+    #[test]
+    fn test_garbled_reply_keeps_connection() {
+        let plug = Plug::new(sim::Sensor::new(sim::Waveform::Constant(450)).with_timing(fast()));
+        let opener = plug.clone();
+        let mut d = Reconnecting::open(move || opener.open()).unwrap();
+        let conn = d.connection();
+        // The simulator replies to a loopback with its payload, which isn't
+        // a valid status.
+        let r: device::Result<wire::response::Status> =
+            d.execute(wire::command::Loopback(vec![0x01, 0x02, 0x03]));
+        assert!(matches!(r, Err(device::Error::Garbled(_))));
+        assert!(conn.is_connected());
+    }
+}
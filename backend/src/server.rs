@@ -1,14 +1,27 @@
+use crate::api;
+use crate::api::ABCSetting;
+use crate::calibration;
 use crate::device;
+use crate::events;
+use crate::export;
+use crate::history;
+use crate::metrics;
+use crate::mqtt;
+use crate::push;
+use crate::reconnect;
+use crate::store;
 use crate::wire;
+use chrono::TimeZone;
+use futures_util::stream;
+use futures_util::{SinkExt, StreamExt};
 use gotham::hyper;
 use gotham::router::builder::*;
-use governor;
 use http;
-use log::{error, info};
+use log::{debug, error, info};
 use mime;
-use prometheus;
-use prometheus::Encoder;
 use serde;
+use sha1::{Digest, Sha1};
+use std::error;
 use std::fmt;
 use std::io;
 use std::panic::RefUnwindSafe;
@@ -22,64 +35,242 @@ use gotham::helpers::http::response as gotham_response;
 use gotham::middleware::state::StateMiddleware;
 use gotham::state::FromState;
 use gotham::state::State as GothamState;
+use tokio_tungstenite::tungstenite as ws;
+use tokio_tungstenite::WebSocketStream;
 
-// Based on https://www.esrl.noaa.gov/gmd/ccgg/trends/. Ambient concentration
-// should be +/-5ppm ish.
-const AMBIENT_CONCENTRATION: wire::Concentration = wire::Concentration::PPM(410);
+/// The reference concentration used for calibration by default. Based on
+/// https://gml.noaa.gov/ccgg/trends/. Ambient concentration should be
+/// +/-5ppm ish, but rises a few ppm every year, so configure a more recent
+/// value (`calibration.reference_ppm`) as this one goes stale.
+pub const AMBIENT_CONCENTRATION: wire::Concentration = wire::Concentration::PPM(425);
 
-// The maximum rate that we can take measurements from a device. Basically
-// a random guess.
-const MAX_MEASURE_RATE: time::Duration = time::Duration::from_secs(15);
+/// How often the device is sampled by default. The device only updates its
+/// reading every few seconds, so there's no point in sampling faster.
+pub const DEFAULT_SAMPLE_INTERVAL: time::Duration = time::Duration::from_secs(15);
 
-// The approximate height of Mt. Everest. Used for sanity-checking the
-// given elevation on configureation.
-const MT_EVEREST_HEIGHT: wire::Distance = wire::Distance::Feet(29_000);
+/// How much measurement history is kept in memory by default.
+pub const DEFAULT_HISTORY_WINDOW: time::Duration = time::Duration::from_secs(24 * 60 * 60);
 
+/// The most measurement history that may be kept in memory, about 10 years.
+pub const MAX_HISTORY_WINDOW: time::Duration = time::Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+// The file calibration results are persisted to, in the storage directory.
+const CALIBRATION_HISTORY_FILE: &str = "calibrations.jsonl";
+
+// How long a client of the control channel has to authenticate before it's
+// disconnected.
+const CONTROL_AUTH_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+// How often a comment is sent on an otherwise quiet event stream, so that
+// proxies and clients don't give up on it between measurements.
+const STREAM_KEEP_ALIVE: time::Duration = time::Duration::from_secs(15);
+
+// The only version of the WebSocket protocol, per RFC 6455, and so the only
+// one the control channel speaks.
+const WEBSOCKET_VERSION: &str = "13";
+
+/// The approximate height of Mt. Everest. Used for sanity-checking the
+/// given elevation on configureation.
+pub const MT_EVEREST_HEIGHT: wire::Distance = wire::Distance::Feet(29_000);
+
+/// Error is why the server failed to handle a request. Each kind of error is
+/// served with its own HTTP status, see `Error::status`.
 #[derive(Debug)]
-pub struct Error(String);
+pub enum Error {
+    /// The request was malformed, or asked for something out of range.
+    BadRequest(String),
+    /// The device is busy with another operation, e.g., calibration.
+    Busy,
+    /// Nothing can be served yet, e.g., before the first measurement.
+    NotReady(String),
+    /// There's nothing to act on, e.g., no calibration to cancel.
+    NotFound(String),
+    /// The route exists, but doesn't take the request's method.
+    MethodNotAllowed(String),
+    /// The client hasn't proven it may do what it asked, e.g., on the
+    /// control channel.
+    Unauthorized(String),
+    /// The client asked to switch to a protocol version the server doesn't
+    /// speak, e.g., a WebSocket version other than 13.
+    UpgradeRequired(String),
+    /// Talking to the device failed.
+    Device(device::Error),
+    /// Durably storing or loading measurements failed.
+    Store(store::Error),
+    /// Persisting calibration results failed.
+    Calibration(calibration::Error),
+    Io(io::Error),
+    Internal(String),
+}
 
 impl Error {
-    fn to_response(self) -> http::Response<hyper::Body> {
-        return http::response::Builder::default()
-            .status(http::StatusCode::INTERNAL_SERVER_ERROR)
-            .body(hyper::Body::from(self.0))
+    /// The HTTP status the error is served with. Failures to reach the
+    /// device are distinguished from the device replying with garbage, so
+    /// that an outage can be told apart from a flaky sensor, and both from
+    /// client mistakes.
+    pub fn status(&self) -> http::StatusCode {
+        return match self {
+            Error::BadRequest(_) => http::StatusCode::BAD_REQUEST,
+            Error::Busy => http::StatusCode::CONFLICT,
+            Error::NotReady(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::NotFound(_) => http::StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed(_) => http::StatusCode::METHOD_NOT_ALLOWED,
+            Error::Unauthorized(_) => http::StatusCode::UNAUTHORIZED,
+            Error::UpgradeRequired(_) => http::StatusCode::UPGRADE_REQUIRED,
+            Error::Device(device::Error::DeadlineExceeded(_)) => http::StatusCode::GATEWAY_TIMEOUT,
+            Error::Device(device::Error::Cancelled) => http::StatusCode::CONFLICT,
+            Error::Device(e) if e.is_unavailable() => http::StatusCode::SERVICE_UNAVAILABLE,
+            Error::Device(_) => http::StatusCode::BAD_GATEWAY,
+            Error::Store(_) | Error::Calibration(_) | Error::Io(_) | Error::Internal(_) => {
+                http::StatusCode::INTERNAL_SERVER_ERROR
+            }
+        };
+    }
+
+    /// A stable, machine-readable name for the kind of error, served in the
+    /// error envelope of the versioned API.
+    pub fn code(&self) -> &'static str {
+        return match self {
+            Error::BadRequest(_) => "bad_request",
+            Error::Busy => "busy",
+            Error::NotReady(_) => "not_ready",
+            Error::NotFound(_) => "not_found",
+            Error::MethodNotAllowed(_) => "method_not_allowed",
+            Error::Unauthorized(_) => "unauthorized",
+            Error::UpgradeRequired(_) => "upgrade_required",
+            Error::Device(device::Error::DeadlineExceeded(_)) => "deadline_exceeded",
+            Error::Device(device::Error::Cancelled) => "cancelled",
+            Error::Device(e) if e.is_unavailable() => "device_unavailable",
+            Error::Device(_) => "device_error",
+            Error::Store(_) | Error::Calibration(_) | Error::Io(_) | Error::Internal(_) => {
+                "internal"
+            }
+        };
+    }
+
+    fn to_response(&self) -> http::Response<hyper::Body> {
+        let mut resp = http::response::Builder::default()
+            .status(self.status())
+            .body(hyper::Body::from(self.to_string()))
             .unwrap();
+        self.add_challenge(&mut resp);
+        return resp;
+    }
+
+    // Tell refused clients how to get through: how to authenticate, or which
+    // WebSocket version to speak.
+    fn add_challenge(&self, resp: &mut http::Response<hyper::Body>) {
+        match self {
+            Error::Unauthorized(_) => {
+                resp.headers_mut().insert(
+                    http::header::WWW_AUTHENTICATE,
+                    http::HeaderValue::from_static("Bearer"),
+                );
+            }
+            Error::UpgradeRequired(_) => {
+                resp.headers_mut().insert(
+                    http::header::SEC_WEBSOCKET_VERSION,
+                    http::HeaderValue::from_static(WEBSOCKET_VERSION),
+                );
+            }
+            _ => {}
+        }
+    }
+
+    // Serve the error in the envelope of the versioned API.
+    fn to_api_response(&self) -> http::Response<hyper::Body> {
+        let status = self.status();
+        let body = api::ErrorBody::new(status.as_u16(), self.code(), self.to_string());
+        let mut resp = json_response(&body);
+        *resp.status_mut() = status;
+        self.add_challenge(&mut resp);
+        return resp;
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
-        return self.0.fmt(f);
+        match self {
+            Error::BadRequest(s) => write!(f, "bad request: {}", s),
+            Error::Busy => write!(f, "device is busy"),
+            Error::NotReady(s)
+            | Error::NotFound(s)
+            | Error::MethodNotAllowed(s)
+            | Error::Unauthorized(s)
+            | Error::UpgradeRequired(s) => s.fmt(f),
+            Error::Device(e) => write!(f, "device error: {}", e),
+            Error::Store(e) => write!(f, "storage error: {}", e),
+            Error::Calibration(e) => write!(f, "calibration history error: {}", e),
+            Error::Io(e) => e.fmt(f),
+            Error::Internal(s) => s.fmt(f),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Device(e) => Some(e),
+            Error::Store(e) => Some(e),
+            Error::Calibration(e) => Some(e),
+            Error::Io(e) => Some(e),
+            Error::BadRequest(_)
+            | Error::Busy
+            | Error::NotReady(_)
+            | Error::NotFound(_)
+            | Error::MethodNotAllowed(_)
+            | Error::Unauthorized(_)
+            | Error::UpgradeRequired(_)
+            | Error::Internal(_) => None,
+        }
     }
 }
 
 impl From<&str> for Error {
     fn from(e: &str) -> Error {
-        return Error(e.to_string());
+        return Error::Internal(e.to_string());
     }
 }
 
 impl From<String> for Error {
     fn from(e: String) -> Error {
-        return Error(e.to_string());
+        return Error::Internal(e);
     }
 }
 
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
-        return Error(e.to_string());
+        return Error::Io(e);
     }
 }
 
 impl From<device::Error> for Error {
     fn from(e: device::Error) -> Error {
-        return Error(e.to_string());
+        return Error::Device(e);
+    }
+}
+
+impl From<history::Error> for Error {
+    fn from(e: history::Error) -> Error {
+        return Error::BadRequest(e.to_string());
+    }
+}
+
+impl From<store::Error> for Error {
+    fn from(e: store::Error) -> Error {
+        return Error::Store(e);
+    }
+}
+
+impl From<calibration::Error> for Error {
+    fn from(e: calibration::Error) -> Error {
+        return Error::Calibration(e);
     }
 }
 
 impl From<sync::mpsc::RecvError> for Error {
     fn from(e: sync::mpsc::RecvError) -> Error {
-        return Error(e.to_string());
+        return Error::Internal(e.to_string());
     }
 }
 
@@ -87,13 +278,34 @@ type Result<T> = result::Result<T, Error>;
 
 pub trait Device {
     fn read_co2(&mut self) -> Result<wire::Concentration>;
-    fn calibrate_co2<T: Fn(time::Duration)>(
+    /// Calibrate the device against `reference`, giving up once `limit` is
+    /// reached. `progress` is called as calibration enters each stage.
+    fn calibrate_co2<T, P>(
         &mut self,
         reference: wire::Concentration,
         sleep_fn: T,
-    ) -> Result<()>;
+        limit: &device::Limit,
+        progress: P,
+    ) -> Result<()>
+    where
+        T: Fn(time::Duration),
+        P: Fn(device::CalibrationStage);
+    fn read_status(&mut self) -> Result<wire::response::Status>;
+    fn read_identity(&mut self) -> Result<device::Identity>;
+    /// How many times the device has been reconnected, see
+    /// `device::Device::reconnects`.
+    fn reconnects(&self) -> u64 {
+        return 0;
+    }
     fn read_elevation(&mut self) -> Result<wire::Distance>;
     fn set_elevation(&mut self, to: wire::Distance) -> Result<()>;
+    fn run_self_test<T: Fn(time::Duration)>(
+        &mut self,
+        sleep_fn: T,
+    ) -> Result<wire::response::SelfTest>;
+    fn read_abc(&mut self) -> Result<wire::response::ABCState>;
+    fn set_abc(&mut self, to: wire::Toggle) -> Result<()>;
+    fn reset_abc(&mut self) -> Result<()>;
 }
 
 impl<D: device::Device> Device for D {
@@ -101,12 +313,32 @@ impl<D: device::Device> Device for D {
         return self.read_co2().map_err(Error::from);
     }
 
-    fn calibrate_co2<T: Fn(time::Duration)>(
+    fn calibrate_co2<T, P>(
         &mut self,
         reference: wire::Concentration,
         sleep_fn: T,
-    ) -> Result<()> {
-        return self.calibrate_co2(reference, sleep_fn).map_err(Error::from);
+        limit: &device::Limit,
+        progress: P,
+    ) -> Result<()>
+    where
+        T: Fn(time::Duration),
+        P: Fn(device::CalibrationStage),
+    {
+        return self
+            .calibrate_co2_with_progress(reference, sleep_fn, limit, progress)
+            .map_err(Error::from);
+    }
+
+    fn read_status(&mut self) -> Result<wire::response::Status> {
+        return self.read_status().map_err(Error::from);
+    }
+
+    fn read_identity(&mut self) -> Result<device::Identity> {
+        return self.read_identity().map_err(Error::from);
+    }
+
+    fn reconnects(&self) -> u64 {
+        return self.reconnects();
     }
 
     fn read_elevation(&mut self) -> Result<wire::Distance> {
@@ -116,60 +348,266 @@ impl<D: device::Device> Device for D {
     fn set_elevation(&mut self, to: wire::Distance) -> Result<()> {
         return self.set_elevation(to).map_err(Error::from);
     }
+
+    fn run_self_test<T: Fn(time::Duration)>(
+        &mut self,
+        sleep_fn: T,
+    ) -> Result<wire::response::SelfTest> {
+        return self.run_self_test(sleep_fn).map_err(Error::from);
+    }
+
+    fn read_abc(&mut self) -> Result<wire::response::ABCState> {
+        return self.read_abc().map_err(Error::from);
+    }
+
+    fn set_abc(&mut self, to: wire::Toggle) -> Result<()> {
+        return self.set_abc(to).map_err(Error::from);
+    }
+
+    fn reset_abc(&mut self) -> Result<()> {
+        return self.reset_abc().map_err(Error::from);
+    }
 }
 
 pub trait Manager {
-    fn measure(&self) -> Result<wire::Concentration>;
+    /// Start any background work, e.g., sampling the device.
+    fn start(&self, sampling: &Sampling) -> Result<()>;
+    /// Finish any background work that would otherwise be lost when the
+    /// process exits, e.g., send readings that haven't been pushed yet.
+    fn shutdown(&self);
+    /// Returns the most recent measurement, without touching the device.
+    fn measure(&self) -> Result<device::Reading>;
+    /// Returns the recorded measurements between `since` (default: the
+    /// start of the history window) and `until` (default: now), summarized
+    /// into buckets `step` wide.
+    fn history(
+        &self,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+        step: Option<chrono::Duration>,
+    ) -> Result<Vec<history::Bucket>>;
+    /// Returns every recorded measurement between `since` (default: the
+    /// oldest recorded measurement) and `until` (default: now). Durably
+    /// stored measurements are included, if storage is enabled, and are
+    /// read as the records are iterated over.
+    fn records(
+        &self,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Records>;
     fn elevation(&self) -> Result<wire::Distance>;
-    fn calibrate(&self) -> ();
+    /// Start calibrating the device against `reference` in the background,
+    /// and return the job tracking it. If `opts.stability` is set, the job
+    /// first checks that the readings have settled, and fails without
+    /// calibrating if they haven't. Fails with `Error::BadRequest` if
+    /// `reference` is out of bounds, and `Error::Busy` if a calibration is
+    /// already in progress.
+    fn calibrate(
+        &self,
+        reference: wire::Concentration,
+        opts: calibration::Options,
+    ) -> Result<calibration::Job>;
+    /// Returns the calibration in progress, and the results of the last
+    /// few calibrations.
+    fn calibration(&self) -> calibration::Summary;
+    /// Cancel the calibration in progress, and return it. The calibration
+    /// stops shortly after, once the device is done with the command in
+    /// flight. Fails with `Error::NotFound` if no calibration is in
+    /// progress.
+    fn cancel_calibration(&self) -> Result<calibration::Job>;
     fn is_ready(&self) -> bool;
     fn configure_elevation(&self, to: wire::Distance) -> Result<()>;
+    /// Start a self-test of the device in the background. Fails with
+    /// `Error::Busy` if the device is in use, e.g., calibrating.
+    fn self_test(&self) -> Result<()>;
+    /// Returns the outcome of the last self-test, if one has finished: its
+    /// result, or why it couldn't be run.
+    fn last_self_test(&self) -> Option<SelfTestOutcome>;
+    /// Returns what the device reported about itself on start, if it did.
+    fn identity(&self) -> Option<device::Identity>;
+    /// Subscribe to new measurements, status changes and calibration
+    /// progress, as they happen.
+    fn subscribe(&self) -> events::Subscription;
+    fn abc(&self) -> Result<wire::response::ABCState>;
+    /// Returns the ABC state last read from or configured on the device,
+    /// without touching the device.
+    fn last_abc(&self) -> Option<wire::response::ABCState>;
+    fn configure_abc(&self, to: ABCSetting) -> Result<()>;
+}
+
+/// Sampling configures how a `Manager` samples its device in the background.
+#[derive(Debug, Clone)]
+pub struct Sampling {
+    /// How often to take a measurement.
+    pub interval: time::Duration,
+    /// How much measurement history to keep in memory.
+    pub history_window: time::Duration,
+    /// Where to durably store measurements, if anywhere. Stored
+    /// measurements are loaded back into memory on start.
+    pub storage: Option<store::Options>,
+    /// Where to push every measurement to, e.g., InfluxDB.
+    pub exporters: Vec<push::Target>,
+}
+
+impl Default for Sampling {
+    fn default() -> Sampling {
+        return Sampling {
+            interval: DEFAULT_SAMPLE_INTERVAL,
+            history_window: DEFAULT_HISTORY_WINDOW,
+            storage: None,
+            exporters: Vec::new(),
+        };
+    }
+}
+
+/// SelfTestOutcome is the result of a self-test, or why it couldn't be run.
+pub type SelfTestOutcome = result::Result<wire::response::SelfTest, String>;
+
+/// Records is recorded measurements, a chunk at a time, oldest first.
+pub type Records = Box<dyn Iterator<Item = Result<Vec<device::Reading>>> + Send>;
+
+// Recorder keeps track of the measurements taken by the sampler.
+struct Recorder {
+    history: history::History,
+    store: Option<store::Store>,
+    // The elevation the device is configured for, attached to every
+    // reading.
+    elevation: Option<wire::Distance>,
+    // What the device reported about itself on start, or since it was last
+    // reconnected, attached to every published reading.
+    identity: Option<device::Identity>,
+    // How many times the device had been reconnected when `identity` was
+    // read.
+    identity_reconnects: u64,
+    events: events::Hub,
+    pushers: Vec<push::Pusher>,
 }
 
-type RateLimiter<C> =
-    governor::RateLimiter<governor::state::direct::NotKeyed, governor::state::InMemoryState, C>;
+impl Recorder {
+    fn record(&mut self, r: device::Reading) {
+        let previous = self.history.latest().and_then(|p| p.status);
+        self.history.push(r);
+        if let Some(s) = &mut self.store {
+            if let Err(e) = s.append(&r) {
+                error!("Failed to store measurement: {}", e);
+            }
+        }
+        for p in self.pushers.iter() {
+            p.push(&r);
+        }
+        self.events.publish(api::Event::Reading(api::Reading::new(
+            &r,
+            self.identity.as_ref(),
+            r.at,
+        )));
+        if let Some(status) = r.status {
+            if previous != Some(status) {
+                self.events.publish(api::Event::Status(status.into()));
+            }
+        }
+    }
+}
 
-pub struct DeviceManager<D, C: governor::clock::Clock> {
+pub struct DeviceManager<D> {
     device: sync::Arc<sync::Mutex<D>>,
-    limiter: sync::Arc<RateLimiter<C>>,
-    last_measure: sync::Arc<sync::Mutex<Option<wire::Concentration>>>,
+    recorder: sync::Arc<sync::Mutex<Recorder>>,
+    last_self_test: sync::Arc<sync::Mutex<Option<SelfTestOutcome>>>,
+    last_abc: sync::Arc<sync::Mutex<Option<wire::response::ABCState>>>,
+    calibrations: sync::Arc<sync::Mutex<calibration::Tracker>>,
+    // Cancels the calibration in progress, if any.
+    calibration_cancel: sync::Arc<sync::Mutex<Option<device::Cancel>>>,
+    events: events::Hub,
 }
 
-impl<D, C: governor::clock::Clock> Clone for DeviceManager<D, C> {
+impl<D> Clone for DeviceManager<D> {
     fn clone(&self) -> Self {
         return DeviceManager {
             device: self.device.clone(),
-            limiter: self.limiter.clone(),
-            last_measure: self.last_measure.clone(),
+            recorder: self.recorder.clone(),
+            last_self_test: self.last_self_test.clone(),
+            last_abc: self.last_abc.clone(),
+            calibrations: self.calibrations.clone(),
+            calibration_cancel: self.calibration_cancel.clone(),
+            events: self.events.clone(),
         };
     }
 }
 
-impl<D> DeviceManager<D, governor::clock::DefaultClock> {
-    fn new(dev: D) -> Self {
-        return DeviceManager::new_with_clock(dev, &governor::clock::DefaultClock::default());
+// Take a single measurement from the device, and pass it to `recorder`. If
+// the device is busy (e.g., calibrating), no measurement is recorded.
+fn sample_into<D: Device>(device: &sync::Mutex<D>, recorder: &sync::Mutex<Recorder>) {
+    let mut dev = match device.try_lock() {
+        Ok(guard) => guard,
+        Err(sync::TryLockError::WouldBlock) => {
+            debug!("device busy, skipping sample");
+            return;
+        }
+        e @ Err(_) => e.unwrap(),
+    };
+    let c = match dev.read_co2() {
+        Ok(c) => c,
+        Err(e) => {
+            error!("Failed to sample co2: {}", e);
+            return;
+        }
+    };
+    let mut reading = device::Reading::new(c, chrono::Utc::now());
+    match dev.read_status() {
+        Ok(s) => reading.status = Some(s),
+        Err(e) => error!("Failed to read status: {}", e),
+    }
+    // A different sensor may have been plugged in while disconnected, so
+    // its identity is read again after every reconnect.
+    let reconnects = dev.reconnects();
+    let identity = if recorder.lock().unwrap().identity_reconnects != reconnects {
+        match dev.read_identity() {
+            Ok(id) => Some(id),
+            Err(e) => {
+                error!("Failed to read identity after reconnecting: {}", e);
+                None
+            }
+        }
+    } else {
+        None
+    };
+    let mut recorder = recorder.lock().unwrap();
+    if let Some(id) = identity {
+        recorder.identity = Some(id);
+        recorder.identity_reconnects = reconnects;
     }
+    reading.elevation = recorder.elevation;
+    recorder.record(reading);
 }
 
-impl<D, C: governor::clock::Clock> DeviceManager<D, C> {
-    fn new_with_clock(dev: D, clock: &C) -> Self {
+impl<D> DeviceManager<D> {
+    fn new(dev: D) -> Self {
+        let events = events::Hub::new();
         return DeviceManager {
             device: sync::Arc::new(sync::Mutex::from(dev)),
-            // Only allow one measurement per 15s.
-            limiter: sync::Arc::new(RateLimiter::direct_with_clock(
-                governor::Quota::with_period(MAX_MEASURE_RATE)
-                    .expect("Option should only be none for zero durations."),
-                clock,
-            )),
-            last_measure: sync::Arc::new(sync::Mutex::new(Option::None)),
+            recorder: sync::Arc::new(sync::Mutex::new(Recorder {
+                history: history::History::new(
+                    chrono::Duration::from_std(DEFAULT_HISTORY_WINDOW).unwrap(),
+                ),
+                store: None,
+                elevation: None,
+                identity: None,
+                identity_reconnects: 0,
+                events: events.clone(),
+                pushers: Vec::new(),
+            })),
+            last_self_test: sync::Arc::new(sync::Mutex::new(Option::None)),
+            last_abc: sync::Arc::new(sync::Mutex::new(None)),
+            calibrations: sync::Arc::new(sync::Mutex::new(calibration::Tracker::new())),
+            calibration_cancel: sync::Arc::new(sync::Mutex::new(None)),
+            events: events,
         };
     }
 
-    fn maybe_lock_device(&self) -> Result<sync::MutexGuard<D>> {
+    fn maybe_lock_device(&self) -> Result<sync::MutexGuard<'_, D>> {
         let _dev = match self.device.try_lock() {
             Ok(guard) => guard,
             Err(sync::TryLockError::WouldBlock) => {
-                return Err(Error::from("rate limited, but no measurement taken"));
+                return Err(Error::Busy);
             }
             // Just panic if we get a poisoned/other error. This shouldn't
             // happen, and indicates a run-time bug.
@@ -177,47 +615,246 @@ impl<D, C: governor::clock::Clock> DeviceManager<D, C> {
         };
         return Ok(_dev);
     }
+
+    // Move the calibration in progress to `state`, and publish it.
+    fn advance_calibration(&self, state: calibration::State) {
+        let job = {
+            let mut calibrations = self.calibrations.lock().unwrap();
+            calibrations.advance(state, chrono::Utc::now());
+            calibrations.current().cloned()
+        };
+        if let Some(job) = job {
+            self.events.publish(api::Event::Calibration(job));
+        }
+    }
+}
+
+impl<D: Device + Send + 'static> DeviceManager<D> {
+    /// Take a single measurement from the device.
+    fn sample(&self) {
+        sample_into(&self.device, &self.recorder);
+    }
+
+    /// Start sampling the device on a background thread, as configured by
+    /// `sampling`. Stored measurements are loaded, and the first sample is
+    /// taken before returning. The thread exits once every handle to this
+    /// manager has been dropped.
+    fn start_sampling(&self, sampling: &Sampling) -> Result<()> {
+        {
+            // Like everywhere else, the device is locked before the
+            // recorder.
+            let mut dev = self.device.lock().unwrap();
+            let mut recorder = self.recorder.lock().unwrap();
+            // Windows too large for chrono are effectively unbounded anyway.
+            let window = chrono::Duration::from_std(sampling.history_window)
+                .unwrap_or_else(|_| chrono::Duration::max_value());
+            recorder.history.set_window(window);
+            if let Some(opts) = &sampling.storage {
+                let s = store::Store::open(opts.clone())?;
+                // A window reaching back past what chrono can represent
+                // covers the whole store.
+                let since = chrono::Utc::now()
+                    .checked_sub_signed(window)
+                    .unwrap_or(chrono::MIN_DATETIME);
+                let loaded = s.read_since(since)?;
+                info!("Loaded {} stored measurements", loaded.len());
+                for r in loaded.into_iter() {
+                    recorder.history.push(r);
+                }
+                recorder.store = Some(s);
+                *self.calibrations.lock().unwrap() =
+                    calibration::Tracker::open(opts.dir.join(CALIBRATION_HISTORY_FILE))?;
+            }
+            for t in sampling.exporters.iter() {
+                let pusher = t
+                    .start()
+                    .map_err(|e| Error::Internal(format!("exporter {}: {}", t.name, e)))?;
+                recorder.pushers.push(pusher);
+            }
+            recorder.elevation = dev.read_elevation().ok();
+            recorder.identity = dev.read_identity().ok();
+            recorder.identity_reconnects = dev.reconnects();
+            *self.last_abc.lock().unwrap() = dev.read_abc().ok();
+        }
+        self.sample();
+        let interval = sampling.interval;
+        let device = sync::Arc::downgrade(&self.device);
+        let recorder = sync::Arc::downgrade(&self.recorder);
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            match (device.upgrade(), recorder.upgrade()) {
+                (Some(device), Some(recorder)) => sample_into(&device, &recorder),
+                _ => return,
+            }
+        });
+        return Ok(());
+    }
 }
 
-impl<D, C> Manager for DeviceManager<D, C>
+impl<D> Manager for DeviceManager<D>
 where
     D: Device + Send + 'static,
-    C: governor::clock::Clock + Send + Sync + 'static,
 {
+    fn start(&self, sampling: &Sampling) -> Result<()> {
+        return self.start_sampling(sampling);
+    }
+
+    fn shutdown(&self) {
+        // Closing waits for the pushers to send what they have, so it isn't
+        // done under the lock.
+        let pushers = std::mem::take(&mut self.recorder.lock().unwrap().pushers);
+        for p in pushers.into_iter() {
+            p.close();
+        }
+    }
+
     fn is_ready(&self) -> bool {
         // If we can lock the device, then we're "ready" to receive
         // measurements.
         return self.maybe_lock_device().is_ok();
     }
 
-    fn measure(&self) -> Result<wire::Concentration> {
-        let mut last_measure = self.last_measure.lock().unwrap();
-        if self.limiter.check().is_err() {
-            // We're rate-limited. Just return the previous measure.
-            return Ok(last_measure.expect(
-                "Since this only triggers when we are rate limited, \
-                         there should always be a value in this option.",
-            ));
-        }
-        let measurement = self.maybe_lock_device()?.read_co2()?;
-        *last_measure = Some(measurement);
-        return Ok(measurement);
+    fn measure(&self) -> Result<device::Reading> {
+        return match self.recorder.lock().unwrap().history.latest() {
+            Some(r) => Ok(r),
+            None => Err(Error::NotReady(String::from(
+                "no measurement has been taken yet",
+            ))),
+        };
+    }
+
+    fn history(
+        &self,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+        step: Option<chrono::Duration>,
+    ) -> Result<Vec<history::Bucket>> {
+        let window = self.recorder.lock().unwrap().history.window();
+        let until = until.unwrap_or_else(chrono::Utc::now);
+        // A window reaching back past what chrono can represent covers
+        // everything.
+        let since = since.unwrap_or_else(|| {
+            return until
+                .checked_sub_signed(window)
+                .unwrap_or(chrono::MIN_DATETIME);
+        });
+        let history = &self.recorder.lock().unwrap().history;
+        return Ok(history.downsample(since, until, step)?);
+    }
+
+    fn records(
+        &self,
+        since: Option<chrono::DateTime<chrono::Utc>>,
+        until: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Records> {
+        let until = until.unwrap_or_else(chrono::Utc::now);
+        let since = since.unwrap_or_else(|| chrono::Utc.timestamp(0, 0));
+        // Only which segments to read is looked up under the lock, so
+        // sampling carries on while they're read.
+        let snapshot = {
+            let recorder = self.recorder.lock().unwrap();
+            match &recorder.store {
+                Some(s) => s.snapshot(),
+                None => {
+                    let rs = recorder.history.range(since, until);
+                    return Ok(Box::new(std::iter::once(Ok(rs))));
+                }
+            }
+        };
+        return Ok(Box::new(snapshot.read_since(since).map(move |rs| {
+            return Ok(rs?.into_iter().filter(|r| r.at <= until).collect());
+        })));
     }
 
-    fn calibrate(&self) -> () {
+    fn calibrate(
+        &self,
+        reference: wire::Concentration,
+        opts: calibration::Options,
+    ) -> Result<calibration::Job> {
+        calibration::check_reference(reference).map_err(|e| Error::BadRequest(e.to_string()))?;
+        let cancel = device::Cancel::new();
+        let job = {
+            let mut calibrations = self.calibrations.lock().unwrap();
+            let job = calibrations
+                .request(reference, chrono::Utc::now())
+                .ok_or(Error::Busy)?;
+            // The job's cancel token is stored before anyone can find the
+            // job, so cancelling it is never missed.
+            *self.calibration_cancel.lock().unwrap() = Some(cancel.clone());
+            job
+        };
+        self.events.publish(api::Event::Calibration(job.clone()));
         let (calibration_started, calibration_in_progress) = sync::mpsc::channel();
         let mgr = (*self).clone();
         thread::spawn(move || {
             let mut dev = mgr.device.lock().unwrap();
             calibration_started.send(()).unwrap();
             info!("Starting calibration in the background...");
-            let r = dev.calibrate_co2(AMBIENT_CONCENTRATION, thread::sleep);
-            if let Some(err) = r.err() {
-                error!("Failed to calibrate: {}", err);
+            // The deadline starts once the device is ours, and sleeping is cut
+            // short by cancellation.
+            let limit = device::Limit::timeout(opts.timeout).with_cancel(cancel.clone());
+            let sleep_fn = |d| cancel.sleep(d);
+            let r = match &opts.stability {
+                Some(s) => {
+                    mgr.advance_calibration(calibration::State::CheckingStability);
+                    check_stability(&mut *dev, s, sleep_fn, &limit)
+                }
+                None => Ok(()),
+            }
+            .and_then(|_| {
+                return dev.calibrate_co2(reference, sleep_fn, &limit, |stage| {
+                    info!("Calibration stage: {:?}", stage);
+                    mgr.advance_calibration(stage.into());
+                });
+            });
+
+            // Free the device before the job is reported finished.
+            drop(dev);
+
+            // The cancel token is cleared along with finishing the job, so it
+            // isn't mixed up with the next job's. Like everywhere else, the
+            // calibrations are locked before the cancel token.
+            let mut calibrations = mgr.calibrations.lock().unwrap();
+            *mgr.calibration_cancel.lock().unwrap() = None;
+            let finished = match r {
+                Ok(()) => calibrations.finish(Ok(()), chrono::Utc::now()),
+                Err(Error::Device(device::Error::Cancelled)) => {
+                    info!("Calibration cancelled");
+                    calibrations.finish_cancelled(chrono::Utc::now())
+                }
+                Err(e) => {
+                    error!("Failed to calibrate: {}", e);
+                    calibrations.finish(Err(e.to_string()), chrono::Utc::now())
+                }
+            };
+            if let Err(e) = finished {
+                error!("Failed to persist calibration result: {}", e);
+            }
+            // The job is recorded in memory even if persisting it failed.
+            if let Some(job) = calibrations.last() {
+                mgr.events.publish(api::Event::Calibration(job.clone()));
             }
         });
-        calibration_in_progress.recv().unwrap();
-        return;
+        calibration_in_progress.recv()?;
+        return Ok(job);
+    }
+
+    fn calibration(&self) -> calibration::Summary {
+        return self.calibrations.lock().unwrap().summary();
+    }
+
+    fn cancel_calibration(&self) -> Result<calibration::Job> {
+        // Held while cancelling, so the job can't finish, and the next one
+        // start, in between.
+        let calibrations = self.calibrations.lock().unwrap();
+        let job = calibrations
+            .current()
+            .cloned()
+            .ok_or_else(|| Error::NotFound(String::from("no calibration in progress")))?;
+        if let Some(cancel) = &*self.calibration_cancel.lock().unwrap() {
+            cancel.cancel();
+        }
+        return Ok(job);
     }
 
     fn elevation(&self) -> Result<wire::Distance> {
@@ -225,24 +862,144 @@ where
     }
 
     fn configure_elevation(&self, to: wire::Distance) -> Result<()> {
-        return self.maybe_lock_device()?.set_elevation(to);
+        let mut dev = self.maybe_lock_device()?;
+        dev.set_elevation(to)?;
+        // The device may round the elevation, so record what it actually
+        // ended up using.
+        self.recorder.lock().unwrap().elevation = dev.read_elevation().ok();
+        return Ok(());
+    }
+
+    fn self_test(&self) -> Result<()> {
+        let (self_test_started, self_test_in_progress) = sync::mpsc::channel();
+        let mgr = (*self).clone();
+        thread::spawn(move || {
+            // Don't wait for the device, e.g., for a calibration to finish.
+            let mut dev = match mgr.maybe_lock_device() {
+                Ok(d) => d,
+                Err(e) => {
+                    self_test_started.send(Err(e)).unwrap();
+                    return;
+                }
+            };
+            self_test_started.send(Ok(())).unwrap();
+            info!("Starting self-test in the background...");
+            let outcome = match dev.run_self_test(thread::sleep) {
+                Ok(r) => {
+                    info!("Self-test finished, passed: {}", r.passed());
+                    mgr.events
+                        .publish(api::Event::SelfTest(api::SelfTestReport::from(&r)));
+                    Ok(r)
+                }
+                Err(err) => {
+                    error!("Failed to run self-test: {}", err);
+                    Err(err.to_string())
+                }
+            };
+            *mgr.last_self_test.lock().unwrap() = Some(outcome);
+        });
+        return self_test_in_progress.recv()?;
+    }
+
+    fn last_self_test(&self) -> Option<SelfTestOutcome> {
+        return self.last_self_test.lock().unwrap().clone();
+    }
+
+    fn identity(&self) -> Option<device::Identity> {
+        return self.recorder.lock().unwrap().identity.clone();
+    }
+
+    fn subscribe(&self) -> events::Subscription {
+        return self.events.subscribe();
+    }
+
+    fn abc(&self) -> Result<wire::response::ABCState> {
+        let a = self.maybe_lock_device()?.read_abc()?;
+        *self.last_abc.lock().unwrap() = Some(a);
+        return Ok(a);
+    }
+
+    fn last_abc(&self) -> Option<wire::response::ABCState> {
+        return *self.last_abc.lock().unwrap();
+    }
+
+    fn configure_abc(&self, to: ABCSetting) -> Result<()> {
+        let mut dev = self.maybe_lock_device()?;
+        let state = match to {
+            ABCSetting::On => wire::response::ABCState::On,
+            ABCSetting::Off => wire::response::ABCState::Off,
+            ABCSetting::Reset => return dev.reset_abc(),
+        };
+        dev.set_abc(match state {
+            wire::response::ABCState::On => wire::Toggle::On,
+            wire::response::ABCState::Off => wire::Toggle::Off,
+        })?;
+        *self.last_abc.lock().unwrap() = Some(state);
+        return Ok(());
+    }
+}
+
+// Check that the readings from `dev` have settled, as configured by `s`.
+fn check_stability<D, T>(
+    dev: &mut D,
+    s: &calibration::Stability,
+    sleep_fn: T,
+    limit: &device::Limit,
+) -> Result<()>
+where
+    D: Device,
+    T: Fn(time::Duration),
+{
+    let mut readings = Vec::with_capacity(s.samples);
+    for i in 0..s.samples {
+        if i > 0 {
+            sleep_fn(s.interval);
+        }
+        limit.check_cancelled()?;
+        readings.push(dev.read_co2()?);
     }
+    return s
+        .check(&readings)
+        .map_err(|e| Error::NotReady(e.to_string()));
+}
+
+/// CalibrateRequest is the optional body of a request to calibrate. Fields
+/// that are omitted fall back to the server's configuration.
+#[derive(Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct CalibrateRequest {
+    /// The concentration of the air or gas the sensor is exposed to.
+    reference_ppm: Option<u16>,
+    /// Whether to check that the readings have settled before calibrating.
+    check_stability: Option<bool>,
 }
 
 pub struct Server<M> {
-    registry: sync::Arc<sync::Mutex<prometheus::Registry>>,
     manager: M,
-    co2_metric: prometheus::Gauge,
+    metrics: sync::Arc<sync::Mutex<metrics::Metrics>>,
     static_dir: String,
+    calibration_reference: wire::Concentration,
+    calibration: calibration::Options,
+    // The token clients must present to change the sensor's
+    // configuration, if any. The control channel is only enabled with one.
+    control_token: Option<String>,
+    // How long a client of the control channel has to authenticate.
+    control_auth_timeout: time::Duration,
+    // How often to send a keep-alive on `GET /api/v1/stream`.
+    stream_keep_alive: time::Duration,
 }
 
 impl<M: Clone> Clone for Server<M> {
     fn clone(&self) -> Self {
         return Server {
-            registry: self.registry.clone(),
             manager: self.manager.clone(),
-            co2_metric: self.co2_metric.clone(),
+            metrics: self.metrics.clone(),
             static_dir: self.static_dir.clone(),
+            calibration_reference: self.calibration_reference,
+            calibration: self.calibration,
+            control_token: self.control_token.clone(),
+            control_auth_timeout: self.control_auth_timeout,
+            stream_keep_alive: self.stream_keep_alive,
         };
     }
 }
@@ -250,6 +1007,13 @@ impl<M: Clone> Clone for Server<M> {
 pub struct Builder<M> {
     manager: Option<M>,
     static_dir: String,
+    sampling: Sampling,
+    calibration_reference: wire::Concentration,
+    calibration: calibration::Options,
+    connection: Option<reconnect::Connection>,
+    command_stats: Option<device::CommandStats>,
+    control_token: Option<String>,
+    mqtt: Option<mqtt::Options>,
 }
 
 impl<M> Default for Builder<M> {
@@ -257,11 +1021,18 @@ impl<M> Default for Builder<M> {
         return Builder {
             manager: None,
             static_dir: String::new(),
+            sampling: Sampling::default(),
+            calibration_reference: AMBIENT_CONCENTRATION,
+            calibration: calibration::Options::default(),
+            connection: None,
+            command_stats: None,
+            control_token: None,
+            mqtt: None,
         };
     }
 }
 
-impl<M> Builder<M> {
+impl<M: Manager> Builder<M> {
     pub fn manager(&mut self, manager: M) -> &mut Self {
         self.manager = Some(manager);
         return self;
@@ -272,15 +1043,102 @@ impl<M> Builder<M> {
         return self;
     }
 
+    /// Set how often the device is sampled in the background.
+    pub fn sample_interval(&mut self, interval: time::Duration) -> &mut Self {
+        self.sampling.interval = interval;
+        return self;
+    }
+
+    /// Durably store measurements as configured by `opts`.
+    pub fn storage(&mut self, opts: store::Options) -> &mut Self {
+        self.sampling.storage = Some(opts);
+        return self;
+    }
+
+    /// Push every measurement to the collector described by `target`.
+    pub fn exporter(&mut self, target: push::Target) -> &mut Self {
+        self.sampling.exporters.push(target);
+        return self;
+    }
+
+    /// Set how much measurement history is kept in memory.
+    pub fn history_window(&mut self, window: time::Duration) -> &mut Self {
+        self.sampling.history_window = window;
+        return self;
+    }
+
+    /// Set the reference concentration the device is calibrated against.
+    pub fn calibration_reference(&mut self, reference: wire::Concentration) -> &mut Self {
+        self.calibration_reference = reference;
+        return self;
+    }
+
+    /// Check that the readings have settled, as configured by `s`, before
+    /// calibrating, unless a request asks otherwise.
+    pub fn calibration_stability(&mut self, s: calibration::Stability) -> &mut Self {
+        self.calibration.stability = Some(s);
+        return self;
+    }
+
+    /// Set how long the device may take to calibrate before giving up.
+    pub fn calibration_timeout(&mut self, timeout: time::Duration) -> &mut Self {
+        self.calibration.timeout = timeout;
+        return self;
+    }
+
+    /// Export the state of the connection to the device as metrics.
+    pub fn connection(&mut self, connection: reconnect::Connection) -> &mut Self {
+        self.connection = Some(connection);
+        return self;
+    }
+
+    /// Export the latency, outcome and errors of the commands counted by
+    /// `stats` as metrics.
+    pub fn command_stats(&mut self, stats: device::CommandStats) -> &mut Self {
+        self.command_stats = Some(stats);
+        return self;
+    }
+
+    /// Serve the control channel, `GET /api/v1/control`, to clients that
+    /// authenticate with `token`.
+    pub fn control_token(&mut self, token: &str) -> &mut Self {
+        self.control_token = Some(String::from(token));
+        return self;
+    }
+
+    /// Publish readings, status changes and calibration progress to the
+    /// MQTT broker configured by `opts`.
+    pub fn mqtt(&mut self, opts: mqtt::Options) -> &mut Self {
+        self.mqtt = Some(opts);
+        return self;
+    }
+
     pub fn build(self) -> Result<Server<M>> {
-        return Ok(Server::new(
-            self.manager.ok_or(Error::from("No manager provided"))?,
-            &self.static_dir,
-        ));
+        let manager = self.manager.ok_or(Error::from("No manager provided"))?;
+        manager.start(&self.sampling)?;
+        if let Some(opts) = self.mqtt {
+            mqtt::start(opts, manager.identity(), manager.subscribe())?;
+        }
+        let mut server = Server::new(manager, &self.static_dir);
+        server.calibration_reference = self.calibration_reference;
+        server.calibration = self.calibration;
+        server.control_token = self.control_token;
+        {
+            let mut metrics = server.metrics.lock().unwrap();
+            if let Some(c) = self.connection {
+                metrics
+                    .watch_connection(c)
+                    .map_err(|e| Error::Internal(e.to_string()))?;
+            }
+            if let Some(stats) = &self.command_stats {
+                metrics.watch_commands(stats);
+            }
+        }
+        return Ok(server);
     }
 }
 
-impl<D: Device> Builder<DeviceManager<D, governor::clock::DefaultClock>> {
+impl<D: Device> Builder<DeviceManager<D>> {
     pub fn device(&mut self, device: D) -> &mut Self {
         self.manager = Some(DeviceManager::new(device));
         return self;
@@ -289,23 +1147,126 @@ impl<D: Device> Builder<DeviceManager<D, governor::clock::DefaultClock>> {
 
 impl<M> Server<M> {
     fn new(manager: M, static_dir: &'_ str) -> Self {
-        let registry = prometheus::Registry::new();
-        // TODO(jkz): These errors should be propogated probably.
-        let co2_metric = prometheus::Gauge::new(
-            "co2_ppm",
-            "The current concentration of CO2 in the air in parts per million",
-        )
-        .unwrap();
-        registry.register(Box::new(co2_metric.clone())).unwrap();
-
+        // Registering new metrics with a new registry only fails if the
+        // metrics themselves are broken.
+        let metrics = metrics::Metrics::new().expect("failed to register metrics");
         return Server {
-            registry: sync::Arc::new(sync::Mutex::new(registry)),
             manager: manager,
-            co2_metric: co2_metric,
+            metrics: sync::Arc::new(sync::Mutex::new(metrics)),
             static_dir: String::from(static_dir),
+            calibration_reference: AMBIENT_CONCENTRATION,
+            calibration: calibration::Options::default(),
+            control_token: None,
+            control_auth_timeout: CONTROL_AUTH_TIMEOUT,
+            stream_keep_alive: STREAM_KEEP_ALIVE,
         };
     }
 }
+
+/// HistoryQuery holds the query parameters accepted by `GET /history`.
+/// `since` and `until` are RFC 3339 timestamps, `step` is in seconds.
+#[derive(Debug, Default, PartialEq)]
+struct HistoryQuery {
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    step: Option<u32>,
+}
+
+impl HistoryQuery {
+    // Parse the query string of the request in `state`. Unknown parameters
+    // are ignored.
+    fn parse(state: &GothamState) -> Result<HistoryQuery> {
+        let mut q = HistoryQuery::default();
+        for (k, v) in query_pairs(state).iter() {
+            match k.as_str() {
+                "since" => q.since = Some(parse_time(k, v)?),
+                "until" => q.until = Some(parse_time(k, v)?),
+                "step" => {
+                    q.step = Some(v.parse().map_err(|_| {
+                        Error::BadRequest(format!(
+                            "step must be a whole number of seconds, got {:?}",
+                            v
+                        ))
+                    })?)
+                }
+                _ => {}
+            }
+        }
+        return Ok(q);
+    }
+}
+
+/// ExportQuery holds the query parameters accepted by the `/export.*`
+/// routes. `since` and `until` are RFC 3339 timestamps.
+#[derive(Debug, Default, PartialEq)]
+struct ExportQuery {
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl ExportQuery {
+    // Parse the query string of the request in `state`. Unknown parameters
+    // are ignored.
+    fn parse(state: &GothamState) -> Result<ExportQuery> {
+        let mut q = ExportQuery::default();
+        for (k, v) in query_pairs(state).iter() {
+            match k.as_str() {
+                "since" => q.since = Some(parse_time(k, v)?),
+                "until" => q.until = Some(parse_time(k, v)?),
+                _ => {}
+            }
+        }
+        return Ok(q);
+    }
+}
+
+// The paths served under `api::PREFIX`, for answering requests with methods
+// they don't take.
+const V1_PATHS: [&str; 9] = [
+    "/co2",
+    "/history",
+    "/ready",
+    "/stream",
+    "/control",
+    "/elevation",
+    "/abc",
+    "/selftest",
+    "/calibration",
+];
+
+// Every method a client might reasonably send.
+fn any_method() -> Vec<http::Method> {
+    return vec![
+        http::Method::GET,
+        http::Method::HEAD,
+        http::Method::POST,
+        http::Method::PUT,
+        http::Method::PATCH,
+        http::Method::DELETE,
+        http::Method::OPTIONS,
+    ];
+}
+
+// The parameters in the query string of the request in `state`. Queries are
+// parsed by hand, rather than by gotham, so that a malformed one is reported
+// like any other bad request.
+fn query_pairs(state: &GothamState) -> Vec<(String, String)> {
+    let query = http::Uri::borrow_from(state).query().unwrap_or("");
+    return form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect();
+}
+
+// Parse the timestamp `v`, given as the query parameter `name`.
+fn parse_time(name: &str, v: &str) -> Result<chrono::DateTime<chrono::Utc>> {
+    return v.parse().map_err(|e| {
+        Error::BadRequest(format!(
+            "{} must be an RFC 3339 timestamp, got {:?}: {}",
+            name, v, e
+        ))
+    });
+}
+
 fn json_response<J: serde::Serialize>(value: &J) -> http::Response<hyper::Body> {
     let builder = http::response::Builder::default();
     let maybe_resp = match serde_json::to_vec(value) {
@@ -321,6 +1282,163 @@ fn json_response<J: serde::Serialize>(value: &J) -> http::Response<hyper::Body>
     };
 }
 
+// Serve `r` as JSON with `status` if it's a value, or in the error envelope
+// of the versioned API if it's an error.
+fn api_response<J: serde::Serialize>(
+    r: Result<J>,
+    status: http::StatusCode,
+) -> http::Response<hyper::Body> {
+    return match r {
+        Ok(v) => {
+            let mut resp = json_response(&v);
+            if resp.status().is_success() {
+                *resp.status_mut() = status;
+            }
+            resp
+        }
+        Err(e) => e.to_api_response(),
+    };
+}
+
+// Read the body of the request in `state` as JSON.
+async fn read_body<T: serde::de::DeserializeOwned>(state: &mut GothamState) -> Result<T> {
+    let body = hyper::body::to_bytes(hyper::Body::take_from(state))
+        .await
+        .map_err(|e| Error::BadRequest(e.to_string()))?;
+    return serde_json::from_slice(&body).map_err(|e| Error::BadRequest(e.to_string()));
+}
+
+// Send `e` to a subscriber of `GET /api/v1/stream`. Returns `false` once the
+// subscriber has gone away.
+async fn send_event(sender: &mut hyper::body::Sender, e: &api::Event) -> bool {
+    let sse = match e.to_sse() {
+        Ok(sse) => sse,
+        Err(err) => {
+            error!("Failed to encode {} event: {}", e.name(), err);
+            return true;
+        }
+    };
+    return sender.send_data(sse.into()).await.is_ok();
+}
+
+// The key of a request to upgrade to a WebSocket, or why it isn't a
+// handshake the server can complete.
+fn websocket_key(headers: &http::HeaderMap) -> Result<String> {
+    let not_upgrade = || Error::BadRequest(String::from("expected a WebSocket upgrade"));
+    let upgrade = headers
+        .get(http::header::UPGRADE)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(not_upgrade)?;
+    // Connection lists options, e.g., `keep-alive, Upgrade`.
+    let connection = headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|o| o.trim().eq_ignore_ascii_case("upgrade"));
+    if !upgrade.eq_ignore_ascii_case("websocket") || !connection {
+        return Err(not_upgrade());
+    }
+    let version = headers
+        .get(http::header::SEC_WEBSOCKET_VERSION)
+        .and_then(|v| v.to_str().ok());
+    if version != Some(WEBSOCKET_VERSION) {
+        return Err(Error::UpgradeRequired(format!(
+            "only WebSocket version {} is supported",
+            WEBSOCKET_VERSION
+        )));
+    }
+    let key = headers
+        .get(http::header::SEC_WEBSOCKET_KEY)
+        .and_then(|v| v.to_str().ok())
+        .ok_or_else(not_upgrade)?;
+    return Ok(String::from(key));
+}
+
+// Compare the token a client gave with the configured one in constant time,
+// so the token can't be guessed a byte at a time. Digests are compared
+// rather than the tokens themselves, so not even the token's length leaks.
+fn same_token(given: &str, token: &str) -> bool {
+    let (given, token) = (
+        Sha1::digest(given.as_bytes()),
+        Sha1::digest(token.as_bytes()),
+    );
+    let diff = given
+        .iter()
+        .zip(token.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    return diff == 0;
+}
+
+// The bearer token the request with `headers` carries, if any.
+fn bearer_token(headers: &http::HeaderMap) -> Option<&str> {
+    let value = headers.get(http::header::AUTHORIZATION)?.to_str().ok()?;
+    let (scheme, token) = value.split_at(value.find(' ')?);
+    if !scheme.eq_ignore_ascii_case("bearer") {
+        return None;
+    }
+    return Some(token.trim());
+}
+
+// The reply to a command on the control channel that failed with `e`.
+fn control_error(id: Option<u64>, e: Error) -> api::Reply {
+    let status = e.status().as_u16();
+    return api::Reply::Error {
+        id: id,
+        error: api::ErrorBody::new(status, e.code(), e.to_string()).error,
+    };
+}
+
+// StreamInput is what a subscriber of `GET /api/v1/stream` is sent.
+enum StreamInput {
+    Event(api::Event),
+    // Nothing has been sent for a while.
+    KeepAlive,
+    // The events have stopped, e.g., as the server is shutting down.
+    Closed,
+}
+
+// ControlInput is what a session on the control channel reacts to.
+enum ControlInput {
+    Message(result::Result<ws::Message, ws::Error>),
+    Event(api::Event),
+    // A command the client sent has finished.
+    Done(api::Reply),
+    // The client has had long enough to authenticate.
+    AuthDeadline,
+    // The client went away.
+    Closed,
+}
+
+// Control is how a session on the control channel handles a message.
+enum Control {
+    Reply(api::Reply),
+    // Run the command in the background, and reply once it's done.
+    Run(Option<u64>, api::Command),
+    // Reply, then hang up on the client.
+    Refuse(api::Reply),
+}
+
+// The close frame sent to clients of the control channel that are refused.
+fn policy_violation(reason: &'static str) -> ws::Message {
+    return ws::Message::Close(Some(ws::protocol::CloseFrame {
+        code: ws::protocol::frame::coding::CloseCode::Policy,
+        reason: reason.into(),
+    }));
+}
+
+// Check that the sensor can sensibly be configured for elevation `d`.
+fn check_elevation(d: wire::Distance) -> Result<()> {
+    // TODO(jkz): allow comparison of these types directly.
+    if d.feet() > MT_EVEREST_HEIGHT.feet() {
+        return Err(Error::BadRequest(format!(
+            "height {} ft. does not exist on earth",
+            d.feet()
+        )));
+    }
+    return Ok(());
+}
+
 impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> gotham::state::StateData
     for Server<M>
 {
@@ -329,33 +1447,163 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> gotham::state::
 impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
     fn render_metrics(mut state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
         let srv = Self::take_from(&mut state);
-        match srv.manager.measure() {
-            Ok(c) => srv.co2_metric.set(c.ppm() as f64),
-            Err(e) => return (state, e.to_response()),
+        let snapshot = metrics::Snapshot {
+            reading: srv.manager.measure().ok(),
+            self_test: srv.manager.last_self_test(),
+            abc: srv.manager.last_abc(),
+            identity: srv.manager.identity(),
+            last_calibration: srv.manager.calibration().history.into_iter().next(),
+            now: chrono::Utc::now(),
+        };
+        let exported = srv.metrics.lock().unwrap();
+        exported.update(&snapshot);
+        let out = match exported.encode() {
+            Ok(out) => out,
+            Err(e) => return (state, Error::from(e.to_string()).to_response()),
         };
-
-        let enc = prometheus::TextEncoder::new();
-        let mut out: Vec<u8> = Vec::new();
-
-        let registry = srv.registry.lock().unwrap();
-
-        if let Err(e) = enc.encode(&registry.gather(), &mut out) {
-            return (state, Error::from(e.to_string()).to_response());
-        }
         let resp =
             gotham_response::create_response(&state, http::StatusCode::OK, mime::TEXT_PLAIN, out);
         return (state, resp);
     }
 
-    fn render_put_calibrate(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+    // Check that the request in `state` may change the sensor's
+    // configuration. Once a control token is configured, it must carry it
+    // as a bearer token.
+    fn authorize(state: &GothamState) -> Result<()> {
+        let token = match &Self::borrow_from(state).control_token {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        return match bearer_token(http::HeaderMap::borrow_from(state)) {
+            Some(given) if same_token(given, token) => Ok(()),
+            Some(_) => Err(Error::Unauthorized(String::from("wrong token"))),
+            None => Err(Error::Unauthorized(String::from(
+                "changing the sensor's configuration requires a bearer token",
+            ))),
+        };
+    }
+
+    // Start calibrating as asked by the body of the request in `state`.
+    async fn start_calibration(state: &mut GothamState) -> Result<calibration::Job> {
+        let body = hyper::body::to_bytes(hyper::Body::take_from(state))
+            .await
+            .map_err(|e| Error::BadRequest(e.to_string()))?;
+        let req: CalibrateRequest = if body.iter().all(u8::is_ascii_whitespace) {
+            CalibrateRequest::default()
+        } else {
+            serde_json::from_slice(&body).map_err(|e| Error::BadRequest(e.to_string()))?
+        };
+
+        return Self::borrow_from(state).calibrate(req);
+    }
+
+    // Start calibrating as asked by `req`, falling back to the server's
+    // configuration.
+    fn calibrate(&self, req: CalibrateRequest) -> Result<calibration::Job> {
+        let reference = req
+            .reference_ppm
+            .map(wire::Concentration::PPM)
+            .unwrap_or(self.calibration_reference);
+        let mut opts = self.calibration;
+        match req.check_stability {
+            Some(true) => opts.stability = Some(opts.stability.unwrap_or_default()),
+            Some(false) => opts.stability = None,
+            None => {}
+        }
+        return self.manager.calibrate(reference, opts);
+    }
+
+    // Configure the sensor for elevation `e`, and return the elevation it
+    // ended up with, as it may round.
+    fn set_elevation(&self, e: api::Elevation) -> Result<api::Elevation> {
+        let d = e.distance().map_err(Error::BadRequest)?;
+        check_elevation(d)?;
+        self.manager.configure_elevation(d)?;
+        return self.manager.elevation().map(api::Elevation::feet);
+    }
+
+    // Configure the sensor's ABC as asked by `a`, and return the resulting
+    // state, which is all a reset can be reported as.
+    fn set_abc(&self, a: api::Abc) -> Result<api::Abc> {
+        self.manager.configure_abc(a.state)?;
+        return self.manager.abc().map(|a| api::Abc { state: a.into() });
+    }
+
+    async fn render_put_calibrate(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_response()));
+        }
+        if let Err(e) = Self::start_calibration(&mut state).await {
+            return Ok((state, e.to_response()));
+        }
+        // Return an empty 200. Progress is tracked by `GET /calibration`.
+        let resp = gotham_response::create_empty_response(&state, http::StatusCode::OK);
+        return Ok((state, resp));
+    }
+
+    async fn render_post_calibration(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_response()));
+        }
+        return Ok(match Self::start_calibration(&mut state).await {
+            Ok(job) => {
+                let mut resp = json_response(&job);
+                *resp.status_mut() = http::StatusCode::ACCEPTED;
+                (state, resp)
+            }
+            Err(e) => (state, e.to_response()),
+        });
+    }
+
+    fn render_delete_calibration(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        if let Err(e) = Self::authorize(&state) {
+            return (state, e.to_response());
+        }
+        let srv = Self::borrow_from(&state);
+        return match srv.manager.cancel_calibration() {
+            Ok(job) => {
+                let mut resp = json_response(&job);
+                *resp.status_mut() = http::StatusCode::ACCEPTED;
+                (state, resp)
+            }
+            Err(e) => (state, e.to_response()),
+        };
+    }
+
+    fn render_calibration(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let resp = json_response(&srv.manager.calibration());
+        return (state, resp);
+    }
+
+    fn render_post_self_test(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        if let Err(e) = Self::authorize(&state) {
+            return (state, e.to_response());
+        }
         let srv = Self::borrow_from(&state);
-        // TODO(jkz): Handle this error correctly.
-        srv.manager.calibrate();
-        // Return an empty 200.
+        if let Err(e) = srv.manager.self_test() {
+            return (state, e.to_response());
+        }
         let resp = gotham_response::create_empty_response(&state, http::StatusCode::OK);
         return (state, resp);
     }
 
+    fn render_self_test(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let report = match srv.manager.last_self_test() {
+            Some(Ok(r)) => Some(api::SelfTestReport::from(&r)),
+            // The last run failing isn't this request failing: report why, as
+            // `GET /api/v1/selftest` does.
+            Some(Err(e)) => {
+                let resp = json_response(&serde_json::json!({ "error": e }));
+                return (state, resp);
+            }
+            None => None,
+        };
+        let resp = json_response(&report);
+        return (state, resp);
+    }
+
     fn render_is_ready(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
         let srv = Self::borrow_from(&state);
         let resp = json_response(&srv.manager.is_ready());
@@ -365,11 +1613,77 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
     fn render_co2(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
         let srv = Self::borrow_from(&state);
         return match srv.manager.measure() {
-            Ok(concentration) => (state, json_response(&concentration.ppm())),
+            Ok(r) => (state, json_response(&r.concentration.ppm())),
+            Err(e) => (state, e.to_response()),
+        };
+    }
+
+    fn render_history(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let query = match HistoryQuery::parse(&state) {
+            Ok(q) => q,
+            Err(e) => return (state, e.to_response()),
+        };
+        let srv = Self::borrow_from(&state);
+        let step = query.step.map(|s| chrono::Duration::seconds(s as i64));
+        return match srv.manager.history(query.since, query.until, step) {
+            Ok(buckets) => (state, json_response(&buckets)),
             Err(e) => (state, e.to_response()),
         };
     }
 
+    // Serve the recorded measurements asked for by the query in `state`,
+    // formatted a chunk at a time by `format` after `header`. The body is
+    // streamed, so a large export is never held in memory all at once.
+    fn render_export(
+        state: GothamState,
+        content_type: mime::Mime,
+        header: String,
+        format: fn(&[device::Reading]) -> Result<String>,
+    ) -> (GothamState, http::Response<hyper::Body>) {
+        let query = match ExportQuery::parse(&state) {
+            Ok(q) => q,
+            Err(e) => return (state, e.to_response()),
+        };
+        let srv = Self::borrow_from(&state);
+        let mut records = match srv.manager.records(query.since, query.until) {
+            Ok(rs) => rs,
+            Err(e) => return (state, e.to_response()),
+        };
+        // Failing to read the first chunk can still be reported properly.
+        // Later failures can only cut the export short.
+        let first = match records.next() {
+            Some(rs) => rs.and_then(|rs| format(&rs)),
+            None => Ok(String::new()),
+        };
+        let first = match first {
+            Ok(f) => f,
+            Err(e) => return (state, e.to_response()),
+        };
+        let rest = records.map(move |rs| {
+            return rs.and_then(|rs| format(&rs)).map_err(|e| {
+                error!("Failed to export measurements: {}", e);
+                return e;
+            });
+        });
+        let chunks = std::iter::once(Ok(header + &first)).chain(rest);
+        let body = hyper::Body::wrap_stream(stream::iter(chunks));
+        let resp =
+            gotham_response::create_response(&state, http::StatusCode::OK, content_type, body);
+        return (state, resp);
+    }
+
+    fn render_export_csv(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let header = format!("{}\n", export::CSV_HEADER);
+        return Self::render_export(state, mime::TEXT_CSV, header, |rs| Ok(export::csv_rows(rs)));
+    }
+
+    fn render_export_jsonl(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let content_type = "application/x-ndjson".parse().unwrap();
+        return Self::render_export(state, content_type, String::new(), |rs| {
+            export::jsonl(rs).map_err(|e| Error::from(e.to_string()))
+        });
+    }
+
     fn render_elevation(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
         let srv = Self::borrow_from(&state);
         return match srv.manager.elevation() {
@@ -379,30 +1693,47 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
     }
 
     async fn render_put_elevation(mut state: GothamState) -> gotham::handler::HandlerResult {
-        let body = match hyper::body::to_bytes(hyper::Body::take_from(&mut state)).await {
-            Ok(bytes) => bytes,
-            Err(e) => return Ok((state, Error::from(e.to_string()).to_response())),
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_response()));
+        }
+        let to_configure = match read_body::<u16>(&mut state)
+            .await
+            .map(wire::Distance::Feet)
+            .and_then(|d| check_elevation(d).map(|_| d))
+        {
+            Ok(d) => d,
+            Err(e) => return Ok((state, e.to_response())),
         };
-        let to_configure_raw: u16 = match serde_json::from_slice(&body) {
-            Ok(v) => v,
-            Err(e) => return Ok((state, Error::from(e.to_string()).to_response())),
+
+        let srv = Self::borrow_from(&state);
+        return Ok(match srv.manager.configure_elevation(to_configure) {
+            Ok(_) => {
+                let resp = gotham_response::create_empty_response(&state, http::StatusCode::OK);
+                (state, resp)
+            }
+            Err(e) => (state, e.to_response()),
+        });
+    }
+
+    fn render_abc(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        return match srv.manager.abc() {
+            Ok(a) => (state, json_response(&ABCSetting::from(a))),
+            Err(e) => (state, e.to_response()),
         };
+    }
 
-        let to_configure = wire::Distance::Feet(to_configure_raw);
-        // TODO(jkz): allow comparison of these types directly.
-        if to_configure.feet() > MT_EVEREST_HEIGHT.feet() {
-            return Ok((
-                state,
-                Error::from(format!(
-                    "height {} ft. does not exist on earth",
-                    to_configure.feet()
-                ))
-                .to_response(),
-            ));
+    async fn render_put_abc(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_response()));
         }
+        let to_configure: ABCSetting = match read_body(&mut state).await {
+            Ok(v) => v,
+            Err(e) => return Ok((state, e.to_response())),
+        };
 
         let srv = Self::borrow_from(&state);
-        return Ok(match srv.manager.configure_elevation(to_configure) {
+        return Ok(match srv.manager.configure_abc(to_configure) {
             Ok(_) => {
                 let resp = gotham_response::create_empty_response(&state, http::StatusCode::OK);
                 (state, resp)
@@ -411,6 +1742,418 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
         });
     }
 
+    fn render_v1_co2(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let identity = srv.manager.identity();
+        let r = srv
+            .manager
+            .measure()
+            .map(|r| api::Reading::new(&r, identity.as_ref(), chrono::Utc::now()));
+        let resp = api_response(r, http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    fn render_v1_history(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let query = match HistoryQuery::parse(&state) {
+            Ok(q) => q,
+            Err(e) => return (state, e.to_api_response()),
+        };
+        let srv = Self::borrow_from(&state);
+        let step = query.step.map(|s| chrono::Duration::seconds(s as i64));
+        let history = srv
+            .manager
+            .history(query.since, query.until, step)
+            .map(|buckets| api::History { buckets: buckets });
+        return (state, api_response(history, http::StatusCode::OK));
+    }
+
+    fn render_v1_method_not_allowed(
+        state: GothamState,
+    ) -> (GothamState, http::Response<hyper::Body>) {
+        let e = Error::MethodNotAllowed(format!(
+            "{} doesn't take {}",
+            http::Uri::borrow_from(&state).path(),
+            http::Method::borrow_from(&state)
+        ));
+        return (state, e.to_api_response());
+    }
+
+    fn render_v1_not_found(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let e = Error::NotFound(format!(
+            "no such route: {} {}",
+            http::Method::borrow_from(&state),
+            http::Uri::borrow_from(&state).path()
+        ));
+        return (state, e.to_api_response());
+    }
+
+    fn render_v1_ready(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let ready = api::Ready {
+            ready: srv.manager.is_ready(),
+        };
+        let resp = api_response(Ok(ready), http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    fn render_v1_elevation(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let e = srv.manager.elevation().map(api::Elevation::feet);
+        let resp = api_response(e, http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    async fn render_v1_put_elevation(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_api_response()));
+        }
+        let r = read_body::<api::Elevation>(&mut state).await;
+        let srv = Self::borrow_from(&state);
+        let r = r.and_then(|e| srv.set_elevation(e));
+        let resp = api_response(r, http::StatusCode::OK);
+        return Ok((state, resp));
+    }
+
+    fn render_v1_abc(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let a = srv.manager.abc().map(|a| api::Abc { state: a.into() });
+        let resp = api_response(a, http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    async fn render_v1_put_abc(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_api_response()));
+        }
+        let r = read_body::<api::Abc>(&mut state).await;
+        let srv = Self::borrow_from(&state);
+        let r = r.and_then(|a| srv.set_abc(a));
+        let resp = api_response(r, http::StatusCode::OK);
+        return Ok((state, resp));
+    }
+
+    fn render_v1_self_test(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let (last, error) = match srv.manager.last_self_test() {
+            Some(Ok(r)) => (Some(api::SelfTestReport::from(&r)), None),
+            Some(Err(e)) => (None, Some(e)),
+            None => (None, None),
+        };
+        let report = api::SelfTest {
+            last: last,
+            error: error,
+        };
+        let resp = api_response(Ok(report), http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    fn render_v1_post_self_test(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        if let Err(e) = Self::authorize(&state) {
+            return (state, e.to_api_response());
+        }
+        let srv = Self::borrow_from(&state);
+        if let Err(e) = srv.manager.self_test() {
+            return (state, e.to_api_response());
+        }
+        // The self-test runs in the background, see `GET /api/v1/selftest`.
+        let resp = gotham_response::create_empty_response(&state, http::StatusCode::ACCEPTED);
+        return (state, resp);
+    }
+
+    fn render_v1_calibration(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let resp = api_response(Ok(srv.manager.calibration()), http::StatusCode::OK);
+        return (state, resp);
+    }
+
+    async fn render_v1_post_calibration(mut state: GothamState) -> gotham::handler::HandlerResult {
+        if let Err(e) = Self::authorize(&state) {
+            return Ok((state, e.to_api_response()));
+        }
+        let r = Self::start_calibration(&mut state).await;
+        let resp = api_response(r, http::StatusCode::ACCEPTED);
+        return Ok((state, resp));
+    }
+
+    fn render_v1_delete_calibration(
+        state: GothamState,
+    ) -> (GothamState, http::Response<hyper::Body>) {
+        if let Err(e) = Self::authorize(&state) {
+            return (state, e.to_api_response());
+        }
+        let srv = Self::borrow_from(&state);
+        let resp = api_response(srv.manager.cancel_calibration(), http::StatusCode::ACCEPTED);
+        return (state, resp);
+    }
+
+    // Serve a client of the control channel on `socket`, until either side
+    // is done with it. Clients that authenticate with `token` may send
+    // commands, and are sent every event as it happens. Clients that give
+    // the wrong token, or take too long to give one, are disconnected.
+    async fn serve_control<S>(self, socket: S, token: String)
+    where
+        S: stream::Stream<Item = result::Result<ws::Message, ws::Error>>
+            + futures_util::Sink<ws::Message>,
+    {
+        let (mut sink, messages) = socket.split();
+        let events = stream::unfold(self.manager.subscribe(), |mut s| async move {
+            return s.next().await.map(|e| (e, s));
+        });
+        // Commands run in the background, so events keep flowing while
+        // they do. Their replies are passed back here.
+        let (done, replies) = tokio::sync::mpsc::unbounded_channel();
+        let replies = stream::unfold(replies, |mut r| async move {
+            return r.recv().await.map(|reply| (reply, r));
+        });
+        let deadline = tokio::time::sleep(self.control_auth_timeout);
+        let mut inputs = Box::pin(stream::select(
+            stream::select(
+                messages
+                    .map(ControlInput::Message)
+                    .chain(stream::once(async { ControlInput::Closed })),
+                events.map(ControlInput::Event),
+            ),
+            stream::select(
+                replies.map(ControlInput::Done),
+                stream::once(deadline).map(|_| ControlInput::AuthDeadline),
+            ),
+        ));
+        let mut authenticated = false;
+        while let Some(input) = inputs.next().await {
+            let (reply, hang_up) = match input {
+                ControlInput::Message(Ok(ws::Message::Text(text))) => {
+                    match self.handle_control(&text, &token, &mut authenticated) {
+                        Control::Reply(r) => (Some(r), None),
+                        Control::Refuse(r) => (Some(r), Some("wrong token")),
+                        Control::Run(id, command) => {
+                            let srv = self.clone();
+                            let done = done.clone();
+                            tokio::spawn(async move {
+                                // Commands talk to the device, so keep them
+                                // off the threads serving requests.
+                                let r =
+                                    tokio::task::spawn_blocking(move || srv.run_command(command))
+                                        .await
+                                        .unwrap_or_else(|e| Err(Error::Internal(e.to_string())));
+                                let reply = match r {
+                                    Ok(data) => api::Reply::Result { id: id, data: data },
+                                    Err(e) => control_error(id, e),
+                                };
+                                // The session may be gone by now.
+                                let _ = done.send(reply);
+                            });
+                            continue;
+                        }
+                    }
+                }
+                ControlInput::Message(Ok(ws::Message::Binary(_))) => (
+                    Some(control_error(
+                        None,
+                        Error::BadRequest(String::from("expected a text message")),
+                    )),
+                    None,
+                ),
+                ControlInput::Message(Ok(ws::Message::Close(_))) | ControlInput::Closed => break,
+                // Pings are answered by the WebSocket itself.
+                ControlInput::Message(Ok(_)) => continue,
+                ControlInput::Message(Err(e)) => {
+                    debug!("control channel failed: {}", e);
+                    break;
+                }
+                ControlInput::Event(e) if authenticated => {
+                    (Some(api::Reply::Event { event: e }), None)
+                }
+                ControlInput::Event(_) => continue,
+                ControlInput::Done(r) => (Some(r), None),
+                ControlInput::AuthDeadline if authenticated => continue,
+                ControlInput::AuthDeadline => (None, Some("authentication timed out")),
+            };
+            if let Some(reply) = reply {
+                let text = match serde_json::to_string(&reply) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        error!("Failed to encode control channel reply: {}", e);
+                        continue;
+                    }
+                };
+                if sink.send(ws::Message::Text(text)).await.is_err() {
+                    break;
+                }
+            }
+            if let Some(reason) = hang_up {
+                let _ = sink.send(policy_violation(reason)).await;
+                break;
+            }
+        }
+        let _ = sink.close().await;
+    }
+
+    // Decide how to handle the message `text` from a client of the control
+    // channel.
+    fn handle_control(&self, text: &str, token: &str, authenticated: &mut bool) -> Control {
+        let req: api::Request = match serde_json::from_str(text) {
+            Ok(r) => r,
+            Err(e) => return Control::Reply(control_error(None, Error::BadRequest(e.to_string()))),
+        };
+        return match req.command {
+            api::Command::Authenticate { token: given } => {
+                if same_token(&given, token) {
+                    *authenticated = true;
+                    return Control::Reply(api::Reply::Authenticated);
+                }
+                Control::Refuse(control_error(
+                    req.id,
+                    Error::Unauthorized(String::from("wrong token")),
+                ))
+            }
+            _ if !*authenticated => Control::Reply(control_error(
+                req.id,
+                Error::Unauthorized(String::from("authenticate before sending commands")),
+            )),
+            command => Control::Run(req.id, command),
+        };
+    }
+
+    // Run `command` for an authenticated client of the control channel, and
+    // return what the matching route of the API would serve.
+    fn run_command(&self, command: api::Command) -> Result<serde_json::Value> {
+        let data = match command {
+            api::Command::Authenticate { .. } => {
+                return Err(Error::from("authentication is handled by the session"))
+            }
+            api::Command::Calibrate {
+                reference_ppm,
+                check_stability,
+            } => serde_json::to_value(self.calibrate(CalibrateRequest {
+                reference_ppm: reference_ppm,
+                check_stability: check_stability,
+            })?),
+            api::Command::SetElevation { elevation } => {
+                serde_json::to_value(self.set_elevation(elevation)?)
+            }
+            api::Command::SetAbc { state } => {
+                serde_json::to_value(self.set_abc(api::Abc { state: state })?)
+            }
+            api::Command::SelfTest => {
+                self.manager.self_test()?;
+                Ok(serde_json::Value::Null)
+            }
+        };
+        return data.map_err(|e| Error::Internal(e.to_string()));
+    }
+
+    fn render_v1_control(mut state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state).clone();
+        let token = match srv.control_token.clone() {
+            Some(t) => t,
+            None => {
+                let e = Error::NotFound(String::from(
+                    "the control channel is disabled, see http.control_token",
+                ));
+                return (state, e.to_api_response());
+            }
+        };
+        let key = match websocket_key(http::HeaderMap::borrow_from(&state)) {
+            Ok(k) => k,
+            Err(e) => return (state, e.to_api_response()),
+        };
+        let on_upgrade = match hyper::upgrade::OnUpgrade::try_take_from(&mut state) {
+            Some(u) => u,
+            None => {
+                let e = Error::from("the connection can't be upgraded");
+                return (state, e.to_api_response());
+            }
+        };
+        tokio::spawn(async move {
+            let upgraded = match on_upgrade.await {
+                Ok(u) => u,
+                Err(e) => {
+                    error!("Failed to upgrade the control channel: {}", e);
+                    return;
+                }
+            };
+            let socket =
+                WebSocketStream::from_raw_socket(upgraded, ws::protocol::Role::Server, None).await;
+            srv.serve_control(socket, token).await;
+        });
+        let resp = http::response::Builder::default()
+            .status(http::StatusCode::SWITCHING_PROTOCOLS)
+            .header(http::header::UPGRADE, "websocket")
+            .header(http::header::CONNECTION, "upgrade")
+            .header(
+                http::header::SEC_WEBSOCKET_ACCEPT,
+                ws::handshake::derive_accept_key(key.as_bytes()),
+            )
+            .body(hyper::Body::empty())
+            .unwrap();
+        return (state, resp);
+    }
+
+    fn render_v1_stream(state: GothamState) -> (GothamState, http::Response<hyper::Body>) {
+        let srv = Self::borrow_from(&state);
+        let events = srv.manager.subscribe();
+        // Start with the latest measurement, so subscribers don't have to
+        // wait for the next one.
+        let identity = srv.manager.identity();
+        let latest = srv.manager.measure().ok().map(|r| {
+            api::Event::Reading(api::Reading::new(&r, identity.as_ref(), chrono::Utc::now()))
+        });
+        let keep_alive = srv.stream_keep_alive;
+        let (mut sender, body) = hyper::Body::channel();
+        tokio::spawn(async move {
+            if let Some(e) = latest {
+                if !send_event(&mut sender, &e).await {
+                    return;
+                }
+            }
+            let start = tokio::time::Instant::now() + keep_alive;
+            let ticks = stream::unfold(
+                tokio::time::interval_at(start, keep_alive),
+                |mut t| async move {
+                    t.tick().await;
+                    return Some((StreamInput::KeepAlive, t));
+                },
+            );
+            let events = stream::unfold(events, |mut s| async move {
+                return s.next().await.map(|e| (e, s));
+            });
+            let mut inputs = Box::pin(stream::select(
+                events
+                    .map(StreamInput::Event)
+                    .chain(stream::once(async { StreamInput::Closed })),
+                ticks,
+            ));
+            while let Some(input) = inputs.next().await {
+                let sent = match input {
+                    StreamInput::Event(e) => send_event(&mut sender, &e).await,
+                    // Lines starting with a colon are comments, which
+                    // clients ignore.
+                    StreamInput::KeepAlive => sender
+                        .send_data(hyper::body::Bytes::from_static(b":keep-alive\n\n"))
+                        .await
+                        .is_ok(),
+                    StreamInput::Closed => return,
+                };
+                if !sent {
+                    return;
+                }
+            }
+        });
+        let resp = http::response::Builder::default()
+            .status(http::StatusCode::OK)
+            .header(http::header::CONTENT_TYPE, "text/event-stream")
+            .header(http::header::CACHE_CONTROL, "no-cache")
+            .body(body)
+            .unwrap();
+        return (state, resp);
+    }
+
+    /// Finish the server's background work before the process exits, e.g.,
+    /// send readings that haven't been pushed yet.
+    pub fn shutdown(&self) {
+        self.manager.shutdown();
+    }
+
     pub fn routes(&self) -> gotham::router::Router {
         let srv: Server<M> = self.clone();
         let srv_middleware = StateMiddleware::new(srv);
@@ -421,10 +2164,57 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
         return gotham::router::builder::build_router(chain, pipelines, |route| {
             route.get("/metrics").to(Self::render_metrics);
             route.get("/co2").to(Self::render_co2);
+            route.get("/history").to(Self::render_history);
+            route.get("/export.csv").to(Self::render_export_csv);
+            route.get("/export.jsonl").to(Self::render_export_jsonl);
             route.get("/isready").to(Self::render_is_ready);
-            route.put("/calibrate").to(Self::render_put_calibrate);
+            route.put("/calibrate").to_async(Self::render_put_calibrate);
+            route.get("/calibration").to(Self::render_calibration);
+            route
+                .post("/calibration")
+                .to_async(Self::render_post_calibration);
+            route
+                .delete("/calibration")
+                .to(Self::render_delete_calibration);
             route.get("/elevation").to(Self::render_elevation);
             route.put("/elevation").to_async(Self::render_put_elevation);
+            route.get("/selftest").to(Self::render_self_test);
+            route.post("/selftest").to(Self::render_post_self_test);
+            route.get("/abc").to(Self::render_abc);
+            route.put("/abc").to_async(Self::render_put_abc);
+
+            route.scope(api::PREFIX, |route| {
+                route.get("/co2").to(Self::render_v1_co2);
+                route.get("/history").to(Self::render_v1_history);
+                route.get("/ready").to(Self::render_v1_ready);
+                route.get("/stream").to(Self::render_v1_stream);
+                route.get("/control").to(Self::render_v1_control);
+                route.get("/elevation").to(Self::render_v1_elevation);
+                route
+                    .put("/elevation")
+                    .to_async(Self::render_v1_put_elevation);
+                route.get("/abc").to(Self::render_v1_abc);
+                route.put("/abc").to_async(Self::render_v1_put_abc);
+                route.get("/selftest").to(Self::render_v1_self_test);
+                route.post("/selftest").to(Self::render_v1_post_self_test);
+                route.get("/calibration").to(Self::render_v1_calibration);
+                route
+                    .post("/calibration")
+                    .to_async(Self::render_v1_post_calibration);
+                route
+                    .delete("/calibration")
+                    .to(Self::render_v1_delete_calibration);
+                // Anything else under the prefix is still answered in the
+                // API's error envelope, rather than by gotham.
+                for path in V1_PATHS.iter() {
+                    route
+                        .request(any_method(), path)
+                        .to(Self::render_v1_method_not_allowed);
+                }
+                route
+                    .request(any_method(), "/*")
+                    .to(Self::render_v1_not_found);
+            });
 
             if !self.static_dir.is_empty() {
                 route.get("/*").to_dir(self.static_dir.clone());
@@ -436,15 +2226,26 @@ impl<M: Manager + Clone + Send + Sync + 'static + RefUnwindSafe> Server<M> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::TempDir;
+    use gotham::test::Server as _;
     use gotham::test::TestServer;
+    use std::convert::TryFrom;
 
     #[derive(Default)]
     struct _FakeDeviceData {
         co2: Option<wire::Concentration>,
+        // Read before `co2`, oldest first.
+        co2_readings: std::collections::VecDeque<wire::Concentration>,
         reference: Option<wire::Concentration>,
         elevation: Option<wire::Distance>,
         calibrate_called_signal: Option<sync::mpsc::Sender<()>>,
         calibrate_wait_signal: Option<sync::mpsc::Receiver<()>>,
+        calibrate_error: Option<fn() -> device::Error>,
+        reconnects: u64,
+        self_test_result: Option<wire::Payload>,
+        abc: Option<ABCSetting>,
+        elevation_error: Option<fn() -> device::Error>,
+        identity: Option<device::Identity>,
     }
 
     #[derive(Clone)]
@@ -454,31 +2255,78 @@ mod tests {
 
     impl Device for FakeDevice {
         fn read_co2(&mut self) -> Result<wire::Concentration> {
-            let data = self.data.lock().unwrap();
+            let mut data = self.data.lock().unwrap();
+            if let Some(c) = data.co2_readings.pop_front() {
+                return Ok(c);
+            }
             return match data.co2 {
                 Some(c) => Ok(c),
                 None => Err(Error::from("no concentration set on fake")),
             };
         }
 
-        fn calibrate_co2<T: Fn(time::Duration)>(
+        fn calibrate_co2<T, P>(
             &mut self,
             reference: wire::Concentration,
             _sleep_fn: T,
-        ) -> Result<()> {
+            limit: &device::Limit,
+            progress: P,
+        ) -> Result<()>
+        where
+            T: Fn(time::Duration),
+            P: Fn(device::CalibrationStage),
+        {
             let mut data = self.data.lock().unwrap();
+            data.reference = Option::from(reference);
+            progress(device::CalibrationStage::VerifyingReference);
+            progress(device::CalibrationStage::Calibrating);
             if let Some(chan) = &data.calibrate_called_signal {
                 chan.send(()).unwrap();
             }
-            data.reference = Option::from(reference);
             if let Some(chan) = &data.calibrate_wait_signal {
-                chan.recv_timeout(time::Duration::from_secs(30)).unwrap();
+                // Wait to be told to finish, or to be cancelled.
+                let deadline = time::Instant::now() + time::Duration::from_secs(30);
+                loop {
+                    match chan.recv_timeout(time::Duration::from_millis(10)) {
+                        Ok(()) => break,
+                        Err(sync::mpsc::RecvTimeoutError::Timeout) => {
+                            limit.check_cancelled()?;
+                            assert!(
+                                time::Instant::now() < deadline,
+                                "calibration never finished"
+                            );
+                        }
+                        Err(e) => panic!("{}", e),
+                    }
+                }
+            }
+            if let Some(e) = data.calibrate_error {
+                return Err(Error::from(e()));
             }
             return Ok(());
         }
 
+        fn read_status(&mut self) -> Result<wire::response::Status> {
+            return Ok(wire::response::StatusFlags::default().into());
+        }
+
+        fn read_identity(&mut self) -> Result<device::Identity> {
+            let data = self.data.lock().unwrap();
+            return data
+                .identity
+                .clone()
+                .ok_or_else(|| Error::from("no identity set on fake"));
+        }
+
+        fn reconnects(&self) -> u64 {
+            return self.data.lock().unwrap().reconnects;
+        }
+
         fn read_elevation(&mut self) -> Result<wire::Distance> {
             let data = self.data.lock().unwrap();
+            if let Some(e) = data.elevation_error {
+                return Err(Error::from(e()));
+            }
             return match data.elevation {
                 Some(d) => Ok(d),
                 None => Err(Error::from("no elevation set on fake")),
@@ -490,6 +2338,42 @@ mod tests {
             data.elevation = Option::from(to);
             return Ok(());
         }
+
+        fn run_self_test<T: Fn(time::Duration)>(
+            &mut self,
+            _sleep_fn: T,
+        ) -> Result<wire::response::SelfTest> {
+            let data = self.data.lock().unwrap();
+            return match &data.self_test_result {
+                Some(p) => wire::response::SelfTest::try_from(p.clone())
+                    .map_err(|e| Error::from(e.to_string())),
+                None => Err(Error::from("no self-test result set on fake")),
+            };
+        }
+
+        fn read_abc(&mut self) -> Result<wire::response::ABCState> {
+            let data = self.data.lock().unwrap();
+            return match data.abc {
+                Some(ABCSetting::On) => Ok(wire::response::ABCState::On),
+                Some(ABCSetting::Off) => Ok(wire::response::ABCState::Off),
+                _ => Err(Error::from("no ABC state set on fake")),
+            };
+        }
+
+        fn set_abc(&mut self, to: wire::Toggle) -> Result<()> {
+            let mut data = self.data.lock().unwrap();
+            data.abc = Some(match to {
+                wire::Toggle::On => ABCSetting::On,
+                wire::Toggle::Off => ABCSetting::Off,
+            });
+            return Ok(());
+        }
+
+        fn reset_abc(&mut self) -> Result<()> {
+            let mut data = self.data.lock().unwrap();
+            data.abc = Some(ABCSetting::Reset);
+            return Ok(());
+        }
     }
 
     impl FakeDevice {
@@ -498,6 +2382,13 @@ mod tests {
             data.co2 = Option::from(to);
         }
 
+        // Reconnect to the fake as a sensor with identity `id`.
+        fn reconnect_as(&self, id: device::Identity) {
+            let mut data = self.data.lock().unwrap();
+            data.identity = Some(id);
+            data.reconnects += 1;
+        }
+
         fn reference(&self) -> Option<wire::Concentration> {
             let data = self.data.lock().unwrap();
             return data.reference;
@@ -507,6 +2398,11 @@ mod tests {
             let data = self.data.lock().unwrap();
             return data.elevation;
         }
+
+        fn abc(&self) -> Option<ABCSetting> {
+            let data = self.data.lock().unwrap();
+            return data.abc;
+        }
     }
 
     #[derive(Default)]
@@ -520,6 +2416,11 @@ mod tests {
             return self;
         }
 
+        fn with_co2_readings(mut self, ppms: &[u16]) -> Self {
+            self.data.co2_readings = ppms.iter().map(|p| wire::Concentration::PPM(*p)).collect();
+            return self;
+        }
+
         fn with_elevation(mut self, d: wire::Distance) -> Self {
             self.data.elevation = Option::from(d);
             return self;
@@ -535,6 +2436,31 @@ mod tests {
             return self;
         }
 
+        fn with_calibrate_error(mut self, e: fn() -> device::Error) -> Self {
+            self.data.calibrate_error = Some(e);
+            return self;
+        }
+
+        fn with_abc(mut self, a: ABCSetting) -> Self {
+            self.data.abc = Option::from(a);
+            return self;
+        }
+
+        fn with_elevation_error(mut self, e: fn() -> device::Error) -> Self {
+            self.data.elevation_error = Some(e);
+            return self;
+        }
+
+        fn with_identity(mut self, id: device::Identity) -> Self {
+            self.data.identity = Some(id);
+            return self;
+        }
+
+        fn with_self_test_result(mut self, p: wire::Payload) -> Self {
+            self.data.self_test_result = Option::from(p);
+            return self;
+        }
+
         fn build(self) -> FakeDevice {
             return FakeDevice {
                 data: sync::Mutex::new(self.data).into(),
@@ -543,31 +2469,86 @@ mod tests {
     }
 
     #[test]
-    fn test_manager_double_read() {
+    fn test_manager_sample() {
         let fake = FakeBuilder::default()
             .with_co2(wire::Concentration::PPM(200))
             .build();
-        let clock = governor::clock::FakeRelativeClock::default();
-        let mgr = DeviceManager::new_with_clock(fake.clone(), &clock);
+        let mgr = DeviceManager::new(fake.clone());
 
-        assert_eq!(mgr.measure().unwrap(), wire::Concentration::PPM(200));
+        // Nothing has been sampled yet.
+        assert!(mgr.measure().is_err());
 
-        // Now we update the fake's CO2 concentration, but don't move forward
-        // time. The manager should rate-limit the request, and we should see
-        // stale data.
+        mgr.sample();
+        let first = mgr.measure().unwrap();
+        assert_eq!(first.concentration, wire::Concentration::PPM(200));
 
+        // Now we update the fake's CO2 concentration, but don't take another
+        // sample. The manager should keep returning the old measurement.
         fake.set_co2(wire::Concentration::PPM(55));
-        assert_eq!(mgr.measure().unwrap(), wire::Concentration::PPM(200));
+        assert_eq!(mgr.measure().unwrap(), first);
 
-        // Advance *just* past the max measure rate, so we can trigger another
-        // measurement. Then we should see the updated value.
-        clock.advance(MAX_MEASURE_RATE + time::Duration::from_secs(1));
-        assert_eq!(mgr.measure().unwrap(), wire::Concentration::PPM(55));
+        // Once sampled, we should see the updated value.
+        mgr.sample();
+        let second = mgr.measure().unwrap();
+        assert_eq!(second.concentration, wire::Concentration::PPM(55));
+        assert!(second.at >= first.at);
+    }
+
+    #[test]
+    fn test_manager_sample_while_busy() {
+        let (started_in, started_out) = sync::mpsc::channel();
+        let (wait_in, wait_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(200))
+            .with_calibrate_called_signal(started_in)
+            .with_calibrate_wait_signal(wait_out)
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        mgr.sample();
+
+        mgr.calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
+        started_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap();
 
-        // Just for good measure, change the concentration back, and
-        // make sure we see latch the updated concentration.
-        fake.set_co2(wire::Concentration::PPM(200));
-        assert_eq!(mgr.measure().unwrap(), wire::Concentration::PPM(55));
+        // Sampling is skipped while the device is calibrating, but the last
+        // measurement is still available.
+        mgr.sample();
+        assert_eq!(
+            mgr.measure().unwrap().concentration,
+            wire::Concentration::PPM(200)
+        );
+        wait_in.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_manager_background_sampling() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(200))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        mgr.start_sampling(&Sampling {
+            interval: time::Duration::from_millis(10),
+            ..Sampling::default()
+        })
+        .unwrap();
+        // The first sample is taken immediately.
+        assert_eq!(
+            mgr.measure().unwrap().concentration,
+            wire::Concentration::PPM(200)
+        );
+
+        fake.set_co2(wire::Concentration::PPM(55));
+        let mut got = None;
+        for _ in 0..50 {
+            got = mgr.measure().ok().map(|r| r.concentration);
+            if got == Some(wire::Concentration::PPM(55)) {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        assert_eq!(got, Some(wire::Concentration::PPM(55)));
     }
 
     fn read_json<T: serde::de::DeserializeOwned>(r: gotham::test::TestResponse) -> Result<T> {
@@ -581,6 +2562,94 @@ mod tests {
         };
     }
 
+    #[test]
+    fn test_manager_events() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let mut events = mgr.subscribe();
+
+        // The first measurement has a status to report, the next one's is
+        // unchanged.
+        mgr.sample();
+        fake.set_co2(wire::Concentration::PPM(410));
+        mgr.sample();
+        let got: Vec<(&str, Option<u16>)> = std::iter::from_fn(|| events.try_next())
+            .map(|e| {
+                let ppm = match &e {
+                    api::Event::Reading(r) => Some(r.ppm),
+                    _ => None,
+                };
+                return (e.name(), ppm);
+            })
+            .collect();
+        assert_eq!(
+            got,
+            vec![
+                ("reading", Some(400)),
+                ("status", None),
+                ("reading", Some(410))
+            ]
+        );
+
+        // Every step of a calibration is published, up to its result.
+        mgr.calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
+        let mut states = Vec::new();
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while states.last() != Some(&calibration::State::Succeeded) {
+            assert!(time::Instant::now() < deadline, "got {:?}", states);
+            match events.try_next() {
+                Some(api::Event::Calibration(job)) => states.push(job.state),
+                Some(e) => panic!("unexpected event {:?}", e),
+                None => thread::sleep(time::Duration::from_millis(10)),
+            }
+        }
+        assert_eq!(
+            states,
+            vec![
+                calibration::State::Requested,
+                calibration::State::VerifyingReference,
+                calibration::State::Calibrating,
+                calibration::State::Succeeded,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_manager_exporters() {
+        let collector = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(612))
+            .build();
+        let mgr = DeviceManager::new(fake);
+        let mut sampling = Sampling::default();
+        sampling.interval = time::Duration::from_secs(3600);
+        sampling.exporters.push(push::Target {
+            name: String::from("influx"),
+            collector: push::Collector::InfluxUdp {
+                address: collector.local_addr().unwrap().to_string(),
+                line: push::LineProtocol::default(),
+            },
+            options: push::Options {
+                batch_size: 2,
+                ..push::Options::default()
+            },
+        });
+        // The first measurement is taken on start, and pushed on shutdown,
+        // rather than waiting for the batch to fill up.
+        mgr.start(&sampling).unwrap();
+        mgr.shutdown();
+        let mut buf = [0u8; 1500];
+        let n = collector.recv(&mut buf).unwrap();
+        let line = String::from_utf8_lossy(&buf[..n]).to_string();
+        assert!(line.starts_with("co2 ppm=612i,"), "got {:?}", line);
+    }
+
     #[test]
     fn test_metrics() {
         // Arrange.
@@ -601,6 +2670,85 @@ mod tests {
         assert_eq!(reply.status(), 200);
         let body = reply.read_utf8_body().unwrap();
         assert!(body.contains("co2_ppm 100"));
+        assert!(body.contains("co2_status{flag=\"warmup\"} 0"));
+        assert!(!body.contains("co2_serial_connected"));
+    }
+
+    #[test]
+    fn test_metrics_device_state() {
+        let fake = FakeBuilder::default()
+            .with_elevation(wire::Distance::Feet(1500))
+            .with_abc(ABCSetting::On)
+            .with_identity(device::Identity {
+                serial_number: String::from("1234"),
+                compile_subvol: String::from("A01"),
+                compile_date: chrono::NaiveDate::from_ymd(2019, 3, 5),
+            })
+            .build();
+        let mut builder = Builder::default();
+        builder.device(fake.clone());
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let scrape = || {
+            let reply = test_server
+                .client()
+                .get("http://localhost/metrics")
+                .perform()
+                .unwrap();
+            assert_eq!(reply.status(), 200);
+            return reply.read_utf8_body().unwrap();
+        };
+
+        // The sensor hasn't been measured, but everything else is known.
+        let body = scrape();
+        assert!(body.contains("co2_ppm NaN"));
+        assert!(body.contains("co2_abc_enabled 1"));
+        assert!(body.contains("serial_number=\"1234\""));
+
+        // Configuring the sensor shows up without another read.
+        fake.set_co2(wire::Concentration::PPM(500));
+        srv.manager.sample();
+        srv.manager.configure_abc(ABCSetting::Off).unwrap();
+        let body = scrape();
+        assert!(body.contains("co2_ppm 500"));
+        assert!(body.contains("co2_elevation_feet 1500"));
+        assert!(body.contains("co2_abc_enabled 0"));
+        assert!(body.contains("co2_last_calibration_success NaN"));
+
+        // A different sensor shows up once reconnected and sampled.
+        fake.reconnect_as(device::Identity {
+            serial_number: String::from("5678"),
+            compile_subvol: String::from("A01"),
+            compile_date: chrono::NaiveDate::from_ymd(2019, 3, 5),
+        });
+        srv.manager.sample();
+        let body = scrape();
+        assert!(body.contains("serial_number=\"5678\""));
+        assert!(!body.contains("serial_number=\"1234\""));
+    }
+
+    #[test]
+    fn test_metrics_connection() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(100))
+            .build();
+        let mut builder = Builder::default();
+        builder
+            .connection(reconnect::Connection::default())
+            .device(fake);
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let reply = test_server
+            .client()
+            .get("http://localhost/metrics")
+            .perform()
+            .unwrap();
+
+        assert_eq!(reply.status(), 200);
+        let body = reply.read_utf8_body().unwrap();
+        assert!(body.contains("co2_serial_connected 0"));
+        assert!(body.contains("co2_serial_reconnects_total 0"));
     }
 
     #[test]
@@ -635,6 +2783,241 @@ mod tests {
         assert_eq!(fake.reference(), Some(AMBIENT_CONCENTRATION));
     }
 
+    #[test]
+    fn test_calibration_configured_reference() {
+        let (called_in, called_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_calibrate_called_signal(called_in)
+            .build();
+        let mut builder = Builder::default();
+        builder
+            .device(fake.clone())
+            .calibration_reference(wire::Concentration::PPM(425));
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let reply = test_server
+            .client()
+            .put("http://localhost/calibrate", "", mime::APPLICATION_JSON)
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        assert!(called_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .is_ok());
+        assert_eq!(fake.reference(), Some(wire::Concentration::PPM(425)));
+    }
+
+    #[test]
+    fn test_calibration_job() {
+        let (started_in, started_out) = sync::mpsc::channel();
+        let (wait_in, wait_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_calibrate_called_signal(started_in)
+            .with_calibrate_wait_signal(wait_out)
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let srv = Server::new(mgr.clone(), "");
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let post_calibration = || {
+            return test_server
+                .client()
+                .post("http://localhost/calibration", "", mime::APPLICATION_JSON)
+                .perform()
+                .unwrap();
+        };
+        let get_calibration = || -> calibration::Summary {
+            let reply = test_server
+                .client()
+                .get("http://localhost/calibration")
+                .perform()
+                .unwrap();
+            assert_eq!(reply.status(), http::StatusCode::OK);
+            return serde_json::from_slice(&reply.read_body().unwrap()).unwrap();
+        };
+
+        let reply = post_calibration();
+        assert_eq!(reply.status(), http::StatusCode::ACCEPTED);
+        let job: calibration::Job = serde_json::from_slice(&reply.read_body().unwrap()).unwrap();
+        assert_eq!(job.reference_ppm, AMBIENT_CONCENTRATION.ppm());
+        started_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap();
+
+        // Only one calibration runs at a time.
+        assert_eq!(post_calibration().status(), http::StatusCode::CONFLICT);
+        let current = get_calibration().current.unwrap();
+        assert_eq!(current.id, job.id);
+        assert_eq!(current.state, calibration::State::Calibrating);
+        assert!(current.started_at.is_some());
+
+        wait_in.send(()).unwrap();
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        let mut summary = get_calibration();
+        while summary.current.is_some() && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(10));
+            summary = get_calibration();
+        }
+        assert_eq!(summary.current, None);
+        assert_eq!(summary.history.len(), 1);
+        assert_eq!(summary.history[0].id, job.id);
+        assert_eq!(summary.history[0].state, calibration::State::Succeeded);
+    }
+
+    #[test]
+    fn test_calibration_request_body() {
+        let (called_in, called_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_calibrate_called_signal(called_in)
+            .build();
+        let mut builder = Builder::default();
+        builder.device(fake.clone());
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let put_calibrate = |body: &'static str| {
+            return test_server
+                .client()
+                .put("http://localhost/calibrate", body, mime::APPLICATION_JSON)
+                .perform()
+                .unwrap()
+                .status();
+        };
+
+        // Out of bounds, or malformed.
+        assert_eq!(
+            put_calibrate(r#"{"reference_ppm": 41}"#),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(put_calibrate("2000"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(
+            put_calibrate(r#"{"reference": 2000}"#),
+            http::StatusCode::BAD_REQUEST
+        );
+        assert_eq!(fake.reference(), None);
+
+        // Bottled reference gas.
+        assert_eq!(
+            put_calibrate(r#"{"reference_ppm": 2000}"#),
+            http::StatusCode::OK
+        );
+        called_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(fake.reference(), Some(wire::Concentration::PPM(2000)));
+    }
+
+    // Waits for the calibration in progress on `mgr` to finish, and returns
+    // its result.
+    fn wait_calibrated<M: Manager>(mgr: &M) -> calibration::Job {
+        let deadline = time::Instant::now() + time::Duration::from_secs(5);
+        while mgr.calibration().current.is_some() && time::Instant::now() < deadline {
+            thread::sleep(time::Duration::from_millis(10));
+        }
+        let summary = mgr.calibration();
+        assert_eq!(summary.current, None);
+        return summary.history[0].clone();
+    }
+
+    #[test]
+    fn test_calibration_stability() {
+        let stability = calibration::Stability {
+            samples: 3,
+            interval: time::Duration::from_millis(1),
+            tolerance_ppm: 10,
+        };
+
+        // Readings still falling after being carried outdoors.
+        let fake = FakeBuilder::default()
+            .with_co2_readings(&[900, 600, 450])
+            .with_co2(wire::Concentration::PPM(430))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        mgr.calibrate(
+            AMBIENT_CONCENTRATION,
+            calibration::Options {
+                stability: Some(stability),
+                ..calibration::Options::default()
+            },
+        )
+        .unwrap();
+        let job = wait_calibrated(&mgr);
+        assert_eq!(job.state, calibration::State::Failed);
+        assert!(job.reason.unwrap().contains("still moving"));
+        assert_eq!(fake.reference(), None);
+
+        // Once they've settled, calibration goes ahead.
+        mgr.calibrate(
+            AMBIENT_CONCENTRATION,
+            calibration::Options {
+                stability: Some(stability),
+                ..calibration::Options::default()
+            },
+        )
+        .unwrap();
+        let job = wait_calibrated(&mgr);
+        assert_eq!(job.state, calibration::State::Succeeded);
+        assert_eq!(fake.reference(), Some(AMBIENT_CONCENTRATION));
+    }
+
+    #[test]
+    fn test_cancel_calibration() {
+        let (started_in, started_out) = sync::mpsc::channel();
+        let (_wait_in, wait_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_calibrate_called_signal(started_in)
+            .with_calibrate_wait_signal(wait_out)
+            .build();
+        let mgr = DeviceManager::new(fake);
+        let srv = Server::new(mgr.clone(), "");
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let delete_calibration = || {
+            return test_server
+                .client()
+                .delete("http://localhost/calibration")
+                .perform()
+                .unwrap();
+        };
+
+        // Nothing to cancel.
+        assert_eq!(delete_calibration().status(), http::StatusCode::NOT_FOUND);
+
+        // A calibration that never finishes on its own.
+        let job = mgr
+            .calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
+        started_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap();
+        let reply = delete_calibration();
+        assert_eq!(reply.status(), http::StatusCode::ACCEPTED);
+        let cancelled: calibration::Job =
+            serde_json::from_slice(&reply.read_body().unwrap()).unwrap();
+        assert_eq!(cancelled.id, job.id);
+
+        let finished = wait_calibrated(&mgr);
+        assert_eq!(finished.id, job.id);
+        assert_eq!(finished.state, calibration::State::Cancelled);
+        // The device is free again.
+        assert!(mgr.is_ready());
+    }
+
+    #[test]
+    fn test_calibration_failure() {
+        let fake = FakeBuilder::default()
+            .with_calibrate_error(|| device::Error::Closed)
+            .build();
+        let mgr = DeviceManager::new(fake);
+        mgr.calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
+
+        let job = wait_calibrated(&mgr);
+        assert_eq!(job.state, calibration::State::Failed);
+        assert_eq!(
+            job.reason,
+            Some(Error::Device(device::Error::Closed).to_string())
+        );
+    }
+
     // TODO(jkz): This is a mediocre test. It should fail when if `wait_in.send`
     // is never called. Currently, if the calibration thread panics, it's not
     // visibile to this test.
@@ -667,7 +3050,8 @@ mod tests {
         assert!(is_ready());
 
         // Start a calibration, plus make sure the calibration thread is going.
-        mgr.calibrate();
+        mgr.calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
         started_out
             .recv_timeout(time::Duration::from_secs(5))
             .unwrap();
@@ -745,4 +3129,979 @@ mod tests {
         assert_eq!(reply.status(), 200);
         assert_eq!(fake.elevation(), Some(wire::Distance::Feet(500)));
     }
+
+    #[test]
+    fn test_error_statuses() {
+        let get_elevation = |fake: FakeDevice| {
+            let srv = Server::new(DeviceManager::new(fake), "");
+            let test_server = TestServer::new(srv.routes()).unwrap();
+            return test_server
+                .client()
+                .get("http://localhost/elevation")
+                .perform()
+                .unwrap()
+                .status();
+        };
+        // The device not answering is an outage...
+        let fake = FakeBuilder::default()
+            .with_elevation_error(|| {
+                device::Error::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"))
+            })
+            .build();
+        assert_eq!(get_elevation(fake), http::StatusCode::SERVICE_UNAVAILABLE);
+        // ...while a garbled reply points at a flaky sensor.
+        let fake = FakeBuilder::default()
+            .with_elevation_error(|| {
+                device::Error::from(wire::ParseError::Length {
+                    expected: 2,
+                    got: 1,
+                })
+            })
+            .build();
+        assert_eq!(get_elevation(fake), http::StatusCode::BAD_GATEWAY);
+        // A device stuck in some state gave up waiting.
+        let fake = FakeBuilder::default()
+            .with_elevation_error(|| {
+                device::Error::DeadlineExceeded(wire::response::StatusFlags::default().into())
+            })
+            .build();
+        assert_eq!(get_elevation(fake), http::StatusCode::GATEWAY_TIMEOUT);
+
+        let (started_in, started_out) = sync::mpsc::channel();
+        let (wait_in, wait_out) = sync::mpsc::channel();
+        let fake = FakeBuilder::default()
+            .with_calibrate_called_signal(started_in)
+            .with_calibrate_wait_signal(wait_out)
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let srv = Server::new(mgr.clone(), "");
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let put_elevation = |body: &'static str| {
+            return test_server
+                .client()
+                .put("http://localhost/elevation", body, mime::APPLICATION_JSON)
+                .perform()
+                .unwrap()
+                .status();
+        };
+
+        // Nothing has been measured yet.
+        let reply = test_server
+            .client()
+            .get("http://localhost/co2")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+
+        // Client mistakes.
+        assert_eq!(put_elevation("high"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(put_elevation("40000"), http::StatusCode::BAD_REQUEST);
+        assert_eq!(fake.elevation(), None);
+
+        // The device can't be configured while it's calibrating.
+        mgr.calibrate(AMBIENT_CONCENTRATION, calibration::Options::default())
+            .unwrap();
+        started_out
+            .recv_timeout(time::Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(put_elevation("500"), http::StatusCode::CONFLICT);
+        wait_in.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_api_v1() {
+        let fake = FakeBuilder::default()
+            .with_elevation(wire::Distance::Feet(1500))
+            .with_abc(ABCSetting::On)
+            .with_identity(device::Identity {
+                serial_number: String::from("12345"),
+                compile_subvol: String::from("A01"),
+                compile_date: chrono::NaiveDate::from_ymd(2020, 1, 2),
+            })
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let mut builder = Builder::default();
+        builder.manager(mgr.clone());
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let get = |path: &str| {
+            return test_server
+                .client()
+                .get(format!("http://localhost{}{}", api::PREFIX, path))
+                .perform()
+                .unwrap();
+        };
+        let put = |path: &str, body: &'static str| {
+            return test_server
+                .client()
+                .put(
+                    format!("http://localhost{}{}", api::PREFIX, path),
+                    body,
+                    mime::APPLICATION_JSON,
+                )
+                .perform()
+                .unwrap();
+        };
+
+        // Nothing has been measured yet, since the fake had no co2 on start.
+        let reply = get("/co2");
+        assert_eq!(reply.status(), http::StatusCode::SERVICE_UNAVAILABLE);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.status, 503);
+        assert_eq!(body.error.code, "not_ready");
+
+        fake.set_co2(wire::Concentration::PPM(612));
+        mgr.sample();
+        let reply = get("/co2");
+        assert_eq!(reply.status(), 200);
+        let r: api::Reading = read_json(reply).unwrap();
+        assert_eq!(r.ppm, 612);
+        assert_eq!(r.source.serial_number, Some(String::from("12345")));
+        assert!(r.age_seconds >= 0.0);
+        assert_eq!(r.status.map(|s| s.error), Some(false));
+
+        let reply = get("/elevation");
+        assert_eq!(reply.status(), 200);
+        let e: api::Elevation = read_json(reply).unwrap();
+        assert_eq!(e, api::Elevation::feet(wire::Distance::Feet(1500)));
+
+        // Elevation may be configured in meters, and is served back in feet.
+        let reply = put("/elevation", r#"{"value": 500, "unit": "m"}"#);
+        assert_eq!(reply.status(), 200);
+        let e: api::Elevation = read_json(reply).unwrap();
+        assert_eq!(e, api::Elevation::feet(wire::Distance::Feet(1640)));
+        assert_eq!(fake.elevation(), Some(wire::Distance::Feet(1640)));
+
+        // A bare number is what the old route takes, but not this one.
+        let reply = put("/elevation", "500");
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "bad_request");
+        let reply = put("/elevation", r#"{"value": 40000, "unit": "ft"}"#);
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+        assert_eq!(fake.elevation(), Some(wire::Distance::Feet(1640)));
+
+        let reply = put("/abc", r#"{"state": "off"}"#);
+        assert_eq!(reply.status(), 200);
+        let a: api::Abc = read_json(reply).unwrap();
+        assert_eq!(a.state, ABCSetting::Off);
+        assert_eq!(fake.abc(), Some(ABCSetting::Off));
+
+        let reply = get("/ready");
+        let ready: api::Ready = read_json(reply).unwrap();
+        assert!(ready.ready);
+
+        let reply = get("/selftest");
+        let report: api::SelfTest = read_json(reply).unwrap();
+        assert_eq!(report.last, None);
+
+        let reply = test_server
+            .client()
+            .delete(format!("http://localhost{}/calibration", api::PREFIX))
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::NOT_FOUND);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "not_found");
+
+        // Unknown routes and malformed queries are answered in the API's
+        // error envelope too.
+        let reply = get("/nope");
+        assert_eq!(reply.status(), http::StatusCode::NOT_FOUND);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "not_found");
+        let reply = put("/co2", "612");
+        assert_eq!(reply.status(), http::StatusCode::METHOD_NOT_ALLOWED);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "method_not_allowed");
+        let reply = get("/history?since=yesterday");
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "bad_request");
+        let reply = get("/history?step=-1");
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+
+        // The old routes still serve bare values.
+        let reply = test_server
+            .client()
+            .get("http://localhost/co2")
+            .perform()
+            .unwrap();
+        let ppm: u16 = read_json(reply).unwrap();
+        assert_eq!(ppm, 612);
+    }
+
+    #[test]
+    fn test_stream() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let mut builder = Builder::default();
+        builder.manager(mgr.clone());
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        // Let the first measurement age a little before it's replayed.
+        thread::sleep(time::Duration::from_millis(50));
+
+        let mut reply = test_server
+            .client()
+            .get("http://localhost/api/v1/stream")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        assert_eq!(
+            reply.headers()[http::header::CONTENT_TYPE],
+            "text/event-stream"
+        );
+        let mut body = std::mem::take(reply.body_mut());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut buf = String::new();
+        // Read the stream until it has `n` events in it.
+        let mut read_events = |n: usize| {
+            while buf.matches("\n\n").count() < n {
+                let chunk = runtime
+                    .block_on(async {
+                        let next = hyper::body::HttpBody::data(&mut body);
+                        return tokio::time::timeout(time::Duration::from_secs(5), next).await;
+                    })
+                    .expect("timed out waiting for an event")
+                    .unwrap()
+                    .unwrap();
+                buf.push_str(std::str::from_utf8(&chunk).unwrap());
+            }
+            return buf.clone();
+        };
+
+        // The latest measurement is sent right away...
+        let got = read_events(1);
+        assert!(
+            got.starts_with("event: reading\ndata: {\"ppm\":400,"),
+            "{}",
+            got
+        );
+        let data = got
+            .trim_end()
+            .strip_prefix("event: reading\ndata: ")
+            .unwrap();
+        let r: api::Reading = serde_json::from_str(data).unwrap();
+        assert!(r.age_seconds > 0.0, "{}", got);
+
+        // ...followed by every new one, as it's taken.
+        fake.set_co2(wire::Concentration::PPM(410));
+        mgr.sample();
+        let got = read_events(2);
+        let second = got.split("\n\n").nth(1).unwrap();
+        assert!(
+            second.starts_with("event: reading\ndata: {\"ppm\":410,"),
+            "{}",
+            got
+        );
+    }
+
+    #[test]
+    fn test_stream_keep_alive() {
+        // Nothing has been measured, so there's no event to start with.
+        let mgr = DeviceManager::new(FakeBuilder::default().build());
+        let mut builder = Builder::default();
+        builder.manager(mgr);
+        let mut srv = builder.build().unwrap();
+        srv.stream_keep_alive = time::Duration::from_millis(50);
+        let test_server = TestServer::new(srv.routes()).unwrap();
+
+        let mut reply = test_server
+            .client()
+            .get("http://localhost/api/v1/stream")
+            .perform()
+            .unwrap();
+        let mut body = std::mem::take(reply.body_mut());
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        for _ in 0..2 {
+            let chunk = runtime
+                .block_on(async {
+                    let next = hyper::body::HttpBody::data(&mut body);
+                    return tokio::time::timeout(time::Duration::from_secs(5), next).await;
+                })
+                .expect("timed out waiting for a keep-alive")
+                .unwrap()
+                .unwrap();
+            assert_eq!(chunk, ":keep-alive\n\n");
+        }
+    }
+
+    // Open a session on the control channel of `test_server`.
+    fn connect_control(test_server: &TestServer) -> WebSocketStream<hyper::upgrade::Upgraded> {
+        let mut reply = test_server
+            .client()
+            .get("http://localhost/api/v1/control")
+            .with_header(http::header::UPGRADE, "websocket".parse().unwrap())
+            .with_header(http::header::CONNECTION, "upgrade".parse().unwrap())
+            .with_header(
+                http::header::SEC_WEBSOCKET_KEY,
+                "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+            )
+            .with_header(http::header::SEC_WEBSOCKET_VERSION, "13".parse().unwrap())
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(
+            reply.headers()[http::header::SEC_WEBSOCKET_ACCEPT],
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+        let upgraded = test_server
+            .run_future(hyper::upgrade::on(&mut *reply))
+            .unwrap();
+        return test_server.run_future(WebSocketStream::from_raw_socket(
+            upgraded,
+            ws::protocol::Role::Client,
+            None,
+        ));
+    }
+
+    // Send `msg` on `socket`, unless it's empty, and return the next message
+    // from the server.
+    fn control_exchange(
+        test_server: &TestServer,
+        socket: &mut WebSocketStream<hyper::upgrade::Upgraded>,
+        msg: &str,
+    ) -> ws::Message {
+        return test_server.run_future(async {
+            if !msg.is_empty() {
+                socket
+                    .send(ws::Message::Text(String::from(msg)))
+                    .await
+                    .unwrap();
+            }
+            return tokio::time::timeout(time::Duration::from_secs(5), socket.next())
+                .await
+                .expect("timed out waiting for a reply")
+                .unwrap()
+                .unwrap();
+        });
+    }
+
+    // The close code of `msg`, which must be a close frame.
+    fn close_code(msg: ws::Message) -> ws::protocol::frame::coding::CloseCode {
+        return match msg {
+            ws::Message::Close(Some(frame)) => frame.code,
+            m => panic!("expected a close frame, got {:?}", m),
+        };
+    }
+
+    #[test]
+    fn test_control() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .with_elevation(wire::Distance::Feet(1500))
+            .with_abc(ABCSetting::On)
+            .with_self_test_result(wire::Payload(vec![0x0F, 0x01, 12, 12]))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        let mut builder = Builder::default();
+        builder.manager(mgr.clone()).control_token("hunter2");
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+
+        let mut socket = connect_control(&test_server);
+        let mut send = |msg: &str| -> api::Reply {
+            let got = control_exchange(&test_server, &mut socket, msg);
+            return serde_json::from_str(&got.into_text().unwrap()).unwrap();
+        };
+        let error_code = |r: api::Reply| match r {
+            api::Reply::Error { error, .. } => error.code,
+            r => panic!("expected an error, got {:?}", r),
+        };
+
+        // Nothing is done until the client authenticates.
+        let got = send(r#"{"type": "set_abc", "id": 1, "state": "off"}"#);
+        assert_eq!(error_code(got), "unauthorized");
+        assert_eq!(fake.abc(), Some(ABCSetting::On));
+        let got = send(r#"{"type": "authenticate", "token": "hunter2"}"#);
+        assert_eq!(got, api::Reply::Authenticated);
+
+        let got = send(r#"{"type": "set_abc", "id": 2, "state": "off"}"#);
+        assert_eq!(
+            got,
+            api::Reply::Result {
+                id: Some(2),
+                data: serde_json::json!({"state": "off"}),
+            }
+        );
+        assert_eq!(fake.abc(), Some(ABCSetting::Off));
+
+        let got =
+            send(r#"{"type": "set_elevation", "id": 3, "elevation": {"value": 500, "unit": "m"}}"#);
+        assert_eq!(
+            got,
+            api::Reply::Result {
+                id: Some(3),
+                data: serde_json::json!({"value": 1640, "unit": "ft"}),
+            }
+        );
+        let got = send(r#"{"type": "set_elevation", "id": 4, "elevation": 500}"#);
+        assert_eq!(error_code(got), "bad_request");
+        assert_eq!(error_code(send("reboot")), "bad_request");
+
+        // New measurements are pushed as they're taken.
+        fake.set_co2(wire::Concentration::PPM(410));
+        mgr.sample();
+        match send("") {
+            api::Reply::Event {
+                event: api::Event::Reading(r),
+            } => assert_eq!(r.ppm, 410),
+            r => panic!("expected a reading, got {:?}", r),
+        }
+
+        // A self-test is started right away, and its result is pushed once
+        // it's done. Commands run in the background, so the two may arrive
+        // in either order.
+        let mut got = [send(r#"{"type": "self_test", "id": 5}"#), send("")];
+        got.sort_by_key(|r| !matches!(r, api::Reply::Result { .. }));
+        assert_eq!(
+            got[0],
+            api::Reply::Result {
+                id: Some(5),
+                data: serde_json::Value::Null,
+            }
+        );
+        match &got[1] {
+            api::Reply::Event {
+                event: api::Event::SelfTest(r),
+            } => assert!(r.passed),
+            r => panic!("expected a self-test result, got {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_control_refused() {
+        let fake = FakeBuilder::default().with_abc(ABCSetting::On).build();
+        let mut srv = Server::new(DeviceManager::new(fake.clone()), "");
+        srv.control_token = Some(String::from("hunter2"));
+        srv.control_auth_timeout = time::Duration::from_millis(50);
+        let test_server = TestServer::new(srv.routes()).unwrap();
+
+        // A wrong token is refused, and the client is hung up on, so it
+        // can't keep guessing.
+        let mut socket = connect_control(&test_server);
+        let got = control_exchange(
+            &test_server,
+            &mut socket,
+            r#"{"type": "authenticate", "token": "hunter3"}"#,
+        );
+        let reply: api::Reply = serde_json::from_str(&got.into_text().unwrap()).unwrap();
+        assert!(matches!(reply, api::Reply::Error { error, .. } if error.code == "unauthorized"));
+        assert_eq!(
+            close_code(control_exchange(&test_server, &mut socket, "")),
+            ws::protocol::frame::coding::CloseCode::Policy
+        );
+
+        // As is a client that never authenticates.
+        let mut socket = connect_control(&test_server);
+        assert_eq!(
+            close_code(control_exchange(&test_server, &mut socket, "")),
+            ws::protocol::frame::coding::CloseCode::Policy
+        );
+        assert_eq!(fake.abc(), Some(ABCSetting::On));
+    }
+
+    #[test]
+    fn test_control_token_required() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .with_elevation(wire::Distance::Feet(1500))
+            .with_abc(ABCSetting::On)
+            .build();
+        let mut srv = Server::new(DeviceManager::new(fake.clone()), "");
+        srv.control_token = Some(String::from("hunter2"));
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let client = test_server.client();
+        let put = |path: &str, body: &'static str, token: Option<&str>| {
+            let mut req = client.put(
+                format!("http://localhost{}", path),
+                body,
+                mime::APPLICATION_JSON,
+            );
+            if let Some(t) = token {
+                req = req.with_header(
+                    http::header::AUTHORIZATION,
+                    format!("Bearer {}", t).parse().unwrap(),
+                );
+            }
+            return req.perform().unwrap();
+        };
+
+        // Every route that changes the sensor requires the token.
+        let routes: Vec<(&str, &str)> = vec![
+            ("PUT", "/calibrate"),
+            ("POST", "/calibration"),
+            ("DELETE", "/calibration"),
+            ("PUT", "/elevation"),
+            ("POST", "/selftest"),
+            ("PUT", "/abc"),
+            ("POST", "/api/v1/calibration"),
+            ("DELETE", "/api/v1/calibration"),
+            ("PUT", "/api/v1/elevation"),
+            ("PUT", "/api/v1/abc"),
+            ("POST", "/api/v1/selftest"),
+        ];
+        for (method, path) in routes.into_iter() {
+            let url = format!("http://localhost{}", path);
+            let req = match method {
+                "PUT" => client.put(url, "", mime::APPLICATION_JSON),
+                "POST" => client.post(url, "", mime::APPLICATION_JSON),
+                _ => client.delete(url),
+            };
+            let reply = req.perform().unwrap();
+            assert_eq!(
+                reply.status(),
+                http::StatusCode::UNAUTHORIZED,
+                "{} {}",
+                method,
+                path
+            );
+            assert_eq!(reply.headers()[http::header::WWW_AUTHENTICATE], "Bearer");
+        }
+
+        let reply = put("/api/v1/abc", r#"{"state": "off"}"#, Some("hunter3"));
+        assert_eq!(reply.status(), http::StatusCode::UNAUTHORIZED);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "unauthorized");
+        assert_eq!(fake.abc(), Some(ABCSetting::On));
+
+        let reply = put("/api/v1/abc", r#"{"state": "off"}"#, Some("hunter2"));
+        assert_eq!(reply.status(), http::StatusCode::OK);
+        assert_eq!(fake.abc(), Some(ABCSetting::Off));
+        let reply = put("/elevation", "1400", Some("hunter2"));
+        assert_eq!(reply.status(), http::StatusCode::OK);
+
+        // Reading is still open to anyone.
+        let reply = test_server
+            .client()
+            .get("http://localhost/api/v1/abc")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::OK);
+    }
+
+    #[test]
+    fn test_control_handshake() {
+        let fake = FakeBuilder::default().build();
+        let mut builder = Builder::default();
+        builder.device(fake).control_token("hunter2");
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let handshake = |connection: &str, version: &str| {
+            return test_server
+                .client()
+                .get("http://localhost/api/v1/control")
+                .with_header(http::header::UPGRADE, "websocket".parse().unwrap())
+                .with_header(http::header::CONNECTION, connection.parse().unwrap())
+                .with_header(
+                    http::header::SEC_WEBSOCKET_KEY,
+                    "dGhlIHNhbXBsZSBub25jZQ==".parse().unwrap(),
+                )
+                .with_header(
+                    http::header::SEC_WEBSOCKET_VERSION,
+                    version.parse().unwrap(),
+                )
+                .perform()
+                .unwrap();
+        };
+
+        let reply = handshake("close", "13");
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+
+        let reply = handshake("upgrade", "8");
+        assert_eq!(reply.status(), http::StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(reply.headers()[http::header::SEC_WEBSOCKET_VERSION], "13");
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "upgrade_required");
+
+        let reply = handshake("keep-alive, Upgrade", "13");
+        assert_eq!(reply.status(), http::StatusCode::SWITCHING_PROTOCOLS);
+    }
+
+    #[test]
+    fn test_control_disabled() {
+        let fake = FakeBuilder::default().build();
+        let srv = Server::new(DeviceManager::new(fake), "");
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let reply = test_server
+            .client()
+            .get("http://localhost/api/v1/control")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::NOT_FOUND);
+        let body: api::ErrorBody = read_json(reply).unwrap();
+        assert_eq!(body.error.code, "not_found");
+    }
+
+    #[test]
+    fn test_self_test() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .with_self_test_result(wire::Payload(vec![0x0F, 0x01, 12, 12]))
+            .build();
+        let mut builder = Builder::default();
+        builder.device(fake);
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let get_self_test = || -> Option<api::SelfTestReport> {
+            let reply = test_server
+                .client()
+                .get("http://localhost/selftest")
+                .perform()
+                .unwrap();
+            assert_eq!(reply.status(), 200);
+            return read_json(reply).unwrap();
+        };
+
+        // No self-test has been run yet.
+        assert_eq!(get_self_test(), None);
+
+        let reply = test_server
+            .client()
+            .post("http://localhost/selftest", "", mime::APPLICATION_JSON)
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+
+        // The self-test runs in the background, so poll for the result.
+        let mut report = None;
+        for _ in 0..50 {
+            report = get_self_test();
+            if report.is_some() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        assert_eq!(
+            report,
+            Some(api::SelfTestReport {
+                passed: true,
+                good_dsp_cycles: 12,
+                total_dsp_cycles: 12,
+            }),
+        );
+
+        let reply = test_server
+            .client()
+            .get("http://localhost/metrics")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        let body = reply.read_utf8_body().unwrap();
+        assert!(body.contains("co2_selftest_passed 1"));
+    }
+
+    #[test]
+    fn test_self_test_busy_or_failed() {
+        // The fake fails self-tests, having no result to report.
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake);
+        let test_server = TestServer::new(Server::new(mgr.clone(), "").routes()).unwrap();
+        let post_self_test = || {
+            return test_server
+                .client()
+                .post(
+                    format!("http://localhost{}/selftest", api::PREFIX),
+                    "",
+                    mime::APPLICATION_JSON,
+                )
+                .perform()
+                .unwrap();
+        };
+
+        // A self-test can't start while the device is in use, and doesn't
+        // wait for it.
+        {
+            let _dev = mgr.device.lock().unwrap();
+            let reply = post_self_test();
+            assert_eq!(reply.status(), http::StatusCode::CONFLICT);
+            let body: api::ErrorBody = read_json(reply).unwrap();
+            assert_eq!(body.error.code, "busy");
+        }
+
+        // A self-test that couldn't be run is reported as such.
+        assert_eq!(post_self_test().status(), http::StatusCode::ACCEPTED);
+        let mut report = api::SelfTest {
+            last: None,
+            error: None,
+        };
+        for _ in 0..50 {
+            let reply = test_server
+                .client()
+                .get(format!("http://localhost{}/selftest", api::PREFIX))
+                .perform()
+                .unwrap();
+            report = read_json(reply).unwrap();
+            if report.error.is_some() {
+                break;
+            }
+            thread::sleep(time::Duration::from_millis(100));
+        }
+        assert_eq!(report.last, None);
+        assert!(report.error.unwrap().contains("no self-test result"));
+        let reply = test_server
+            .client()
+            .get("http://localhost/selftest")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::OK);
+        let body: serde_json::Value = read_json(reply).unwrap();
+        assert!(body["error"]
+            .as_str()
+            .unwrap()
+            .contains("no self-test result"));
+    }
+
+    #[test]
+    fn test_read_abc() {
+        let fake = FakeBuilder::default().with_abc(ABCSetting::Off).build();
+        let mut builder = Builder::default();
+        builder.device(fake.clone());
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let reply = test_server
+            .client()
+            .get("http://localhost/abc")
+            .perform()
+            .unwrap();
+
+        assert_eq!(reply.status(), 200);
+        let abc: ABCSetting = read_json(reply).unwrap();
+        assert_eq!(abc, ABCSetting::Off);
+    }
+
+    #[test]
+    fn test_put_abc() {
+        let fake = FakeBuilder::default().with_abc(ABCSetting::Off).build();
+        let mut builder = Builder::default();
+        builder.device(fake.clone());
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let put_abc = |body: &'static str| {
+            return test_server
+                .client()
+                .put("http://localhost/abc", body, mime::APPLICATION_JSON)
+                .perform()
+                .unwrap();
+        };
+
+        assert_eq!(put_abc("\"on\"").status(), 200);
+        assert_eq!(fake.abc(), Some(ABCSetting::On));
+
+        assert_eq!(put_abc("\"reset\"").status(), 200);
+        assert_eq!(fake.abc(), Some(ABCSetting::Reset));
+
+        assert_eq!(put_abc("\"off\"").status(), 200);
+        assert_eq!(fake.abc(), Some(ABCSetting::Off));
+
+        // Unknown settings are rejected, and don't touch the device.
+        assert_ne!(put_abc("\"sideways\"").status(), 200);
+        assert_eq!(fake.abc(), Some(ABCSetting::Off));
+    }
+
+    #[test]
+    fn test_history() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        mgr.sample();
+        fake.set_co2(wire::Concentration::PPM(600));
+        mgr.sample();
+        let srv = Server::new(mgr.clone(), "");
+        let test_server = TestServer::new(srv.routes()).unwrap();
+
+        let get_history = |query: &str| -> Vec<history::Bucket> {
+            let reply = test_server
+                .client()
+                .get(format!("http://localhost/history{}", query))
+                .perform()
+                .unwrap();
+            assert_eq!(reply.status(), 200);
+            return read_json(reply).unwrap();
+        };
+
+        // Without a step, every sample is returned.
+        let raw = get_history("");
+        assert_eq!(
+            raw.iter().map(|b| b.max).collect::<Vec<u16>>(),
+            vec![400, 600],
+        );
+
+        // A large step summarizes them into a single bucket.
+        let since = raw[0]
+            .start
+            .to_rfc3339_opts(chrono::SecondsFormat::Secs, true);
+        let summary = get_history(&format!("?since={}&step=3600", since));
+        assert_eq!(summary.len(), 1);
+        assert_eq!(
+            (
+                summary[0].min,
+                summary[0].max,
+                summary[0].mean,
+                summary[0].count
+            ),
+            (400, 600, 500.0, 2),
+        );
+
+        // Nothing was recorded before the first sample.
+        let none = get_history(&format!("?until={}", "2000-01-01T00:00:00Z"));
+        assert!(none.is_empty());
+
+        // Too fine a step over too long a span is refused.
+        let reply = test_server
+            .client()
+            .get("http://localhost/history?since=2000-01-01T00:00:00Z&step=1")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_history_unbounded_window() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake);
+        let sampling = Sampling {
+            interval: time::Duration::from_secs(3600),
+            history_window: time::Duration::from_secs(u64::MAX),
+            ..Sampling::default()
+        };
+        mgr.start_sampling(&sampling).unwrap();
+        let got = mgr.history(None, None, None).unwrap();
+        assert_eq!(got.len(), 1);
+        // Nothing was poisoned along the way.
+        mgr.sample();
+        assert_eq!(mgr.history(None, None, None).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_storage_survives_restart() {
+        let dir = TempDir::new("server-storage");
+        let sampling = Sampling {
+            // Long enough that only the initial sample is taken.
+            interval: time::Duration::from_secs(3600),
+            // Too long for chrono, so the whole store is loaded.
+            history_window: time::Duration::from_secs(u64::MAX),
+            storage: Some(store::Options::new(dir.path())),
+            ..Sampling::default()
+        };
+
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let first = DeviceManager::new(fake.clone());
+        first.start_sampling(&sampling).unwrap();
+        drop(first);
+
+        // A "restarted" manager should have the first manager's sample
+        // alongside its own.
+        fake.set_co2(wire::Concentration::PPM(600));
+        let second = DeviceManager::new(fake.clone());
+        second.start_sampling(&sampling).unwrap();
+        let got: Vec<u16> = second
+            .history(None, None, None)
+            .unwrap()
+            .iter()
+            .map(|b| b.max)
+            .collect();
+        assert_eq!(got, vec![400, 600]);
+    }
+
+    #[test]
+    fn test_export_stored() {
+        let dir = TempDir::new("server-export");
+        let mut storage = store::Options::new(dir.path());
+        // A few readings per segment, so the export spans several.
+        storage.segment_bytes = 64;
+        let sampling = Sampling {
+            interval: time::Duration::from_secs(3600),
+            storage: Some(storage),
+            ..Sampling::default()
+        };
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .build();
+        let mgr = DeviceManager::new(fake.clone());
+        mgr.start_sampling(&sampling).unwrap();
+        for ppm in 401..410 {
+            fake.set_co2(wire::Concentration::PPM(ppm));
+            mgr.sample();
+        }
+        let test_server = TestServer::new(Server::new(mgr, "").routes()).unwrap();
+
+        let reply = test_server
+            .client()
+            .get("http://localhost/export.csv")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        let body = reply.read_utf8_body().unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines[0], export::CSV_HEADER);
+        let got: Vec<u16> = lines[1..]
+            .iter()
+            .map(|l| l.split(',').nth(1).unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(got, (400..410).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn test_export() {
+        let fake = FakeBuilder::default()
+            .with_co2(wire::Concentration::PPM(400))
+            .with_elevation(wire::Distance::Feet(1500))
+            .build();
+        let mut builder = Builder::default();
+        builder.device(fake.clone());
+        let srv = builder.build().unwrap();
+        let test_server = TestServer::new(srv.routes()).unwrap();
+
+        let reply = test_server
+            .client()
+            .get("http://localhost/export.csv")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        let body = reply.read_utf8_body().unwrap();
+        let lines: Vec<&str> = body.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0], export::CSV_HEADER);
+        assert!(lines[1].ends_with(",400,false,false,false,false,false,1500"));
+
+        let reply = test_server
+            .client()
+            .get("http://localhost/export.jsonl")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        let body = reply.read_utf8_body().unwrap();
+        let record: export::Record = serde_json::from_str(body.trim()).unwrap();
+        assert_eq!(record.co2_ppm, 400);
+        assert_eq!(record.elevation_ft, Some(1500));
+
+        // Nothing was recorded before 2000.
+        let reply = test_server
+            .client()
+            .get("http://localhost/export.jsonl?until=2000-01-01T00:00:00Z")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        assert_eq!(reply.read_utf8_body().unwrap(), "");
+    }
 }
@@ -0,0 +1,571 @@
+//! A software Telaire T6615.
+//!
+//! `Sensor` models the device: it answers every command in `wire::command`,
+//! goes through warmup, calibration and self-test on a timer, and reports
+//! concentrations from a configurable `Waveform`. `Simulator` serves a
+//! `Sensor` on a pseudo-terminal, so anything that talks to a real sensor
+//! over a serial port (e.g., `device::T6615`, or the `co2` binary) can talk
+//! to the simulated one instead.
+use crate::device;
+use crate::wire;
+use log::warn;
+use serialport;
+use serialport::SerialPort;
+use std::convert::TryFrom;
+use std::f64::consts::PI;
+use std::io;
+use std::io::{Read, Write};
+use std::result;
+use std::str;
+use std::sync;
+use std::sync::atomic;
+use std::thread;
+use std::time;
+
+/// Waveform is the concentration reported by a simulated sensor over time.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Waveform {
+    /// A fixed concentration, in ppm.
+    Constant(u16),
+    /// A sine wave around `mean`, swinging `amplitude` ppm either way.
+    Sine {
+        mean: u16,
+        amplitude: u16,
+        period: time::Duration,
+    },
+    /// Each of `values` (in ppm) in turn, held for `step`. Starts over after
+    /// the last value.
+    Sequence {
+        values: Vec<u16>,
+        step: time::Duration,
+    },
+}
+
+impl Waveform {
+    /// Returns the concentration, in ppm, `elapsed` after the waveform
+    /// started.
+    pub fn at(&self, elapsed: time::Duration) -> u16 {
+        match self {
+            Waveform::Constant(ppm) => return *ppm,
+            Waveform::Sine {
+                mean,
+                amplitude,
+                period,
+            } => {
+                let phase = elapsed.as_secs_f64() / period.as_secs_f64().max(f64::EPSILON);
+                let v = *mean as f64 + *amplitude as f64 * (2.0 * PI * phase).sin();
+                return v.round().clamp(0.0, u16::MAX as f64) as u16;
+            }
+            Waveform::Sequence { values, step } => {
+                if values.is_empty() {
+                    return 0;
+                }
+                let idx = elapsed.as_millis() / step.as_millis().max(1);
+                return values[(idx % values.len() as u128) as usize];
+            }
+        }
+    }
+}
+
+impl str::FromStr for Waveform {
+    type Err = String;
+
+    /// Parse a waveform from `constant:<ppm>`,
+    /// `sine:<mean>:<amplitude>:<period-secs>` or
+    /// `sequence:<step-secs>:<ppm>,<ppm>,...`.
+    fn from_str(s: &str) -> result::Result<Waveform, String> {
+        let num = |v: &str| {
+            v.parse::<u16>()
+                .map_err(|e| format!("invalid number {:?} in waveform: {}", v, e))
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        return match parts.as_slice() {
+            ["constant", ppm] => Ok(Waveform::Constant(num(ppm)?)),
+            ["sine", mean, amplitude, period] => Ok(Waveform::Sine {
+                mean: num(mean)?,
+                amplitude: num(amplitude)?,
+                period: time::Duration::from_secs(num(period)? as u64),
+            }),
+            ["sequence", step, values] => Ok(Waveform::Sequence {
+                values: values
+                    .split(',')
+                    .map(num)
+                    .collect::<result::Result<Vec<u16>, String>>()?,
+                step: time::Duration::from_secs(num(step)? as u64),
+            }),
+            _ => Err(format!(
+                "unrecognized waveform {:?}, expected constant:<ppm>, \
+                 sine:<mean>:<amplitude>:<period-secs> or \
+                 sequence:<step-secs>:<ppm>,<ppm>,...",
+                s
+            )),
+        };
+    }
+}
+
+/// Timing configures how long a simulated sensor spends in each of its
+/// long-running states.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Timing {
+    pub warmup: time::Duration,
+    pub calibration: time::Duration,
+    pub self_test: time::Duration,
+    /// How often a reading is pushed in stream mode.
+    pub stream_interval: time::Duration,
+}
+
+impl Default for Timing {
+    fn default() -> Timing {
+        return Timing {
+            warmup: time::Duration::from_secs(30),
+            calibration: time::Duration::from_secs(60),
+            self_test: time::Duration::from_secs(10),
+            stream_interval: time::Duration::from_secs(2),
+        };
+    }
+}
+
+/// Sensor is a model of a T6615. Time is measured from when the sensor was
+/// powered on, and is passed in explicitly, so the model itself is
+/// deterministic.
+#[derive(Debug, Clone)]
+pub struct Sensor {
+    waveform: Waveform,
+    timing: Timing,
+    serial_number: String,
+    self_test_passes: bool,
+    elevation: wire::Distance,
+    reference: wire::Concentration,
+    abc: wire::response::ABCState,
+    idle: bool,
+    streaming: bool,
+    // The correction applied to the waveform by the last calibration.
+    offset: i32,
+    warmup_started: time::Duration,
+    calibration_started: Option<time::Duration>,
+    self_test_started: Option<time::Duration>,
+}
+
+impl Sensor {
+    pub fn new(waveform: Waveform) -> Sensor {
+        return Sensor {
+            waveform: waveform,
+            timing: Timing::default(),
+            serial_number: String::from("SIM00001"),
+            self_test_passes: true,
+            elevation: wire::Distance::Feet(0),
+            reference: wire::Concentration::PPM(0),
+            abc: wire::response::ABCState::On,
+            idle: false,
+            streaming: false,
+            offset: 0,
+            warmup_started: time::Duration::from_secs(0),
+            calibration_started: None,
+            self_test_started: None,
+        };
+    }
+
+    pub fn with_timing(mut self, timing: Timing) -> Sensor {
+        self.timing = timing;
+        return self;
+    }
+
+    pub fn with_serial_number(mut self, serial_number: &str) -> Sensor {
+        self.serial_number = String::from(serial_number);
+        return self;
+    }
+
+    /// Make the sensor report a failed self-test.
+    pub fn with_failing_self_test(mut self) -> Sensor {
+        self.self_test_passes = false;
+        return self;
+    }
+
+    pub fn timing(&self) -> &Timing {
+        return &self.timing;
+    }
+
+    /// Returns `true` if the sensor is pushing readings on its own.
+    pub fn is_streaming(&self) -> bool {
+        return self.streaming;
+    }
+
+    // Finish any long-running operation that is over by `at`.
+    fn advance(&mut self, at: time::Duration) {
+        if let Some(start) = self.calibration_started {
+            let end = start + self.timing.calibration;
+            if at >= end {
+                // Calibration makes the sensor read the reference
+                // concentration at the time it finished.
+                self.offset = self.reference.ppm() as i32 - self.waveform.at(end) as i32;
+                self.calibration_started = None;
+            }
+        }
+        if let Some(start) = self.self_test_started {
+            if at >= start + self.timing.self_test {
+                self.self_test_started = None;
+            }
+        }
+    }
+
+    fn in_warmup(&self, at: time::Duration) -> bool {
+        return at < self.warmup_started + self.timing.warmup;
+    }
+
+    /// Returns the sensor's status at `at`.
+    pub fn status(&mut self, at: time::Duration) -> wire::response::Status {
+        self.advance(at);
+        return wire::response::StatusFlags {
+            in_err: false,
+            in_warmup: self.in_warmup(at),
+            in_calibration: self.calibration_started.is_some(),
+            in_idle: self.idle,
+            in_self_test: self.self_test_started.is_some(),
+        }
+        .into();
+    }
+
+    /// Returns the concentration the sensor reports at `at`. Reads 0ppm
+    /// while warming up.
+    pub fn co2(&mut self, at: time::Duration) -> wire::Concentration {
+        self.advance(at);
+        if self.in_warmup(at) {
+            return wire::Concentration::PPM(0);
+        }
+        let v = self.waveform.at(at) as i32 + self.offset;
+        return wire::Concentration::PPM(v.clamp(0, u16::MAX as i32) as u16);
+    }
+
+    /// Handle the request `req`, received at `at`. Returns the reply, or
+    /// `None` if the request gets no reply.
+    pub fn handle(&mut self, req: &wire::Payload, at: time::Duration) -> Option<wire::Payload> {
+        self.advance(at);
+        // Any command takes the sensor out of stream mode.
+        self.streaming = false;
+        let is = |p: wire::Payload| *req == p;
+        let ack = Some(wire::Payload::from(wire::response::Ack));
+
+        if is(wire::command::Read(wire::Variable::GasPPM).into()) {
+            return Some(wire::response::GasPPM::with_ppm(self.co2(at).ppm()).into());
+        } else if is(wire::command::Read(wire::Variable::SerialNumber).into()) {
+            let mut bs = self.serial_number.clone().into_bytes();
+            bs.push(0x00);
+            return Some(wire::Payload(bs));
+        } else if is(wire::command::Read(wire::Variable::CompileSubvol).into()) {
+            return Some(wire::Payload(b"SIM".to_vec()));
+        } else if is(wire::command::Read(wire::Variable::CompileDate).into()) {
+            return Some(wire::Payload(b"201001".to_vec()));
+        } else if is(wire::command::Read(wire::Variable::Elevation).into()) {
+            return Some(wire::response::Elevation(self.elevation).into());
+        } else if is(wire::command::VerifySinglePointCalibration.into()) {
+            return Some(wire::response::GasPPM::with_ppm(self.reference.ppm()).into());
+        } else if let Ok(wire::command::UpdateElevation(d)) =
+            wire::command::UpdateElevation::try_from(req.clone())
+        {
+            self.elevation = d;
+            return ack;
+        } else if let Ok(wire::command::SetSinglePointPPM(c)) =
+            wire::command::SetSinglePointPPM::try_from(req.clone())
+        {
+            self.reference = c;
+            return ack;
+        } else if is(wire::command::Warmup.into()) {
+            self.warmup_started = at;
+            return ack;
+        } else if is(wire::command::StartSinglePointCalibration.into()) {
+            self.calibration_started = Some(at);
+            return ack;
+        } else if is(wire::command::Status.into()) {
+            return Some(self.status(at).into());
+        } else if is(wire::command::Idle(wire::Toggle::On).into()) {
+            self.idle = true;
+            return ack;
+        } else if is(wire::command::Idle(wire::Toggle::Off).into()) {
+            self.idle = false;
+            return ack;
+        } else if is(wire::command::ABCLogic.into()) {
+            return Some(self.abc.into());
+        } else if is(wire::command::SetABCLogic(wire::Toggle::On).into()) {
+            self.abc = wire::response::ABCState::On;
+            return Some(self.abc.into());
+        } else if is(wire::command::SetABCLogic(wire::Toggle::Off).into()) {
+            self.abc = wire::response::ABCState::Off;
+            return Some(self.abc.into());
+        } else if is(wire::command::ResetABCLogic.into()) || is(wire::command::Halt.into()) {
+            return ack;
+        } else if is(wire::command::StartSelfTest.into()) {
+            self.self_test_started = Some(at);
+            return ack;
+        } else if is(wire::command::SelfTestResults.into()) {
+            let result = if self.self_test_passes { 0x01 } else { 0x00 };
+            return Some(wire::Payload(vec![0x0F, result, 0x05, 0x05]));
+        } else if is(wire::command::StreamData.into()) {
+            self.streaming = true;
+            return None;
+        } else if req.first() == Some(&0x00) {
+            // Loopback: echo back everything after the command byte.
+            return Some(wire::Payload(req[1..].to_vec()));
+        }
+        warn!("simulator ignoring unknown command {:02X?}", **req);
+        return None;
+    }
+}
+
+// How long the simulator waits for a request before checking whether it
+// should push a streamed reading, or stop.
+const POLL_INTERVAL: time::Duration = time::Duration::from_millis(20);
+
+/// Simulator serves a simulated `Sensor` on a pseudo-terminal, found at
+/// `path()`, until it is dropped.
+pub struct Simulator {
+    path: String,
+    stop: sync::Arc<atomic::AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    // Held open, so the pseudo-terminal stays up while nothing else has it
+    // open.
+    _slave: serialport::TTYPort,
+}
+
+impl Simulator {
+    /// Start serving `sensor`. The sensor is powered on immediately.
+    pub fn start(sensor: Sensor) -> device::Result<Simulator> {
+        let (mut master, slave) = serialport::TTYPort::pair()?;
+        let path = slave
+            .name()
+            .ok_or_else(|| device::Error::from("pseudo-terminal has no name"))?;
+        master.set_timeout(POLL_INTERVAL)?;
+        let stop = sync::Arc::new(atomic::AtomicBool::new(false));
+        let thread_stop = stop.clone();
+        let thread = thread::spawn(move || serve(master, sensor, thread_stop));
+        return Ok(Simulator {
+            path: path,
+            stop: stop,
+            thread: Some(thread),
+            _slave: slave,
+        });
+    }
+
+    /// Returns the path of the TTY the simulated sensor is attached to.
+    pub fn path(&self) -> &str {
+        return &self.path;
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.stop.store(true, atomic::Ordering::SeqCst);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+fn serve(mut port: serialport::TTYPort, mut sensor: Sensor, stop: sync::Arc<atomic::AtomicBool>) {
+    let started = time::Instant::now();
+    let mut decoder = wire::Decoder::with_address(wire::REQUEST_ADDRESS);
+    let mut chunk: [u8; 64] = [0; 64];
+    let mut next_push = time::Duration::from_secs(0);
+    while !stop.load(atomic::Ordering::SeqCst) {
+        match port.read(&mut chunk) {
+            Ok(n) => decoder.push(&chunk[..n]),
+            Err(e)
+                if e.kind() == io::ErrorKind::TimedOut
+                    || e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                warn!("simulator failed to read request: {}", e);
+                return;
+            }
+        }
+        let at = started.elapsed();
+        let mut out: Vec<u8> = Vec::new();
+        for req in decoder.by_ref() {
+            if let Some(reply) = sensor.handle(&req, at) {
+                out.extend_from_slice(&wire::Message::reply(reply));
+            }
+        }
+        if !sensor.is_streaming() {
+            next_push = at;
+        } else if at >= next_push {
+            let d = wire::response::StreamData::with_ppm(sensor.co2(at).ppm());
+            out.extend_from_slice(&wire::Message::reply(d.into()));
+            next_push = at + sensor.timing().stream_interval;
+        }
+        if out.is_empty() {
+            continue;
+        }
+        if let Err(e) = port.write_all(&out) {
+            warn!("simulator failed to write reply: {}", e);
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::{Device, StreamDevice};
+
+    fn secs(s: u64) -> time::Duration {
+        return time::Duration::from_secs(s);
+    }
+
+    // Timing fast enough for tests to wait out.
+    fn fast() -> Timing {
+        return Timing {
+            warmup: time::Duration::from_millis(0),
+            calibration: time::Duration::from_millis(50),
+            self_test: time::Duration::from_millis(50),
+            stream_interval: time::Duration::from_millis(50),
+        };
+    }
+
+    #[test]
+    fn test_waveform() {
+        assert_eq!("constant:450".parse(), Ok(Waveform::Constant(450)));
+
+        let sine: Waveform = "sine:450:50:60".parse().unwrap();
+        assert_eq!(sine.at(secs(0)), 450);
+        assert_eq!(sine.at(secs(15)), 500);
+        assert_eq!(sine.at(secs(45)), 400);
+
+        let seq: Waveform = "sequence:10:400,500".parse().unwrap();
+        assert_eq!(seq.at(secs(0)), 400);
+        assert_eq!(seq.at(secs(10)), 500);
+        assert_eq!(seq.at(secs(25)), 400);
+
+        assert!("sine:450".parse::<Waveform>().is_err());
+        assert!("constant:lots".parse::<Waveform>().is_err());
+    }
+
+    #[test]
+    fn test_sensor_warmup() {
+        let mut s = Sensor::new(Waveform::Constant(450));
+        assert!(s.status(secs(0)).in_warmup());
+        assert_eq!(s.co2(secs(0)), wire::Concentration::PPM(0));
+        assert!(s.status(secs(30)).is_normal());
+        assert_eq!(s.co2(secs(30)), wire::Concentration::PPM(450));
+
+        // Restarting warmup.
+        s.handle(&wire::command::Warmup.into(), secs(60));
+        assert!(s.status(secs(89)).in_warmup());
+        assert!(!s.status(secs(90)).in_warmup());
+    }
+
+    #[test]
+    fn test_sensor_calibration() {
+        let mut s = Sensor::new(Waveform::Constant(450));
+        let ack = Some(wire::Payload::default());
+        let req = wire::command::SetSinglePointPPM(wire::Concentration::PPM(420)).into();
+        assert_eq!(s.handle(&req, secs(30)), ack);
+        assert_eq!(
+            s.handle(&wire::command::StartSinglePointCalibration.into(), secs(30)),
+            ack
+        );
+        assert!(s.status(secs(60)).in_calibration());
+        assert_eq!(s.co2(secs(60)), wire::Concentration::PPM(450));
+        assert!(s.status(secs(90)).is_normal());
+        assert_eq!(s.co2(secs(90)), wire::Concentration::PPM(420));
+    }
+
+    #[test]
+    fn test_sensor_stream_mode() {
+        let mut s = Sensor::new(Waveform::Constant(450));
+        assert_eq!(s.handle(&wire::command::StreamData.into(), secs(30)), None);
+        assert!(s.is_streaming());
+        // Any other command ends stream mode.
+        assert!(s.handle(&wire::command::Status.into(), secs(31)).is_some());
+        assert!(!s.is_streaming());
+    }
+
+    // The tests below talk to the simulator through a real `T6615`, over a
+    // pseudo-terminal.
+    fn connect(sim: &Simulator) -> device::T6615 {
+        let mut opts = device::SerialOptions::new(sim.path());
+        opts.timeout = secs(5);
+        return device::T6615::open(&opts).unwrap();
+    }
+
+    fn short_sleep(_: time::Duration) {
+        thread::sleep(time::Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_simulator() {
+        let sim = Simulator::start(
+            Sensor::new(Waveform::Constant(450))
+                .with_timing(fast())
+                .with_serial_number("TEST1"),
+        )
+        .unwrap();
+        let mut d = connect(&sim);
+
+        let mut info: Vec<u8> = Vec::new();
+        crate::tools::info(&mut d, &mut info).unwrap();
+        assert!(String::from_utf8(info).unwrap().contains("Serial: TEST1"));
+
+        d.wait_warmup(short_sleep, &device::Limit::none()).unwrap();
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+
+        d.set_elevation(wire::Distance::Feet(1500)).unwrap();
+        assert_eq!(d.read_elevation().unwrap(), wire::Distance::Feet(1500));
+
+        d.disable_abc().unwrap();
+        assert_eq!(d.read_abc().unwrap(), wire::response::ABCState::Off);
+
+        assert!(d.run_self_test(short_sleep).unwrap().passed());
+
+        d.calibrate_co2(
+            wire::Concentration::PPM(420),
+            short_sleep,
+            &device::Limit::none(),
+        )
+        .unwrap();
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(420));
+
+        let looped: wire::response::Loopback = d
+            .execute(wire::command::Loopback(vec![0x01, 0x02]))
+            .unwrap();
+        assert_eq!(looped, wire::response::Loopback(vec![0x01, 0x02]));
+    }
+
+    #[test]
+    fn test_simulator_stream() {
+        let sim =
+            Simulator::start(Sensor::new(Waveform::Constant(450)).with_timing(fast())).unwrap();
+        let mut d = connect(&sim);
+        {
+            let readings: Vec<device::Reading> = d
+                .stream_co2()
+                .unwrap()
+                .take(3)
+                .map(|r| r.unwrap())
+                .collect();
+            assert!(readings
+                .iter()
+                .all(|r| r.concentration == wire::Concentration::PPM(450)));
+        }
+        // Polling works again once the stream is dropped.
+        assert_eq!(d.read_co2().unwrap(), wire::Concentration::PPM(450));
+    }
+
+    #[test]
+    fn test_simulator_server() {
+        use crate::server;
+        use gotham::test::TestServer;
+
+        let sim =
+            Simulator::start(Sensor::new(Waveform::Constant(612)).with_timing(fast())).unwrap();
+        let mut builder = server::Builder::default();
+        builder.device(connect(&sim));
+        let srv = builder.build().unwrap();
+
+        let test_server = TestServer::new(srv.routes()).unwrap();
+        let reply = test_server
+            .client()
+            .get("http://localhost/co2")
+            .perform()
+            .unwrap();
+        assert_eq!(reply.status(), 200);
+        assert_eq!(reply.read_utf8_body().unwrap(), "612");
+    }
+}
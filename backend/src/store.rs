@@ -0,0 +1,585 @@
+//! An append-only, on-disk log of readings.
+//!
+//! The log is a directory of numbered segment files (`00000001.seg`, ...).
+//! Readings are only ever appended to the newest segment. Once a segment
+//! grows past `Options::segment_bytes` a new one is started, and whole
+//! segments are deleted once they fall outside of the retention limits.
+//!
+//! Each record in a segment is laid out as:
+//!
+//! ```text
+//! +--------+------------------+-----------------+
+//! | len u8 | payload len bytes| crc32(payload)  |
+//! +--------+------------------+-----------------+
+//! ```
+//!
+//! The payload starts with the reading's timestamp (milliseconds since the
+//! epoch, `i64` big endian) followed by the concentration (ppm, `u16` big
+//! endian). It may be followed by a byte of presence flags, the status byte
+//! and the elevation (feet, `u16` big endian). Readers ignore any trailing
+//! payload bytes they don't understand, so fields can be added later without
+//! breaking old logs.
+//!
+//! A crash (or power loss) in the middle of an append leaves a torn record
+//! at the end of the newest segment. It is detected by its length or
+//! checksum, and truncated away when the store is opened. A record corrupted
+//! anywhere else, e.g., by a flipped bit, is skipped: readers resync at the
+//! next intact record after it.
+use crate::device;
+use crate::wire;
+use chrono::TimeZone;
+use log::warn;
+use std::convert::{TryFrom, TryInto};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path;
+use std::result;
+use std::time;
+
+#[derive(Debug)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        return self.0.fmt(f);
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<&str> for Error {
+    fn from(e: &str) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+impl From<String> for Error {
+    fn from(e: String) -> Error {
+        return Error(e);
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        return Error(e.to_string());
+    }
+}
+
+pub type Result<T> = result::Result<T, Error>;
+
+const SEGMENT_EXTENSION: &str = "seg";
+
+// The size of the fixed part of the payload: timestamp + ppm.
+const MIN_PAYLOAD_LEN: usize = 10;
+
+// The size of the payload including status and elevation.
+const CONTEXT_PAYLOAD_LEN: usize = 14;
+
+// Bits in the presence flags byte.
+const HAS_STATUS: u8 = 0b01;
+const HAS_ELEVATION: u8 = 0b10;
+
+/// Options configures a `Store`.
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// The directory segments are stored in. Created if it does not exist.
+    pub dir: path::PathBuf,
+    /// Start a new segment once the current one reaches this size.
+    pub segment_bytes: u64,
+    /// Delete segments whose newest reading is older than this.
+    pub retention: time::Duration,
+    /// Delete the oldest segments once the store grows past this size.
+    pub max_bytes: u64,
+}
+
+impl Options {
+    /// Options with default limits for a store in `dir`.
+    pub fn new<P: Into<path::PathBuf>>(dir: P) -> Options {
+        return Options {
+            dir: dir.into(),
+            segment_bytes: 1 << 20,
+            retention: time::Duration::from_secs(30 * 24 * 60 * 60),
+            max_bytes: 64 << 20,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Segment {
+    seq: u64,
+    path: path::PathBuf,
+    len: u64,
+    newest: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+/// Store is a durable, append-only log of readings.
+pub struct Store {
+    opts: Options,
+    // Ordered from oldest to newest. Never empty once opened.
+    segments: Vec<Segment>,
+    current: fs::File,
+}
+
+fn encode(r: &device::Reading) -> Vec<u8> {
+    let mut payload: Vec<u8> = Vec::with_capacity(CONTEXT_PAYLOAD_LEN);
+    payload.extend_from_slice(&r.at.timestamp_millis().to_be_bytes());
+    payload.extend_from_slice(&r.concentration.ppm().to_be_bytes());
+
+    let mut flags: u8 = 0;
+    let mut status: u8 = 0;
+    let mut elevation: u16 = 0;
+    if let Some(s) = r.status {
+        flags |= HAS_STATUS;
+        status = wire::Payload::from(s)[0];
+    }
+    if let Some(e) = r.elevation {
+        flags |= HAS_ELEVATION;
+        elevation = e.feet();
+    }
+    payload.push(flags);
+    payload.push(status);
+    payload.extend_from_slice(&elevation.to_be_bytes());
+
+    let mut out: Vec<u8> = Vec::with_capacity(payload.len() + 5);
+    out.push(payload.len() as u8);
+    out.extend_from_slice(&payload);
+    out.extend_from_slice(&crc32fast::hash(&payload).to_be_bytes());
+    return out;
+}
+
+// Decode the record at the start of `bs`. Returns the reading and the
+// number of bytes consumed, or `None` if the record is torn or corrupt.
+fn decode(bs: &[u8]) -> Option<(device::Reading, usize)> {
+    let len = *bs.first()? as usize;
+    if len < MIN_PAYLOAD_LEN || bs.len() < 1 + len + 4 {
+        return None;
+    }
+    let payload = &bs[1..1 + len];
+    let crc = u32::from_be_bytes(bs[1 + len..1 + len + 4].try_into().ok()?);
+    if crc32fast::hash(payload) != crc {
+        return None;
+    }
+    let millis = i64::from_be_bytes(payload[0..8].try_into().ok()?);
+    let ppm = u16::from_be_bytes(payload[8..10].try_into().ok()?);
+    let mut r = device::Reading::new(
+        wire::Concentration::PPM(ppm),
+        chrono::Utc.timestamp_millis(millis),
+    );
+    if len >= CONTEXT_PAYLOAD_LEN {
+        let flags = payload[10];
+        if flags & HAS_STATUS != 0 {
+            r.status = wire::response::Status::try_from(wire::Payload(vec![payload[11]])).ok();
+        }
+        if flags & HAS_ELEVATION != 0 {
+            let feet = u16::from_be_bytes(payload[12..14].try_into().ok()?);
+            r.elevation = Some(wire::Distance::Feet(feet));
+        }
+    }
+    return Some((r, 1 + len + 4));
+}
+
+// Decoded is what was recovered from a segment.
+#[derive(Debug, PartialEq)]
+struct Decoded {
+    readings: Vec<device::Reading>,
+    // The end of the last intact record. Anything after it is a torn write.
+    end: usize,
+    // How many corrupt bytes were skipped before `end`.
+    skipped: usize,
+}
+
+// Decode every intact record in `bs`. A corrupt record is skipped by
+// searching for the next offset an intact record decodes at, so it doesn't
+// take every record after it down with it.
+fn decode_all(bs: &[u8]) -> Decoded {
+    let mut d = Decoded {
+        readings: Vec::new(),
+        end: 0,
+        skipped: 0,
+    };
+    let mut offset = 0;
+    while offset < bs.len() {
+        match decode(&bs[offset..]) {
+            Some((r, n)) => {
+                d.readings.push(r);
+                d.skipped += offset - d.end;
+                offset += n;
+                d.end = offset;
+            }
+            None => offset += 1,
+        }
+    }
+    return d;
+}
+
+fn segment_path(dir: &path::Path, seq: u64) -> path::PathBuf {
+    return dir.join(format!("{:08}.{}", seq, SEGMENT_EXTENSION));
+}
+
+fn open_append(p: &path::Path) -> Result<fs::File> {
+    return Ok(fs::OpenOptions::new().create(true).append(true).open(p)?);
+}
+
+// Create the segment at `p`, and make sure its directory entry survives a
+// crash along with its contents.
+fn create_segment(p: &path::Path) -> Result<fs::File> {
+    let f = open_append(p)?;
+    if let Some(dir) = p.parent() {
+        fs::File::open(dir)?.sync_all()?;
+    }
+    return Ok(f);
+}
+
+impl Store {
+    /// Open the store described by `opts`, recovering from any torn writes
+    /// left behind by a crash.
+    pub fn open(opts: Options) -> Result<Store> {
+        fs::create_dir_all(&opts.dir)?;
+
+        let mut seqs: Vec<u64> = Vec::new();
+        for entry in fs::read_dir(&opts.dir)? {
+            let p = entry?.path();
+            if p.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            if let Some(seq) = p
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                seqs.push(seq);
+            }
+        }
+        seqs.sort_unstable();
+
+        let mut segments: Vec<Segment> = Vec::new();
+        for (i, seq) in seqs.iter().enumerate() {
+            let path = segment_path(&opts.dir, *seq);
+            let bs = fs::read(&path)?;
+            let d = decode_all(&bs);
+            if d.skipped > 0 {
+                warn!("skipped {} corrupt bytes in {}", d.skipped, path.display());
+            }
+            if d.end < bs.len() {
+                if i == seqs.len() - 1 {
+                    warn!(
+                        "truncating {} torn bytes from the end of {}",
+                        bs.len() - d.end,
+                        path.display()
+                    );
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(d.end as u64)?;
+                } else {
+                    warn!(
+                        "ignoring {} corrupt bytes at the end of {}",
+                        bs.len() - d.end,
+                        path.display()
+                    );
+                }
+            }
+            segments.push(Segment {
+                seq: *seq,
+                path: path,
+                len: d.end as u64,
+                newest: d.readings.last().map(|r| r.at),
+            });
+        }
+
+        if segments.is_empty() {
+            let path = segment_path(&opts.dir, 1);
+            segments.push(Segment {
+                seq: 1,
+                path: path,
+                len: 0,
+                newest: None,
+            });
+        }
+
+        let current = if seqs.is_empty() {
+            create_segment(&segments[0].path)?
+        } else {
+            open_append(&segments.last().unwrap().path)?
+        };
+        let mut store = Store {
+            opts: opts,
+            segments: segments,
+            current: current,
+        };
+        store.enforce_retention(chrono::Utc::now())?;
+        return Ok(store);
+    }
+
+    /// Durably append a reading to the store.
+    pub fn append(&mut self, r: &device::Reading) -> Result<()> {
+        if self.segments.last().unwrap().len >= self.opts.segment_bytes {
+            self.rotate()?;
+        }
+        let record = encode(r);
+        self.current.write_all(&record)?;
+        self.current.sync_data()?;
+
+        let segment = self.segments.last_mut().unwrap();
+        segment.len += record.len() as u64;
+        segment.newest = Some(r.at);
+        return self.enforce_retention(r.at);
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let seq = self.segments.last().unwrap().seq + 1;
+        let path = segment_path(&self.opts.dir, seq);
+        self.current = create_segment(&path)?;
+        self.segments.push(Segment {
+            seq: seq,
+            path: path,
+            len: 0,
+            newest: None,
+        });
+        return Ok(());
+    }
+
+    // Delete the oldest segments until the store is within its limits. The
+    // current segment is never deleted.
+    fn enforce_retention(&mut self, now: chrono::DateTime<chrono::Utc>) -> Result<()> {
+        let retention = chrono::Duration::from_std(self.opts.retention)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
+        while self.segments.len() > 1 {
+            let total: u64 = self.segments.iter().map(|s| s.len).sum();
+            let oldest = &self.segments[0];
+            let expired = match oldest.newest {
+                Some(at) => now - at > retention,
+                None => true,
+            };
+            if !expired && total <= self.opts.max_bytes {
+                break;
+            }
+            fs::remove_file(&oldest.path)?;
+            self.segments.remove(0);
+        }
+        return Ok(());
+    }
+
+    /// Read back every stored reading taken at or after `since`, oldest
+    /// first.
+    pub fn read_since(&self, since: chrono::DateTime<chrono::Utc>) -> Result<Vec<device::Reading>> {
+        let mut out = Vec::new();
+        for readings in self.snapshot().read_since(since) {
+            out.extend(readings?);
+        }
+        return Ok(out);
+    }
+
+    /// Returns the segments the store is made up of right now, which can be
+    /// read without holding on to the store.
+    pub fn snapshot(&self) -> Snapshot {
+        return Snapshot {
+            segments: self.segments.clone(),
+        };
+    }
+
+    /// Returns the total size of all segments, in bytes.
+    pub fn size(&self) -> u64 {
+        return self.segments.iter().map(|s| s.len).sum();
+    }
+}
+
+/// Snapshot is the segments of a `Store` as of some point in time. Readings
+/// appended after it was taken aren't included.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    segments: Vec<Segment>,
+}
+
+impl Snapshot {
+    /// Read back the readings taken at or after `since`, a segment at a
+    /// time, oldest first. Segments deleted since the snapshot was taken
+    /// are skipped, since everything in them has expired.
+    pub fn read_since(
+        self,
+        since: chrono::DateTime<chrono::Utc>,
+    ) -> impl Iterator<Item = Result<Vec<device::Reading>>> {
+        return self
+            .segments
+            .into_iter()
+            .filter(move |s| matches!(s.newest, Some(at) if at >= since))
+            .filter_map(move |segment| {
+                let bs = match fs::read(&segment.path) {
+                    Ok(bs) => bs,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+                    Err(e) => return Some(Err(Error::from(e))),
+                };
+                let d = decode_all(&bs[..(segment.len as usize).min(bs.len())]);
+                return Some(Ok(d
+                    .readings
+                    .into_iter()
+                    .filter(|r| r.at >= since)
+                    .collect()));
+            });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn reading(secs: i64, ppm: u16) -> device::Reading {
+        return device::Reading::new(
+            wire::Concentration::PPM(ppm),
+            chrono::Utc.timestamp(secs, 0),
+        );
+    }
+
+    fn epoch() -> chrono::DateTime<chrono::Utc> {
+        return chrono::Utc.timestamp(0, 0);
+    }
+
+    #[test]
+    fn test_encode_decode() {
+        let r = reading(1_600_000_000, 412);
+        let bs = encode(&r);
+        assert_eq!(decode(&bs), Some((r, bs.len())));
+        // Any truncation is detected.
+        for n in 0..bs.len() {
+            assert_eq!(decode(&bs[..n]), None);
+        }
+        // Status and elevation are round-tripped.
+        let mut with_context = r;
+        with_context.status = Some(wire::response::Status::from(wire::response::StatusFlags {
+            in_warmup: true,
+            ..wire::response::StatusFlags::default()
+        }));
+        with_context.elevation = Some(wire::Distance::Feet(1500));
+        let ctx = encode(&with_context);
+        assert_eq!(decode(&ctx), Some((with_context, ctx.len())));
+
+        // Records written before status and elevation were stored still
+        // decode.
+        let mut old: Vec<u8> = vec![MIN_PAYLOAD_LEN as u8];
+        old.extend_from_slice(&bs[1..1 + MIN_PAYLOAD_LEN]);
+        old.extend_from_slice(&crc32fast::hash(&bs[1..1 + MIN_PAYLOAD_LEN]).to_be_bytes());
+        assert_eq!(decode(&old), Some((r, old.len())));
+
+        // As is a flipped bit.
+        let mut corrupt = bs.clone();
+        corrupt[5] ^= 0x1;
+        assert_eq!(decode(&corrupt), None);
+    }
+
+    #[test]
+    fn test_reopen() {
+        let dir = TempDir::new("store-reopen");
+        let want = vec![reading(100, 400), reading(115, 410), reading(130, 420)];
+        {
+            let mut s = Store::open(Options::new(dir.path())).unwrap();
+            for r in want.iter() {
+                s.append(r).unwrap();
+            }
+        }
+        let s = Store::open(Options::new(dir.path())).unwrap();
+        assert_eq!(s.read_since(epoch()).unwrap(), want);
+        assert_eq!(
+            s.read_since(chrono::Utc.timestamp(115, 0)).unwrap(),
+            want[1..].to_vec()
+        );
+    }
+
+    #[test]
+    fn test_recover_torn_write() {
+        let dir = TempDir::new("store-torn");
+        {
+            let mut s = Store::open(Options::new(dir.path())).unwrap();
+            s.append(&reading(100, 400)).unwrap();
+            s.append(&reading(115, 410)).unwrap();
+        }
+        // Simulate a crash half-way through writing a third record.
+        let path = segment_path(dir.path(), 1);
+        let torn = encode(&reading(130, 420));
+        let mut f = open_append(&path).unwrap();
+        f.write_all(&torn[..torn.len() / 2]).unwrap();
+        drop(f);
+
+        let mut s = Store::open(Options::new(dir.path())).unwrap();
+        assert_eq!(
+            s.read_since(epoch()).unwrap(),
+            vec![reading(100, 400), reading(115, 410)]
+        );
+        // Appends after recovery land after the last good record.
+        s.append(&reading(145, 430)).unwrap();
+        let s = Store::open(Options::new(dir.path())).unwrap();
+        assert_eq!(s.read_since(epoch()).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_skip_corrupt_record() {
+        let dir = TempDir::new("store-corrupt");
+        let record_len = encode(&reading(0, 0)).len();
+        let mut opts = Options::new(dir.path());
+        opts.segment_bytes = 3 * record_len as u64;
+        opts.retention = time::Duration::from_secs(u64::MAX);
+        {
+            let mut s = Store::open(opts.clone()).unwrap();
+            for i in 0..6 {
+                s.append(&reading(100 + i * 15, 400 + i as u16)).unwrap();
+            }
+        }
+        // Flip a bit in the middle record of both the older and the newest
+        // segment.
+        for seq in 1..=2 {
+            let path = segment_path(dir.path(), seq);
+            let mut bs = fs::read(&path).unwrap();
+            bs[record_len + 5] ^= 0x1;
+            fs::write(&path, &bs).unwrap();
+        }
+
+        // Only the corrupt records are lost, and nothing is truncated.
+        let mut s = Store::open(opts.clone()).unwrap();
+        let want = vec![
+            reading(100, 400),
+            reading(130, 402),
+            reading(145, 403),
+            reading(175, 405),
+        ];
+        assert_eq!(s.read_since(epoch()).unwrap(), want);
+        assert_eq!(
+            fs::metadata(segment_path(dir.path(), 2)).unwrap().len(),
+            3 * record_len as u64
+        );
+        s.append(&reading(190, 406)).unwrap();
+        let s = Store::open(opts).unwrap();
+        assert_eq!(s.read_since(epoch()).unwrap().len(), 5);
+    }
+
+    #[test]
+    fn test_rotation_and_retention() {
+        let dir = TempDir::new("store-retention");
+        let record_len = encode(&reading(0, 0)).len() as u64;
+        let mut opts = Options::new(dir.path());
+        // Two records per segment, and at most three segments.
+        opts.segment_bytes = 2 * record_len;
+        opts.max_bytes = 6 * record_len;
+        opts.retention = time::Duration::from_secs(1000);
+
+        let mut s = Store::open(opts.clone()).unwrap();
+        for i in 0..10 {
+            s.append(&reading(i * 10, 400 + i as u16)).unwrap();
+        }
+        assert!(s.size() <= opts.max_bytes);
+        let got = s.read_since(epoch()).unwrap();
+        assert_eq!(got.first(), Some(&reading(40, 404)));
+        assert_eq!(got.last(), Some(&reading(90, 409)));
+
+        // A reading far in the future expires everything else, including
+        // from a snapshot taken before.
+        let snapshot = s.snapshot();
+        s.append(&reading(10_000, 500)).unwrap();
+        assert_eq!(s.read_since(epoch()).unwrap(), vec![reading(10_000, 500)]);
+        let got: Vec<Vec<device::Reading>> =
+            snapshot.read_since(epoch()).collect::<Result<_>>().unwrap();
+        assert!(got.is_empty());
+    }
+}
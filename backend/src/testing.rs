@@ -0,0 +1,33 @@
+//! Helpers shared by the tests of several modules.
+use std::fs;
+use std::path;
+use std::process;
+
+/// TempDir is a new, empty directory under the system's temporary
+/// directory. It's removed, along with everything in it, when dropped.
+pub struct TempDir(path::PathBuf);
+
+impl TempDir {
+    /// Create a directory whose name starts with `name`, e.g., the name of
+    /// the test using it.
+    pub fn new(name: &str) -> TempDir {
+        let p = std::env::temp_dir().join(format!(
+            "co2-{}-{}-{}",
+            name,
+            process::id(),
+            chrono::Utc::now().timestamp_nanos()
+        ));
+        fs::create_dir_all(&p).unwrap();
+        return TempDir(p);
+    }
+
+    pub fn path(&self) -> &path::Path {
+        return &self.0;
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
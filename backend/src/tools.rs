@@ -0,0 +1,319 @@
+//! Maintenance commands that drive a `device::Device` directly, without
+//! starting the server. Used by the `co2` binary's subcommands to service a
+//! sensor in the field. Every command writes a human-readable report to
+//! `out`.
+use crate::device;
+use crate::device::Device;
+use crate::wire;
+use std::io::Write;
+use std::time;
+
+/// Print the device's identity: its serial number and software version.
+pub fn info<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let id = d.read_identity()?;
+    writeln!(out, "Device: Telaire T6615")?;
+    writeln!(out, "  Serial: {}", id.serial_number)?;
+    writeln!(
+        out,
+        "  Software Version: {}.{}",
+        id.compile_subvol, id.compile_date
+    )?;
+    return Ok(());
+}
+
+/// Print a single CO2 measurement.
+pub fn read<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let c = d.read_co2()?;
+    writeln!(out, "{} ppm", c.ppm())?;
+    return Ok(());
+}
+
+/// Print a timestamped CO2 measurement every `interval`, `count` times or
+/// forever if `count` is `None`. `sleep_fn` is called between measurements.
+pub fn watch<D, W, T>(
+    d: &mut D,
+    out: &mut W,
+    interval: time::Duration,
+    count: Option<usize>,
+    sleep_fn: T,
+) -> device::Result<()>
+where
+    D: Device,
+    W: Write,
+    T: Fn(time::Duration),
+{
+    let mut taken = 0;
+    loop {
+        let c = d.read_co2()?;
+        writeln!(
+            out,
+            "{} {} ppm",
+            chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            c.ppm()
+        )?;
+        out.flush()?;
+        taken += 1;
+        if count == Some(taken) {
+            return Ok(());
+        }
+        sleep_fn(interval);
+    }
+}
+
+/// Print the device's status flags.
+pub fn status<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let s = d.read_status()?;
+    writeln!(out, "{}", s)?;
+    writeln!(out, "  Error: {}", s.is_err())?;
+    writeln!(out, "  Warmup: {}", s.in_warmup())?;
+    writeln!(out, "  Calibration: {}", s.in_calibration())?;
+    writeln!(out, "  Idle: {}", s.in_idle())?;
+    writeln!(out, "  Self-test: {}", s.in_self_test())?;
+    return Ok(());
+}
+
+/// Calibrate the device against `reference`, blocking until calibration is
+/// finished. Gives up after `timeout`.
+pub fn calibrate<D, W, T>(
+    d: &mut D,
+    out: &mut W,
+    reference: wire::Concentration,
+    timeout: time::Duration,
+    sleep_fn: T,
+) -> device::Result<()>
+where
+    D: Device,
+    W: Write,
+    T: Fn(time::Duration),
+{
+    writeln!(
+        out,
+        "Calibrating against {} ppm, this may take a few minutes...",
+        reference.ppm()
+    )?;
+    out.flush()?;
+    d.calibrate_co2(reference, sleep_fn, &device::Limit::timeout(timeout))?;
+    writeln!(out, "Calibration complete.")?;
+    return Ok(());
+}
+
+/// Print the elevation the device is configured for.
+pub fn get_elevation<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let e = d.read_elevation()?;
+    writeln!(out, "{} ft", e.feet())?;
+    return Ok(());
+}
+
+/// Configure the device's elevation, and print the elevation it settled on.
+pub fn set_elevation<D: Device, W: Write>(
+    d: &mut D,
+    out: &mut W,
+    to: wire::Distance,
+) -> device::Result<()> {
+    d.set_elevation(to)?;
+    return get_elevation(d, out);
+}
+
+/// Print whether automatic baseline correction is enabled.
+pub fn get_abc<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let state = match d.read_abc()? {
+        wire::response::ABCState::On => "on",
+        wire::response::ABCState::Off => "off",
+    };
+    writeln!(out, "ABC: {}", state)?;
+    return Ok(());
+}
+
+/// Turn automatic baseline correction on or off.
+pub fn set_abc<D: Device, W: Write>(d: &mut D, out: &mut W, t: wire::Toggle) -> device::Result<()> {
+    d.set_abc(t)?;
+    return get_abc(d, out);
+}
+
+/// Reset the automatic baseline correction history.
+pub fn reset_abc<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    d.reset_abc()?;
+    writeln!(out, "ABC history reset.")?;
+    return Ok(());
+}
+
+/// Run the device's self-test and print the result. Fails if the self-test
+/// did not pass.
+pub fn self_test<D, W, T>(d: &mut D, out: &mut W, sleep_fn: T) -> device::Result<()>
+where
+    D: Device,
+    W: Write,
+    T: Fn(time::Duration),
+{
+    writeln!(out, "Running self-test...")?;
+    out.flush()?;
+    let r = d.run_self_test(sleep_fn)?;
+    writeln!(
+        out,
+        "Self-test {}: {}/{} good DSP cycles",
+        if r.passed() { "passed" } else { "failed" },
+        r.good_dsp_cycles(),
+        r.total_dsp_cycles()
+    )?;
+    if !r.passed() {
+        return Err(device::Error::from("self-test failed"));
+    }
+    return Ok(());
+}
+
+/// The payload sent by `loopback`. Exercises every bit in both states,
+/// along with the bytes framing is most likely to trip over. The sensor
+/// echoes at most 16 bytes.
+pub const LOOPBACK_PATTERN: [u8; 16] = [
+    0x00, 0xFF, 0x55, 0xAA, 0x01, 0x02, 0x04, 0x08, 0x10, 0x20, 0x40, 0x80, 0xFE, 0x7F, 0x0F, 0xF0,
+];
+
+/// Check the connection to the sensor end to end, with its Loopback
+/// command: the sensor must echo `LOOPBACK_PATTERN` back unchanged.
+pub fn loopback<D: Device, W: Write>(d: &mut D, out: &mut W) -> device::Result<()> {
+    let sent = LOOPBACK_PATTERN.to_vec();
+    let wire::response::Loopback(got) = d.execute(wire::command::Loopback(sent.clone()))?;
+    if got != sent {
+        return Err(device::Error::from(format!(
+            "sent {:02X?} but the sensor echoed {:02X?}, check the baud rate and wiring",
+            sent, got
+        )));
+    }
+    writeln!(out, "Loopback OK: the sensor echoed {} bytes.", sent.len())?;
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryFrom;
+
+    // Scripted is a `Device` that replies to requests from a fixed script,
+    // and acks anything that isn't scripted.
+    struct Scripted {
+        replies: Vec<(wire::Payload, wire::Payload)>,
+        sent: Vec<wire::Payload>,
+    }
+
+    impl Scripted {
+        fn new(replies: Vec<(wire::Payload, wire::Payload)>) -> Scripted {
+            return Scripted {
+                replies: replies,
+                sent: Vec::new(),
+            };
+        }
+    }
+
+    impl Device for Scripted {
+        fn execute<S, T>(&mut self, s: S) -> device::Result<T>
+        where
+            S: Into<wire::Payload>,
+            T: TryFrom<wire::Payload, Error = wire::ParseError>,
+        {
+            let p: wire::Payload = s.into();
+            self.sent.push(p.clone());
+            let r = self
+                .replies
+                .iter()
+                .find(|(req, _)| *req == p)
+                .map(|(_, rep)| rep.clone())
+                .unwrap_or_else(|| wire::response::Ack.into());
+            return Ok(T::try_from(r)?);
+        }
+    }
+
+    fn run<F: FnOnce(&mut Vec<u8>) -> device::Result<()>>(f: F) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        f(&mut out).unwrap();
+        return String::from_utf8(out).unwrap();
+    }
+
+    #[test]
+    fn test_read() {
+        let mut d = Scripted::new(vec![(
+            wire::command::Read(wire::Variable::GasPPM).into(),
+            wire::response::GasPPM::with_ppm(612).into(),
+        )]);
+        assert_eq!(run(|out| read(&mut d, out)), "612 ppm\n");
+
+        let lines = run(|out| watch(&mut d, out, time::Duration::from_secs(5), Some(3), |_| {}));
+        assert_eq!(lines.lines().count(), 3);
+        assert!(lines.lines().all(|l| l.ends_with(" 612 ppm")));
+    }
+
+    #[test]
+    fn test_status() {
+        let mut d = Scripted::new(vec![(
+            wire::command::Status.into(),
+            wire::response::Status::from(wire::response::StatusFlags {
+                in_warmup: true,
+                ..wire::response::StatusFlags::default()
+            })
+            .into(),
+        )]);
+        let got = run(|out| status(&mut d, out));
+        assert!(got.starts_with("Status(.W...)\n"));
+        assert!(got.contains("  Warmup: true\n"));
+        assert!(got.contains("  Error: false\n"));
+    }
+
+    #[test]
+    fn test_set_elevation() {
+        let mut d = Scripted::new(vec![(
+            wire::command::Read(wire::Variable::Elevation).into(),
+            wire::response::Elevation(wire::Distance::Feet(1500)).into(),
+        )]);
+        let got = run(|out| set_elevation(&mut d, out, wire::Distance::Feet(1400)));
+        assert_eq!(got, "1500 ft\n");
+        // The elevation is rounded before being sent to the device.
+        assert_eq!(
+            d.sent[0],
+            wire::command::UpdateElevation(wire::Distance::Feet(1500)).into()
+        );
+    }
+
+    #[test]
+    fn test_abc() {
+        let mut d = Scripted::new(vec![
+            (
+                wire::command::SetABCLogic(wire::Toggle::Off).into(),
+                wire::response::ABCState::Off.into(),
+            ),
+            (
+                wire::command::ABCLogic.into(),
+                wire::response::ABCState::Off.into(),
+            ),
+        ]);
+        assert_eq!(
+            run(|out| set_abc(&mut d, out, wire::Toggle::Off)),
+            "ABC: off\n"
+        );
+        assert_eq!(run(|out| reset_abc(&mut d, out)), "ABC history reset.\n");
+        assert_eq!(
+            d.sent.last(),
+            Some(&wire::Payload::from(wire::command::ResetABCLogic))
+        );
+    }
+
+    #[test]
+    fn test_loopback() {
+        let sim = crate::sim::Simulator::start(crate::sim::Sensor::new(
+            crate::sim::Waveform::Constant(450),
+        ))
+        .unwrap();
+        let mut opts = device::SerialOptions::new(sim.path());
+        opts.timeout = time::Duration::from_secs(5);
+        let mut d = device::T6615::open(&opts).unwrap();
+        assert_eq!(
+            run(|out| loopback(&mut d, out)),
+            "Loopback OK: the sensor echoed 16 bytes.\n"
+        );
+
+        // A sensor that echoes something else fails the check.
+        let mut bad = Scripted::new(vec![(
+            wire::command::Loopback(LOOPBACK_PATTERN.to_vec()).into(),
+            wire::Payload(vec![0x00, 0xFF]),
+        )]);
+        assert!(loopback(&mut bad, &mut Vec::new()).is_err());
+    }
+}
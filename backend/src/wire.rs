@@ -1,11 +1,11 @@
-use std::array;
 use std::convert::{TryFrom, TryInto};
+use std::error;
 use std::fmt;
 use std::ops::Deref;
 use std::result;
 use std::string;
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, Default, PartialEq, Clone)]
 pub struct Payload(pub Vec<u8>);
 
 impl Deref for Payload {
@@ -24,24 +24,39 @@ impl From<Payload> for Vec<u8> {
     }
 }
 
-impl Default for Payload {
-    fn default() -> Payload {
-        Payload(Vec::new())
-    }
-}
-
 #[derive(Debug, PartialEq)]
 pub struct Message(Vec<u8>);
 
-impl From<Payload> for Message {
-    fn from(p: Payload) -> Message {
+/// The flag byte that starts every Tsunami frame.
+pub const FLAG: u8 = 0xFF;
+
+/// The address byte used on frames sent to the sensor.
+pub const REQUEST_ADDRESS: u8 = 0xFE;
+
+/// The address byte used on frames sent by the sensor.
+pub const REPLY_ADDRESS: u8 = 0xFA;
+
+impl Message {
+    fn frame(address: u8, p: Payload) -> Message {
         assert!(p.len() <= (u8::MAX as usize));
-        let bs: Vec<u8> = vec![0xFF, 0xFE, (p.len() as u8)]
+        let bs: Vec<u8> = vec![FLAG, address, (p.len() as u8)]
             .into_iter()
-            .chain(Vec::from(p).into_iter())
+            .chain(Vec::from(p))
             .collect();
         return Message(bs);
     }
+
+    /// Frame `p` as a reply sent by the sensor, rather than a request sent
+    /// to it.
+    pub fn reply(p: Payload) -> Message {
+        return Message::frame(REPLY_ADDRESS, p);
+    }
+}
+
+impl From<Payload> for Message {
+    fn from(p: Payload) -> Message {
+        return Message::frame(REQUEST_ADDRESS, p);
+    }
 }
 
 impl Deref for Message {
@@ -53,6 +68,103 @@ impl Deref for Message {
     }
 }
 
+/// Decoder incrementally re-assembles Tsunami reply frames from a stream of
+/// bytes. Bytes may be fed in arbitrarily sized chunks via `push`, and
+/// complete payloads are pulled out with `next_payload` (or by iterating).
+///
+/// Bytes that cannot be the start of a reply frame (e.g., line noise, or the
+/// tail of a frame that was only partially received) are discarded until the
+/// next `FLAG` byte is found.
+#[derive(Debug)]
+pub struct Decoder {
+    address: u8,
+    buf: Vec<u8>,
+    discarded: usize,
+}
+
+impl Default for Decoder {
+    fn default() -> Decoder {
+        Decoder::new()
+    }
+}
+
+impl Decoder {
+    /// Construct a decoder for frames sent by the sensor.
+    pub fn new() -> Decoder {
+        return Decoder::with_address(REPLY_ADDRESS);
+    }
+
+    /// Construct a decoder for frames with the given address byte.
+    pub fn with_address(address: u8) -> Decoder {
+        return Decoder {
+            address: address,
+            buf: Vec::new(),
+            discarded: 0,
+        };
+    }
+
+    /// Add received bytes to the decoder.
+    pub fn push(&mut self, bs: &[u8]) {
+        self.buf.extend_from_slice(bs);
+    }
+
+    /// Returns the number of bytes that have been skipped while searching
+    /// for the start of a frame.
+    pub fn discarded(&self) -> usize {
+        return self.discarded;
+    }
+
+    /// Returns the number of buffered bytes that have not yet been decoded
+    /// into a payload.
+    pub fn pending(&self) -> usize {
+        return self.buf.len();
+    }
+
+    // Drop bytes from the front of the buffer until it starts with something
+    // that could be a valid frame header.
+    fn resync(&mut self) {
+        loop {
+            let skip = match self.buf.iter().position(|b| *b == FLAG) {
+                Some(idx) => idx,
+                None => self.buf.len(),
+            };
+            self.discarded += skip;
+            self.buf.drain(..skip);
+            // A flag followed by the wrong address is not a frame start, so
+            // drop the flag and keep looking.
+            if self.buf.len() >= 2 && self.buf[1] != self.address {
+                self.discarded += 1;
+                self.buf.remove(0);
+                continue;
+            }
+            return;
+        }
+    }
+
+    /// Decode the next complete payload, if one is available. Returns `None`
+    /// if more bytes are needed.
+    pub fn next_payload(&mut self) -> Option<Payload> {
+        self.resync();
+        if self.buf.len() < 3 {
+            return None;
+        }
+        let length = self.buf[2] as usize;
+        if self.buf.len() < 3 + length {
+            return None;
+        }
+        let body: Vec<u8> = self.buf.drain(..3 + length).skip(3).collect();
+        return Some(Payload(body));
+    }
+}
+
+impl Iterator for Decoder {
+    type Item = Payload;
+
+    fn next(&mut self) -> Option<Payload> {
+        self.next_payload()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub struct Request {
     flag: u8,
@@ -111,44 +223,71 @@ impl Concentration {
     }
 }
 
+/// ParseError is why a payload could not be decoded into a message.
 #[derive(Debug, PartialEq)]
-pub struct ParseError(String);
-
-impl ToString for ParseError {
-    fn to_string(&self) -> String {
-        let ParseError(s) = self;
-        return s.clone();
-    }
+pub enum ParseError {
+    /// The payload is the wrong length for the message.
+    Length { expected: usize, got: usize },
+    /// The payload is longer than the message allows.
+    TooLong { max: usize, got: usize },
+    /// The payload does not start with the message's command bytes.
+    WrongCommand,
+    /// A field of the payload holds a value the message doesn't allow.
+    InvalidValue(String),
+    /// Text in the payload is not valid UTF-8.
+    Utf8(string::FromUtf8Error),
+    /// A date in the payload is malformed.
+    Date(chrono::ParseError),
 }
 
-impl From<String> for ParseError {
-    fn from(s: String) -> ParseError {
-        ParseError(s)
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            ParseError::Length { expected, got } => {
+                write!(f, "payload should be {} bytes, got {}", expected, got)
+            }
+            ParseError::TooLong { max, got } => {
+                write!(f, "payload should be at most {} bytes, got {}", max, got)
+            }
+            ParseError::WrongCommand => write!(f, "payload is for a different command"),
+            ParseError::InvalidValue(s) => s.fmt(f),
+            ParseError::Utf8(_) => write!(f, "utf8 decode error"),
+            ParseError::Date(_) => write!(f, "invalid date"),
+        }
     }
 }
 
-impl From<&str> for ParseError {
-    fn from(s: &str) -> ParseError {
-        ParseError(s.to_string())
+impl error::Error for ParseError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ParseError::Utf8(e) => Some(e),
+            ParseError::Date(e) => Some(e),
+            _ => None,
+        }
     }
 }
 
 impl From<chrono::ParseError> for ParseError {
     fn from(p: chrono::ParseError) -> ParseError {
-        ParseError(format!("chrono parse error: {}", p))
+        ParseError::Date(p)
     }
 }
 
 impl From<string::FromUtf8Error> for ParseError {
     fn from(f: string::FromUtf8Error) -> ParseError {
-        ParseError(format!("utf8 decode error: {}", f))
+        ParseError::Utf8(f)
     }
 }
 
-impl From<array::TryFromSliceError> for ParseError {
-    fn from(t: array::TryFromSliceError) -> ParseError {
-        ParseError(format!("cannot corce slice to array: {}", t))
+// Check that `p` is exactly `expected` bytes long.
+fn check_len(p: &Payload, expected: usize) -> Result<()> {
+    if p.len() != expected {
+        return Err(ParseError::Length {
+            expected: expected,
+            got: p.len(),
+        });
     }
+    return Ok(());
 }
 
 type Result<T> = result::Result<T, ParseError>;
@@ -181,15 +320,11 @@ pub mod command {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<UpdateElevation> {
-            if !p.starts_with(&vec![0x03, 0x0F]) {
-                return Err(ParseError::from(
-                    "invalid command code for update elevation",
-                ));
+            if !p.starts_with(&[0x03, 0x0F]) {
+                return Err(ParseError::WrongCommand);
             }
-            let raw: [u8; 2] = Vec::from(&p[2..])
-                .try_into()
-                .expect("should have two bytes");
-            let value = u16::from_be_bytes(raw);
+            check_len(&p, 4)?;
+            let value = u16::from_be_bytes([p[2], p[3]]);
             return Ok(UpdateElevation(Distance::Feet(value)));
         }
     }
@@ -226,9 +361,7 @@ pub mod command {
 
         fn try_from(p: Payload) -> Result<VerifySinglePointCalibration> {
             if Vec::from(p) != vec![0x02, 0x11] {
-                return Err(ParseError::from(
-                    "wrong command bytes for verify single point calibration",
-                ));
+                return Err(ParseError::WrongCommand);
             }
             return Ok(VerifySinglePointCalibration);
         }
@@ -248,10 +381,11 @@ pub mod command {
     impl TryFrom<Payload> for SetSinglePointPPM {
         type Error = ParseError;
         fn try_from(p: Payload) -> Result<SetSinglePointPPM> {
-            if !p.starts_with(&vec![0x03, 0x11]) {
-                return Err(ParseError::from("incorrect command bytes"));
+            if !p.starts_with(&[0x03, 0x11]) {
+                return Err(ParseError::WrongCommand);
             }
-            let value = u16::from_be_bytes(p[2..].try_into()?);
+            check_len(&p, 4)?;
+            let value = u16::from_be_bytes([p[2], p[3]]);
             return Ok(SetSinglePointPPM(Concentration::PPM(value)));
         }
     }
@@ -323,7 +457,7 @@ pub mod command {
         fn from(l: Loopback) -> Payload {
             let Loopback(vs) = l;
             assert!(vs.len() <= 16);
-            let res: Vec<u8> = vec![0x00].into_iter().chain(vs.into_iter()).collect();
+            let res: Vec<u8> = vec![0x00].into_iter().chain(vs).collect();
             return Payload(res);
         }
     }
@@ -355,6 +489,57 @@ pub mod command {
         }
     }
 
+    /// Info describes a command, as far as sending it is concerned.
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub struct Info {
+        /// The command's name, e.g., for logs and metrics.
+        pub name: &'static str,
+        /// Whether sending the command twice does the same as sending it
+        /// once, so it's safe to send again if the reply is lost.
+        pub idempotent: bool,
+        /// How long the reply to the command is, if that's known up front.
+        pub reply_len: Option<usize>,
+    }
+
+    impl Info {
+        fn new(name: &'static str, idempotent: bool, reply_len: Option<usize>) -> Info {
+            return Info {
+                name: name,
+                idempotent: idempotent,
+                reply_len: reply_len,
+            };
+        }
+    }
+
+    /// Describe the command encoded in `p`. Commands that start something
+    /// on the device, e.g., `StartSinglePointCalibration`, aren't
+    /// idempotent, and neither is anything unrecognized.
+    pub fn info(p: &Payload) -> Info {
+        return match p.as_slice() {
+            [0x02, 0x03] => Info::new("Read(GasPPM)", true, Some(2)),
+            [0x02, 0x01] => Info::new("Read(SerialNumber)", true, None),
+            [0x02, 0x0D] => Info::new("Read(CompileSubvol)", true, Some(3)),
+            [0x02, 0x0C] => Info::new("Read(CompileDate)", true, Some(6)),
+            [0x02, 0x0F] => Info::new("Read(Elevation)", true, Some(2)),
+            [0x02, 0x11] => Info::new("VerifySinglePointCalibration", true, Some(2)),
+            [0x03, 0x0F, _, _] => Info::new("UpdateElevation", true, Some(0)),
+            [0x03, 0x11, _, _] => Info::new("SetSinglePointPPM", true, Some(0)),
+            [0x84] => Info::new("Warmup", false, Some(0)),
+            [0x9B] => Info::new("StartSinglePointCalibration", false, Some(0)),
+            [0xB6] => Info::new("Status", true, Some(1)),
+            [0xB9, _] => Info::new("Idle", true, Some(0)),
+            [0xB7, 0x00] => Info::new("ABCLogic", true, Some(1)),
+            [0xB7, 0x01] | [0xB7, 0x02] => Info::new("SetABCLogic", true, Some(1)),
+            [0xB7, 0x03] => Info::new("ResetABCLogic", true, Some(0)),
+            [0x95] => Info::new("Halt", true, Some(0)),
+            [0x00, args @ ..] => Info::new("Loopback", true, Some(args.len())),
+            [0xC0, 0x00] => Info::new("StartSelfTest", false, Some(0)),
+            [0xC0, 0x01] => Info::new("SelfTestResults", true, Some(4)),
+            [0xBD] => Info::new("StreamData", false, None),
+            _ => Info::new("Unknown", false, None),
+        };
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -482,6 +667,29 @@ pub mod command {
         fn test_stream_data() {
             assert_eq!(Payload::from(command::StreamData), Payload(vec![0xBD]));
         }
+
+        #[test]
+        fn test_info() {
+            let status = info(&Payload::from(command::Status));
+            assert_eq!(status.name, "Status");
+            assert!(status.idempotent);
+            assert_eq!(status.reply_len, Some(1));
+
+            let calibrate = info(&Payload::from(command::StartSinglePointCalibration));
+            assert_eq!(calibrate.name, "StartSinglePointCalibration");
+            assert!(!calibrate.idempotent);
+
+            let elevation = info(&Payload::from(command::UpdateElevation(Distance::Feet(
+                500,
+            ))));
+            assert_eq!(elevation.name, "UpdateElevation");
+            assert!(elevation.idempotent);
+
+            let loopback = info(&Payload::from(command::Loopback(vec![0x01, 0x02])));
+            assert_eq!(loopback.reply_len, Some(2));
+
+            assert!(!info(&Payload(vec![0xEE])).idempotent);
+        }
     }
 }
 
@@ -495,9 +703,7 @@ pub mod response {
     impl TryFrom<Payload> for Ack {
         type Error = ParseError;
         fn try_from(p: Payload) -> Result<Ack> {
-            if p.len() != 0 {
-                return Err(ParseError::from("payload not empty"));
-            }
+            check_len(&p, 0)?;
             return Ok(Ack);
         }
     }
@@ -525,9 +731,7 @@ pub mod response {
     impl TryFrom<Payload> for GasPPM {
         type Error = ParseError;
         fn try_from(p: Payload) -> Result<GasPPM> {
-            if p.len() != 2 {
-                return Err(ParseError::from("payload should consist of 2 bytes"));
-            }
+            check_len(&p, 2)?;
             let raw: [u8; 2] = Vec::from(p).try_into().expect("as per assertion");
             let value = u16::from_be_bytes(raw);
             return Ok(GasPPM(Concentration::PPM(value)));
@@ -541,6 +745,38 @@ pub mod response {
         }
     }
 
+    /// A single measurement pushed by the device while it is in stream mode
+    /// (see `command::StreamData`).
+    #[derive(Debug, PartialEq)]
+    pub struct StreamData(Concentration);
+
+    impl StreamData {
+        pub fn with_ppm(p: u16) -> StreamData {
+            StreamData(Concentration::PPM(p))
+        }
+
+        pub fn concentration(&self) -> Concentration {
+            let StreamData(c) = self;
+            return *c;
+        }
+    }
+
+    impl TryFrom<Payload> for StreamData {
+        type Error = ParseError;
+        fn try_from(p: Payload) -> Result<StreamData> {
+            check_len(&p, 2)?;
+            let value = u16::from_be_bytes([p[0], p[1]]);
+            return Ok(StreamData(Concentration::PPM(value)));
+        }
+    }
+
+    impl From<StreamData> for Payload {
+        fn from(s: StreamData) -> Payload {
+            let bytes: [u8; 2] = s.concentration().ppm().to_be_bytes();
+            return Payload(Vec::from(bytes));
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     pub struct SerialNumber(String);
 
@@ -555,7 +791,10 @@ pub mod response {
 
         fn try_from(p: Payload) -> Result<SerialNumber> {
             if p.len() > 15 {
-                return Err(ParseError::from("payload should have 15 bytes"));
+                return Err(ParseError::TooLong {
+                    max: 15,
+                    got: p.len(),
+                });
             }
             let bytes: Vec<u8> = Vec::from(p).into_iter().take_while(|v| *v != 0x0).collect();
             return Ok(SerialNumber(String::from_utf8(bytes)?));
@@ -575,9 +814,7 @@ pub mod response {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<CompileSubvol> {
-            if p.len() != 3 {
-                return Err(ParseError::from("invalid subvol"));
-            }
+            check_len(&p, 3)?;
             return Ok(CompileSubvol(String::from_utf8(Vec::from(p))?));
         }
     }
@@ -595,9 +832,7 @@ pub mod response {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<CompileDate> {
-            if p.len() != 6 {
-                return Err(ParseError::from("invalid date length"));
-            }
+            check_len(&p, 6)?;
             let date_raw: String = String::from_utf8(p.into())?;
             let date = chrono::NaiveDate::parse_from_str(&date_raw, "%y%m%d")?;
             return Ok(CompileDate(date));
@@ -611,9 +846,7 @@ pub mod response {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<Elevation> {
-            if p.len() != 2 {
-                return Err(ParseError::from("elevation should be 2 bytes"));
-            }
+            check_len(&p, 2)?;
             // Should always succeed due to preceeding length check.
             let num = u16::from_be_bytes(Vec::from(p).try_into().unwrap());
             return Ok(Elevation(Distance::Feet(num)));
@@ -628,7 +861,7 @@ pub mod response {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone, Copy)]
     pub struct Status {
         v: u8,
     }
@@ -690,9 +923,7 @@ pub mod response {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<Status> {
-            if p.len() != 1 {
-                return Err(ParseError::from("status should be a single byte"));
-            }
+            check_len(&p, 1)?;
             return Ok(Status { v: p[0] });
         }
     }
@@ -742,7 +973,7 @@ pub mod response {
         }
     }
 
-    #[derive(Debug, PartialEq)]
+    #[derive(Debug, PartialEq, Clone, Copy)]
     pub enum ABCState {
         On,
         Off,
@@ -752,13 +983,11 @@ pub mod response {
         type Error = ParseError;
 
         fn try_from(p: Payload) -> Result<ABCState> {
-            if p.len() != 1 {
-                return Err(ParseError::from("ABC state should be a single byte"));
-            }
+            check_len(&p, 1)?;
             match p[0] {
                 0x1 => Ok(ABCState::On),
                 0x2 => Ok(ABCState::Off),
-                unk => Err(ParseError::from(format!(
+                unk => Err(ParseError::InvalidValue(format!(
                     "ABC State {:#X} not recognized",
                     unk
                 ))),
@@ -766,6 +995,15 @@ pub mod response {
         }
     }
 
+    impl From<ABCState> for Payload {
+        fn from(a: ABCState) -> Payload {
+            match a {
+                ABCState::On => Payload(vec![0x1]),
+                ABCState::Off => Payload(vec![0x2]),
+            }
+        }
+    }
+
     #[derive(Debug, PartialEq)]
     pub struct Loopback(pub Vec<u8>);
 
@@ -779,20 +1017,21 @@ pub mod response {
         }
     }
 
-    #[derive(Debug, PartialEq)]
-    enum SelfTestStatus {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum SelfTestStatus {
         Unknown,
         Ok,
     }
 
-    #[derive(Debug, PartialEq)]
-    enum TestResult {
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum TestResult {
         Pass,
         Fail,
     }
 
-    #[derive(Debug, PartialEq)]
-    struct SelfTest {
+    /// The result of a device self-test. See `command::SelfTestResults`.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct SelfTest {
         status: SelfTestStatus,
         result: TestResult,
         good_dsp: u8,
@@ -800,12 +1039,26 @@ pub mod response {
     }
 
     impl SelfTest {
+        /// Returns `true` if the device reported a passing self-test, and
+        /// every DSP cycle in the test was good.
         pub fn passed(&self) -> bool {
             return self.status == SelfTestStatus::Ok
                 && self.result == TestResult::Pass
                 && self.good_dsp == self.total_dsp;
         }
 
+        pub fn status(&self) -> SelfTestStatus {
+            return self.status;
+        }
+
+        pub fn result(&self) -> TestResult {
+            return self.result;
+        }
+
+        pub fn good_dsp_cycles(&self) -> u8 {
+            return self.good_dsp;
+        }
+
         pub fn total_dsp_cycles(&self) -> u8 {
             return self.total_dsp;
         }
@@ -814,9 +1067,7 @@ pub mod response {
     impl TryFrom<Payload> for SelfTest {
         type Error = ParseError;
         fn try_from(p: Payload) -> Result<SelfTest> {
-            if p.len() != 4 {
-                return Err(ParseError::from("expected exactly 4 bytes"));
-            }
+            check_len(&p, 4)?;
             let flag = match p[0] {
                 0x0F => SelfTestStatus::Ok,
                 _ => SelfTestStatus::Unknown,
@@ -825,7 +1076,7 @@ pub mod response {
                 0x01 => TestResult::Pass,
                 0x00 => TestResult::Fail,
                 unk => {
-                    return Err(ParseError::from(format!(
+                    return Err(ParseError::InvalidValue(format!(
                         "unrecognized test result {:#X}",
                         unk
                     )))
@@ -840,6 +1091,20 @@ pub mod response {
         }
     }
 
+    impl From<SelfTest> for Payload {
+        fn from(s: SelfTest) -> Payload {
+            let status = match s.status {
+                SelfTestStatus::Ok => 0x0F,
+                SelfTestStatus::Unknown => 0x00,
+            };
+            let result = match s.result {
+                TestResult::Pass => 0x01,
+                TestResult::Fail => 0x00,
+            };
+            return Payload(vec![status, result, s.good_dsp, s.total_dsp]);
+        }
+    }
+
     #[cfg(test)]
     mod tests {
         use super::*;
@@ -870,6 +1135,15 @@ pub mod response {
             );
         }
 
+        #[test]
+        fn test_stream_data() {
+            assert_eq!(
+                StreamData::try_from(Payload(vec![0x02, 0x58])),
+                Ok(StreamData(Concentration::PPM(600))),
+            );
+            assert!(StreamData::try_from(Payload(vec![0x02])).is_err());
+        }
+
         #[test]
         fn test_serial_number() {
             assert_eq!(
@@ -944,6 +1218,28 @@ pub mod response {
             );
         }
 
+        #[test]
+        fn test_parse_errors() {
+            assert_eq!(
+                GasPPM::try_from(Payload(vec![0x01])),
+                Err(ParseError::Length {
+                    expected: 2,
+                    got: 1
+                }),
+            );
+            assert_eq!(
+                SerialNumber::try_from(Payload(vec![b'x'; 16])),
+                Err(ParseError::TooLong {
+                    max: 16 - 1,
+                    got: 16
+                }),
+            );
+            // The underlying error is kept as the source.
+            let e = CompileSubvol::try_from(Payload(vec![0xC3, 0x28, b'A'])).unwrap_err();
+            assert!(matches!(e, ParseError::Utf8(_)));
+            assert!(error::Error::source(&e).is_some());
+        }
+
         #[test]
         fn test_self_test() {
             assert!(SelfTest::try_from(Payload(vec![0x0F, 0x01, 12, 12]))
@@ -969,3 +1265,67 @@ pub mod response {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decoder_single_frame() {
+        let mut d = Decoder::new();
+        d.push(&[0xFF, 0xFA, 0x02, 0x01, 0x90]);
+        assert_eq!(d.next_payload(), Some(Payload(vec![0x01, 0x90])));
+        assert_eq!(d.next_payload(), None);
+        assert_eq!(d.discarded(), 0);
+    }
+
+    #[test]
+    fn test_decoder_empty_payload() {
+        let mut d = Decoder::new();
+        d.push(&[0xFF, 0xFA, 0x00]);
+        assert_eq!(d.next_payload(), Some(Payload::default()));
+    }
+
+    #[test]
+    fn test_decoder_chunked() {
+        let mut d = Decoder::new();
+        // Feed the frame one byte at a time, a payload should only come out
+        // once the last byte has arrived.
+        let frame = [0xFF, 0xFA, 0x03, 0x0A, 0x0B, 0x0C];
+        for b in &frame[..frame.len() - 1] {
+            d.push(&[*b]);
+            assert_eq!(d.next_payload(), None);
+        }
+        d.push(&frame[frame.len() - 1..]);
+        assert_eq!(d.next_payload(), Some(Payload(vec![0x0A, 0x0B, 0x0C])));
+    }
+
+    #[test]
+    fn test_decoder_resync() {
+        let mut d = Decoder::new();
+        // Leading garbage, a flag with a bad address, and then two
+        // back-to-back frames.
+        d.push(&[0x00, 0x12, 0xFF, 0x01, 0xFF, 0xFF, 0xFA, 0x01, 0xB6]);
+        d.push(&[0xFF, 0xFA, 0x01, 0x02]);
+        let got: Vec<Payload> = d.by_ref().collect();
+        assert_eq!(got, vec![Payload(vec![0xB6]), Payload(vec![0x02])]);
+        assert_eq!(d.discarded(), 5);
+        assert_eq!(d.pending(), 0);
+    }
+
+    #[test]
+    fn test_decoder_request_address() {
+        let mut d = Decoder::with_address(REQUEST_ADDRESS);
+        d.push(&Message::from(Payload::from(command::Status)));
+        assert_eq!(d.next_payload(), Some(Payload::from(command::Status)));
+    }
+
+    #[test]
+    fn test_message_reply() {
+        let reply = Message::reply(Payload(vec![0x01, 0x90]));
+        assert_eq!(*reply, vec![0xFF, 0xFA, 0x02, 0x01, 0x90]);
+        let mut d = Decoder::new();
+        d.push(&reply);
+        assert_eq!(d.next_payload(), Some(Payload(vec![0x01, 0x90])));
+    }
+}